@@ -0,0 +1,18 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use process_tx::engine::Engine;
+use process_tx::transaction::Transaction;
+
+fn fork_100k_transactions(c: &mut Criterion) {
+    let mut engine = Engine::with_capacity(1_000, 100_000);
+    for tx in 1..=100_000u32 {
+        let client = u16::try_from(tx % 1_000).expect("Client out of range");
+        engine
+            .process_transaction(Transaction::deposit(client, tx, 1.0))
+            .expect("Failed to process deposit");
+    }
+
+    c.bench_function("fork 100k transactions", |b| b.iter(|| engine.fork()));
+}
+
+criterion_group!(benches, fork_100k_transactions);
+criterion_main!(benches);
@@ -0,0 +1,28 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use process_tx::engine::Engine;
+use process_tx::report::{self, Options};
+use process_tx::transaction::Transaction;
+use std::io;
+
+fn report_60k_accounts(c: &mut Criterion) {
+    let mut engine = Engine::new();
+    for client in 0..60_000u16 {
+        engine
+            .process_transaction(Transaction::deposit(
+                client,
+                u32::from(client),
+                f64::from(client) + 1.6234,
+            ))
+            .expect("Failed to process deposit");
+    }
+
+    c.bench_function("report 60k accounts", |b| {
+        b.iter(|| {
+            report::write_accounts(io::sink(), engine.accounts_by_client(), &Options::default())
+                .expect("Failed to write report");
+        });
+    });
+}
+
+criterion_group!(benches, report_60k_accounts);
+criterion_main!(benches);
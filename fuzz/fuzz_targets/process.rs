@@ -0,0 +1,22 @@
+#![no_main]
+
+use arbitrary::Unstructured;
+use libfuzzer_sys::fuzz_target;
+use process_tx::engine::Engine;
+use process_tx::transaction::Transaction;
+
+// Interpret the input as a sequence of transactions and check that no
+// sequence, accepted or rejected, leaves the engine in an inconsistent state.
+fuzz_target!(|data: &[u8]| {
+    let mut u = Unstructured::new(data);
+    let mut engine = Engine::new();
+    while !u.is_empty() {
+        let Ok(transaction) = u.arbitrary::<Transaction>() else {
+            return;
+        };
+        let _ = engine.process_transaction(transaction);
+        if let Err(violation) = engine.check_invariants() {
+            panic!("invariant violated: {violation}");
+        }
+    }
+});
@@ -1,15 +1,96 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Default, Serialize)]
+/// Why an account was locked, which decides whether the lock can be lifted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LockReason {
+    /// Locked by a chargeback; permanent.
+    Chargeback,
+    /// Frozen by an operator; can be lifted with an unfreeze.
+    Administrative,
+    /// Frozen on suspicion of fraud; permanent.
+    Fraud,
+}
+
+impl LockReason {
+    /// Whether an unfreeze may lift a lock with this reason.
+    #[must_use]
+    pub fn is_reversible(self) -> bool {
+        match self {
+            LockReason::Administrative => true,
+            LockReason::Chargeback | LockReason::Fraud => false,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Account {
     pub client: u16,
     pub available: f64,
     pub held: f64,
+    // Deposits waiting for a settle row: counted in `total`, not `available`
+    #[serde(default)]
+    pub pending: f64,
     pub total: f64,
     pub locked: bool,
+    // Why the account is locked, set whenever `locked` is true
+    pub lock_reason: Option<LockReason>,
+    // Number of deposits currently under dispute
+    pub open_disputes: u32,
+    // Disputed amounts that could not be held because the funds were gone
+    #[serde(default)]
+    pub dispute_shortfall: f64,
+    // Id of the last transaction successfully applied to the account
+    #[serde(default)]
+    pub last_tx: Option<u32>,
+    // Number of transactions successfully applied to the account
+    #[serde(default)]
+    pub applied: u64,
+    // Highest total and held funds reached after any applied transaction
+    #[serde(default)]
+    pub peak_total: f64,
+    #[serde(default)]
+    pub peak_held: f64,
+    // Funds removed by chargebacks so far
+    #[serde(default)]
+    pub chargeback_losses: f64,
 }
 
 impl Account {
+    /// Lock the account for the given reason.
+    pub fn lock(&mut self, reason: LockReason) {
+        self.locked = true;
+        self.lock_reason = Some(reason);
+    }
+
+    /// Raise the peak balances to the current ones where they are higher.
+    pub fn record_peaks(&mut self) {
+        self.peak_total = self.peak_total.max(self.total);
+        self.peak_held = self.peak_held.max(self.held);
+    }
+
+    /// Whether no transaction was ever applied to the account and it never
+    /// held funds, so that it only exists because of rejected transactions.
+    ///
+    /// The last transaction and the peak total are checked along with the
+    /// count, which snapshots saved before it existed restore as zero, and
+    /// which fee credits leave unchanged.
+    #[must_use]
+    pub fn is_inactive(&self) -> bool {
+        self.applied == 0 && self.last_tx.is_none() && self.peak_total == 0.0
+    }
+
+    /// Whether the account is unlocked and every balance would be reported as
+    /// zero.
+    #[must_use]
+    pub fn is_zero(&self) -> bool {
+        !self.locked
+            && [self.available, self.held, self.pending, self.total]
+                .into_iter()
+                .all(|value| truncate(value) == 0.0)
+    }
+
+    #[must_use]
     pub fn formatted_values(&self) -> (String, String, String, bool) {
         (
             Self::format_value(self.available),
@@ -19,35 +100,160 @@ impl Account {
         )
     }
 
-    // Truncate to four decimal places by scaling and converting to integer
-    fn format_value(value: f64) -> String {
-        // Truncate to four decimal places
-        let truncated = (value * 10_000.0).trunc() / 10_000.0;
+    /// Render an amount truncated to four decimal places, with as few
+    /// decimals as needed (at least one).
+    #[must_use]
+    pub fn format_value(value: f64) -> String {
+        let mut rendered = Self::format_value_fixed(value);
+        let kept = rendered.trim_end_matches('0').len();
+        let point = rendered.find('.').unwrap_or(rendered.len());
+        rendered.truncate(kept.max(point + 2));
+        rendered
+    }
 
-        // Conditional formatting based on fractional part
-        if (truncated * 10.0).fract() == 0.0 {
-            format!("{truncated:.1}")
-        } else if (truncated * 100.0).fract() == 0.0 {
-            format!("{truncated:.2}")
-        } else if (truncated * 1000.0).fract() == 0.0 {
-            format!("{truncated:.3}")
+    /// Render an amount truncated to exactly four decimal places.
+    #[must_use]
+    pub fn format_value_fixed(value: f64) -> String {
+        if !value.is_finite() {
+            return format!("{value:.4}");
+        }
+        // Decimals are cut from the shortest decimal rendering rather than
+        // by scaling the float, which turns values such as 0.57 or 1.005,
+        // that have no exact binary representation, into 0.5699 or 1.0049
+        let shortest = value.to_string();
+        let (integer, fraction) = shortest.split_once('.').unwrap_or((&shortest, ""));
+        let fixed = format!("{integer}.{:0<4}", &fraction[..fraction.len().min(4)]);
+        if fixed.bytes().all(|byte| matches!(byte, b'-' | b'0' | b'.')) {
+            "0.0000".to_string()
         } else {
-            format!("{truncated:.4}")
+            fixed
+        }
+    }
+}
+
+// Truncate an amount to four decimal places, as shown in the report
+fn truncate(value: f64) -> f64 {
+    Account::format_value_fixed(value).parse().unwrap_or(value)
+}
+
+/// Read-only view of an account for library consumers, with amounts
+/// truncated to four decimal places like the report.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AccountView {
+    pub client: u16,
+    pub available: f64,
+    pub held: f64,
+    pub pending: f64,
+    pub total: f64,
+    pub locked: bool,
+    pub has_open_disputes: bool,
+    pub open_disputes: u32,
+    /// Highest total funds reached during the run.
+    pub peak_total: f64,
+    /// Highest held funds reached during the run.
+    pub peak_held: f64,
+}
+
+impl From<&Account> for AccountView {
+    fn from(account: &Account) -> Self {
+        AccountView {
+            client: account.client,
+            available: truncate(account.available),
+            held: truncate(account.held),
+            pending: truncate(account.pending),
+            total: truncate(account.total),
+            locked: account.locked,
+            has_open_disputes: account.open_disputes > 0,
+            open_disputes: account.open_disputes,
+            peak_total: truncate(account.peak_total),
+            peak_held: truncate(account.peak_held),
         }
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::float_cmp)]
 mod tests {
     use super::*; // Import all from the current module
 
+    #[test]
+    fn test_lock_reason_reversibility() {
+        assert!(LockReason::Administrative.is_reversible());
+        assert!(!LockReason::Chargeback.is_reversible());
+        assert!(!LockReason::Fraud.is_reversible());
+    }
+
+    #[test]
+    fn test_is_zero() {
+        let mut account = Account {
+            client: 1,
+            available: 0.000_01,
+            ..Default::default()
+        };
+        assert!(account.is_zero());
+        account.held = 0.5;
+        assert!(!account.is_zero());
+        account.held = 0.0;
+        account.lock(LockReason::Chargeback);
+        assert!(!account.is_zero());
+    }
+
+    #[test]
+    fn test_is_inactive() {
+        let mut account = Account {
+            client: 1,
+            ..Default::default()
+        };
+        assert!(account.is_inactive());
+        // Restored from a snapshot saved before the count of applied
+        // transactions existed
+        account.last_tx = Some(4);
+        assert!(!account.is_inactive());
+        // Only credited with fees
+        account.last_tx = None;
+        account.peak_total = 2.5;
+        assert!(!account.is_inactive());
+    }
+
     #[test]
     fn test_format_value_truncation() {
-        assert_eq!(Account::format_value(1000.9999999), "1000.9999");
-        assert_eq!(Account::format_value(1000.12345), "1000.1234");
+        assert_eq!(Account::format_value(1_000.999_999_9), "1000.9999");
+        assert_eq!(Account::format_value(1_000.123_45), "1000.1234");
         assert_eq!(Account::format_value(1000.1), "1000.1");
         assert_eq!(Account::format_value(1000.12), "1000.12");
         assert_eq!(Account::format_value(500.0), "500.0");
-        assert_eq!(Account::format_value(-123.456789), "-123.4567");
+        assert_eq!(Account::format_value(-123.456_789), "-123.4567");
+    }
+
+    // Test that values without an exact binary representation keep their
+    // minimal decimals
+    #[test]
+    fn test_format_value_inexact_decimals() {
+        assert_eq!(Account::format_value(0.1), "0.1");
+        assert_eq!(Account::format_value(0.3), "0.3");
+        assert_eq!(Account::format_value(0.7), "0.7");
+        assert_eq!(Account::format_value(0.12), "0.12");
+        assert_eq!(Account::format_value(0.1 + 0.2), "0.3");
+        assert_eq!(Account::format_value(-0.7), "-0.7");
+        assert_eq!(Account::format_value(0.0), "0.0");
+        assert_eq!(Account::format_value(0.57), "0.57");
+        assert_eq!(Account::format_value(1.005), "1.005");
+        assert_eq!(Account::format_value(-0.000_01), "0.0");
+        let account = Account {
+            available: 0.57,
+            ..Default::default()
+        };
+        assert_eq!(AccountView::from(&account).available, 0.57);
+    }
+
+    #[test]
+    fn test_format_value_fixed() {
+        assert_eq!(Account::format_value_fixed(1_000.999_999_9), "1000.9999");
+        assert_eq!(Account::format_value_fixed(1000.1), "1000.1000");
+        assert_eq!(Account::format_value_fixed(500.0), "500.0000");
+        assert_eq!(Account::format_value_fixed(-1.5), "-1.5000");
+        assert_eq!(Account::format_value_fixed(-123.456_789), "-123.4567");
+        assert_eq!(Account::format_value_fixed(0.57), "0.5700");
+        assert_eq!(Account::format_value_fixed(1.005), "1.0050");
     }
 }
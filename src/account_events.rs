@@ -0,0 +1,147 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+
+use process_tx::account::{Account, LockReason};
+use process_tx::event::{Event, EventHook};
+use process_tx::transaction::Amount;
+
+// Lifecycle event waiting to be written
+#[derive(Debug, Clone, Copy)]
+enum Row {
+    Opened {
+        client: u16,
+        tx: u32,
+    },
+    Locked {
+        client: u16,
+        tx: Option<u32>,
+        reason: LockReason,
+        charged_back: Amount,
+    },
+    Unlocked {
+        client: u16,
+    },
+}
+
+/// Event hook queuing the lifecycle events of the accounts for the
+/// [`AccountEvents`] file it was created with.
+pub struct Collector(Rc<RefCell<Vec<Row>>>);
+
+impl EventHook for Collector {
+    fn on_event(&mut self, event: &Event<'_>) {
+        let row = match *event {
+            Event::AccountOpened { client, tx } => Row::Opened { client, tx },
+            Event::AccountLocked {
+                client,
+                tx,
+                reason,
+                charged_back,
+            } => Row::Locked {
+                client,
+                tx,
+                reason,
+                charged_back,
+            },
+            Event::AccountUnlocked { client } => Row::Unlocked { client },
+            Event::Applied { .. } | Event::Rejected { .. } => return,
+        };
+        self.0.borrow_mut().push(row);
+    }
+}
+
+/// CSV file of the accounts opened, locked and unlocked during the run,
+/// written with `--account-events` in the order the engine reported them.
+///
+/// The `tx` column is the id of the row that opened or locked the account,
+/// empty for a lock or unlock made by an operator. `charged_back` is the
+/// amount charged back by the row locking the account.
+pub struct AccountEvents<W: Write> {
+    writer: csv::Writer<W>,
+    queue: Rc<RefCell<Vec<Row>>>,
+}
+
+impl<W: Write> AccountEvents<W> {
+    /// Start the file with its header, returning the hook to register on the
+    /// engine.
+    pub fn new(writer: W) -> csv::Result<(Self, Collector)> {
+        let mut writer = csv::Writer::from_writer(writer);
+        writer.write_record(["event", "client", "tx", "reason", "charged_back"])?;
+        let queue = Rc::new(RefCell::new(Vec::new()));
+        let collector = Collector(Rc::clone(&queue));
+        Ok((AccountEvents { writer, queue }, collector))
+    }
+
+    /// Write the events collected since the last call.
+    pub fn write(&mut self) -> csv::Result<()> {
+        for row in self.queue.borrow_mut().drain(..) {
+            let (event, client, tx, reason, charged_back) = match row {
+                Row::Opened { client, tx } => ("opened", client, Some(tx), None, None),
+                Row::Locked {
+                    client,
+                    tx,
+                    reason,
+                    charged_back,
+                } => ("locked", client, tx, Some(reason), Some(charged_back)),
+                Row::Unlocked { client } => ("unlocked", client, None, None, None),
+            };
+            let reason = reason.map_or("", |reason| match reason {
+                LockReason::Chargeback => "chargeback",
+                LockReason::Administrative => "administrative",
+                LockReason::Fraud => "fraud",
+            });
+            self.writer.write_record([
+                event,
+                &client.to_string(),
+                &tx.map(|tx| tx.to_string()).unwrap_or_default(),
+                reason,
+                &charged_back.map(Account::format_value).unwrap_or_default(),
+            ])?;
+        }
+        Ok(())
+    }
+
+    /// Write the events still queued, such as those of a row processing was
+    /// aborted on, and flush the file.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.write()?;
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_account_events_in_event_order() {
+        let (mut events, mut collector) =
+            AccountEvents::new(Vec::new()).expect("Failed to write header");
+        collector.on_event(&Event::AccountOpened { client: 1, tx: 1 });
+        collector.on_event(&Event::AccountLocked {
+            client: 1,
+            tx: Some(1),
+            reason: LockReason::Chargeback,
+            charged_back: 12.5,
+        });
+        events.write().expect("Failed to write events");
+        collector.on_event(&Event::AccountLocked {
+            client: 2,
+            tx: None,
+            reason: LockReason::Administrative,
+            charged_back: 0.0,
+        });
+        collector.on_event(&Event::AccountUnlocked { client: 2 });
+        events.write().expect("Failed to write events");
+
+        let output = events.writer.into_inner().expect("Failed to flush events");
+        assert_eq!(
+            String::from_utf8(output).expect("Events not valid UTF-8"),
+            "event,client,tx,reason,charged_back\n\
+             opened,1,1,,\n\
+             locked,1,1,chargeback,12.5\n\
+             locked,2,,administrative,0.0\n\
+             unlocked,2,,,\n"
+        );
+    }
+}
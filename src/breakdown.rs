@@ -0,0 +1,154 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+// Clients tracked at most before the ones with the fewest errors are evicted
+const DEFAULT_CAPACITY: usize = 4_096;
+
+/// Rejection counts of a client, per error code.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ClientErrors {
+    pub total: u64,
+    pub by_code: BTreeMap<&'static str, u64>,
+}
+
+/// Bounded per-client histogram of rejected transactions, printed with
+/// `--error-breakdown`.
+///
+/// At most `capacity` clients are tracked. When a new client would exceed
+/// it, the half of the tracked clients with the fewest errors is evicted in
+/// one pass, so the cost of eviction is amortized over the following
+/// insertions. The counts of a client evicted and seen again start over, so
+/// they are lower bounds, but clients with many errors stay tracked as long as
+/// they outnumber the errors of the evicted ones.
+pub struct ErrorBreakdown {
+    top: usize,
+    capacity: usize,
+    clients: HashMap<u16, ClientErrors>,
+}
+
+impl ErrorBreakdown {
+    /// Breakdown reporting the `top` clients with the most errors.
+    pub fn new(top: usize) -> Self {
+        Self::with_capacity(top, DEFAULT_CAPACITY)
+    }
+
+    fn with_capacity(top: usize, capacity: usize) -> Self {
+        ErrorBreakdown {
+            top,
+            // Never evict the clients about to be reported
+            capacity: capacity.max(top.saturating_mul(2)).max(1),
+            clients: HashMap::new(),
+        }
+    }
+
+    /// Count a rejection with error `code` for `client`.
+    pub fn record(&mut self, client: u16, code: &'static str) {
+        if !self.clients.contains_key(&client) && self.clients.len() >= self.capacity {
+            self.evict();
+        }
+        let errors = self.clients.entry(client).or_default();
+        errors.total += 1;
+        *errors.by_code.entry(code).or_default() += 1;
+    }
+
+    // Drop the half of the tracked clients with the fewest errors
+    fn evict(&mut self) {
+        let mut totals: Vec<u64> = self.clients.values().map(|errors| errors.total).collect();
+        let middle = totals.len() / 2;
+        let (_, &mut threshold, _) = totals.select_nth_unstable(middle);
+        self.clients.retain(|_, errors| errors.total > threshold);
+    }
+
+    /// Tracked clients with the most errors, by error count descending then
+    /// client id.
+    pub fn top(&self) -> Vec<(u16, &ClientErrors)> {
+        let mut clients: Vec<(u16, &ClientErrors)> = self
+            .clients
+            .iter()
+            .map(|(&client, errors)| (client, errors))
+            .collect();
+        clients.sort_unstable_by(|a, b| b.1.total.cmp(&a.1.total).then(a.0.cmp(&b.0)));
+        clients.truncate(self.top);
+        clients
+    }
+
+    /// Table of the [`ErrorBreakdown::top`] clients, one line per client.
+    pub fn table(&self) -> String {
+        let top = self.top();
+        let mut table = format!("Errors by client (top {}):\n", top.len());
+        let _ = writeln!(table, "{:>6} {:>10}  codes", "client", "errors");
+        for (client, errors) in top {
+            let codes: Vec<String> = errors
+                .by_code
+                .iter()
+                .map(|(code, count)| format!("{code}={count}"))
+                .collect();
+            let _ = writeln!(
+                table,
+                "{client:>6} {:>10}  {}",
+                errors.total,
+                codes.join(" ")
+            );
+        }
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test that the client with most errors tops the breakdown with exact counts
+    #[test]
+    fn test_breakdown_orders_clients_by_errors() {
+        let mut breakdown = ErrorBreakdown::new(2);
+        for client in 1..=10 {
+            breakdown.record(client, "E_TX_NOT_FOUND");
+        }
+        for _ in 0..30 {
+            breakdown.record(7, "E_INSUFFICIENT_FUNDS");
+        }
+        for _ in 0..5 {
+            breakdown.record(7, "E_ACCOUNT_LOCKED");
+            breakdown.record(3, "E_ACCOUNT_LOCKED");
+        }
+
+        let top = breakdown.top();
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].0, 7);
+        assert_eq!(top[0].1.total, 36);
+        assert_eq!(
+            top[0].1.by_code,
+            BTreeMap::from([
+                ("E_ACCOUNT_LOCKED", 5),
+                ("E_INSUFFICIENT_FUNDS", 30),
+                ("E_TX_NOT_FOUND", 1),
+            ])
+        );
+        assert_eq!((top[1].0, top[1].1.total), (3, 6));
+        assert_eq!(
+            breakdown.table(),
+            "Errors by client (top 2):\n\
+             client     errors  codes\n     \
+             7         36  E_ACCOUNT_LOCKED=5 E_INSUFFICIENT_FUNDS=30 E_TX_NOT_FOUND=1\n     \
+             3          6  E_ACCOUNT_LOCKED=5 E_TX_NOT_FOUND=1\n"
+        );
+    }
+
+    // Test that the number of tracked clients stays bounded and keeps the top one
+    #[test]
+    fn test_breakdown_is_bounded() {
+        let mut breakdown = ErrorBreakdown::with_capacity(1, 100);
+        for _ in 0..50 {
+            breakdown.record(7, "E_INSUFFICIENT_FUNDS");
+        }
+        for client in 1_000..11_000 {
+            breakdown.record(client, "E_TX_NOT_FOUND");
+            assert!(breakdown.clients.len() <= 100);
+        }
+
+        let top = breakdown.top();
+        assert_eq!(top[0].0, 7);
+        assert_eq!(top[0].1.total, 50);
+    }
+}
@@ -0,0 +1,1609 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::log::LogFormat;
+use process_tx::generate::Spec;
+use process_tx::policy::{
+    ChargebackOverdraw, FeeSchedule, InsufficientFunds, LockOnChargeback, WithdrawalsDuringDispute,
+};
+use process_tx::report::{LockedFormat, OutputFormat, Quote};
+use process_tx::transaction::{Amount, TxFilter};
+
+// Records between two progress lines when `--progress` is given alone
+const DEFAULT_PROGRESS_EVERY: u64 = 100_000;
+// Clients listed when `--error-breakdown` is given alone
+const DEFAULT_ERROR_BREAKDOWN_TOP: usize = 20;
+
+pub const OPTIONS_HELP: &str = "\
+Options:
+  --account-events <path>                  Write the accounts opened, locked and unlocked during the run as CSV
+  --allow-deposits-when-locked             Accept deposits on locked accounts, still rejecting their other transactions
+  --allow-exponent                         Accept input amounts written with an exponent, such as 1.5e3
+  --capture-extra                          Keep the unknown input columns and echo them in the rejects file
+  --checkpoint-every <n>                   Also save the --save-state state every n records, to --resume after a crash
+  --clients <ids>                          Only process transactions of these clients (comma-separated)
+  --command-log <path>                     Log every transaction handed to the engine with its result
+  --crlf                                   End the lines of the CSV report with CRLF
+  --decimal-separator <char>               Separator of the fractional part of report amounts [default: .]
+  --delta-report <path>                    Write the clients whose balances changed since the --previous report as CSV
+  --deny-clients <file>                    Reject transactions of the clients listed in file, one per line or comma-separated
+  --dispute-clamp-to-available             Hold at most the available funds when a deposit is disputed
+  --dump-transactions <path>               Write the status of the stored transactions matching the filters as CSV
+  --dust-threshold <amount>                Skip deposits and withdrawals below this amount as no-ops
+  --error-breakdown                        Print the clients with the most rejected transactions to stderr
+  --error-breakdown-top <n>                Clients listed by the error breakdown, implies --error-breakdown [default: 20]
+  --exclude-clients <ids>                  Skip transactions of these clients (comma-separated)
+  --export-transactions <path>             Write the stored deposits and withdrawals as CSV
+  --extended-output                        Append the last_tx, peak balance, open_disputes and chargeback_losses columns to the CSV report
+  --fail-fast                              Stop at the first rejected record without writing the report
+  --fee <deposit|withdrawal>:<threshold>:<rate>[:<fee account>]
+                                           Charge rate times the amount above threshold, first match wins (repeatable)
+  --filter-disputed                        Only dump the transactions under dispute
+  --filter-min-amount <amount>             Only dump the transactions of at least this amount
+  --filter-type <deposit|withdrawal>       Only dump the transactions of this type
+  --fixed-decimals                         Print amounts with exactly four decimal places
+  --insufficient-funds <error|warn>        Count withdrawals rejected for insufficient funds as errors or skipped [default: error]
+  --ledger <path>                          Write every balance movement as double-entry CSV
+  --lock-on-chargeback <always|never|above:<amount>>
+                                           Which chargebacks lock the account [default: always]
+  --lock-on-cumulative-loss <amount>       Lock an account once its chargebacks removed more than this amount
+  --locked-format <bool|binary|yesno>      Rendering of the locked column [default: bool]
+  --log-format <text|json>                 Format of the diagnostics printed on stderr [default: text]
+  --max-accounts <n>                       Abort once a transaction would create more than n accounts
+  --max-balance <amount>                   Reject deposits that would push an account's total above this amount
+  --max-field-len <n>                      Refuse records with a field longer than n bytes
+  --max-open-disputes-per-client <n>       Reject disputes of a client that already has n disputes open
+  --max-redisputes <n>                     Reject disputing a deposit again after n redisputes
+  --max-single-deposit <amount>            Reject deposits above this amount
+  --max-total-deposits-per-client <amount>
+                                           Reject deposits once a client's cumulative deposits exceed this amount
+  --max-transactions <n>                   Abort once more than n deposits and withdrawals would be stored
+  --max-transactions-per-client <n>        Reject transactions of a client once it has submitted n of them
+  --merge-states <snapshot>...             Merge snapshots and print the combined report instead of processing a file
+  --metrics-addr <addr>                    Serve Prometheus metrics on http://<addr>/metrics while processing
+  --mmap                                   Read the input file through a memory map, for very large inputs
+  --no-lock-on-chargeback                  Reverse the funds on chargeback without locking the account
+  --no-timing                              Omit timestamps and durations from stderr
+  --nonzero-only                           Leave unlocked accounts with all balances at zero out of the report
+  --on-chargeback-overdraw <reject|allow-negative|clamp-to-zero>
+                                           Chargeback of an already withdrawn deposit [default: allow-negative]
+  --output <path>                          Write the accounts report to a file instead of stdout
+  --output-format <csv|table|parquet>      Format of the accounts report [default: csv]
+  --pending-deposits                       Keep deposits pending until a settle row, adding a pending report column
+  --previous <path>                        Accounts report of a previous run to compare against, requires --delta-report
+  --progress                               Print the number of processed records to stderr periodically
+  --progress-every <n>                     Records between two progress lines, implies --progress [default: 100000]
+  --quote <never|always|necessary>         Quoting of the report fields [default: necessary]
+  --reference-grace <n>                    Keep up to n disputes of not yet seen transactions pending [default: 0]
+  --rejects <path>                         Write the rows rejected by the engine as CSV, with the error
+  --repl <snapshot>                        Load a snapshot and read commands from stdin instead of processing a file
+  --report-on-abort                        Write the report of the accounts so far when processing is aborted
+  --require-explicit-open                  Reject transactions of clients without an open transaction
+  --reserve <n>                            Pre-allocate room for about n transactions
+  --resume <state>                         Load a state saved with --save-state and continue its input file where it stopped
+  --round-to-cents                         Round deposit and withdrawal amounts half-up to two decimal places
+  --save-state <path>                      Save a snapshot of the engine state after processing
+  --skip-inactive                          Leave accounts without any successful transaction out of the report, even with funds
+  --strict-amounts                         Refuse deposits and withdrawals with an empty, infinite or NaN amount as parse errors
+  --strict-held                            Reject resolves that would leave an account with negative held funds
+  --table                                  Print the accounts report as an aligned table, same as --output-format table
+  --trace-tx <id>                          Print a processing narrative for this tx id (repeatable)
+  --wal <path>                             Append every applied transaction to a write-ahead log
+  --wal-recover <path>                     Replay a write-ahead log before processing the input
+  --warn-held                              Warn on stderr about accounts left with held funds
+  --warn-slow-rows <ms>                    Warn on stderr about rows taking more than ms milliseconds to process
+  --withdrawals-during-dispute <allow|block>
+                                           Whether an open dispute blocks all withdrawals [default: allow]";
+
+pub const GENERATE_HELP: &str = "\
+Generate options:
+  --rows <n>                               Number of rows, numbered as transaction ids from 1 [default: 1000]
+  --clients <n>                            Number of clients [default: 10]
+  --dispute-rate <rate>                    Share of the rows opening a dispute, from 0 to 1 [default: 0.01]
+  --seed <n>                               Seed of the generator; the same options give the same file [default: 0]
+  --out <path>                             Write the rows to a file instead of stdout";
+
+pub const COMPARE_HELP: &str = "\
+Compare options, followed by any processing option but --output, --output-format, --save-state,
+--dump-transactions, --export-transactions and --delta-report:
+  --input <path>                           Input file to process
+  --baseline <path>                        Accounts report to compare the resulting report with
+  --tolerance <amount>                     Largest difference between two amounts still equal [default: 0]";
+
+/// Subcommand selected by the first program argument.
+#[derive(Debug)]
+pub enum Command {
+    /// Process an input file, with `run` or without any subcommand.
+    Run(Box<Options>),
+    /// Write a synthetic input file, with `generate`.
+    Generate(GenerateOptions),
+    /// Process an input file and compare the report with a baseline, with
+    /// `compare`.
+    Compare(Box<CompareOptions>),
+}
+
+impl Command {
+    /// Parse the program arguments (excluding the program name).
+    pub fn parse<I>(args: I) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut args = args.into_iter().peekable();
+        match args.peek().map(String::as_str) {
+            Some("generate") => {
+                args.next();
+                GenerateOptions::parse(args).map(Command::Generate)
+            }
+            Some("compare") => {
+                args.next();
+                CompareOptions::parse(args).map(|options| Command::Compare(Box::new(options)))
+            }
+            Some("run") => {
+                args.next();
+                Options::parse(args).map(|options| Command::Run(Box::new(options)))
+            }
+            _ => Options::parse(args).map(|options| Command::Run(Box::new(options))),
+        }
+    }
+
+    /// Format of the diagnostics printed while running the command.
+    pub fn log_format(&self) -> LogFormat {
+        match self {
+            Command::Run(options) => options.log_format,
+            Command::Generate(_) => LogFormat::default(),
+            Command::Compare(options) => options.run.log_format,
+        }
+    }
+}
+
+/// Format requested with `--log-format` among `args`, for reporting arguments
+/// that [`Command::parse`] refused. Text when the option is missing or its
+/// value invalid.
+pub fn requested_log_format(args: &[String]) -> LogFormat {
+    args.windows(2)
+        .rev()
+        .find(|pair| pair[0] == "--log-format")
+        .and_then(|pair| pair[1].parse().ok())
+        .unwrap_or_default()
+}
+
+/// Options of the `generate` subcommand.
+#[derive(Debug, Default)]
+pub struct GenerateOptions {
+    pub spec: Spec,
+    pub out: Option<String>,
+}
+
+impl GenerateOptions {
+    fn parse<I>(args: I) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut options = GenerateOptions::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--rows" => options.spec.rows = Options::count(&mut args, &arg)?,
+                "--clients" => options.spec.clients = Options::count(&mut args, &arg)?,
+                "--dispute-rate" => {
+                    let value = Options::value(&mut args, &arg)?;
+                    options.spec.dispute_rate = value
+                        .parse()
+                        .ok()
+                        .filter(|rate| (0.0..=1.0).contains(rate))
+                        .ok_or_else(|| format!("invalid rate '{value}' for option '{arg}'"))?;
+                }
+                "--seed" => options.spec.seed = Options::count(&mut args, &arg)?,
+                "--out" => options.out = Some(Options::value(&mut args, &arg)?),
+                other => return Err(format!("unexpected argument '{other}' for 'generate'")),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Options of the `compare` subcommand.
+#[derive(Debug)]
+pub struct CompareOptions {
+    pub baseline: String,
+    pub tolerance: Amount,
+    /// Options of the processing whose report is compared.
+    pub run: Options,
+}
+
+impl CompareOptions {
+    fn parse<I>(args: I) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut baseline = None;
+        let mut tolerance = 0.0;
+        // Arguments left to the processing options, the input file included
+        let mut run = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--input" => run.push(Options::value(&mut args, &arg)?),
+                "--baseline" => baseline = Some(Options::value(&mut args, &arg)?),
+                "--tolerance" => {
+                    let value = Options::value(&mut args, &arg)?;
+                    tolerance = value
+                        .parse()
+                        .ok()
+                        .filter(|tolerance: &Amount| *tolerance >= 0.0)
+                        .ok_or_else(|| format!("invalid tolerance '{value}' for option '{arg}'"))?;
+                }
+                // The compared report is printed as a diff instead of written
+                "--output"
+                | "--output-format"
+                | "--save-state"
+                | "--dump-transactions"
+                | "--export-transactions"
+                | "--delta-report" => {
+                    return Err(format!("option '{arg}' cannot be used with 'compare'"));
+                }
+                _ => run.push(arg),
+            }
+        }
+        Ok(CompareOptions {
+            baseline: baseline.ok_or("option '--baseline' is required for 'compare'")?,
+            tolerance,
+            run: Options::parse(run)?,
+        })
+    }
+}
+
+/// Command line options accepted by the binary.
+// Each flag maps to its own field, as parsed
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, Default)]
+pub struct Options {
+    pub input_path: String,
+    pub account_events: Option<String>,
+    pub allow_exponent: bool,
+    pub capture_extra: bool,
+    // Records between two checkpoints of the `--save-state` state
+    pub checkpoint_every: Option<u64>,
+    pub clients: Option<HashSet<u16>>,
+    pub crlf: bool,
+    pub decimal_separator: Option<char>,
+    pub delta_report: Option<String>,
+    pub dump_transactions: Option<String>,
+    // Transactions written by `--dump-transactions`
+    pub dump_filter: TxFilter,
+    // Number of clients listed by the error breakdown, if enabled
+    pub error_breakdown: Option<usize>,
+    pub exclude_clients: HashSet<u16>,
+    pub export_transactions: Option<String>,
+    pub extended_output: bool,
+    pub fail_fast: bool,
+    pub fixed_decimals: bool,
+    pub ledger: Option<String>,
+    pub locked_format: LockedFormat,
+    pub log_format: LogFormat,
+    pub max_accounts: Option<usize>,
+    pub max_field_len: Option<usize>,
+    pub max_redisputes: Option<u32>,
+    pub max_transactions: Option<usize>,
+    // Snapshots to merge instead of processing an input file
+    pub merge_states: Vec<String>,
+    pub metrics_addr: Option<SocketAddr>,
+    pub mmap: bool,
+    pub no_timing: bool,
+    pub nonzero_only: bool,
+    pub output: Option<String>,
+    pub output_format: OutputFormat,
+    // Accounts report the `--delta-report` is computed against
+    pub previous: Option<String>,
+    // Interval of the progress lines, if enabled
+    pub progress: Option<u64>,
+    pub quote: Quote,
+    pub reference_grace: usize,
+    pub rejects: Option<String>,
+    pub report_on_abort: bool,
+    pub repl: Option<String>,
+    pub reserve: usize,
+    // State whose input cursor the run continues from
+    pub resume: Option<String>,
+    pub save_state: Option<String>,
+    pub skip_inactive: bool,
+    pub strict_amounts: bool,
+    pub trace_tx: HashSet<u32>,
+    pub warn_held: bool,
+    // Processing time above which a row is reported as slow
+    pub warn_slow_rows: Option<Duration>,
+    pub wal: Option<String>,
+    // Write-ahead log replayed before processing the input
+    pub wal_recover: Option<String>,
+    // Replayable log of the processed transactions, rejected ones included
+    pub command_log: Option<String>,
+    pub max_single_deposit: Option<Amount>,
+    pub max_total_deposits_per_client: Option<Amount>,
+    pub max_balance: Option<Amount>,
+    pub withdrawals_during_dispute: WithdrawalsDuringDispute,
+    pub on_chargeback_overdraw: ChargebackOverdraw,
+    pub dispute_clamp_to_available: bool,
+    pub pending_deposits: bool,
+    pub dust_threshold: Option<Amount>,
+    pub fees: FeeSchedule,
+    pub strict_held: bool,
+    pub max_open_disputes_per_client: Option<u32>,
+    pub lock_on_chargeback: LockOnChargeback,
+    pub lock_on_cumulative_loss: Option<Amount>,
+    pub require_explicit_open: bool,
+    pub round_to_cents: bool,
+    // File listing the clients whose transactions are rejected
+    pub deny_clients: Option<String>,
+    pub allow_deposits_when_locked: bool,
+    pub max_transactions_per_client: Option<usize>,
+    pub insufficient_funds: InsufficientFunds,
+}
+
+impl Options {
+    /// Parse the program arguments (excluding the program name).
+    pub fn parse<I>(args: I) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut options = Options::default();
+        let mut positional = Vec::new();
+        let mut merge_states = false;
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--account-events" => {
+                    options.account_events = Some(Self::value(&mut args, &arg)?);
+                }
+                "--allow-exponent" => options.allow_exponent = true,
+                "--capture-extra" => options.capture_extra = true,
+                "--clients" => {
+                    let clients = options.clients.get_or_insert_with(HashSet::new);
+                    clients.extend(Self::client_ids(&mut args, &arg)?);
+                }
+                "--crlf" => options.crlf = true,
+                "--decimal-separator" => {
+                    options.decimal_separator = Some(Self::separator(&mut args, &arg)?);
+                }
+                "--error-breakdown" => {
+                    options.error_breakdown = options
+                        .error_breakdown
+                        .or(Some(DEFAULT_ERROR_BREAKDOWN_TOP));
+                }
+                "--error-breakdown-top" => {
+                    options.error_breakdown = Some(Self::count(&mut args, &arg)?);
+                }
+                "--exclude-clients" => {
+                    let clients = Self::client_ids(&mut args, &arg)?;
+                    options.exclude_clients.extend(clients);
+                }
+                "--export-transactions" => {
+                    options.export_transactions = Some(Self::value(&mut args, &arg)?);
+                }
+                "--extended-output" => options.extended_output = true,
+                "--fail-fast" => options.fail_fast = true,
+                "--fixed-decimals" => options.fixed_decimals = true,
+                "--ledger" => options.ledger = Some(Self::value(&mut args, &arg)?),
+                "--locked-format" => {
+                    options.locked_format = Self::value(&mut args, &arg)?.parse()?;
+                }
+                "--log-format" => options.log_format = Self::value(&mut args, &arg)?.parse()?,
+                "--max-accounts" => options.max_accounts = Some(Self::count(&mut args, &arg)?),
+                "--max-field-len" => {
+                    options.max_field_len = Some(Self::count(&mut args, &arg)?);
+                }
+                "--max-redisputes" => {
+                    options.max_redisputes = Some(Self::count(&mut args, &arg)?);
+                }
+                "--max-transactions" => {
+                    options.max_transactions = Some(Self::count(&mut args, &arg)?);
+                }
+                "--merge-states" => merge_states = true,
+                "--metrics-addr" => options.metrics_addr = Some(Self::address(&mut args, &arg)?),
+                "--mmap" => options.mmap = true,
+                "--no-timing" => options.no_timing = true,
+                "--output" => options.output = Some(Self::value(&mut args, &arg)?),
+                "--output-format" => {
+                    options.output_format = Self::value(&mut args, &arg)?.parse()?;
+                }
+                "--progress" => {
+                    options.progress = options.progress.or(Some(DEFAULT_PROGRESS_EVERY));
+                }
+                "--progress-every" => {
+                    options.progress = Some(Self::positive_count(&mut args, &arg)?);
+                }
+                "--quote" => options.quote = Self::value(&mut args, &arg)?.parse()?,
+                "--reference-grace" => options.reference_grace = Self::count(&mut args, &arg)?,
+                "--rejects" => options.rejects = Some(Self::value(&mut args, &arg)?),
+                "--report-on-abort" => options.report_on_abort = true,
+                "--repl" => options.repl = Some(Self::value(&mut args, &arg)?),
+                "--skip-inactive" => options.skip_inactive = true,
+                "--strict-amounts" => options.strict_amounts = true,
+                "--table" => options.output_format = OutputFormat::Table,
+                "--nonzero-only" => options.nonzero_only = true,
+                "--reserve" => options.reserve = Self::count(&mut args, &arg)?,
+                "--warn-held" => options.warn_held = true,
+                "--warn-slow-rows" => {
+                    let millis = Self::count(&mut args, &arg)?;
+                    options.warn_slow_rows = Some(Duration::from_millis(millis));
+                }
+                "--wal" => options.wal = Some(Self::value(&mut args, &arg)?),
+                "--wal-recover" => options.wal_recover = Some(Self::value(&mut args, &arg)?),
+                "--command-log" => options.command_log = Some(Self::value(&mut args, &arg)?),
+                "--trace-tx" => {
+                    options.trace_tx.insert(Self::tx_id(&mut args, &arg)?);
+                }
+                flag if options.parse_policy(flag, &mut args)? => {}
+                flag if options.parse_dump(flag, &mut args)? => {}
+                flag if options.parse_delta_report(flag, &mut args)? => {}
+                flag if options.parse_state(flag, &mut args)? => {}
+                flag if flag.starts_with("--") => {
+                    return Err(format!("unknown option '{flag}'"));
+                }
+                _ => positional.push(arg),
+            }
+        }
+
+        options.validate(positional, merge_states)
+    }
+
+    // Parse `--dump-transactions` or a flag narrowing it, returning whether
+    // `flag` is one
+    fn parse_dump<I>(&mut self, flag: &str, args: &mut I) -> Result<bool, String>
+    where
+        I: Iterator<Item = String>,
+    {
+        if flag == "--dump-transactions" {
+            self.dump_transactions = Some(Self::value(args, flag)?);
+            return Ok(true);
+        }
+        let filter = self.dump_filter;
+        self.dump_filter = match flag {
+            "--filter-disputed" => filter.disputed(true),
+            "--filter-min-amount" => filter.min_amount(Self::amount(args, flag)?),
+            "--filter-type" => filter.kind(Self::value(args, flag)?.parse()?),
+            _ => return Ok(false),
+        };
+        Ok(true)
+    }
+
+    // Parse a flag of the delta report against a previous run, returning
+    // whether `flag` is one
+    fn parse_delta_report<I>(&mut self, flag: &str, args: &mut I) -> Result<bool, String>
+    where
+        I: Iterator<Item = String>,
+    {
+        match flag {
+            "--delta-report" => self.delta_report = Some(Self::value(args, flag)?),
+            "--previous" => self.previous = Some(Self::value(args, flag)?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    // Parse a flag saving or resuming the engine state, returning whether
+    // `flag` is one
+    fn parse_state<I>(&mut self, flag: &str, args: &mut I) -> Result<bool, String>
+    where
+        I: Iterator<Item = String>,
+    {
+        match flag {
+            "--checkpoint-every" => self.checkpoint_every = Some(Self::positive_count(args, flag)?),
+            "--resume" => self.resume = Some(Self::value(args, flag)?),
+            "--save-state" => self.save_state = Some(Self::value(args, flag)?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    // Parse a flag setting an engine policy, returning whether `flag` is one
+    fn parse_policy<I>(&mut self, flag: &str, args: &mut I) -> Result<bool, String>
+    where
+        I: Iterator<Item = String>,
+    {
+        match flag {
+            "--max-single-deposit" => {
+                self.max_single_deposit = Some(Self::amount(args, flag)?);
+            }
+            "--max-total-deposits-per-client" => {
+                self.max_total_deposits_per_client = Some(Self::amount(args, flag)?);
+            }
+            "--max-balance" => self.max_balance = Some(Self::amount(args, flag)?),
+            "--withdrawals-during-dispute" => {
+                self.withdrawals_during_dispute = Self::value(args, flag)?.parse()?;
+            }
+            "--on-chargeback-overdraw" => {
+                self.on_chargeback_overdraw = Self::value(args, flag)?.parse()?;
+            }
+            "--dispute-clamp-to-available" => self.dispute_clamp_to_available = true,
+            "--pending-deposits" => self.pending_deposits = true,
+            "--dust-threshold" => {
+                self.dust_threshold = Some(Self::amount(args, flag)?);
+            }
+            "--fee" => self.fees.rules.push(Self::value(args, flag)?.parse()?),
+            "--strict-held" => self.strict_held = true,
+            "--max-open-disputes-per-client" => {
+                self.max_open_disputes_per_client = Some(Self::count(args, flag)?);
+            }
+            "--no-lock-on-chargeback" => self.lock_on_chargeback = LockOnChargeback::Never,
+            "--lock-on-chargeback" => {
+                self.lock_on_chargeback = Self::value(args, flag)?.parse()?;
+            }
+            "--lock-on-cumulative-loss" => {
+                self.lock_on_cumulative_loss = Some(Self::amount(args, flag)?);
+            }
+            "--require-explicit-open" => self.require_explicit_open = true,
+            "--round-to-cents" => self.round_to_cents = true,
+            "--deny-clients" => self.deny_clients = Some(Self::value(args, flag)?),
+            "--allow-deposits-when-locked" => self.allow_deposits_when_locked = true,
+            "--max-transactions-per-client" => {
+                self.max_transactions_per_client = Some(Self::count(args, flag)?);
+            }
+            "--insufficient-funds" => {
+                self.insufficient_funds = Self::value(args, flag)?.parse()?;
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    // Assign the positional arguments to the selected mode and reject
+    // combinations of options that cannot work together
+    fn validate(mut self, mut positional: Vec<String>, merge_states: bool) -> Result<Self, String> {
+        match (self.repl.is_some(), merge_states, positional.len()) {
+            (true, true, _) => {
+                return Err("options '--repl' and '--merge-states' cannot be combined".to_string());
+            }
+            // The REPL reads its commands from stdin
+            (true, false, 0) => {}
+            (true, false, _) => {
+                return Err("option '--repl' does not take an input file".to_string());
+            }
+            (false, true, 0) => {
+                return Err("option '--merge-states' requires at least one snapshot".to_string());
+            }
+            (false, true, _) => self.merge_states = positional,
+            (false, false, 0) => return Err("missing input file".to_string()),
+            (false, false, 1) => self.input_path = positional.remove(0),
+            (false, false, _) => {
+                return Err(format!("unexpected argument '{}'", positional[1]));
+            }
+        }
+        if self.metrics_addr.is_some() && !cfg!(feature = "metrics") {
+            return Err(
+                "option '--metrics-addr' requires building with --features metrics".to_string(),
+            );
+        }
+        if self.dump_transactions.is_none() && self.dump_filter != TxFilter::new() {
+            return Err("filter options require '--dump-transactions'".to_string());
+        }
+        if self.checkpoint_every.is_some() && self.save_state.is_none() {
+            return Err("option '--checkpoint-every' requires '--save-state'".to_string());
+        }
+        if self.previous.is_some() != self.delta_report.is_some() {
+            return Err(
+                "options '--previous' and '--delta-report' must be given together".to_string(),
+            );
+        }
+        if !self.output_format.is_text() && self.output.is_none() {
+            return Err("option '--output' is required for binary output formats".to_string());
+        }
+        if !self.output_format.is_text() && self.extended_output {
+            return Err("option '--extended-output' is only supported for text output".to_string());
+        }
+        if !self.output_format.is_text() && self.pending_deposits {
+            return Err(
+                "option '--pending-deposits' is only supported for text output".to_string(),
+            );
+        }
+        if !self.output_format.is_text() && self.decimal_separator.is_some() {
+            return Err(
+                "option '--decimal-separator' is only supported for text output".to_string(),
+            );
+        }
+        // Unquoted amounts would be split in two fields by their comma
+        if self.decimal_separator == Some(',') && self.quote == Quote::Never {
+            return Err(
+                "option '--decimal-separator ,' cannot be combined with '--quote never'"
+                    .to_string(),
+            );
+        }
+        Ok(self)
+    }
+
+    /// Whether transactions of `client` pass the `--clients` and
+    /// `--exclude-clients` filters.
+    pub fn accepts_client(&self, client: u16) -> bool {
+        self.clients
+            .as_ref()
+            .is_none_or(|clients| clients.contains(&client))
+            && !self.exclude_clients.contains(&client)
+    }
+
+    fn client_ids<I>(args: &mut I, flag: &str) -> Result<Vec<u16>, String>
+    where
+        I: Iterator<Item = String>,
+    {
+        Self::value(args, flag)?
+            .split(',')
+            .map(|id| {
+                id.trim()
+                    .parse()
+                    .map_err(|_| format!("invalid client id '{id}' for option '{flag}'"))
+            })
+            .collect()
+    }
+
+    fn count<T, I>(args: &mut I, flag: &str) -> Result<T, String>
+    where
+        T: FromStr,
+        I: Iterator<Item = String>,
+    {
+        let value = Self::value(args, flag)?;
+        value
+            .parse()
+            .map_err(|_| format!("invalid count '{value}' for option '{flag}'"))
+    }
+
+    fn positive_count<I>(args: &mut I, flag: &str) -> Result<u64, String>
+    where
+        I: Iterator<Item = String>,
+    {
+        let value = Self::value(args, flag)?;
+        value
+            .parse()
+            .ok()
+            .filter(|&count| count > 0)
+            .ok_or_else(|| format!("invalid count '{value}' for option '{flag}'"))
+    }
+
+    fn tx_id<I>(args: &mut I, flag: &str) -> Result<u32, String>
+    where
+        I: Iterator<Item = String>,
+    {
+        let value = Self::value(args, flag)?;
+        value
+            .parse()
+            .map_err(|_| format!("invalid transaction id '{value}' for option '{flag}'"))
+    }
+
+    fn separator<I>(args: &mut I, flag: &str) -> Result<char, String>
+    where
+        I: Iterator<Item = String>,
+    {
+        let value = Self::value(args, flag)?;
+        let mut chars = value.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if !c.is_ascii_digit() && c != '-' => Ok(c),
+            _ => Err(format!(
+                "invalid separator '{value}' for option '{flag}', \
+                 expected a single character other than a digit or '-'"
+            )),
+        }
+    }
+
+    fn value<I>(args: &mut I, flag: &str) -> Result<String, String>
+    where
+        I: Iterator<Item = String>,
+    {
+        args.next()
+            .ok_or_else(|| format!("option '{flag}' requires a value"))
+    }
+
+    fn address<I>(args: &mut I, flag: &str) -> Result<SocketAddr, String>
+    where
+        I: Iterator<Item = String>,
+    {
+        let value = Self::value(args, flag)?;
+        value
+            .parse()
+            .map_err(|_| format!("invalid address '{value}' for option '{flag}'"))
+    }
+
+    fn amount<I>(args: &mut I, flag: &str) -> Result<Amount, String>
+    where
+        I: Iterator<Item = String>,
+    {
+        let value = Self::value(args, flag)?;
+        value
+            .parse()
+            .map_err(|_| format!("invalid amount '{value}' for option '{flag}'"))
+    }
+}
+
+/// Parse a list of client ids given one per line or comma-separated, as
+/// read by `--deny-clients`. A first line without any id, such as a CSV
+/// header, is skipped.
+pub fn parse_client_list(text: &str) -> Result<HashSet<u16>, String> {
+    let mut clients = HashSet::new();
+    for (index, line) in text.lines().enumerate() {
+        let ids: Vec<&str> = line
+            .split(',')
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .collect();
+        let is_number = |id: &&str| id.bytes().all(|b| b.is_ascii_digit());
+        if index == 0 && !ids.iter().any(is_number) {
+            continue;
+        }
+        for id in ids {
+            let client = id
+                .parse()
+                .map_err(|_| format!("invalid client id '{id}' on line {}", index + 1))?;
+            clients.insert(client);
+        }
+    }
+    Ok(clients)
+}
+
+#[cfg(test)]
+#[allow(clippy::float_cmp)]
+mod tests {
+    use super::*;
+    use process_tx::policy::FeeRule;
+    use process_tx::transaction::{TxKind, Type as TransactionType};
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        Options::parse(args.iter().map(ToString::to_string))
+    }
+
+    fn parse_command(args: &[&str]) -> Result<Command, String> {
+        Command::parse(args.iter().map(ToString::to_string))
+    }
+
+    #[test]
+    fn test_parse_run_command() {
+        for args in [&["run", "transactions.csv"][..], &["transactions.csv"]] {
+            match parse_command(args).expect("Failed to parse arguments") {
+                Command::Run(options) => assert_eq!(options.input_path, "transactions.csv"),
+                _ => panic!("Expected the run command"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_generate_command() {
+        let Command::Generate(options) = parse_command(&["generate"]).expect("Failed to parse")
+        else {
+            panic!("Expected the generate command");
+        };
+        assert_eq!(options.spec, Spec::default());
+        assert_eq!(options.out, None);
+
+        let Command::Generate(options) = parse_command(&[
+            "generate",
+            "--rows",
+            "500",
+            "--clients",
+            "3",
+            "--dispute-rate",
+            "0.1",
+            "--seed",
+            "42",
+            "--out",
+            "fixture.csv",
+        ])
+        .expect("Failed to parse arguments") else {
+            panic!("Expected the generate command");
+        };
+        assert_eq!(
+            options.spec,
+            Spec {
+                rows: 500,
+                clients: 3,
+                dispute_rate: 0.1,
+                seed: 42,
+            }
+        );
+        assert_eq!(options.out.as_deref(), Some("fixture.csv"));
+
+        assert!(parse_command(&["generate", "--dispute-rate", "1.5"]).is_err());
+        assert!(parse_command(&["generate", "--clients", "70000"]).is_err());
+        assert!(parse_command(&["generate", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_compare_command() {
+        let Command::Compare(options) = parse_command(&[
+            "compare",
+            "--input",
+            "big.csv",
+            "--baseline",
+            "accounts_old.csv",
+            "--tolerance",
+            "0.0001",
+            "--fixed-decimals",
+        ])
+        .expect("Failed to parse arguments") else {
+            panic!("Expected the compare command");
+        };
+        assert_eq!(options.baseline, "accounts_old.csv");
+        assert_eq!(options.tolerance, 0.0001);
+        assert_eq!(options.run.input_path, "big.csv");
+        assert!(options.run.fixed_decimals);
+
+        assert!(parse_command(&["compare", "--input", "big.csv"]).is_err());
+        assert!(parse_command(&["compare", "--baseline", "accounts_old.csv"]).is_err());
+        let negative = [
+            "compare",
+            "--tolerance",
+            "-1",
+            "--baseline",
+            "a.csv",
+            "big.csv",
+        ];
+        assert!(parse_command(&negative).is_err());
+        for flag in [
+            "--output",
+            "--output-format",
+            "--save-state",
+            "--dump-transactions",
+        ] {
+            let args = ["compare", "--baseline", "a.csv", flag, "out", "big.csv"];
+            assert_eq!(
+                parse_command(&args).map(drop),
+                Err(format!("option '{flag}' cannot be used with 'compare'"))
+            );
+        }
+    }
+
+    // Test that option descriptions start in the same column, on the line of
+    // the option or on the next one when it is too long
+    #[test]
+    fn test_help_alignment() {
+        const COLUMN: usize = 43;
+        for help in [OPTIONS_HELP, GENERATE_HELP, COMPARE_HELP] {
+            let lines: Vec<&str> = help.lines().collect();
+            for (i, line) in lines.iter().enumerate() {
+                if !line.starts_with("  -") {
+                    continue;
+                }
+                let aligned = line.len() > COLUMN
+                    && line[..COLUMN].ends_with("  ")
+                    && !line[COLUMN..].starts_with(' ');
+                let wrapped = lines.get(i + 1).is_some_and(|next| {
+                    next.len() > COLUMN
+                        && next[..COLUMN].trim().is_empty()
+                        && !next[COLUMN..].starts_with(' ')
+                });
+                assert!(aligned || wrapped, "Misaligned help line: {line}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_help_sorted() {
+        let options: Vec<&str> = OPTIONS_HELP
+            .lines()
+            .filter_map(|line| line.strip_prefix("  --"))
+            .filter_map(|line| line.split_whitespace().next())
+            .collect();
+        let mut sorted = options.clone();
+        sorted.sort_unstable();
+        assert_eq!(options, sorted);
+    }
+
+    #[test]
+    fn test_parse_input_only() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert_eq!(options.input_path, "transactions.csv");
+        assert_eq!(options.locked_format, LockedFormat::Bool);
+        assert!(!options.fail_fast);
+        assert_eq!(options.export_transactions, None);
+    }
+
+    #[test]
+    fn test_parse_export_transactions() {
+        let options = parse(&["--export-transactions", "out.csv", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert_eq!(options.export_transactions.as_deref(), Some("out.csv"));
+    }
+
+    #[test]
+    fn test_parse_dump_transactions() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert_eq!(options.dump_transactions, None);
+        assert_eq!(options.dump_filter, TxFilter::new());
+        let options = parse(&[
+            "--dump-transactions",
+            "dump.csv",
+            "--filter-type",
+            "withdrawal",
+            "--filter-min-amount",
+            "100",
+            "--filter-disputed",
+            "transactions.csv",
+        ])
+        .expect("Failed to parse arguments");
+        assert_eq!(options.dump_transactions.as_deref(), Some("dump.csv"));
+        assert_eq!(
+            options.dump_filter,
+            TxFilter::new()
+                .kind(TxKind::Withdrawal)
+                .min_amount(100.0)
+                .disputed(true)
+        );
+        assert!(parse(&["--filter-disputed", "transactions.csv"]).is_err());
+        let args = [
+            "--dump-transactions",
+            "d.csv",
+            "--filter-type",
+            "dispute",
+            "t.csv",
+        ];
+        assert!(parse(&args).is_err());
+    }
+
+    #[test]
+    fn test_parse_delta_report() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert_eq!((options.previous, options.delta_report), (None, None));
+        let options = parse(&[
+            "--previous",
+            "yesterday.csv",
+            "--delta-report",
+            "delta.csv",
+            "transactions.csv",
+        ])
+        .expect("Failed to parse arguments");
+        assert_eq!(options.previous.as_deref(), Some("yesterday.csv"));
+        assert_eq!(options.delta_report.as_deref(), Some("delta.csv"));
+        assert!(parse(&["--previous", "yesterday.csv", "transactions.csv"]).is_err());
+        assert!(parse(&["--delta-report", "delta.csv", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_fail_fast() {
+        let options =
+            parse(&["transactions.csv", "--fail-fast"]).expect("Failed to parse arguments");
+        assert!(options.fail_fast);
+    }
+
+    #[test]
+    fn test_parse_report_format() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert!(!options.fixed_decimals);
+        assert_eq!(options.quote, Quote::Necessary);
+        let options = parse(&["--fixed-decimals", "--quote", "always", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert!(options.fixed_decimals);
+        assert_eq!(options.quote, Quote::Always);
+        assert!(parse(&["--quote", "sometimes", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_crlf() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert!(!options.crlf);
+        let options = parse(&["--crlf", "transactions.csv"]).expect("Failed to parse arguments");
+        assert!(options.crlf);
+    }
+
+    #[test]
+    fn test_parse_activity_options() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert!(!options.skip_inactive);
+        assert!(!options.nonzero_only);
+        assert!(!options.extended_output);
+        let options = parse(&[
+            "--skip-inactive",
+            "--nonzero-only",
+            "--extended-output",
+            "transactions.csv",
+        ])
+        .expect("Failed to parse arguments");
+        assert!(options.skip_inactive);
+        assert!(options.nonzero_only);
+        assert!(options.extended_output);
+    }
+
+    #[test]
+    fn test_parse_warn_held() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert!(!options.warn_held);
+        let options =
+            parse(&["--warn-held", "transactions.csv"]).expect("Failed to parse arguments");
+        assert!(options.warn_held);
+    }
+
+    #[test]
+    fn test_parse_strict_amounts() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert!(!options.strict_amounts);
+        let options =
+            parse(&["--strict-amounts", "transactions.csv"]).expect("Failed to parse arguments");
+        assert!(options.strict_amounts);
+    }
+
+    #[test]
+    fn test_parse_allow_exponent() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert!(!options.allow_exponent);
+        let options =
+            parse(&["--allow-exponent", "transactions.csv"]).expect("Failed to parse arguments");
+        assert!(options.allow_exponent);
+    }
+
+    #[test]
+    fn test_parse_mmap() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert!(!options.mmap);
+        let options = parse(&["--mmap", "transactions.csv"]).expect("Failed to parse arguments");
+        assert!(options.mmap);
+    }
+
+    #[test]
+    fn test_parse_warn_slow_rows() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert_eq!(options.warn_slow_rows, None);
+        let options = parse(&["--warn-slow-rows", "250", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert_eq!(options.warn_slow_rows, Some(Duration::from_millis(250)));
+        assert!(parse(&["--warn-slow-rows", "fast", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_reference_grace() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert_eq!(options.reference_grace, 0);
+        let options = parse(&["--reference-grace", "1000", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert_eq!(options.reference_grace, 1000);
+        assert!(parse(&["--reference-grace", "-1", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_locked_format() {
+        let options = parse(&["--locked-format", "yesno", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert_eq!(options.locked_format, LockedFormat::YesNo);
+    }
+
+    #[test]
+    fn test_parse_log_format() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert_eq!(options.log_format, LogFormat::Text);
+        let options = parse(&["--log-format", "json", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert_eq!(options.log_format, LogFormat::Json);
+        assert!(parse(&["--log-format", "xml", "transactions.csv"]).is_err());
+
+        let args = |args: &[&str]| args.iter().map(ToString::to_string).collect::<Vec<_>>();
+        let requested = requested_log_format(&args(&["--bogus", "--log-format", "json"]));
+        assert_eq!(requested, LogFormat::Json);
+        let requested = requested_log_format(&args(&["--log-format", "xml", "--bogus"]));
+        assert_eq!(requested, LogFormat::Text);
+    }
+
+    #[test]
+    fn test_parse_client_filters() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert!(options.accepts_client(1));
+
+        let options = parse(&[
+            "--clients",
+            "1,2",
+            "--clients",
+            "3",
+            "--exclude-clients",
+            "2",
+            "transactions.csv",
+        ])
+        .expect("Failed to parse arguments");
+        assert!(options.accepts_client(1));
+        assert!(!options.accepts_client(2));
+        assert!(options.accepts_client(3));
+        assert!(!options.accepts_client(4));
+
+        let options = parse(&["--exclude-clients", "7", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert!(options.accepts_client(1));
+        assert!(!options.accepts_client(7));
+
+        assert!(parse(&["--clients", "1,x", "transactions.csv"]).is_err());
+        assert!(parse(&["--clients", "70000", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_deposit_limits() {
+        let options = parse(&[
+            "--max-single-deposit",
+            "500.5",
+            "--max-total-deposits-per-client",
+            "1000",
+            "--max-balance",
+            "2500",
+            "transactions.csv",
+        ])
+        .expect("Failed to parse arguments");
+        assert_eq!(options.max_single_deposit, Some(500.5));
+        assert_eq!(options.max_total_deposits_per_client, Some(1000.0));
+        assert_eq!(options.max_balance, Some(2500.0));
+        assert!(parse(&["--max-single-deposit", "abc", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_withdrawals_during_dispute() {
+        let options = parse(&["--withdrawals-during-dispute", "block", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert_eq!(
+            options.withdrawals_during_dispute,
+            WithdrawalsDuringDispute::Block
+        );
+        assert!(parse(&["--withdrawals-during-dispute", "never", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_on_chargeback_overdraw() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert_eq!(
+            options.on_chargeback_overdraw,
+            ChargebackOverdraw::AllowNegative
+        );
+        let options = parse(&[
+            "--on-chargeback-overdraw",
+            "clamp-to-zero",
+            "transactions.csv",
+        ])
+        .expect("Failed to parse arguments");
+        assert_eq!(
+            options.on_chargeback_overdraw,
+            ChargebackOverdraw::ClampToZero
+        );
+        assert!(parse(&["--on-chargeback-overdraw", "ignore", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_dispute_clamp_to_available() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert!(!options.dispute_clamp_to_available);
+        let options = parse(&["--dispute-clamp-to-available", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert!(options.dispute_clamp_to_available);
+    }
+
+    #[test]
+    fn test_parse_pending_deposits() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert!(!options.pending_deposits);
+        let options =
+            parse(&["--pending-deposits", "transactions.csv"]).expect("Failed to parse arguments");
+        assert!(options.pending_deposits);
+    }
+
+    #[test]
+    fn test_parse_dust_threshold() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert_eq!(options.dust_threshold, None);
+        let options = parse(&["--dust-threshold", "0.0001", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert_eq!(options.dust_threshold, Some(0.0001));
+        assert!(parse(&["--dust-threshold", "dust", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_fee() {
+        let options = parse(&[
+            "--fee",
+            "withdrawal:10000:0.02:9999",
+            "--fee",
+            "withdrawal:1000:0.01",
+            "transactions.csv",
+        ])
+        .expect("Failed to parse arguments");
+        assert_eq!(
+            options.fees.rules,
+            [
+                FeeRule {
+                    applies_to: TransactionType::Withdrawal,
+                    threshold: 10_000.0,
+                    rate: 0.02,
+                    fee_account: Some(9999),
+                },
+                FeeRule {
+                    applies_to: TransactionType::Withdrawal,
+                    threshold: 1_000.0,
+                    rate: 0.01,
+                    fee_account: None,
+                },
+            ]
+        );
+        for rule in [
+            "dispute:0:0.01",
+            "withdrawal:1000",
+            "withdrawal:1000:-0.01",
+            "withdrawal:1000:0.01:70000",
+            "withdrawal:1000:0.01:1:2",
+        ] {
+            assert!(
+                parse(&["--fee", rule, "transactions.csv"]).is_err(),
+                "{rule}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_max_open_disputes_per_client() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert_eq!(options.max_open_disputes_per_client, None);
+        let options = parse(&["--max-open-disputes-per-client", "5", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert_eq!(options.max_open_disputes_per_client, Some(5));
+        assert!(parse(&["--max-open-disputes-per-client", "-1", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_strict_held() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert!(!options.strict_held);
+        let options =
+            parse(&["--strict-held", "transactions.csv"]).expect("Failed to parse arguments");
+        assert!(options.strict_held);
+    }
+
+    #[test]
+    fn test_parse_lock_on_chargeback() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert_eq!(options.lock_on_chargeback, LockOnChargeback::Always);
+        assert_eq!(options.lock_on_cumulative_loss, None);
+        let options = parse(&["--no-lock-on-chargeback", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert_eq!(options.lock_on_chargeback, LockOnChargeback::Never);
+        let options = parse(&[
+            "--lock-on-chargeback",
+            "above:0.5",
+            "--lock-on-cumulative-loss",
+            "10",
+            "transactions.csv",
+        ])
+        .expect("Failed to parse arguments");
+        assert_eq!(
+            options.lock_on_chargeback,
+            LockOnChargeback::AboveAmount(0.5)
+        );
+        assert_eq!(options.lock_on_cumulative_loss, Some(10.0));
+        for invalid in ["sometimes", "above:", "above:-1", "above:x"] {
+            assert!(parse(&["--lock-on-chargeback", invalid, "transactions.csv"]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_parse_require_explicit_open() {
+        let options = parse(&["--require-explicit-open", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert!(options.require_explicit_open);
+    }
+
+    #[test]
+    fn test_parse_round_to_cents() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert!(!options.round_to_cents);
+        let options =
+            parse(&["--round-to-cents", "transactions.csv"]).expect("Failed to parse arguments");
+        assert!(options.round_to_cents);
+    }
+
+    #[test]
+    fn test_parse_allow_deposits_when_locked() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert!(!options.allow_deposits_when_locked);
+        let options = parse(&["--allow-deposits-when-locked", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert!(options.allow_deposits_when_locked);
+    }
+
+    #[test]
+    fn test_parse_max_transactions_per_client() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert_eq!(options.max_transactions_per_client, None);
+        let options = parse(&["--max-transactions-per-client", "100", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert_eq!(options.max_transactions_per_client, Some(100));
+        assert!(parse(&["--max-transactions-per-client", "x", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_insufficient_funds() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert_eq!(options.insufficient_funds, InsufficientFunds::Error);
+        let options = parse(&["--insufficient-funds", "warn", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert_eq!(options.insufficient_funds, InsufficientFunds::Warn);
+        assert!(parse(&["--insufficient-funds", "ignore", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_deny_clients() {
+        let options = parse(&["--deny-clients", "denied.csv", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert_eq!(options.deny_clients.as_deref(), Some("denied.csv"));
+        assert!(parse(&["transactions.csv", "--deny-clients"]).is_err());
+
+        let expected = HashSet::from([1, 2, 7]);
+        assert_eq!(parse_client_list("1\n2\n\n7\n"), Ok(expected.clone()));
+        assert_eq!(parse_client_list("client\n1, 2\n7"), Ok(expected));
+        assert_eq!(parse_client_list(""), Ok(HashSet::new()));
+        assert!(parse_client_list("1\nx\n").is_err());
+        assert!(parse_client_list("70000").is_err());
+    }
+
+    #[test]
+    fn test_parse_no_timing() {
+        let options =
+            parse(&["--no-timing", "transactions.csv"]).expect("Failed to parse arguments");
+        assert!(options.no_timing);
+    }
+
+    #[test]
+    fn test_parse_output() {
+        let options = parse(&["--output", "accounts.csv", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert_eq!(options.output.as_deref(), Some("accounts.csv"));
+        assert_eq!(options.output_format, OutputFormat::Csv);
+        assert!(parse(&["--output-format", "json", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_table() {
+        let options = parse(&["--table", "--extended-output", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert_eq!(options.output_format, OutputFormat::Table);
+        assert!(options.output.is_none());
+        let options = parse(&["--output-format", "table", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert_eq!(options.output_format, OutputFormat::Table);
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn test_parse_output_format_parquet() {
+        let options = parse(&[
+            "--output-format",
+            "parquet",
+            "--output",
+            "accounts.parquet",
+            "transactions.csv",
+        ])
+        .expect("Failed to parse arguments");
+        assert_eq!(options.output_format, OutputFormat::Parquet);
+        assert!(parse(&["--output-format", "parquet", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_metrics_addr() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert_eq!(options.metrics_addr, None);
+        let result = parse(&["--metrics-addr", "127.0.0.1:9090", "transactions.csv"]);
+        if cfg!(feature = "metrics") {
+            let options = result.expect("Failed to parse arguments");
+            assert_eq!(options.metrics_addr, "127.0.0.1:9090".parse().ok());
+        } else {
+            assert!(result.is_err());
+        }
+        assert!(parse(&["--metrics-addr", "localhost", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_merge_states() {
+        let options =
+            parse(&["--merge-states", "a.snap", "b.snap"]).expect("Failed to parse arguments");
+        assert_eq!(options.merge_states, ["a.snap", "b.snap"]);
+        assert_eq!(options.input_path, "");
+        assert!(parse(&["--merge-states"]).is_err());
+        assert!(parse(&["--merge-states", "--repl", "a.snap", "b.snap"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_progress() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert_eq!(options.progress, None);
+        let options =
+            parse(&["--progress", "transactions.csv"]).expect("Failed to parse arguments");
+        assert_eq!(options.progress, Some(DEFAULT_PROGRESS_EVERY));
+        let options = parse(&["--progress-every", "500", "--progress", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert_eq!(options.progress, Some(500));
+        assert!(parse(&["--progress-every", "0", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_snapshots() {
+        let options = parse(&["--repl", "state.snapshot"]).expect("Failed to parse arguments");
+        assert_eq!(options.repl.as_deref(), Some("state.snapshot"));
+        assert!(parse(&["--repl", "state.snapshot", "transactions.csv"]).is_err());
+
+        let options = parse(&["--save-state", "state.snapshot", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert_eq!(options.save_state.as_deref(), Some("state.snapshot"));
+
+        let options = parse(&["--resume", "state.snapshot", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert_eq!(options.resume.as_deref(), Some("state.snapshot"));
+
+        let options = parse(&[
+            "--save-state",
+            "state.snapshot",
+            "--checkpoint-every",
+            "1000",
+            "transactions.csv",
+        ])
+        .expect("Failed to parse arguments");
+        assert_eq!(options.checkpoint_every, Some(1000));
+        assert!(parse(&["--checkpoint-every", "1000", "transactions.csv"]).is_err());
+        assert!(parse(&[
+            "--save-state",
+            "state.snapshot",
+            "--checkpoint-every",
+            "0",
+            "transactions.csv"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_reserve() {
+        let options = parse(&["--reserve", "1000000", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert_eq!(options.reserve, 1_000_000);
+        assert!(parse(&["--reserve", "-1", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_decimal_separator() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert_eq!(options.decimal_separator, None);
+        let options = parse(&["--decimal-separator", ",", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert_eq!(options.decimal_separator, Some(','));
+        for invalid in ["", ",,", "5", "-"] {
+            assert!(parse(&["--decimal-separator", invalid, "transactions.csv"]).is_err());
+        }
+        let unquoted = [
+            "--decimal-separator",
+            ",",
+            "--quote",
+            "never",
+            "transactions.csv",
+        ];
+        assert!(parse(&unquoted).is_err());
+        let options = parse(&[
+            "--decimal-separator",
+            ";",
+            "--quote",
+            "never",
+            "transactions.csv",
+        ])
+        .expect("Failed to parse arguments");
+        assert_eq!(options.decimal_separator, Some(';'));
+    }
+
+    #[test]
+    fn test_parse_error_breakdown() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert_eq!(options.error_breakdown, None);
+        let options =
+            parse(&["--error-breakdown", "transactions.csv"]).expect("Failed to parse arguments");
+        assert_eq!(options.error_breakdown, Some(20));
+        let options = parse(&["--error-breakdown-top", "5", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert_eq!(options.error_breakdown, Some(5));
+        assert!(parse(&["--error-breakdown-top", "x", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_rejects() {
+        let options = parse(&[
+            "--rejects",
+            "rejects.csv",
+            "--capture-extra",
+            "transactions.csv",
+        ])
+        .expect("Failed to parse arguments");
+        assert_eq!(options.rejects.as_deref(), Some("rejects.csv"));
+        assert!(options.capture_extra);
+        assert!(parse(&["transactions.csv", "--rejects"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_ledger() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert_eq!(options.ledger, None);
+        let options = parse(&["--ledger", "ledger.csv", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert_eq!(options.ledger.as_deref(), Some("ledger.csv"));
+        assert!(parse(&["transactions.csv", "--ledger"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_account_events() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert_eq!(options.account_events, None);
+        let options = parse(&["--account-events", "events.csv", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert_eq!(options.account_events.as_deref(), Some("events.csv"));
+        assert!(parse(&["transactions.csv", "--account-events"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_capacity_limits() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert_eq!(options.max_accounts, None);
+        assert_eq!(options.max_transactions, None);
+        assert!(!options.report_on_abort);
+
+        let options = parse(&[
+            "--max-accounts",
+            "10",
+            "--max-transactions",
+            "5000",
+            "--report-on-abort",
+            "transactions.csv",
+        ])
+        .expect("Failed to parse arguments");
+        assert_eq!(options.max_accounts, Some(10));
+        assert_eq!(options.max_transactions, Some(5000));
+        assert!(options.report_on_abort);
+        assert!(parse(&["--max-transactions", "many", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_max_field_len() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert_eq!(options.max_field_len, None);
+        let options = parse(&["--max-field-len", "64", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert_eq!(options.max_field_len, Some(64));
+        assert!(parse(&["--max-field-len", "-1", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_max_redisputes() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert_eq!(options.max_redisputes, None);
+        let options = parse(&["--max-redisputes", "3", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert_eq!(options.max_redisputes, Some(3));
+    }
+
+    #[test]
+    fn test_parse_wal() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert_eq!((options.wal, options.wal_recover), (None, None));
+        let options = parse(&[
+            "--wal",
+            "run.wal",
+            "--wal-recover",
+            "run.wal",
+            "transactions.csv",
+        ])
+        .expect("Failed to parse arguments");
+        assert_eq!(options.wal.as_deref(), Some("run.wal"));
+        assert_eq!(options.wal_recover.as_deref(), Some("run.wal"));
+    }
+
+    #[test]
+    fn test_parse_command_log() {
+        let options = parse(&["transactions.csv"]).expect("Failed to parse arguments");
+        assert_eq!(options.command_log, None);
+        let options = parse(&["--command-log", "run.log", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert_eq!(options.command_log.as_deref(), Some("run.log"));
+    }
+
+    #[test]
+    fn test_parse_trace_tx() {
+        let options = parse(&["--trace-tx", "1", "--trace-tx", "48213", "transactions.csv"])
+            .expect("Failed to parse arguments");
+        assert_eq!(options.trace_tx, HashSet::from([1, 48213]));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["a.csv", "b.csv"]).is_err());
+        assert!(parse(&["--locked-format"]).is_err());
+        assert!(parse(&["--unknown", "a.csv"]).is_err());
+    }
+}
@@ -0,0 +1,183 @@
+//! Log of every transaction handed to the engine and of its result, for
+//! deterministic debugging.
+//!
+//! Unlike the write-ahead log, which only holds the applied transactions, the
+//! command log also records the rejected attempts with their error code, so
+//! that [`Engine::replay_log`] can check that a replay takes the same path as
+//! the logged run. Records use the framing of [`crate::wal`], followed by the
+//! result: a zero byte for success, or a one byte and the length-prefixed
+//! error code.
+//!
+//! [`Engine::replay_log`]: crate::engine::Engine::replay_log
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use crate::error::{CommandLog as CommandLogError, Transaction as TransactionError};
+use crate::transaction::Transaction;
+use crate::wal;
+
+/// Appends the transactions processed by an engine and their results, one
+/// record per call, see [`Engine::record_commands`].
+///
+/// [`Engine::record_commands`]: crate::engine::Engine::record_commands
+pub struct Writer {
+    inner: Box<dyn Write>,
+    // Reused encoding buffer
+    record: Vec<u8>,
+    // First write failure, after which nothing more is recorded
+    error: Option<io::Error>,
+}
+
+impl Writer {
+    pub fn new(inner: impl Write + 'static) -> Self {
+        Writer {
+            inner: Box::new(inner),
+            record: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// Create the log at `path`, truncating it if it exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be created.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Writer::new(File::create(path)?))
+    }
+
+    // Append and flush a record, so that the log is complete up to a crash
+    pub(crate) fn append(
+        &mut self,
+        transaction: &Transaction,
+        result: &Result<(), TransactionError>,
+    ) {
+        if self.error.is_some() {
+            return;
+        }
+        wal::start_record(&mut self.record);
+        wal::encode(transaction, &mut self.record);
+        match result {
+            Ok(()) => self.record.push(0),
+            Err(e) => {
+                let code = e.code().as_bytes();
+                self.record.push(1);
+                self.record
+                    .push(u8::try_from(code.len()).unwrap_or(u8::MAX));
+                self.record
+                    .extend_from_slice(&code[..code.len().min(usize::from(u8::MAX))]);
+            }
+        }
+        let written = wal::seal_record(&mut self.record)
+            .and_then(|()| self.inner.write_all(&self.record))
+            .and_then(|()| self.inner.flush());
+        if let Err(e) = written {
+            self.error = Some(e);
+        }
+    }
+
+    // The first write failure, if any
+    pub(crate) fn finish(self) -> io::Result<()> {
+        self.error.map_or(Ok(()), Err)
+    }
+}
+
+/// A transaction read back from a command log, with the result it had.
+#[derive(Debug)]
+pub struct Command {
+    pub transaction: Transaction,
+    /// The error code of a rejected transaction.
+    pub result: Result<(), String>,
+}
+
+/// Read the records of a command log, ignoring a torn final record.
+///
+/// # Errors
+///
+/// Returns an error if reading fails, or if a complete record other than the
+/// last one fails its checksum or cannot be decoded.
+pub fn read<R: Read>(mut reader: R) -> Result<Vec<Command>, CommandLogError> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    let (payloads, _) = wal::records(&bytes).map_err(CommandLogError::Checksum)?;
+    payloads
+        .into_iter()
+        .map(|(offset, payload)| decode(payload).ok_or(CommandLogError::Record(offset)))
+        .collect()
+}
+
+fn decode(payload: &[u8]) -> Option<Command> {
+    let mut rest = payload;
+    let transaction = wal::decode_prefix(&mut rest)?;
+    let result = match rest.split_first()? {
+        (0, []) => Ok(()),
+        (1, [len, code @ ..]) if code.len() == usize::from(*len) => {
+            Err(String::from_utf8(code.to_vec()).ok()?)
+        }
+        _ => return None,
+    };
+    Some(Command {
+        transaction,
+        result,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Shares the bytes written with the test once the engine owns the writer
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Test that rejected transactions are logged with their error code
+    #[test]
+    fn test_read_logged_results() {
+        let bytes = Shared::default();
+        let mut engine = Engine::new();
+        engine.record_commands(Writer::new(bytes.clone()));
+        for transaction in csv::Reader::from_reader(
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             withdrawal,1,2,20.0\n\
+             dispute,1,1,\n"
+                .as_bytes(),
+        )
+        .deserialize()
+        {
+            let _ = engine.process_transaction(transaction.expect("Failed to parse transaction"));
+        }
+        engine
+            .finish_command_log()
+            .expect("Failed to write command log");
+
+        let commands = read(bytes.0.borrow().as_slice()).expect("Failed to read log");
+        let results: Vec<_> = commands
+            .iter()
+            .map(|command| (command.transaction.tx, command.result.clone()))
+            .collect();
+        assert_eq!(
+            results,
+            [
+                (1, Ok(())),
+                (2, Err("E_INSUFFICIENT_FUNDS".to_string())),
+                (1, Ok(())),
+            ]
+        );
+    }
+}
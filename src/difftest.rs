@@ -0,0 +1,277 @@
+//! Differential testing of engine implementations.
+//!
+//! [`verify`] feeds the same transactions to two implementations of
+//! [`Subject`] and compares every account touched so far after each step, so
+//! that a rewrite of the engine internals can be checked against the current
+//! behavior on generated workloads.
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::engine::Engine;
+use crate::error::Transaction as TransactionError;
+use crate::generate::Lcg;
+use crate::store::TransactionStore;
+use crate::transaction::{Amount, Transaction, Type as TransactionType};
+
+/// Exact balances and lock status of an account.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AccountState {
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    pub locked: bool,
+}
+
+/// Engine implementation under differential test.
+pub trait Subject {
+    /// Apply a single transaction.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of a rejected transaction.
+    fn process(&mut self, transaction: Transaction) -> Result<(), TransactionError>;
+
+    /// Balances of the client's account, if it exists.
+    fn account_view(&self, client: u16) -> Option<AccountState>;
+
+    /// Ids of the client's transactions currently under dispute, sorted.
+    fn open_disputes(&self, client: u16) -> Vec<u32>;
+}
+
+impl<S: TransactionStore> Subject for Engine<S> {
+    fn process(&mut self, transaction: Transaction) -> Result<(), TransactionError> {
+        self.process_transaction(transaction)
+    }
+
+    fn account_view(&self, client: u16) -> Option<AccountState> {
+        self.accounts.get(&client).map(|account| AccountState {
+            available: account.available,
+            held: account.held,
+            total: account.total,
+            locked: account.locked,
+        })
+    }
+
+    fn open_disputes(&self, client: u16) -> Vec<u32> {
+        let mut disputed: Vec<u32> = self
+            .transactions()
+            .filter(|(_, transaction)| transaction.client == client && transaction.is_disputed())
+            .map(|(tx, _)| tx)
+            .collect();
+        disputed.sort_unstable();
+        disputed
+    }
+}
+
+/// First point where two implementations disagree.
+#[derive(Debug)]
+pub struct Divergence {
+    /// Client whose state differs.
+    pub client: u16,
+    /// Description of the differing state, left then right.
+    pub details: String,
+    /// Transactions processed up to and including the diverging one.
+    pub sequence: Vec<Transaction>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Client {} diverged after {} transactions: {}",
+            self.client,
+            self.sequence.len(),
+            self.details
+        )?;
+        writeln!(f, "type,client,tx,amount")?;
+        for transaction in &self.sequence {
+            let amount = transaction
+                .amount
+                .map(|amount| amount.to_string())
+                .unwrap_or_default();
+            writeln!(
+                f,
+                "{},{},{},{amount}",
+                transaction.t_type.as_str(),
+                transaction.client,
+                transaction.tx
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Divergence {}
+
+/// Run `transactions` through both implementations, comparing the accounts
+/// and open disputes of every client seen so far after each transaction.
+///
+/// Rejections are not compared as such, only their effect on the state.
+///
+/// # Errors
+///
+/// Returns the first [`Divergence`] found, with the sequence leading to it.
+pub fn verify<L, R, I>(left: &mut L, right: &mut R, transactions: I) -> Result<(), Divergence>
+where
+    L: Subject,
+    R: Subject,
+    I: IntoIterator<Item = Transaction>,
+{
+    let mut sequence = Vec::new();
+    let mut clients = BTreeSet::new();
+    for transaction in transactions {
+        clients.insert(transaction.client);
+        sequence.push(transaction.clone());
+        let _ = left.process(transaction.clone());
+        let _ = right.process(transaction);
+
+        for &client in &clients {
+            let details = differences(&*left, &*right, client);
+            if !details.is_empty() {
+                return Err(Divergence {
+                    client,
+                    details,
+                    sequence,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+// Describe the state of `client` that differs between the two implementations
+fn differences<L: Subject, R: Subject>(left: &L, right: &R, client: u16) -> String {
+    let mut differences = Vec::new();
+    let (ours, theirs) = (left.account_view(client), right.account_view(client));
+    if ours != theirs {
+        differences.push(format!("account {ours:?} != {theirs:?}"));
+    }
+    let (ours, theirs) = (left.open_disputes(client), right.open_disputes(client));
+    if ours != theirs {
+        differences.push(format!("open disputes {ours:?} != {theirs:?}"));
+    }
+    differences.join(", ")
+}
+
+/// Generate a deterministic workload of `count` transactions over `clients`
+/// clients, mixing deposits and withdrawals with disputes, resolves and
+/// chargebacks of earlier deposits. The same seed gives the same workload.
+#[must_use]
+pub fn workload(seed: u64, clients: u16, count: u32) -> Vec<Transaction> {
+    let mut rng = Lcg(seed);
+    let clients = clients.max(1);
+    // Earlier deposits as (tx, client), targeted by the dispute rows
+    let mut deposits: Vec<(u32, u16)> = Vec::new();
+    let mut transactions = Vec::with_capacity(count as usize);
+    for tx in 1..=count {
+        let roll = rng.below(100);
+        let transaction = if roll < 15 && !deposits.is_empty() {
+            let index = rng.below(u32::try_from(deposits.len()).unwrap_or(u32::MAX));
+            let (target, client) = deposits[index as usize];
+            let t_type = match roll % 3 {
+                0 => TransactionType::Dispute,
+                1 => TransactionType::Resolve,
+                _ => TransactionType::Chargeback,
+            };
+            row(t_type, client, target, None)
+        } else {
+            let client = u16::try_from(rng.below(u32::from(clients))).unwrap_or_default();
+            // Amounts with up to four decimal places, below 1 000
+            let amount = Amount::from(rng.below(10_000_000)) / 10_000.0;
+            if roll < 60 {
+                deposits.push((tx, client));
+                row(TransactionType::Deposit, client, tx, Some(amount))
+            } else {
+                row(TransactionType::Withdrawal, client, tx, Some(amount))
+            }
+        };
+        transactions.push(transaction);
+    }
+    transactions
+}
+
+fn row(t_type: TransactionType, client: u16, tx: u32, amount: Option<Amount>) -> Transaction {
+    Transaction::new(t_type, client, tx, amount)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::SpillStore;
+
+    // Engine that silently drops one transaction id, to exercise the reporting
+    struct Dropping {
+        engine: Engine,
+        tx: u32,
+    }
+
+    impl Subject for Dropping {
+        fn process(&mut self, transaction: Transaction) -> Result<(), TransactionError> {
+            if transaction.tx == self.tx {
+                return Ok(());
+            }
+            self.engine.process(transaction)
+        }
+
+        fn account_view(&self, client: u16) -> Option<AccountState> {
+            Subject::account_view(&self.engine, client)
+        }
+
+        fn open_disputes(&self, client: u16) -> Vec<u32> {
+            self.engine.open_disputes(client)
+        }
+    }
+
+    // Test that spilling transactions to disk does not change the engine
+    // behavior on generated workloads
+    #[test]
+    fn test_spill_store_matches_default() {
+        let dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        // Every step lists the open disputes of each client, reading the
+        // spilled transactions back, so the workloads are kept small
+        for seed in 0..4 {
+            let store = SpillStore::create(&dir.path().join(format!("spill{seed}")), 16)
+                .expect("Failed to create spill store");
+            let mut default = Engine::new();
+            let mut spilling = Engine::with_store(store);
+            if let Err(divergence) = verify(&mut default, &mut spilling, workload(seed, 5, 400)) {
+                panic!("{divergence}");
+            }
+            assert!(spilling.store().stats().spilled > 0);
+        }
+    }
+
+    // Test that the first divergence is reported with the sequence leading to it
+    #[test]
+    fn test_verify_reports_first_divergence() {
+        let transactions = vec![
+            row(TransactionType::Deposit, 1, 1, Some(10.0)),
+            row(TransactionType::Deposit, 2, 2, Some(5.0)),
+            row(TransactionType::Withdrawal, 2, 3, Some(1.0)),
+        ];
+        let mut dropping = Dropping {
+            engine: Engine::new(),
+            tx: 2,
+        };
+        let divergence = verify(&mut Engine::new(), &mut dropping, transactions)
+            .expect_err("Expected a divergence");
+
+        assert_eq!(divergence.client, 2);
+        assert_eq!(divergence.sequence.len(), 2);
+        let report = divergence.to_string();
+        assert!(report.starts_with("Client 2 diverged after 2 transactions: account Some("));
+        assert!(report.ends_with("deposit,1,1,10\ndeposit,2,2,5\n"));
+    }
+
+    #[test]
+    fn test_workload_is_deterministic() {
+        let first = workload(7, 10, 100);
+        let second = workload(7, 10, 100);
+        assert_eq!(first.len(), 100);
+        assert!(first
+            .iter()
+            .zip(&second)
+            .all(|(a, b)| a.tx == b.tx && a.t_type == b.t_type && a.amount == b.amount));
+    }
+}
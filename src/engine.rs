@@ -1,980 +1,5108 @@
-use std::collections::HashMap;
+use crate::account::{Account, AccountView, LockReason};
+use crate::command_log;
+use crate::error::{
+    Capacity, CommandLog as CommandLogError, Imbalance as ImbalanceError, Invariant,
+    Merge as MergeError, Transaction as TransactionError,
+};
+use crate::event::{Event, EventHook};
+use crate::handler::TransactionHandler;
+use crate::input::Provenance;
+use crate::ledger::{self, Entry as LedgerEntry};
+use crate::policy::{
+    ChargebackOverdraw, FeeSchedule, InsufficientFunds, LockOnChargeback, Policy,
+    WithdrawalsDuringDispute,
+};
+use crate::store::TransactionStore;
+use crate::transaction::{
+    Amount, DisputeState, StoredTx, Transaction, TxKind, TxStatus, Type as TransactionType,
+};
+use std::borrow::Cow;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
-use crate::account::Account;
-use crate::error::Transaction as TransactionError;
-use crate::transaction::{Transaction, Type as TransactionType};
+mod deposit;
+mod dispute;
+mod withdrawal;
 
-pub struct Engine {
-    pub accounts: HashMap<u16, Account>,
-    pub transactions: HashMap<u32, Transaction>,
+/// Hash map used for the engine state. Keys are small integers, so with the
+/// `fast-hash` feature the DoS-resistant `SipHash` is swapped for `FxHash`.
+pub type Map<K, V> = std::collections::HashMap<K, V, MapHasher>;
+#[cfg(not(feature = "fast-hash"))]
+pub type MapHasher = std::hash::RandomState;
+#[cfg(feature = "fast-hash")]
+pub type MapHasher = rustc_hash::FxBuildHasher;
+
+pub type AccountMap = Map<u16, Account>;
+pub type TransactionMap = Map<u32, StoredTx>;
+
+// Accounts shown by the `Debug` output of an engine
+const DEBUG_SAMPLE: usize = 3;
+
+// Difference tolerated by `Engine::global_check`, growing with the funds
+// moved once they exceed a million
+const IMBALANCE_TOLERANCE: Amount = 1e-6;
+
+/// Transaction processing engine, generic over where the deposits and
+/// withdrawals are stored.
+pub struct Engine<S = TransactionMap> {
+    // Read through `account`, `account_view` or `all_account_views`
+    pub(crate) accounts: AccountMap,
+    // Deposits and withdrawals by id; dispute rows only reference them
+    transactions: S,
+    policy: Policy,
+    // Cumulative amount successfully deposited per client during this run
+    deposited: Map<u16, Amount>,
+    // Rows submitted per client during this run, counted only under the
+    // `max_transactions_per_client` policy
+    submitted: Map<u16, usize>,
+    // Client ids in the order their accounts were created
+    order: Vec<u16>,
+    hook: Option<Box<dyn EventHook>>,
+    // Rows referencing a transaction not seen yet, oldest first, and the
+    // number of them per referenced id
+    pending: VecDeque<Transaction>,
+    pending_ids: Map<u32, usize>,
+    reorder_stats: ReorderStats,
+    // Deposits and withdrawals skipped by the `dust_threshold` policy
+    dust_skipped: u64,
+    // Fees charged by the `fees` policy, and the one charged by the last
+    // transaction when ledger recording is enabled
+    fees_collected: Amount,
+    last_fee: Option<Fee>,
+    // Entries not taken yet, when ledger recording is enabled
+    ledger: Option<Vec<LedgerEntry>>,
+    commands: Option<command_log::Writer>,
+    // Funds moved so far; the sum of the account totals is only taken by
+    // `global_totals`
+    totals: GlobalTotals,
+    // Handler applying each transaction type, the built-in ones included
+    handlers: Map<TransactionType, Rc<dyn TransactionHandler<S>>>,
+}
+
+// Fee charged on a transaction by the `fees` policy
+#[derive(Debug, Clone, Copy)]
+struct Fee {
+    amount: Amount,
+    account: Option<u16>,
+}
+
+/// Final result of a row handed to [`Engine::submit`] or [`Engine::finish`].
+#[derive(Debug)]
+pub struct Outcome {
+    pub transaction: Transaction,
+    pub result: Result<(), TransactionError>,
+}
+
+/// Balances an engine would reach after a batch of transactions, computed by
+/// [`Engine::simulate`] without changing the engine.
+#[derive(Debug)]
+pub struct SimulationResult {
+    /// Every account after the batch, ordered by client.
+    pub accounts: Vec<AccountView>,
+    /// The rejected transactions with their error, in input order.
+    pub errors: Vec<Outcome>,
+}
+
+/// Counters of the rows parked by the `reference_grace` policy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReorderStats {
+    /// Rows parked because their referenced transaction was unknown.
+    pub parked: u64,
+    /// Parked rows applied once the referenced transaction arrived.
+    pub matched_late: u64,
+    /// Parked rows rejected because the buffer overflowed or the input ended.
+    pub expired: u64,
+}
+
+/// Funds that entered and left the accounts, and the sum of the account
+/// totals they should add up to, see [`Engine::global_check`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct GlobalTotals {
+    /// Account totals restored from snapshots or merged from other engines.
+    pub opening: Amount,
+    /// Applied deposits, pending ones included.
+    pub deposits: Amount,
+    /// Applied withdrawals, without their fee.
+    pub withdrawals: Amount,
+    /// Funds removed by chargebacks, less any overdraft written off.
+    pub chargebacks: Amount,
+    /// Deposits reversed by refunds.
+    pub refunds: Amount,
+    /// Fees charged without a fee account to credit them to.
+    pub fees: Amount,
+    /// Net funds credited by custom handlers with [`Engine::adjust`].
+    pub adjustments: Amount,
+    /// Sum of the account totals.
+    pub accounts: Amount,
+}
+
+impl GlobalTotals {
+    /// Sum the account totals should have: the opening balance plus the
+    /// deposits, less the withdrawals, chargebacks, refunds and fees.
+    #[must_use]
+    pub fn expected(&self) -> Amount {
+        self.opening + self.deposits
+            - self.withdrawals
+            - self.chargebacks
+            - self.refunds
+            - self.fees
+            + self.adjustments
+    }
+
+    // Funds counted by the totals, whatever their direction
+    fn volume(&self) -> Amount {
+        self.opening.abs()
+            + self.deposits
+            + self.withdrawals
+            + self.chargebacks.abs()
+            + self.refunds
+            + self.fees
+            + self.adjustments.abs()
+    }
+
+    fn add(&mut self, other: &GlobalTotals) {
+        self.opening += other.opening;
+        self.deposits += other.deposits;
+        self.withdrawals += other.withdrawals;
+        self.chargebacks += other.chargebacks;
+        self.refunds += other.refunds;
+        self.fees += other.fees;
+        self.adjustments += other.adjustments;
+    }
+}
+
+/// Transaction of a batch rejected by [`Engine::process_all`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Failure {
+    /// 1-based position of the transaction in the batch.
+    pub position: u64,
+    /// Input file and line the transaction was read from, if known.
+    pub provenance: Option<Provenance>,
+    pub error: TransactionError,
+}
+
+/// Counts and timing of a batch processed with [`Engine::process_all`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProcessSummary {
+    pub successful: u64,
+    pub errors: u64,
+    /// Withdrawals rejected for insufficient funds under
+    /// [`InsufficientFunds::Warn`], which are not counted as errors.
+    pub skipped: u64,
+    /// Each rejected transaction, in order.
+    pub failures: Vec<Failure>,
+    /// Wall-clock time spent processing the batch, or `None` if it was not
+    /// measured.
+    pub elapsed: Option<Duration>,
+}
+
+impl ProcessSummary {
+    /// Number of transactions processed, accepted, rejected or skipped.
+    #[must_use]
+    pub fn processed(&self) -> u64 {
+        self.successful + self.errors + self.skipped
+    }
+
+    /// Processed transactions per second, or `None` if no time was measured.
+    #[must_use]
+    // Counts stay far below 2^52, where the conversion would lose precision
+    #[allow(clippy::cast_precision_loss)]
+    pub fn throughput(&self) -> Option<f64> {
+        let seconds = self.elapsed?.as_secs_f64();
+        (seconds > 0.0).then(|| self.processed() as f64 / seconds)
+    }
+}
+
+/// Run `process` and return its result with the wall-clock time it took, as
+/// measured for [`ProcessSummary::elapsed`].
+pub fn timed<T>(process: impl FnOnce() -> T) -> (T, Duration) {
+    let started = Instant::now();
+    let result = process();
+    (result, started.elapsed())
+}
+
+/// Builder used to configure the engine policy before processing starts.
+#[derive(Debug, Default)]
+pub struct EngineBuilder {
+    policy: Policy,
+    accounts_capacity: usize,
+    transactions_capacity: usize,
+}
+
+impl EngineBuilder {
+    /// Size hint for inputs of about `transactions` records. Client ids are
+    /// `u16`, so the accounts map never needs more than 65 536 slots.
+    #[must_use]
+    pub fn reserve(mut self, transactions: usize) -> Self {
+        self.accounts_capacity = transactions.min(usize::from(u16::MAX) + 1);
+        self.transactions_capacity = transactions;
+        self
+    }
+
+    #[must_use]
+    pub fn max_single_deposit(mut self, limit: Option<Amount>) -> Self {
+        self.policy.max_single_deposit = limit;
+        self
+    }
+
+    #[must_use]
+    pub fn max_total_deposits_per_client(mut self, limit: Option<Amount>) -> Self {
+        self.policy.max_total_deposits_per_client = limit;
+        self
+    }
+
+    #[must_use]
+    pub fn withdrawals_during_dispute(mut self, policy: WithdrawalsDuringDispute) -> Self {
+        self.policy.withdrawals_during_dispute = policy;
+        self
+    }
+
+    #[must_use]
+    pub fn on_chargeback_overdraw(mut self, policy: ChargebackOverdraw) -> Self {
+        self.policy.on_chargeback_overdraw = policy;
+        self
+    }
+
+    /// Number of dispute, resolve and chargeback rows referencing an unknown
+    /// transaction that [`Engine::submit`] keeps pending; 0 disables it.
+    #[must_use]
+    pub fn reference_grace(mut self, rows: usize) -> Self {
+        self.policy.reference_grace = rows;
+        self
+    }
+
+    #[must_use]
+    pub fn dispute_clamp_to_available(mut self, clamp: bool) -> Self {
+        self.policy.dispute_clamp_to_available = clamp;
+        self
+    }
+
+    /// Hard cap on the number of stored deposits and withdrawals.
+    #[must_use]
+    pub fn max_transactions(mut self, limit: Option<usize>) -> Self {
+        self.policy.max_transactions = limit;
+        self
+    }
+
+    /// Hard cap on the number of accounts.
+    #[must_use]
+    pub fn max_accounts(mut self, limit: Option<usize>) -> Self {
+        self.policy.max_accounts = limit;
+        self
+    }
+
+    /// Cap on the number of times a deposit can be disputed again after its
+    /// first dispute was settled.
+    #[must_use]
+    pub fn max_redisputes(mut self, limit: Option<u32>) -> Self {
+        self.policy.max_redisputes = limit;
+        self
+    }
+
+    /// Keep deposited funds pending until a settle row for the deposit.
+    #[must_use]
+    pub fn pending_deposits(mut self, enabled: bool) -> Self {
+        self.policy.pending_deposits = enabled;
+        self
+    }
+
+    /// Cap on the total funds of each account, enforced on deposits.
+    #[must_use]
+    pub fn max_balance(mut self, limit: Option<Amount>) -> Self {
+        self.policy.max_balance = limit;
+        self
+    }
+
+    /// Skip deposits and withdrawals below this amount as no-ops.
+    #[must_use]
+    pub fn dust_threshold(mut self, threshold: Option<Amount>) -> Self {
+        self.policy.dust_threshold = threshold;
+        self
+    }
+
+    /// Fees charged on top of matching deposits and withdrawals.
+    #[must_use]
+    pub fn fee_schedule(mut self, schedule: FeeSchedule) -> Self {
+        self.policy.fees = schedule;
+        self
+    }
+
+    /// Reject resolves that would leave negative held funds.
+    #[must_use]
+    pub fn strict_held(mut self, strict: bool) -> Self {
+        self.policy.strict_held = strict;
+        self
+    }
+
+    /// Cap on the disputes open at once on each account.
+    #[must_use]
+    pub fn max_open_disputes_per_client(mut self, limit: Option<u32>) -> Self {
+        self.policy.max_open_disputes_per_client = limit;
+        self
+    }
+
+    /// Which chargebacks lock the account, all of them by default.
+    #[must_use]
+    pub fn lock_on_chargeback(mut self, lock: LockOnChargeback) -> Self {
+        self.policy.lock_on_chargeback = lock;
+        self
+    }
+
+    /// Lock the account once its cumulative chargeback losses exceed `limit`.
+    #[must_use]
+    pub fn lock_on_cumulative_loss(mut self, limit: Option<Amount>) -> Self {
+        self.policy.lock_on_cumulative_loss = limit;
+        self
+    }
+
+    /// Whether accounts must be created by an `open` transaction.
+    #[must_use]
+    pub fn require_explicit_open(mut self, require: bool) -> Self {
+        self.policy.require_explicit_open = require;
+        self
+    }
+
+    /// Whether deposit and withdrawal amounts are rounded to cents.
+    #[must_use]
+    pub fn round_to_cents(mut self, round: bool) -> Self {
+        self.policy.round_to_cents = round;
+        self
+    }
+
+    /// Clients whose transactions are rejected.
+    #[must_use]
+    pub fn deny_clients(mut self, clients: HashSet<u16>) -> Self {
+        self.policy.deny_clients = clients;
+        self
+    }
+
+    /// Whether locked accounts accept deposits.
+    #[must_use]
+    pub fn allow_deposits_when_locked(mut self, allow: bool) -> Self {
+        self.policy.allow_deposits_when_locked = allow;
+        self
+    }
+
+    /// Cap on the transactions each client may submit during the run.
+    #[must_use]
+    pub fn max_transactions_per_client(mut self, limit: Option<usize>) -> Self {
+        self.policy.max_transactions_per_client = limit;
+        self
+    }
+
+    /// Whether withdrawals rejected for insufficient funds count as errors.
+    #[must_use]
+    pub fn insufficient_funds(mut self, policy: InsufficientFunds) -> Self {
+        self.policy.insufficient_funds = policy;
+        self
+    }
+
+    #[must_use]
+    pub fn build(self) -> Engine {
+        let mut engine = Engine::with_capacity(self.accounts_capacity, self.transactions_capacity);
+        engine.policy = self.policy;
+        engine
+    }
+
+    /// Build an engine keeping its transactions in `store`. The transactions
+    /// size hint given to [`EngineBuilder::reserve`] is not applied to it.
+    #[must_use]
+    pub fn build_with_store<S: TransactionStore>(self, store: S) -> Engine<S> {
+        let mut engine = Engine::with_store(store);
+        engine.reserve_accounts(self.accounts_capacity);
+        engine.policy = self.policy;
+        engine
+    }
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Counts and the first accounts created rather than the whole state, which
+// can hold millions of entries
+impl<S: TransactionStore> fmt::Debug for Engine<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sample: Vec<&Account> = self.accounts_in_order().take(DEBUG_SAMPLE).collect();
+        f.debug_struct("Engine")
+            .field("accounts", &self.accounts.len())
+            .field("transactions", &self.transactions.len())
+            .field("pending", &self.pending.len())
+            .field("sample", &sample)
+            .field("policy", &self.policy)
+            .finish_non_exhaustive()
+    }
 }
 
 impl Engine {
+    #[must_use]
     pub fn new() -> Self {
+        Self::with_capacity(0, 0)
+    }
+
+    /// Create an engine whose maps are pre-sized for the given number of
+    /// accounts and transactions, avoiding rehashing on large inputs.
+    #[must_use]
+    pub fn with_capacity(accounts: usize, transactions: usize) -> Self {
+        let mut engine = Engine::with_store(Map::with_capacity_and_hasher(
+            transactions,
+            MapHasher::default(),
+        ));
+        engine.reserve_accounts(accounts);
+        engine
+    }
+
+    /// Create an engine rejecting deposits that would push an account's
+    /// total funds above `limit`, with an otherwise default policy.
+    #[must_use]
+    pub fn with_max_balance(limit: Amount) -> Self {
+        Self::builder().max_balance(Some(limit)).build()
+    }
+
+    /// Create an engine rejecting the transactions of a client once it has
+    /// submitted `max_per_client` of them, with an otherwise default policy.
+    #[must_use]
+    pub fn with_rate_limit(max_per_client: usize) -> Self {
+        Self::builder()
+            .max_transactions_per_client(Some(max_per_client))
+            .build()
+    }
+
+    #[must_use]
+    pub fn builder() -> EngineBuilder {
+        EngineBuilder::default()
+    }
+}
+
+impl<S: TransactionStore> Engine<S> {
+    /// Create an engine with the default policy that keeps its transactions
+    /// in `store`, which should be empty.
+    #[must_use]
+    pub fn with_store(store: S) -> Self {
         Engine {
-            accounts: HashMap::new(),
-            transactions: HashMap::new(),
+            accounts: AccountMap::default(),
+            transactions: store,
+            policy: Policy::default(),
+            deposited: Map::default(),
+            submitted: Map::default(),
+            order: Vec::new(),
+            hook: None,
+            pending: VecDeque::new(),
+            pending_ids: Map::default(),
+            reorder_stats: ReorderStats::default(),
+            dust_skipped: 0,
+            fees_collected: 0.0,
+            last_fee: None,
+            ledger: None,
+            commands: None,
+            totals: GlobalTotals::default(),
+            handlers: builtin_handlers(),
         }
     }
 
-    pub fn process_transaction(
+    fn reserve_accounts(&mut self, accounts: usize) {
+        self.accounts.reserve(accounts);
+        self.deposited.reserve(accounts);
+    }
+
+    /// Account of the given client.
+    #[must_use]
+    pub fn account(&self, client: u16) -> Option<&Account> {
+        self.accounts.get(&client)
+    }
+
+    /// Raw accounts map, kept for consumers of the former public field.
+    #[deprecated(
+        note = "use `Engine::account`, `Engine::account_view` or `Engine::all_account_views`"
+    )]
+    #[must_use]
+    pub fn accounts(&self) -> &AccountMap {
+        &self.accounts
+    }
+
+    /// Read-only view of the given client's account, with amounts truncated
+    /// like the report.
+    #[must_use]
+    pub fn account_view(&self, client: u16) -> Option<AccountView> {
+        self.accounts.get(&client).map(AccountView::from)
+    }
+
+    /// Views of every account, in ascending client id order.
+    #[must_use]
+    pub fn all_account_views(&self) -> Vec<AccountView> {
+        self.accounts_by_client().map(AccountView::from).collect()
+    }
+
+    /// Iterate over the accounts in ascending client id order.
+    ///
+    /// Client ids are `u16`, so walking the whole id space gives a sorted
+    /// traversal without collecting or sorting the accounts.
+    pub fn accounts_by_client(&self) -> impl Iterator<Item = &Account> {
+        (0..=u16::MAX).filter_map(|client| self.accounts.get(&client))
+    }
+
+    /// Accounts with funds held by open disputes, by client id.
+    pub fn accounts_with_held(&self) -> impl Iterator<Item = &Account> {
+        self.accounts_by_client()
+            .filter(|account| account.held > 0.0)
+    }
+
+    /// Iterate over the accounts in the order they were created by processing.
+    pub fn accounts_in_order(&self) -> impl Iterator<Item = &Account> {
+        self.order
+            .iter()
+            .filter_map(|client| self.accounts.get(client))
+    }
+
+    // Cumulative successful deposits per client, saved in snapshots
+    pub(crate) fn deposited(&self) -> impl Iterator<Item = (u16, Amount)> + '_ {
+        self.deposited
+            .iter()
+            .map(|(&client, &amount)| (client, amount))
+    }
+
+    // Rebuild the engine state from a snapshot. Accounts are given in
+    // creation order; the caller checks for duplicate ids.
+    pub(crate) fn restore(
         &mut self,
-        transaction: Transaction,
-    ) -> Result<(), TransactionError> {
-        let client_id = transaction.client;
-        let account = self.accounts.entry(client_id).or_insert_with(|| Account {
-            client: client_id,
-            ..Default::default()
-        });
+        accounts: Vec<Account>,
+        transactions: Vec<(u32, StoredTx)>,
+        deposited: Vec<(u16, Amount)>,
+        fees_collected: Amount,
+    ) {
+        self.fees_collected += fees_collected;
+        for account in accounts {
+            self.totals.opening += account.total;
+            self.order.push(account.client);
+            self.accounts.insert(account.client, account);
+        }
+        for (tx, transaction) in transactions {
+            self.transactions.insert(tx, transaction);
+        }
+        self.deposited.extend(deposited);
+    }
 
-        if account.locked {
-            return Err(TransactionError::AccountLocked(client_id));
+    /// Store holding the deposits and withdrawals.
+    #[must_use]
+    pub fn store(&self) -> &S {
+        &self.transactions
+    }
+
+    /// Stored deposit or withdrawal with the given id, read back as a copy
+    /// when the store keeps it outside memory.
+    #[must_use]
+    pub fn transaction(&self, tx: u32) -> Option<Cow<'_, StoredTx>> {
+        self.transactions.load(tx)
+    }
+
+    /// Whether the stored transaction with the given id is under dispute, or
+    /// `None` if no such transaction is stored.
+    #[must_use]
+    pub fn is_disputed(&self, tx: u32) -> Option<bool> {
+        self.transaction(tx)
+            .map(|transaction| transaction.is_disputed())
+    }
+
+    /// Number of accounts.
+    #[must_use]
+    pub fn account_count(&self) -> usize {
+        self.accounts.len()
+    }
+
+    /// Number of stored deposits and withdrawals.
+    #[must_use]
+    pub fn transaction_count(&self) -> usize {
+        self.transactions.len()
+    }
+
+    /// Iterate over the stored deposits and withdrawals, in no particular
+    /// order, those the store keeps outside memory being read back as copies.
+    pub fn transactions(&self) -> impl Iterator<Item = (u32, Cow<'_, StoredTx>)> {
+        self.transactions.iter()
+    }
+
+    /// Status of the stored deposits and withdrawals, in no particular
+    /// order. Each status is a copy, so filtering them, for example with a
+    /// [`TxFilter`](crate::transaction::TxFilter), clones nothing else.
+    pub fn transactions_iter(&self) -> impl Iterator<Item = TxStatus> + '_ {
+        self.transactions
+            .iter()
+            .map(|(tx, transaction)| TxStatus::new(tx, &transaction))
+    }
+
+    /// Stored deposits and withdrawals, sorted by transaction id.
+    #[must_use]
+    pub fn transactions_by_id(&self) -> Vec<(u32, Cow<'_, StoredTx>)> {
+        let mut transactions: Vec<(u32, Cow<'_, StoredTx>)> = self.transactions().collect();
+        transactions.sort_unstable_by_key(|&(tx, _)| tx);
+        transactions
+    }
+
+    /// Net flow of a client: its stored deposits minus its stored
+    /// withdrawals, whatever their dispute state, or `None` if the client has
+    /// no account. Walks every stored transaction.
+    ///
+    /// The amounts are summed in transaction id order, so that the result
+    /// does not depend on the order of the transaction map.
+    #[must_use]
+    pub fn net_flow(&self, client: u16) -> Option<Amount> {
+        self.accounts.get(&client)?;
+        let mut flows: Vec<(u32, Amount)> = self
+            .transactions
+            .iter()
+            .filter(|(_, stored)| stored.client == client)
+            .map(|(tx, stored)| match stored.kind {
+                TxKind::Deposit => (tx, stored.amount),
+                TxKind::Withdrawal => (tx, -stored.amount),
+            })
+            .collect();
+        flows.sort_unstable_by_key(|&(tx, _)| tx);
+        Some(flows.into_iter().map(|(_, amount)| amount).sum())
+    }
+
+    /// Verify the internal consistency of the engine state.
+    ///
+    /// Checks that balances are finite, that
+    /// `total == available + held + pending`, that each account's held funds,
+    /// dispute shortfall and open dispute count match its disputed deposits,
+    /// that its pending funds match its undisputed pending deposits, that
+    /// locked accounts record a lock reason, and that
+    /// stored transactions reference known accounts. Intended for tests and
+    /// fuzzing: it walks every account and transaction.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first [`Invariant`] found to be violated.
+    pub fn check_invariants(&self) -> Result<(), Invariant> {
+        let mut disputed: Map<u16, (Amount, Amount, u32)> = Map::default();
+        let mut pending: Map<u16, Amount> = Map::default();
+        for (tx, transaction) in self.transactions.iter() {
+            if !self.accounts.contains_key(&transaction.client) {
+                return Err(Invariant::UnknownClient(tx, transaction.client));
+            }
+            if transaction.pending && !transaction.is_disputed() {
+                *pending.entry(transaction.client).or_default() += transaction.amount;
+            }
+            if transaction.is_disputed() {
+                if transaction.kind != TxKind::Deposit {
+                    return Err(Invariant::DisputedNonDeposit(tx));
+                }
+                let entry = disputed.entry(transaction.client).or_default();
+                entry.0 += transaction.held;
+                entry.1 += transaction.shortfall();
+                entry.2 += 1;
+            }
+        }
+
+        for account in self.accounts.values() {
+            let client = account.client;
+            if ![
+                account.available,
+                account.held,
+                account.pending,
+                account.total,
+                account.dispute_shortfall,
+            ]
+            .iter()
+            .all(|value| value.is_finite())
+            {
+                return Err(Invariant::NonFiniteBalance(client));
+            }
+            if !approx_eq(
+                account.total,
+                account.available + account.held + account.pending,
+            ) {
+                return Err(Invariant::TotalMismatch(client));
+            }
+            if !approx_eq(
+                account.pending,
+                pending.get(&client).copied().unwrap_or_default(),
+            ) {
+                return Err(Invariant::PendingMismatch(client));
+            }
+            let (held, shortfall, open_disputes) =
+                disputed.get(&client).copied().unwrap_or_default();
+            if !approx_eq(account.held, held) {
+                return Err(Invariant::HeldMismatch(client));
+            }
+            if !approx_eq(account.dispute_shortfall, shortfall) {
+                return Err(Invariant::ShortfallMismatch(client));
+            }
+            if account.open_disputes != open_disputes {
+                return Err(Invariant::OpenDisputesMismatch(client));
+            }
+            if account.locked != account.lock_reason.is_some() {
+                return Err(Invariant::LockReasonMismatch(client));
+            }
+        }
+        Ok(())
+    }
+
+    /// Funds moved by the engine so far, with the current sum of the account
+    /// totals.
+    #[must_use]
+    pub fn global_totals(&self) -> GlobalTotals {
+        GlobalTotals {
+            accounts: self
+                .accounts
+                .values()
+                .fold(0.0, |sum, account| sum + account.total),
+            ..self.totals
         }
+    }
 
-        match transaction.t_type {
-            TransactionType::Deposit => self.process_deposit(transaction),
-            TransactionType::Withdrawal => self.process_withdrawal(transaction),
-            TransactionType::Dispute => self.process_dispute(&transaction),
-            TransactionType::Resolve => self.process_resolve(&transaction),
-            TransactionType::Chargeback => self.process_chargeback(&transaction),
+    /// Check that the account totals add up to the funds deposited, less
+    /// those withdrawn, charged back or paid as fees. Cheap enough to run
+    /// at the end of every run: a difference means an engine bug.
+    ///
+    /// The sums are compared to within 1e-6, or a millionth of a millionth
+    /// of the funds moved beyond a million. Engines merged with
+    /// [`Engine::merge`] are expected to hold distinct clients, as the funds
+    /// moved by both are added up.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`ImbalanceError`] with both sums if they differ.
+    pub fn global_check(&self) -> Result<GlobalTotals, ImbalanceError> {
+        let totals = self.global_totals();
+        let expected = totals.expected();
+        let tolerance = IMBALANCE_TOLERANCE.max(totals.volume() * IMBALANCE_TOLERANCE * 1e-6);
+        if (totals.accounts - expected).abs() <= tolerance {
+            Ok(totals)
+        } else {
+            Err(ImbalanceError {
+                expected,
+                actual: totals.accounts,
+            })
         }
     }
 
-    fn process_deposit(&mut self, transaction: Transaction) -> Result<(), TransactionError> {
+    /// Lock an existing account, rejecting any further transaction on it.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`TransactionError::AccountNotFound`] if the client has no
+    /// account, or [`TransactionError::AccountLocked`] if it is already locked,
+    /// in which case the original lock reason is kept.
+    pub fn freeze(&mut self, client: u16, reason: LockReason) -> Result<(), TransactionError> {
         let account = self
             .accounts
-            .get_mut(&transaction.client)
-            .ok_or(TransactionError::AccountNotFound(transaction.client))?;
-        let amount = transaction
-            .amount
-            .ok_or(TransactionError::InvalidAmount(transaction.tx))?;
-
-        account.available += amount;
-        account.total += amount;
-        self.transactions.insert(transaction.tx, transaction);
+            .get_mut(&client)
+            .ok_or(TransactionError::AccountNotFound(client))?;
+        if account.locked {
+            return Err(TransactionError::AccountLocked(client));
+        }
+        account.lock(reason);
+        if let Some(hook) = self.hook.as_mut() {
+            hook.on_event(&Event::AccountLocked {
+                client,
+                tx: None,
+                reason,
+                charged_back: 0.0,
+            });
+        }
         Ok(())
     }
 
-    fn process_withdrawal(&mut self, transaction: Transaction) -> Result<(), TransactionError> {
+    /// Unlock an account whose lock reason is reversible. Unfreezing an
+    /// account that is not locked has no effect.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`TransactionError::AccountNotFound`] if the client has no
+    /// account, or [`TransactionError::LockNotReversible`] if it was locked by
+    /// a chargeback or for fraud.
+    pub fn unfreeze(&mut self, client: u16) -> Result<(), TransactionError> {
         let account = self
             .accounts
-            .get_mut(&transaction.client)
-            .ok_or(TransactionError::AccountNotFound(transaction.client))?;
-        let amount = transaction
-            .amount
-            .ok_or(TransactionError::InvalidAmount(transaction.tx))?;
+            .get_mut(&client)
+            .ok_or(TransactionError::AccountNotFound(client))?;
+        if let Some(reason) = account.lock_reason {
+            if !reason.is_reversible() {
+                return Err(TransactionError::LockNotReversible(client));
+            }
+        }
+        let was_locked = account.locked;
+        account.locked = false;
+        account.lock_reason = None;
+        if let (true, Some(hook)) = (was_locked, self.hook.as_mut()) {
+            hook.on_event(&Event::AccountUnlocked { client });
+        }
+        Ok(())
+    }
 
-        if account.available >= amount {
-            account.available -= amount;
-            account.total -= amount;
-            self.transactions.insert(transaction.tx, transaction);
-            Ok(())
-        } else {
-            Err(TransactionError::InsufficientFunds(account.client))
+    /// Merge the state of an engine that processed another set of clients,
+    /// such as another shard of an input split by client range.
+    ///
+    /// No client may be present in both engines, as its balances would be
+    /// counted twice in the global totals; transactions present in both must
+    /// be identical. The policy, event hook and command log of `self` are
+    /// kept.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`MergeError::ConflictingAccount`] or
+    /// [`MergeError::ConflictingTransaction`] if an id exists in both engines
+    /// with a different state, and [`MergeError::DuplicateAccount`] if a
+    /// client exists in both with the same state, in which case `self` is
+    /// left unchanged.
+    /// Returns [`MergeError::Inconsistent`] if the merged state fails
+    /// [`Engine::check_invariants`]; the merge has then already been applied.
+    ///
+    /// The transactions of a client must all have been processed by the same
+    /// engine: a dispute routed to another shard than its deposit is rejected
+    /// there and cannot be detected here.
+    pub fn merge<T: TransactionStore>(&mut self, other: Engine<T>) -> Result<(), MergeError> {
+        for account in other.accounts.values() {
+            if let Some(ours) = self.accounts.get(&account.client) {
+                let differences = account_differences(ours, account);
+                if differences.is_empty() {
+                    return Err(MergeError::DuplicateAccount(account.client));
+                }
+                return Err(MergeError::ConflictingAccount {
+                    client: account.client,
+                    details: differences.join(", "),
+                });
+            }
+        }
+        for (tx, transaction) in other.transactions.iter() {
+            if let Some(ours) = self.transactions.load(tx) {
+                let differences = transaction_differences(&ours, &transaction);
+                if !differences.is_empty() {
+                    return Err(MergeError::ConflictingTransaction {
+                        tx,
+                        details: differences.join(", "),
+                    });
+                }
+            }
+        }
+
+        let Engine {
+            mut accounts,
+            transactions,
+            deposited,
+            submitted,
+            order,
+            totals,
+            fees_collected,
+            ..
+        } = other;
+        self.totals.add(&totals);
+        self.fees_collected += fees_collected;
+        for client in order {
+            if let Some(account) = accounts.remove(&client) {
+                if !self.accounts.contains_key(&client) {
+                    self.order.push(client);
+                    self.accounts.insert(client, account);
+                }
+            }
+        }
+        // Accounts inserted directly into the map have no creation order
+        for (client, account) in accounts {
+            self.accounts.entry(client).or_insert(account);
+        }
+        for (tx, transaction) in transactions.iter() {
+            if !self.transactions.contains(tx) {
+                self.transactions.insert(tx, transaction.into_owned());
+            }
+        }
+        for (client, amount) in deposited {
+            self.deposited.entry(client).or_insert(amount);
         }
+        for (client, count) in submitted {
+            self.submitted.entry(client).or_insert(count);
+        }
+
+        self.check_invariants()?;
+        Ok(())
     }
 
-    fn process_dispute(&mut self, transaction: &Transaction) -> Result<(), TransactionError> {
-        let account = self
-            .accounts
-            .get_mut(&transaction.client)
-            .ok_or(TransactionError::AccountNotFound(transaction.client))?;
-        let original_tx = self
-            .transactions
-            .get_mut(&transaction.tx)
-            .ok_or(TransactionError::NotFound(transaction.tx, account.client))?;
-
-        if !original_tx.disputed && original_tx.client == account.client {
-            let amount = original_tx
-                .amount
-                .ok_or(TransactionError::InvalidAmount(transaction.tx))?;
-            if let TransactionType::Deposit = original_tx.t_type {
-                account.available -= amount;
-                account.held += amount;
-                original_tx.disputed = true;
-                Ok(())
+    /// Apply every transaction of `transactions` in order, timing the batch.
+    ///
+    /// Rejected transactions are skipped, as with
+    /// [`Engine::process_transaction`], and their errors collected in the
+    /// summary, except for the withdrawals counted as skipped by the
+    /// [`InsufficientFunds`] policy.
+    pub fn process_all<I>(&mut self, transactions: I) -> ProcessSummary
+    where
+        I: IntoIterator<Item = Transaction>,
+    {
+        let mut summary = ProcessSummary::default();
+        let ((), elapsed) = timed(|| {
+            for (position, transaction) in (1..).zip(transactions) {
+                let provenance = transaction.provenance.clone();
+                let t_type = transaction.t_type.clone();
+                match self.process_transaction(transaction) {
+                    Ok(()) => summary.successful += 1,
+                    Err(e) if self.policy.insufficient_funds.skips(&t_type, &e) => {
+                        summary.skipped += 1;
+                    }
+                    Err(e) => {
+                        summary.errors += 1;
+                        summary.failures.push(Failure {
+                            position,
+                            provenance,
+                            error: e,
+                        });
+                    }
+                }
+            }
+        });
+        summary.elapsed = Some(elapsed);
+        summary
+    }
+
+    /// Balances as of transaction `tx_limit`: replay the rows of
+    /// `transactions` with a `tx` up to `tx_limit`, in order, into a fresh
+    /// engine with the same policy and handlers, and return its accounts by
+    /// client id.
+    ///
+    /// The engine only keeps deposits and withdrawals, not the dispute rows
+    /// applied to them, so the original input must be supplied again. Dispute,
+    /// resolve and chargeback rows carry the id of the transaction they
+    /// reference and are kept when that id is within the limit.
+    #[must_use]
+    pub fn balances_after<I>(&self, transactions: I, tx_limit: u32) -> Vec<AccountView>
+    where
+        I: IntoIterator<Item = Transaction>,
+        S: Default,
+    {
+        let mut replay = Engine::with_store(S::default());
+        replay.policy = self.policy.clone();
+        replay.handlers.clone_from(&self.handlers);
+        for transaction in transactions {
+            if transaction.tx <= tx_limit {
+                let _ = replay.process_transaction(transaction);
+            }
+        }
+        replay.all_account_views()
+    }
+
+    /// Apply a transaction, holding back rows that reference a transaction
+    /// not seen yet when the `reference_grace` policy is enabled.
+    ///
+    /// Returns the rows settled by this call, in order: the given row unless
+    /// it was parked, then any parked rows referencing it that could now be
+    /// applied, then a parked row expired because the buffer overflowed.
+    /// Parked rows referencing the same transaction are retried in arrival
+    /// order right after it, so they may be applied after later rows of the
+    /// same client. With the policy disabled this is equivalent to
+    /// [`Engine::process_transaction`].
+    pub fn submit(&mut self, transaction: Transaction) -> Vec<Outcome> {
+        let grace = self.policy.reference_grace;
+        let references = matches!(
+            transaction.t_type,
+            TransactionType::Dispute
+                | TransactionType::Resolve
+                | TransactionType::Chargeback
+                | TransactionType::Settle
+                | TransactionType::Refund
+        );
+        // Rows of denied clients are rejected right away rather than parked
+        let denied = self.policy.deny_clients.contains(&transaction.client);
+        if grace > 0 && references && !denied && !self.transactions.contains(transaction.tx) {
+            self.reorder_stats.parked += 1;
+            *self.pending_ids.entry(transaction.tx).or_default() += 1;
+            self.pending.push_back(transaction);
+            return if self.pending.len() > grace {
+                self.expire_oldest().into_iter().collect()
             } else {
-                Err(TransactionError::InvalidDispute(transaction.tx))
+                Vec::new()
+            };
+        }
+
+        let tx = transaction.tx;
+        let result = self.process_transaction(transaction.clone());
+        let mut outcomes = vec![Outcome {
+            transaction,
+            result,
+        }];
+        if self.pending_ids.contains_key(&tx) && self.transactions.contains(tx) {
+            self.pending_ids.remove(&tx);
+            let (matched, pending) = std::mem::take(&mut self.pending)
+                .into_iter()
+                .partition(|parked| parked.tx == tx);
+            self.pending = pending;
+            for transaction in matched {
+                self.reorder_stats.matched_late += 1;
+                let result = self.process_transaction(transaction.clone());
+                outcomes.push(Outcome {
+                    transaction,
+                    result,
+                });
             }
-        } else {
-            Err(TransactionError::AlreadyDisputed(transaction.tx))
         }
+        outcomes
     }
 
-    fn process_resolve(&mut self, transaction: &Transaction) -> Result<(), TransactionError> {
-        let account = self
-            .accounts
-            .get_mut(&transaction.client)
-            .ok_or(TransactionError::AccountNotFound(transaction.client))?;
-        let original_tx = self
-            .transactions
-            .get_mut(&transaction.tx)
-            .ok_or(TransactionError::NotFound(transaction.tx, account.client))?;
-
-        if original_tx.disputed && original_tx.client == account.client {
-            let amount = original_tx
-                .amount
-                .ok_or(TransactionError::InvalidAmount(transaction.tx))?;
-            account.available += amount;
-            account.held -= amount;
-            original_tx.disputed = false;
-            Ok(())
-        } else {
-            Err(TransactionError::NotUnderDispute(transaction.tx))
+    /// Reject every row still parked by [`Engine::submit`], oldest first, as
+    /// its referenced transaction never arrived. Call once the input ends.
+    pub fn finish(&mut self) -> Vec<Outcome> {
+        std::iter::from_fn(|| self.expire_oldest()).collect()
+    }
+
+    /// Counters of the rows parked by [`Engine::submit`].
+    #[must_use]
+    pub fn reorder_stats(&self) -> ReorderStats {
+        self.reorder_stats
+    }
+
+    /// Number of rows parked by [`Engine::submit`] and not settled yet, which
+    /// snapshots do not keep.
+    #[must_use]
+    pub fn parked(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Number of deposits and withdrawals skipped by the `dust_threshold`
+    /// policy.
+    #[must_use]
+    pub fn dust_skipped(&self) -> u64 {
+        self.dust_skipped
+    }
+
+    /// Total of the fees charged by the `fees` policy, whether credited to a
+    /// fee account or not.
+    #[must_use]
+    pub fn fees_collected(&self) -> Amount {
+        self.fees_collected
+    }
+
+    fn expire_oldest(&mut self) -> Option<Outcome> {
+        let transaction = self.pending.pop_front()?;
+        if let Some(count) = self.pending_ids.get_mut(&transaction.tx) {
+            *count -= 1;
+            if *count == 0 {
+                self.pending_ids.remove(&transaction.tx);
+            }
+        }
+        self.reorder_stats.expired += 1;
+        let error = TransactionError::NotFound(transaction.tx, transaction.client);
+        if let Some(hook) = self.hook.as_mut() {
+            hook.on_event(&Event::Rejected {
+                transaction: &transaction,
+                before: self.accounts.get(&transaction.client),
+                error: &error,
+            });
+        }
+        Some(Outcome {
+            transaction,
+            result: Err(error),
+        })
+    }
+
+    /// Start or stop recording the balance movements of applied transactions
+    /// as ledger entries, see [`crate::ledger`]. Stopping drops the entries not
+    /// taken yet.
+    pub fn record_ledger(&mut self, enabled: bool) {
+        if !enabled {
+            self.ledger = None;
+        } else if self.ledger.is_none() {
+            self.ledger = Some(Vec::new());
+        }
+    }
+
+    /// Take the ledger entries recorded since the last call, in processing
+    /// order. Empty when recording is disabled.
+    pub fn take_ledger(&mut self) -> Vec<LedgerEntry> {
+        self.ledger.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// Record every transaction handed to [`Engine::process_transaction`]
+    /// from now on, with its result, to `log`, see [`crate::command_log`].
+    pub fn record_commands(&mut self, log: command_log::Writer) {
+        self.commands = Some(log);
+    }
+
+    /// Stop recording commands.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first error met writing the log, after which the
+    /// following commands were not recorded.
+    pub fn finish_command_log(&mut self) -> io::Result<()> {
+        self.commands
+            .take()
+            .map_or(Ok(()), command_log::Writer::finish)
+    }
+
+    /// Replay the command log at `path` into the engine, checking that every
+    /// transaction gets the result it was logged with. Returns the number of
+    /// transactions replayed.
+    ///
+    /// Replaying into an engine built with the policy of the logged run
+    /// rebuilds its state, rejected attempts included.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the log cannot be read or is corrupt, or at the
+    /// first transaction whose result differs from the logged one, with the
+    /// transactions before it applied.
+    pub fn replay_log(&mut self, path: &Path) -> Result<usize, CommandLogError> {
+        let commands = command_log::read(File::open(path)?)?;
+        for command in &commands {
+            let tx = command.transaction.tx;
+            let replayed = self.process_transaction(command.transaction.clone());
+            let logged = command.result.as_ref().err().map(String::as_str);
+            if logged != replayed.as_ref().err().map(TransactionError::code) {
+                return Err(CommandLogError::Diverged {
+                    tx,
+                    logged: logged.unwrap_or("success").to_string(),
+                    replayed: replayed
+                        .map_or_else(|e| e.code().to_string(), |()| "success".to_string()),
+                });
+            }
         }
+        Ok(commands.len())
     }
 
-    fn process_chargeback(&mut self, transaction: &Transaction) -> Result<(), TransactionError> {
+    /// Register an observer notified of every processed transaction.
+    pub fn set_event_hook(&mut self, hook: Box<dyn EventHook>) {
+        self.hook = Some(hook);
+    }
+
+    /// Apply the transactions of type `t_type` with `handler`, replacing the
+    /// handler registered for it, built-in ones included. Transactions of a
+    /// type with no handler are rejected with
+    /// [`TransactionError::UnsupportedType`].
+    pub fn register_handler(
+        &mut self,
+        t_type: TransactionType,
+        handler: impl TransactionHandler<S> + 'static,
+    ) {
+        self.handlers.insert(t_type, Rc::new(handler));
+    }
+
+    /// Credit `amount` to the available and total funds of the account of
+    /// `client`, or debit it when negative. Meant for the handlers of custom
+    /// transaction types; adjustments are counted by [`Engine::global_check`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`TransactionError::AccountNotFound`] if the client has no
+    /// account, or [`TransactionError::BalanceOverflow`] if a balance would
+    /// overflow, leaving the account unchanged.
+    pub fn adjust(&mut self, client: u16, amount: Amount) -> Result<(), TransactionError> {
         let account = self
             .accounts
-            .get_mut(&transaction.client)
-            .ok_or(TransactionError::AccountNotFound(transaction.client))?;
-        let original_tx =
-            self.transactions
-                .get_mut(&transaction.tx)
-                .ok_or(TransactionError::NotFound(
-                    transaction.tx,
-                    transaction.client,
-                ))?;
-
-        if original_tx.disputed && original_tx.client == transaction.client {
-            if let TransactionType::Deposit = original_tx.t_type {
-                let amount = original_tx
-                    .amount
-                    .ok_or(TransactionError::InvalidAmount(transaction.tx))?;
-                account.held -= amount;
-                account.total -= amount;
-
-                original_tx.disputed = false;
-                account.locked = true;
-
-                Ok(())
-            } else {
-                Err(TransactionError::InvalidChargeback(transaction.tx))
-            }
+            .get_mut(&client)
+            .ok_or(TransactionError::AccountNotFound(client))?;
+        let available = checked_add(account.available, amount)
+            .ok_or(TransactionError::BalanceOverflow(client))?;
+        let total =
+            checked_add(account.total, amount).ok_or(TransactionError::BalanceOverflow(client))?;
+        account.available = available;
+        account.total = total;
+        self.totals.adjustments += amount;
+        Ok(())
+    }
+
+    /// Apply a single transaction to the engine state.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`TransactionError`] when the transaction is rejected; the
+    /// account balances are left unchanged in that case.
+    // Takes ownership so rows can be handed over straight from the CSV reader;
+    // only deposits and withdrawals are kept, as a `StoredTx`.
+    #[allow(clippy::needless_pass_by_value)]
+    pub fn process_transaction(
+        &mut self,
+        mut transaction: Transaction,
+    ) -> Result<(), TransactionError> {
+        let moves_funds = matches!(
+            transaction.t_type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        );
+        if self.policy.round_to_cents && moves_funds {
+            transaction.amount = transaction.amount.map(round_to_cents);
+        }
+        let result = self.process_observed(&transaction);
+        if let Some(commands) = self.commands.as_mut() {
+            commands.append(&transaction, &result);
+        }
+        result
+    }
+
+    // Apply `transaction`, reporting the state transition to the ledger and
+    // the event hook
+    fn process_observed(&mut self, transaction: &Transaction) -> Result<(), TransactionError> {
+        // Dust never reaches an account, so there is no state transition to
+        // record or report
+        if self.is_dust(transaction) {
+            self.dust_skipped += 1;
+            return Ok(());
+        }
+        if self.hook.is_none() && self.ledger.is_none() {
+            return self.apply(transaction);
+        }
+
+        // Keep a copy around to describe the state transition
+        let before = self.accounts.get(&transaction.client).cloned();
+        let result = self.apply(transaction);
+
+        // A fee credited back to the account paying it nets out
+        let fee = self
+            .last_fee
+            .take()
+            .filter(|fee| fee.account != Some(transaction.client));
+        if let (Ok(()), Some(entries)) = (&result, self.ledger.as_mut()) {
+            entries.extend(ledger::entries_with_fee(
+                transaction.tx,
+                before.as_ref(),
+                &self.accounts[&transaction.client],
+                fee.map_or(0.0, |fee| fee.amount),
+            ));
+            if let Some(Fee {
+                amount,
+                account: Some(account),
+            }) = fee
+            {
+                entries.extend(ledger::fee_credit(transaction.tx, account, amount));
+            }
+        }
+
+        if let Some(hook) = self.hook.as_mut() {
+            let event = match &result {
+                Ok(()) => Event::Applied {
+                    transaction,
+                    before: before.as_ref(),
+                    after: &self.accounts[&transaction.client],
+                },
+                Err(error) => Event::Rejected {
+                    transaction,
+                    before: before.as_ref(),
+                    error,
+                },
+            };
+            hook.on_event(&event);
+            let after = self.accounts.get(&transaction.client);
+            for event in lifecycle_events(transaction, before.as_ref(), after) {
+                hook.on_event(&event);
+            }
+        }
+        result
+    }
+
+    // Whether the `dust_threshold` policy turns the row into a no-op. Checked
+    // before any other validation: a zero amount is dust rather than rejected
+    // as non-positive, while a negative amount is never dust. Only the deny
+    // list comes first, so that the rows of a denied client are all rejected.
+    fn is_dust(&self, transaction: &Transaction) -> bool {
+        let moves_funds = matches!(
+            transaction.t_type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        ) && !self.policy.deny_clients.contains(&transaction.client);
+        match (self.policy.dust_threshold, transaction.amount) {
+            (Some(threshold), Some(amount)) if moves_funds => (0.0..threshold).contains(&amount),
+            _ => false,
+        }
+    }
+
+    fn apply(&mut self, transaction: &Transaction) -> Result<(), TransactionError> {
+        let client_id = transaction.client;
+        if self.policy.deny_clients.contains(&client_id) {
+            return Err(TransactionError::ClientDenied(client_id));
+        }
+        // Rows rejected by the limit itself are not counted
+        if let Some(limit) = self.policy.max_transactions_per_client {
+            let submitted = self.submitted.entry(client_id).or_default();
+            if *submitted >= limit {
+                return Err(TransactionError::RateLimited(client_id));
+            }
+            *submitted += 1;
+        }
+        // Cloned so that the handler can borrow the engine mutably
+        let handler = self
+            .handlers
+            .get(&transaction.t_type)
+            .cloned()
+            .ok_or(TransactionError::UnsupportedType(transaction.tx))?;
+        if !self.accounts.contains_key(&client_id) {
+            if self.policy.require_explicit_open
+                && transaction.t_type != TransactionType::OpenAccount
+            {
+                return Err(TransactionError::AccountNotOpen(client_id));
+            }
+            check_capacity(
+                self.policy.max_accounts,
+                self.accounts.len(),
+                Capacity::Accounts,
+            )?;
+        }
+        let order = &mut self.order;
+        let account = self.accounts.entry(client_id).or_insert_with(|| {
+            order.push(client_id);
+            Account {
+                client: client_id,
+                ..Default::default()
+            }
+        });
+
+        if account.locked {
+            let exempt = match transaction.t_type {
+                TransactionType::Deposit => self.policy.allow_deposits_when_locked,
+                // No dispute can be opened on a locked account, so one still
+                // open predates the lock and is closed to release its funds
+                TransactionType::Resolve | TransactionType::Chargeback => self
+                    .transactions
+                    .get(transaction.tx)
+                    .is_some_and(|stored| stored.client == client_id && stored.is_disputed()),
+                _ => false,
+            };
+            if !exempt {
+                return Err(TransactionError::AccountLocked(client_id));
+            }
+        }
+
+        handler.apply(self, transaction)?;
+
+        if let Some(account) = self.accounts.get_mut(&client_id) {
+            account.last_tx = Some(transaction.tx);
+            account.applied += 1;
+            account.record_peaks();
+        }
+        Ok(())
+    }
+
+    // Fee the `fees` policy charges on `transaction`, failing if its fee
+    // account could not be credited with it
+    fn fee(&self, transaction: &Transaction) -> Result<Option<Fee>, TransactionError> {
+        let Some(rule) = transaction
+            .amount
+            .and_then(|amount| self.policy.fees.rule_for(&transaction.t_type, amount))
+        else {
+            return Ok(None);
+        };
+        let fee = Fee {
+            amount: transaction.amount.unwrap_or(0.0) * rule.rate,
+            account: rule.fee_account,
+        };
+        // A fee credited back to the account paying it nets out
+        if let Some(client) = fee.account.filter(|&client| client != transaction.client) {
+            self.check_fee_credit(client, fee.amount)?;
+        }
+        Ok(Some(fee))
+    }
+
+    // Check that `amount` can be credited to the fee account of `client`
+    // under the same limits as a deposit, creating the account if needed
+    fn check_fee_credit(&self, client: u16, amount: Amount) -> Result<(), TransactionError> {
+        let Some(account) = self.accounts.get(&client) else {
+            if self.policy.require_explicit_open {
+                return Err(TransactionError::AccountNotOpen(client));
+            }
+            return check_capacity(
+                self.policy.max_accounts,
+                self.accounts.len(),
+                Capacity::Accounts,
+            );
+        };
+        if account.locked && !self.policy.allow_deposits_when_locked {
+            return Err(TransactionError::AccountLocked(client));
+        }
+        if self
+            .policy
+            .max_balance
+            .is_some_and(|limit| account.total + amount > limit)
+        {
+            return Err(TransactionError::BalanceCapExceeded(client));
+        }
+        checked_add(account.available, amount)
+            .and(checked_add(account.total, amount))
+            .map(drop)
+            .ok_or(TransactionError::BalanceOverflow(client))
+    }
+
+    // Credit a fee charged by an applied transaction to its fee account
+    fn collect_fee(&mut self, fee: Fee) {
+        self.fees_collected += fee.amount;
+        if fee.account.is_none() {
+            self.totals.fees += fee.amount;
+        }
+        if let Some(client) = fee.account {
+            let order = &mut self.order;
+            let account = self.accounts.entry(client).or_insert_with(|| {
+                order.push(client);
+                Account {
+                    client,
+                    ..Default::default()
+                }
+            });
+            account.available += fee.amount;
+            account.total += fee.amount;
+            account.record_peaks();
+        }
+        if self.ledger.is_some() {
+            self.last_fee = Some(fee);
+        }
+    }
+
+    // Account of the client of a row referencing a stored transaction, and
+    // that transaction, which must belong to the same client
+    fn resolve_reference(
+        &mut self,
+        tx: u32,
+        client: u16,
+    ) -> Result<(&mut Account, &mut StoredTx), TransactionError> {
+        let account = self
+            .accounts
+            .get_mut(&client)
+            .ok_or(TransactionError::AccountNotFound(client))?;
+        let original_tx = self
+            .transactions
+            .get_mut(tx)
+            .ok_or(TransactionError::NotFound(tx, client))?;
+        if original_tx.client != client {
+            return Err(TransactionError::ClientMismatch(
+                tx,
+                original_tx.client,
+                client,
+            ));
+        }
+        Ok((account, original_tx))
+    }
+}
+
+impl<S: TransactionStore + Clone> Engine<S> {
+    /// Deep copy of the state and policy of the engine, for what-if analysis.
+    ///
+    /// The fork has no event hook, ledger recording or command log, so that
+    /// applying transactions to it has no effect outside of it.
+    #[must_use]
+    pub fn fork(&self) -> Self {
+        Engine {
+            accounts: self.accounts.clone(),
+            transactions: self.transactions.clone(),
+            policy: self.policy.clone(),
+            deposited: self.deposited.clone(),
+            submitted: self.submitted.clone(),
+            order: self.order.clone(),
+            hook: None,
+            pending: self.pending.clone(),
+            pending_ids: self.pending_ids.clone(),
+            reorder_stats: self.reorder_stats,
+            dust_skipped: self.dust_skipped,
+            fees_collected: self.fees_collected,
+            last_fee: None,
+            ledger: None,
+            commands: None,
+            totals: self.totals,
+            handlers: self.handlers.clone(),
+        }
+    }
+
+    /// Apply `transactions` in order to a [`fork`](Engine::fork) of the
+    /// engine, such as pending chargebacks, and return the resulting
+    /// accounts and rejections, leaving the engine itself untouched.
+    ///
+    /// Rows parked by the `reference_grace` policy are settled at the end of
+    /// the batch, as with [`Engine::finish`].
+    #[must_use]
+    pub fn simulate(&self, transactions: &[Transaction]) -> SimulationResult {
+        let mut fork = self.fork();
+        let mut outcomes = Vec::new();
+        for transaction in transactions {
+            outcomes.extend(fork.submit(transaction.clone()));
+        }
+        outcomes.extend(fork.finish());
+        SimulationResult {
+            accounts: fork.all_account_views(),
+            errors: outcomes
+                .into_iter()
+                .filter(|outcome| outcome.result.is_err())
+                .collect(),
+        }
+    }
+}
+
+// Describe the fields that differ between two states of the same account.
+// Merging only accepts exact duplicates, so balances are compared exactly.
+#[allow(clippy::float_cmp)]
+fn account_differences(ours: &Account, theirs: &Account) -> Vec<String> {
+    let mut differences = Vec::new();
+    for (field, a, b) in [
+        ("available", ours.available, theirs.available),
+        ("held", ours.held, theirs.held),
+        ("pending", ours.pending, theirs.pending),
+        ("total", ours.total, theirs.total),
+        (
+            "dispute_shortfall",
+            ours.dispute_shortfall,
+            theirs.dispute_shortfall,
+        ),
+        ("peak_total", ours.peak_total, theirs.peak_total),
+        ("peak_held", ours.peak_held, theirs.peak_held),
+        (
+            "chargeback_losses",
+            ours.chargeback_losses,
+            theirs.chargeback_losses,
+        ),
+    ] {
+        if a != b {
+            differences.push(format!("{field} {a} != {b}"));
+        }
+    }
+    if ours.locked != theirs.locked {
+        differences.push(format!("locked {} != {}", ours.locked, theirs.locked));
+    }
+    if ours.lock_reason != theirs.lock_reason {
+        differences.push(format!(
+            "lock_reason {:?} != {:?}",
+            ours.lock_reason, theirs.lock_reason
+        ));
+    }
+    if ours.open_disputes != theirs.open_disputes {
+        differences.push(format!(
+            "open_disputes {} != {}",
+            ours.open_disputes, theirs.open_disputes
+        ));
+    }
+    if ours.last_tx != theirs.last_tx {
+        differences.push(format!(
+            "last_tx {:?} != {:?}",
+            ours.last_tx, theirs.last_tx
+        ));
+    }
+    if ours.applied != theirs.applied {
+        differences.push(format!("applied {} != {}", ours.applied, theirs.applied));
+    }
+    differences
+}
+
+// Describe the fields that differ between two versions of the same transaction
+#[allow(clippy::float_cmp)]
+fn transaction_differences(ours: &StoredTx, theirs: &StoredTx) -> Vec<String> {
+    let mut differences = Vec::new();
+    if ours.kind != theirs.kind {
+        differences.push(format!(
+            "type {} != {}",
+            ours.kind.as_str(),
+            theirs.kind.as_str()
+        ));
+    }
+    if ours.client != theirs.client {
+        differences.push(format!("client {} != {}", ours.client, theirs.client));
+    }
+    if ours.amount != theirs.amount {
+        differences.push(format!("amount {} != {}", ours.amount, theirs.amount));
+    }
+    if ours.dispute_state != theirs.dispute_state {
+        differences.push(format!(
+            "dispute_state {:?} != {:?}",
+            ours.dispute_state, theirs.dispute_state
+        ));
+    }
+    if ours.held != theirs.held {
+        differences.push(format!("held {} != {}", ours.held, theirs.held));
+    }
+    if ours.disputes != theirs.disputes {
+        differences.push(format!("disputes {} != {}", ours.disputes, theirs.disputes));
+    }
+    if ours.pending != theirs.pending {
+        differences.push(format!("pending {} != {}", ours.pending, theirs.pending));
+    }
+    if ours.refunded != theirs.refunded {
+        differences.push(format!("refunded {} != {}", ours.refunded, theirs.refunded));
+    }
+    if ours.note != theirs.note {
+        differences.push(format!("note {:?} != {:?}", ours.note, theirs.note));
+    }
+    differences
+}
+
+// Changes of the lifecycle of the account of the client of `transaction`,
+// told by comparing the account before and after the row
+fn lifecycle_events(
+    transaction: &Transaction,
+    before: Option<&Account>,
+    after: Option<&Account>,
+) -> Vec<Event<'static>> {
+    let mut events = Vec::new();
+    let Some(after) = after else {
+        return events;
+    };
+    if before.is_none() {
+        events.push(Event::AccountOpened {
+            client: after.client,
+            tx: transaction.tx,
+        });
+    }
+    if after.locked && !before.is_some_and(|account| account.locked) {
+        let losses = before.map_or(0.0, |account| account.chargeback_losses);
+        events.push(Event::AccountLocked {
+            client: after.client,
+            tx: Some(transaction.tx),
+            reason: after.lock_reason.unwrap_or(LockReason::Chargeback),
+            charged_back: after.chargeback_losses - losses,
+        });
+    }
+    events
+}
+
+// Record kept for a deposit or withdrawal with a validated amount
+fn stored(transaction: &Transaction, amount: Amount, kind: TxKind) -> StoredTx {
+    StoredTx {
+        client: transaction.client,
+        amount,
+        kind,
+        dispute_state: DisputeState::Undisputed,
+        held: 0.0,
+        disputes: 0,
+        pending: false,
+        refunded: false,
+        note: transaction.note.clone(),
+    }
+}
+
+// Amount of a deposit or withdrawal, which must be present and strictly
+// positive: a zero amount would store a transaction moving nothing, and a
+// negative one would move funds the wrong way
+fn positive_amount(transaction: &Transaction) -> Result<Amount, TransactionError> {
+    transaction
+        .amount
+        .filter(|&amount| amount > 0.0)
+        .ok_or(TransactionError::InvalidAmount(transaction.tx))
+}
+
+// Round half-up to two decimal places, in a single step so that 10.12496
+// gives 10.12. The nudge, far below the input precision, absorbs the
+// representation error that would round 1.005, stored as 1.00499..., down
+fn round_to_cents(amount: Amount) -> Amount {
+    (amount * 100.0 + 1e-6).round() / 100.0
+}
+
+// Fail once `count` has reached the optional `limit`
+fn check_capacity(
+    limit: Option<usize>,
+    count: usize,
+    capacity: Capacity,
+) -> Result<(), TransactionError> {
+    match limit {
+        Some(limit) if count >= limit => Err(TransactionError::CapacityExceeded(capacity, limit)),
+        _ => Ok(()),
+    }
+}
+
+// Add `delta` to a balance, returning `None` if the result leaves the finite range
+fn checked_add(balance: Amount, delta: Amount) -> Option<Amount> {
+    let result = balance + delta;
+    result.is_finite().then_some(result)
+}
+
+// Handlers of the built-in transaction types, registered on every new engine
+fn builtin_handlers<S: TransactionStore>() -> Map<TransactionType, Rc<dyn TransactionHandler<S>>> {
+    let handlers: [(TransactionType, Rc<dyn TransactionHandler<S>>); 8] = [
+        (TransactionType::Deposit, Rc::new(Engine::process_deposit)),
+        (
+            TransactionType::Withdrawal,
+            Rc::new(Engine::process_withdrawal),
+        ),
+        (TransactionType::Dispute, Rc::new(Engine::process_dispute)),
+        (TransactionType::Resolve, Rc::new(Engine::process_resolve)),
+        (
+            TransactionType::Chargeback,
+            Rc::new(Engine::process_chargeback),
+        ),
+        (TransactionType::Settle, Rc::new(Engine::process_settle)),
+        (TransactionType::Refund, Rc::new(Engine::process_refund)),
+        // The engine creates the account before calling the handler, so
+        // opening an account has nothing left to do
+        (
+            TransactionType::OpenAccount,
+            Rc::new(|_: &mut Engine<S>, _: &Transaction| Ok(())),
+        ),
+    ];
+    handlers.into_iter().collect()
+}
+
+// Compare balances up to the rounding error accumulated by f64 arithmetic
+fn approx_eq(a: Amount, b: Amount) -> bool {
+    let scale = a.abs().max(b.abs()).max(1.0);
+    (a - b).abs() <= scale * 1e-9
+}
+
+#[cfg(test)]
+#[allow(clippy::float_cmp)]
+mod tests {
+    use super::*;
+    use crate::input::Schema;
+    use crate::policy::FeeRule;
+    use crate::transaction::{Transaction, TxFilter, Type as TransactionType};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::Arc;
+
+    // Test processing a deposit transaction
+    #[test]
+    fn test_process_deposit() {
+        let mut engine = Engine::new();
+        let deposit_tx = Transaction::deposit(1, 1, 1000.0);
+
+        engine
+            .process_transaction(deposit_tx)
+            .expect("Failed to process deposit transaction");
+
+        let account = engine
+            .accounts
+            .get(&1)
+            .expect("Account not found after deposit transaction");
+        assert_eq!(account.available, 1000.0);
+        assert_eq!(account.held, 0.0);
+        assert_eq!(account.total, 1000.0);
+        assert!(!account.locked);
+    }
+
+    // Test processing a withdrawal transaction
+    #[test]
+    fn test_process_withdrawal() {
+        let mut engine = Engine::new();
+
+        // First, deposit some funds
+        let deposit_tx = Transaction::deposit(1, 1, 1000.0);
+        engine
+            .process_transaction(deposit_tx)
+            .expect("Failed to process deposit");
+
+        // Then, withdraw some funds
+        let withdrawal_tx = Transaction::withdrawal(1, 2, 500.0);
+        engine
+            .process_transaction(withdrawal_tx)
+            .expect("Failed to process withdrawal");
+
+        let account = engine
+            .accounts
+            .get(&1)
+            .expect("Account not found after withdrawal");
+        assert_eq!(account.available, 500.0);
+        assert_eq!(account.held, 0.0);
+        assert_eq!(account.total, 500.0);
+        assert!(!account.locked);
+    }
+
+    // Test withdrawal with insufficient funds
+    #[test]
+    fn test_withdrawal_insufficient_funds() {
+        let mut engine = Engine::new();
+
+        // Deposit some funds
+        let deposit_tx = Transaction::deposit(1, 1, 300.0);
+        engine
+            .process_transaction(deposit_tx)
+            .expect("Failed to process deposit");
+
+        // Attempt to withdraw more than available
+        let withdrawal_tx = Transaction::withdrawal(1, 2, 500.0);
+        let result = engine.process_transaction(withdrawal_tx);
+
+        assert!(result.is_err());
+        if let Err(TransactionError::InsufficientFunds(client_id)) = result {
+            assert_eq!(client_id, 1);
+        } else {
+            panic!("Expected InsufficientFunds error");
+        }
+
+        // Account balances should remain unchanged
+        let account = engine
+            .accounts
+            .get(&1)
+            .expect("Account not found after insufficient funds withdrawal");
+        assert_eq!(account.available, 300.0);
+        assert_eq!(account.held, 0.0);
+        assert_eq!(account.total, 300.0);
+        assert!(!account.locked);
+    }
+
+    // Test processing a dispute on a deposit
+    #[test]
+    fn test_process_dispute_deposit() {
+        let mut engine = Engine::new();
+
+        // Deposit funds
+        let deposit_tx = Transaction::deposit(1, 1, 1000.0);
+        engine
+            .process_transaction(deposit_tx)
+            .expect("Failed to process deposit");
+
+        // Initiate a dispute on the deposit
+        let dispute_tx = Transaction::dispute(1, 1);
+        engine
+            .process_transaction(dispute_tx)
+            .expect("Failed to process dispute");
+
+        // Check account balances
+        let account = engine
+            .accounts
+            .get(&1)
+            .expect("Account not found after dispute");
+        assert_eq!(account.available, 0.0);
+        assert_eq!(account.held, 1000.0);
+        assert_eq!(account.total, 1000.0);
+        assert!(!account.locked);
+    }
+
+    // Test resolving a dispute
+    #[test]
+    fn test_process_resolve() {
+        let mut engine = Engine::new();
+
+        // Deposit funds and dispute
+        let deposit_tx = Transaction::deposit(1, 1, 500.0);
+        engine
+            .process_transaction(deposit_tx)
+            .expect("Failed to process deposit");
+
+        let dispute_tx = Transaction::dispute(1, 1);
+        engine
+            .process_transaction(dispute_tx)
+            .expect("Failed to process dispute");
+
+        // Resolve the dispute
+        let resolve_tx = Transaction::resolve(1, 1);
+        engine
+            .process_transaction(resolve_tx)
+            .expect("Failed to process resolve");
+
+        // Check account balances
+        let account = engine
+            .accounts
+            .get(&1)
+            .expect("Account not found after resolve");
+        assert_eq!(account.available, 500.0);
+        assert_eq!(account.held, 0.0);
+        assert_eq!(account.total, 500.0);
+        assert!(!account.locked);
+    }
+
+    // Test chargeback processing
+    #[test]
+    fn test_process_chargeback() {
+        let mut engine = Engine::new();
+
+        // Deposit funds and dispute
+        let deposit_tx = Transaction::deposit(1, 1, 400.0);
+        engine
+            .process_transaction(deposit_tx)
+            .expect("Failed to process deposit");
+
+        let dispute_tx = Transaction::dispute(1, 1);
+        engine
+            .process_transaction(dispute_tx)
+            .expect("Failed to process dispute");
+
+        // Process chargeback
+        let chargeback_tx = Transaction::chargeback(1, 1);
+        engine
+            .process_transaction(chargeback_tx)
+            .expect("Failed to process chargeback");
+
+        // Check account balances and locked status
+        let account = engine
+            .accounts
+            .get(&1)
+            .expect("Account not found after chargeback");
+        assert_eq!(account.available, 0.0);
+        assert_eq!(account.held, 0.0);
+        assert_eq!(account.total, 0.0);
+        assert!(account.locked);
+    }
+
+    // Test attempting transactions on a locked account
+    #[test]
+    fn test_transaction_on_locked_account() {
+        let mut engine = Engine::new();
+
+        // Deposit funds and process chargeback to lock the account
+        let deposit_tx = Transaction::deposit(1, 1, 400.0);
+        engine
+            .process_transaction(deposit_tx)
+            .expect("Failed to process deposit");
+
+        let dispute_tx = Transaction::dispute(1, 1);
+        engine
+            .process_transaction(dispute_tx)
+            .expect("Failed to process dispute");
+
+        let chargeback_tx = Transaction::chargeback(1, 1);
+        engine
+            .process_transaction(chargeback_tx)
+            .expect("Failed to process chargeback");
+
+        // Attempt to process a new deposit on the locked account
+        let new_deposit_tx = Transaction::deposit(1, 2, 100.0);
+        let result = engine.process_transaction(new_deposit_tx);
+        assert!(result.is_err());
+        if let Err(TransactionError::AccountLocked(client_id)) = result {
+            assert_eq!(client_id, 1);
+        } else {
+            panic!("Expected AccountLocked error");
+        }
+    }
+
+    // Test that a locked account accepts deposits only when the policy allows
+    // them, and still rejects withdrawals and disputes
+    #[test]
+    fn test_allow_deposits_when_locked() {
+        for allow in [false, true] {
+            let mut engine = Engine::builder().allow_deposits_when_locked(allow).build();
+            process_csv(
+                &mut engine,
+                "type,client,tx,amount\n\
+                 deposit,1,1,50.0\n\
+                 deposit,1,2,20.0\n\
+                 dispute,1,1,\n\
+                 chargeback,1,1,\n",
+            );
+            assert!(engine.accounts[&1].locked);
+
+            let deposit = engine.process_transaction(Transaction::deposit(1, 3, 30.0));
+            let account = &engine.accounts[&1];
+            if allow {
+                assert!(deposit.is_ok());
+                assert_eq!((account.available, account.total), (50.0, 50.0));
+            } else {
+                assert!(matches!(deposit, Err(TransactionError::AccountLocked(1))));
+                assert_eq!((account.available, account.total), (20.0, 20.0));
+            }
+            assert!(engine.accounts[&1].locked);
+            assert!(matches!(
+                engine.process_transaction(Transaction::withdrawal(1, 4, 5.0)),
+                Err(TransactionError::AccountLocked(1))
+            ));
+            assert!(matches!(
+                engine.process_transaction(Transaction::dispute(1, 2)),
+                Err(TransactionError::AccountLocked(1))
+            ));
+            engine.global_check().expect("Global totals do not balance");
+        }
+    }
+
+    // Test that a client is rate limited past its submitted transactions,
+    // rejected ones included, while another client is unaffected
+    #[test]
+    fn test_rate_limit() {
+        let mut engine = Engine::with_rate_limit(3);
+        engine
+            .process_transaction(Transaction::deposit(1, 1, 100.0))
+            .expect("Failed to process deposit");
+        assert!(matches!(
+            engine.process_transaction(Transaction::withdrawal(1, 2, 500.0)),
+            Err(TransactionError::InsufficientFunds(1))
+        ));
+        engine
+            .process_transaction(Transaction::withdrawal(1, 3, 10.0))
+            .expect("Failed to process withdrawal at the limit");
+        for tx in [4, 5] {
+            assert!(matches!(
+                engine.process_transaction(Transaction::deposit(1, tx, 10.0)),
+                Err(TransactionError::RateLimited(1))
+            ));
+        }
+        assert_eq!(engine.accounts[&1].total, 90.0);
+
+        for tx in 6..9 {
+            engine
+                .process_transaction(Transaction::deposit(2, tx, 10.0))
+                .expect("Failed to process deposit of another client");
+        }
+        assert_eq!(engine.accounts[&2].total, 30.0);
+        assert!(matches!(
+            engine.process_transaction(Transaction::deposit(2, 9, 10.0)),
+            Err(TransactionError::RateLimited(2))
+        ));
+    }
+
+    // Test that the disputes still open when an account was locked can be
+    // resolved or charged back, also after restoring a snapshot, while its
+    // other transactions stay rejected
+    #[test]
+    fn test_close_disputes_opened_before_lock() {
+        let mut engine = Engine::new();
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,50.0\n\
+             deposit,1,2,30.0\n\
+             deposit,1,3,20.0\n\
+             dispute,1,1,\n\
+             dispute,1,2,\n\
+             dispute,1,3,\n\
+             chargeback,1,1,\n",
+        );
+        let account = &engine.accounts[&1];
+        assert!(account.locked);
+        assert_eq!((account.available, account.held), (0.0, 50.0));
+        let mut saved = Vec::new();
+        crate::snapshot::save(&engine, &mut saved).expect("Failed to save snapshot");
+        let mut engine = crate::snapshot::load(saved.as_slice()).expect("Failed to load snapshot");
+
+        engine
+            .process_transaction(Transaction::resolve(1, 2))
+            .expect("Failed to resolve a dispute opened before the lock");
+        let account = &engine.accounts[&1];
+        assert_eq!((account.available, account.held), (30.0, 20.0));
+        engine
+            .process_transaction(Transaction::chargeback(1, 3))
+            .expect("Failed to charge back a dispute opened before the lock");
+        let account = &engine.accounts[&1];
+        assert!(account.locked);
+        assert_eq!(
+            (account.available, account.held, account.total),
+            (30.0, 0.0, 30.0)
+        );
+
+        for transaction in [
+            Transaction::resolve(1, 2),
+            Transaction::dispute(1, 2),
+            Transaction::deposit(1, 4, 10.0),
+            Transaction::withdrawal(1, 5, 10.0),
+        ] {
+            assert!(matches!(
+                engine.process_transaction(transaction),
+                Err(TransactionError::AccountLocked(1))
+            ));
+        }
+        engine.check_invariants().expect("Invariant violated");
+        engine.global_check().expect("Global totals do not balance");
+    }
+
+    // Test dispute on a non-existent transaction
+    #[test]
+    fn test_dispute_nonexistent_transaction() {
+        let mut engine = Engine::new();
+
+        // Attempt to dispute a transaction that doesn't exist
+        let dispute_tx = Transaction::dispute(1, 999);
+        let result = engine.process_transaction(dispute_tx);
+
+        assert!(result.is_err());
+        if let Err(TransactionError::NotFound(tx_id, client_id)) = result {
+            assert_eq!(tx_id, 999);
+            assert_eq!(client_id, 1);
+        } else {
+            panic!("Expected TransactionNotFound error");
+        }
+    }
+
+    // Test duplicate dispute on the same transaction
+    #[test]
+    fn test_duplicate_dispute() {
+        let mut engine = Engine::new();
+
+        // Deposit funds and dispute
+        let deposit_tx = Transaction::deposit(1, 1, 300.0);
+        engine
+            .process_transaction(deposit_tx)
+            .expect("Failed to process deposit");
+
+        let dispute_tx = Transaction::dispute(1, 1);
+        engine
+            .process_transaction(dispute_tx)
+            .expect("Failed to process dispute");
+
+        // Attempt to dispute again
+        let duplicate_dispute_tx = Transaction::dispute(1, 1);
+        let result = engine.process_transaction(duplicate_dispute_tx);
+
+        assert!(result.is_err());
+        if let Err(TransactionError::AlreadyDisputed(tx_id)) = result {
+            assert_eq!(tx_id, 1);
+        } else {
+            panic!("Expected TransactionAlreadyDisputed error");
+        }
+    }
+
+    // Test resolve on a non-disputed transaction
+    #[test]
+    fn test_resolve_non_disputed_transaction() {
+        let mut engine = Engine::new();
+
+        // Deposit funds without dispute
+        let deposit_tx = Transaction::deposit(1, 1, 200.0);
+        engine
+            .process_transaction(deposit_tx)
+            .expect("Failed to process deposit");
+
+        // Attempt to resolve without a prior dispute
+        let resolve_tx = Transaction::resolve(1, 1);
+        let result = engine.process_transaction(resolve_tx);
+
+        assert!(result.is_err());
+        if let Err(TransactionError::NotUnderDispute(tx_id)) = result {
+            assert_eq!(tx_id, 1);
+        } else {
+            panic!("Expected TransactionNotUnderDispute error");
+        }
+    }
+
+    // Test chargeback on a non-disputed transaction
+    #[test]
+    fn test_chargeback_non_disputed_transaction() {
+        let mut engine = Engine::new();
+
+        // Deposit funds without dispute
+        let deposit_tx = Transaction::deposit(1, 1, 200.0);
+        engine
+            .process_transaction(deposit_tx)
+            .expect("Failed to process deposit");
+
+        // Attempt to chargeback without a prior dispute
+        let chargeback_tx = Transaction::chargeback(1, 1);
+        let result = engine.process_transaction(chargeback_tx);
+
+        assert!(result.is_err());
+        if let Err(TransactionError::NotUnderDispute(tx_id)) = result {
+            assert_eq!(tx_id, 1);
+        } else {
+            panic!("Expected TransactionNotUnderDispute error");
+        }
+    }
+
+    // Test dispute on a withdrawal (should fail)
+    #[test]
+    fn test_dispute_withdrawal() {
+        let mut engine = Engine::new();
+
+        // Deposit funds
+        let deposit_tx = Transaction::deposit(1, 1, 500.0);
+        engine
+            .process_transaction(deposit_tx)
+            .expect("Failed to process deposit");
+
+        // Withdraw funds
+        let withdrawal_tx = Transaction::withdrawal(1, 2, 200.0);
+        engine
+            .process_transaction(withdrawal_tx)
+            .expect("Failed to process withdrawal");
+
+        // Attempt to dispute the withdrawal
+        let dispute_tx = Transaction::dispute(1, 2);
+        let result = engine.process_transaction(dispute_tx);
+
+        assert!(result.is_err());
+        if let Err(TransactionError::InvalidDispute(tx_id)) = result {
+            assert_eq!(tx_id, 2);
+        } else {
+            panic!("Expected InvalidDisputeTransaction error");
+        }
+    }
+
+    // Test processing multiple clients
+    #[test]
+    fn test_multiple_clients() {
+        let mut engine = Engine::new();
+
+        // Client 1 deposits
+        let deposit_tx1 = Transaction::deposit(1, 1, 1000.0);
+        engine
+            .process_transaction(deposit_tx1)
+            .expect("Failed to process deposit for client 1");
+
+        // Client 2 deposits
+        let deposit_tx2 = Transaction::deposit(2, 2, 2000.0);
+        engine
+            .process_transaction(deposit_tx2)
+            .expect("Failed to process deposit for client 2");
+
+        // Client 1 withdraws
+        let withdrawal_tx1 = Transaction::withdrawal(1, 3, 500.0);
+        engine
+            .process_transaction(withdrawal_tx1)
+            .expect("Failed to process withdrawal for client 1");
+
+        // Client 2 disputes their deposit
+        let dispute_tx2 = Transaction::dispute(2, 2);
+        engine
+            .process_transaction(dispute_tx2)
+            .expect("Failed to process dispute for client 2");
+
+        // Client 2 chargebacks the disputed transaction
+        let chargeback_tx2 = Transaction::chargeback(2, 2);
+        engine
+            .process_transaction(chargeback_tx2)
+            .expect("Failed to process chargeback for client 2");
+
+        // Verify Client 1's account
+        let account1 = engine.accounts.get(&1).expect("Account 1 not found");
+        assert_eq!(account1.available, 500.0);
+        assert_eq!(account1.held, 0.0);
+        assert_eq!(account1.total, 500.0);
+        assert!(!account1.locked);
+
+        // Verify Client 2's account
+        let account2 = engine.accounts.get(&2).expect("Account 2 not found");
+        assert_eq!(account2.available, 0.0);
+        assert_eq!(account2.held, 0.0);
+        assert_eq!(account2.total, 0.0);
+        assert!(account2.locked);
+    }
+
+    // Test attempting to process a transaction for a locked account
+    #[test]
+    fn test_locked_account_transaction_rejection() {
+        let mut engine = Engine::new();
+
+        // Deposit and chargeback to lock the account
+        let deposit_tx = Transaction::deposit(2, 2, 1000.0);
+        engine
+            .process_transaction(deposit_tx)
+            .expect("Failed to process deposit");
+
+        let dispute_tx = Transaction::dispute(2, 2);
+        engine
+            .process_transaction(dispute_tx)
+            .expect("Failed to process dispute");
+
+        let chargeback_tx = Transaction::chargeback(2, 2);
+        engine
+            .process_transaction(chargeback_tx)
+            .expect("Failed to process chargeback");
+
+        // Attempt to process another deposit
+        let new_deposit_tx = Transaction::deposit(2, 3, 500.0);
+        let result = engine.process_transaction(new_deposit_tx);
+
+        assert!(result.is_err());
+        if let Err(TransactionError::AccountLocked(client_id)) = result {
+            assert_eq!(client_id, 2);
+        } else {
+            panic!("Expected AccountLocked error");
+        }
+    }
+
+    // Test insufficient funds for withdrawal after dispute
+    #[test]
+    fn test_withdrawal_after_dispute() {
+        let mut engine = Engine::new();
+
+        // Deposit funds
+        let deposit_tx = Transaction::deposit(1, 1, 500.0);
+        engine
+            .process_transaction(deposit_tx)
+            .expect("Failed to process deposit");
+
+        // Dispute the deposit
+        let dispute_tx = Transaction::dispute(1, 1);
+        engine
+            .process_transaction(dispute_tx)
+            .expect("Failed to process dispute");
+
+        // Attempt to withdraw funds (should fail due to insufficient available funds)
+        let withdrawal_tx = Transaction::withdrawal(1, 2, 100.0);
+        let result = engine.process_transaction(withdrawal_tx);
+
+        assert!(result.is_err());
+        if let Err(TransactionError::InsufficientFunds(client_id)) = result {
+            assert_eq!(client_id, 1);
+        } else {
+            panic!("Expected InsufficientFunds error");
+        }
+    }
+
+    #[test]
+    fn test_invalid_amount_in_deposit() {
+        let mut engine = Engine::new();
+
+        // Attempt to deposit with an invalid (None) amount
+        let deposit_tx = Transaction::new(TransactionType::Deposit, 1, 1, None);
+        let result = engine.process_transaction(deposit_tx);
+
+        assert!(result.is_err());
+        if let Err(TransactionError::InvalidAmount(tx_id)) = result {
+            assert_eq!(tx_id, 1);
+        } else {
+            panic!("Expected InvalidAmount error for deposit transaction");
+        }
+    }
+
+    // Test that a deposit without an amount is never stored, so that no
+    // dispute can reference it
+    #[test]
+    fn test_amountless_deposit_cannot_be_disputed() {
+        let mut engine = Engine::new();
+        engine
+            .process_transaction(Transaction::deposit(1, 1, 10.0))
+            .expect("Failed to process deposit");
+        assert!(matches!(
+            engine.process_transaction(Transaction::new(TransactionType::Deposit, 1, 2, None)),
+            Err(TransactionError::InvalidAmount(2))
+        ));
+        assert!(engine.transaction(2).is_none());
+        assert!(matches!(
+            engine.process_transaction(Transaction::dispute(1, 2)),
+            Err(TransactionError::NotFound(2, 1))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_amount_in_withdrawal() {
+        let mut engine = Engine::new();
+
+        // Attempt to withdraw with an invalid (None) amount
+        let withdrawal_tx = Transaction::new(TransactionType::Withdrawal, 1, 2, None);
+        let result = engine.process_transaction(withdrawal_tx);
+
+        assert!(result.is_err());
+        if let Err(TransactionError::InvalidAmount(tx_id)) = result {
+            assert_eq!(tx_id, 2);
+        } else {
+            panic!("Expected InvalidAmount error for withdrawal transaction");
+        }
+    }
+
+    #[test]
+    fn test_invalid_dispute_on_non_deposit_transaction() {
+        let mut engine = Engine::new();
+
+        // Register a deposit
+        let deposit_tx = Transaction::deposit(1, 1, 600.0);
+        engine
+            .process_transaction(deposit_tx)
+            .expect("Failed to process deposit transaction");
+
+        // Register a withdrawal transaction
+        let withdrawal_tx = Transaction::withdrawal(1, 2, 500.0);
+        engine
+            .process_transaction(withdrawal_tx)
+            .expect("Failed to process withdrawal");
+
+        // Attempt to dispute the withdrawal (invalid operation)
+        let dispute_tx = Transaction::dispute(1, 2);
+        let result = engine.process_transaction(dispute_tx);
+
+        assert!(result.is_err());
+        if let Err(TransactionError::InvalidDispute(tx_id)) = result {
+            assert_eq!(tx_id, 2);
+        } else {
+            panic!("Expected InvalidDispute error for disputing a withdrawal transaction");
+        }
+    }
+
+    #[test]
+    fn test_invalid_chargeback_on_non_deposit_transaction() {
+        let mut engine = Engine::new();
+
+        // Register a deposit
+        let deposit_tx = Transaction::deposit(1, 1, 600.0);
+        engine
+            .process_transaction(deposit_tx)
+            .expect("Failed to process deposit transaction");
+
+        // Register a withdrawal transaction
+        let withdrawal_tx = Transaction::withdrawal(1, 2, 500.0);
+        engine
+            .process_transaction(withdrawal_tx)
+            .expect("Failed to process withdrawal");
+        // Intentionally mark the withdrawal disputed to cover edge case error handling :-)
+        engine
+            .transactions
+            .get_mut(&2)
+            .expect("Transaction not found")
+            .dispute_state = DisputeState::Disputed;
+
+        // Attempt to chargeback the withdrawal (invalid operation)
+        let chargeback_tx = Transaction::chargeback(1, 2);
+
+        let result = engine.process_transaction(chargeback_tx);
+        assert!(result.is_err());
+        if let Err(TransactionError::InvalidChargeback(tx_id)) = result {
+            assert_eq!(tx_id, 2);
+        } else {
+            panic!("Expected InvalidChargeback error for charging back a withdrawal transaction");
+        }
+    }
+
+    // Test that resolving or charging back an undisputed withdrawal reports its type
+    #[test]
+    fn test_resolve_and_chargeback_of_withdrawal() {
+        let mut engine = Engine::new();
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,600.0\n\
+             withdrawal,1,2,500.0\n",
+        );
+        let reference = |t_type| Transaction::new(t_type, 1, 2, None);
+
+        let result = engine.process_transaction(reference(TransactionType::Resolve));
+        assert!(matches!(result, Err(TransactionError::InvalidDispute(2))));
+        let result = engine.process_transaction(reference(TransactionType::Chargeback));
+        assert!(matches!(
+            result,
+            Err(TransactionError::InvalidChargeback(2))
+        ));
+        assert_eq!(engine.accounts[&1].available, 100.0);
+        assert!(!engine.accounts[&1].locked);
+    }
+
+    #[test]
+    fn test_with_capacity() {
+        let engine = Engine::with_capacity(1_000, 100_000);
+        assert!(engine.accounts.capacity() >= 1_000);
+        assert!(engine.transactions.capacity() >= 100_000);
+
+        let engine = Engine::builder().reserve(1_000_000).build();
+        assert!(engine.accounts.capacity() >= 65_536);
+        assert!(engine.transactions.capacity() >= 1_000_000);
+    }
+
+    #[test]
+    fn test_deposit_cap_boundary() {
+        let mut engine = Engine::builder().max_single_deposit(Some(500.0)).build();
+
+        // A deposit equal to the cap is accepted
+        let deposit_tx = Transaction::deposit(1, 1, 500.0);
+        engine
+            .process_transaction(deposit_tx)
+            .expect("Failed to process deposit equal to the cap");
+
+        // A deposit above the cap is rejected
+        let deposit_tx = Transaction::deposit(1, 2, 500.01);
+        let result = engine.process_transaction(deposit_tx);
+
+        if let Err(TransactionError::DepositCapExceeded(tx_id)) = result {
+            assert_eq!(tx_id, 2);
+        } else {
+            panic!("Expected DepositCapExceeded error");
+        }
+
+        let account = engine.accounts.get(&1).expect("Account not found");
+        assert_eq!(account.available, 500.0);
+        assert_eq!(account.total, 500.0);
+        assert!(!engine.transactions.contains_key(&2));
+    }
+
+    #[test]
+    fn test_max_balance() {
+        let mut engine = Engine::with_max_balance(1_000.0);
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,600.0\n\
+             deposit,1,2,400.0\n\
+             deposit,1,3,0.01\n\
+             withdrawal,1,4,100.0\n\
+             deposit,1,5,100.0\n",
+        );
+
+        // Reaching the cap exactly is allowed, going above it is not
+        assert!(outcomes[..2].iter().all(|outcome| outcome.result.is_ok()));
+        assert!(matches!(
+            outcomes[2].result,
+            Err(TransactionError::BalanceCapExceeded(1))
+        ));
+        assert!(!engine.transactions.contains_key(&3));
+        // Funds withdrawn below the cap make room for new deposits
+        assert!(outcomes[3..].iter().all(|outcome| outcome.result.is_ok()));
+        let account = engine.account(1).expect("Account not found");
+        assert_eq!(account.available, 1_000.0);
+        assert_eq!(account.total, 1_000.0);
+    }
+
+    fn withdrawal_fee(fee_account: Option<u16>) -> FeeSchedule {
+        FeeSchedule {
+            rules: vec![FeeRule {
+                applies_to: TransactionType::Withdrawal,
+                threshold: 1_000.0,
+                rate: 0.01,
+                fee_account,
+            }],
+        }
+    }
+
+    // Test that the fee only applies to withdrawals strictly above the threshold
+    #[test]
+    fn test_withdrawal_fee_threshold() {
+        let mut engine = Engine::builder().fee_schedule(withdrawal_fee(None)).build();
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,5000.0\n\
+             withdrawal,1,2,1000.0\n\
+             withdrawal,1,3,2000.0\n\
+             deposit,1,4,3000.0\n",
+        );
+
+        assert!(outcomes.iter().all(|outcome| outcome.result.is_ok()));
+        let account = engine.account(1).expect("Account not found");
+        assert_eq!(account.available, 4_980.0);
+        assert_eq!(account.total, 4_980.0);
+        assert_eq!(engine.fees_collected(), 20.0);
+        // The stored withdrawal keeps its own amount
+        assert_eq!(
+            engine.transaction(3).map(|stored| stored.amount),
+            Some(2_000.0)
+        );
+    }
+
+    // Test that a withdrawal whose fee exceeds the remaining funds is
+    // rejected as a whole
+    #[test]
+    fn test_withdrawal_fee_exceeds_balance() {
+        let mut engine = Engine::builder()
+            .fee_schedule(withdrawal_fee(Some(99)))
+            .build();
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,1005.0\n\
+             withdrawal,1,2,1001.0\n\
+             withdrawal,1,3,995.0\n",
+        );
+
+        assert!(matches!(
+            outcomes[1].result,
+            Err(TransactionError::InsufficientFunds(1))
+        ));
+        assert!(engine.transaction(2).is_none());
+        assert!(engine.account(99).is_none());
+        assert_eq!(engine.fees_collected(), 0.0);
+        // Below the threshold the same funds cover the withdrawal
+        assert!(outcomes[2].result.is_ok());
+        let account = engine.account(1).expect("Account not found");
+        assert_eq!(account.available, 10.0);
+    }
+
+    // Test that the fee account accumulates the fees of every client, and that
+    // the ledger balances them
+    #[test]
+    fn test_fee_account_accumulates() {
+        let mut engine = Engine::builder()
+            .fee_schedule(withdrawal_fee(Some(99)))
+            .build();
+        engine.record_ledger(true);
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,3000.0\n\
+             deposit,2,2,5000.0\n\
+             withdrawal,1,3,2000.0\n\
+             withdrawal,2,4,4000.0\n\
+             withdrawal,2,5,500.0\n",
+        );
+
+        assert!(outcomes.iter().all(|outcome| outcome.result.is_ok()));
+        let fee_account = engine.account(99).expect("Fee account not found");
+        assert_eq!(fee_account.available, 60.0);
+        assert_eq!(fee_account.total, 60.0);
+        assert!(!fee_account.is_inactive());
+        assert_eq!(engine.fees_collected(), 60.0);
+        assert_eq!(engine.account(2).map(|account| account.total), Some(460.0));
+        engine.check_invariants().expect("Invariants violated");
+
+        let entries = engine.take_ledger();
+        let sum = |client: u16, bucket: ledger::Bucket| -> Amount {
+            entries
+                .iter()
+                .filter(|entry| entry.client == client && entry.bucket == bucket)
+                .map(|entry| entry.delta)
+                .sum()
+        };
+        assert_eq!(sum(1, ledger::Bucket::External), -1_000.0);
+        assert_eq!(sum(1, ledger::Bucket::Fee), 20.0);
+        assert_eq!(sum(99, ledger::Bucket::Available), 60.0);
+        assert_eq!(sum(99, ledger::Bucket::Fee), -60.0);
+        for tx in 1..=5 {
+            let net: Amount = entries
+                .iter()
+                .filter(|entry| entry.tx == tx)
+                .map(|entry| entry.delta)
+                .sum();
+            assert_eq!(net, 0.0, "tx {tx}");
+        }
+    }
+
+    // Test that crediting a fee is checked like a deposit to the fee account,
+    // rejecting the transaction charging it, and that the fees collected
+    // are combined by a merge
+    #[test]
+    fn test_fee_account_limits() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,5000.0\n\
+                     withdrawal,1,2,2000.0\n\
+                     withdrawal,1,3,2000.0\n";
+        let mut engine = Engine::builder()
+            .fee_schedule(withdrawal_fee(Some(99)))
+            .max_balance(Some(5_000.0))
+            .build();
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,5000.0\n\
+             withdrawal,1,2,2000.0\n\
+             deposit,99,3,4970.0\n\
+             withdrawal,1,4,2000.0\n",
+        );
+        assert!(outcomes[..3].iter().all(|outcome| outcome.result.is_ok()));
+        assert!(matches!(
+            outcomes[3].result,
+            Err(TransactionError::BalanceCapExceeded(99))
+        ));
+        assert_eq!(
+            engine.account(1).map(|account| account.total),
+            Some(2_980.0)
+        );
+        assert_eq!(engine.fees_collected(), 20.0);
+
+        let mut engine = Engine::builder()
+            .fee_schedule(withdrawal_fee(Some(99)))
+            .require_explicit_open(true)
+            .build();
+        let outcomes = submit_csv(
+            &mut engine,
+            &input.replace("deposit,1,1", "open,1,4,\ndeposit,1,1"),
+        );
+        assert!(matches!(
+            outcomes[2].result,
+            Err(TransactionError::AccountNotOpen(99))
+        ));
+        assert!(engine.account(99).is_none());
+
+        // A locked fee account is not credited, unless deposits are allowed
+        for allow in [false, true] {
+            let mut engine = Engine::builder()
+                .fee_schedule(withdrawal_fee(Some(99)))
+                .allow_deposits_when_locked(allow)
+                .build();
+            let outcomes = submit_csv(
+                &mut engine,
+                &input.replace(
+                    "deposit,1,1",
+                    "deposit,99,5,1.0\ndispute,99,5,\nchargeback,99,5,\ndeposit,1,1",
+                ),
+            );
+            assert_eq!(outcomes[4].result.is_ok(), allow);
+            if !allow {
+                assert!(matches!(
+                    outcomes[4].result,
+                    Err(TransactionError::AccountLocked(99))
+                ));
+            }
+        }
+
+        let mut left = Engine::builder().fee_schedule(withdrawal_fee(None)).build();
+        submit_csv(&mut left, input);
+        let mut right = Engine::builder().fee_schedule(withdrawal_fee(None)).build();
+        submit_csv(
+            &mut right,
+            "type,client,tx,amount\n\
+             deposit,2,11,5000.0\n\
+             withdrawal,2,12,4000.0\n",
+        );
+        left.merge(right).expect("Failed to merge engines");
+        assert_eq!(left.fees_collected(), 80.0);
+    }
+
+    // Test that a deposit fee comes out of the credited funds, and that a
+    // pending deposit cannot pay it
+    #[test]
+    fn test_deposit_fee() {
+        let schedule = FeeSchedule {
+            rules: vec![FeeRule {
+                applies_to: TransactionType::Deposit,
+                threshold: 0.0,
+                rate: 0.5,
+                fee_account: None,
+            }],
+        };
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,100.0\n";
+        let mut engine = Engine::builder().fee_schedule(schedule.clone()).build();
+        assert!(submit_csv(&mut engine, input)[0].result.is_ok());
+        let account = engine.account(1).expect("Account not found");
+        assert_eq!((account.available, account.total), (50.0, 50.0));
+        assert_eq!(
+            engine.transaction(1).map(|stored| stored.amount),
+            Some(100.0)
+        );
+
+        let mut engine = Engine::builder()
+            .fee_schedule(schedule)
+            .pending_deposits(true)
+            .build();
+        assert!(matches!(
+            submit_csv(&mut engine, input)[0].result,
+            Err(TransactionError::InsufficientFunds(1))
+        ));
+        assert_eq!(engine.fees_collected(), 0.0);
+    }
+
+    // Test that a strict policy catches a resolve that would drive an
+    // inconsistent held balance negative
+    #[test]
+    fn test_strict_held_underflow() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,100.0\n\
+                     dispute,1,1,\n";
+        let resolve = "type,client,tx,amount\n\
+                       resolve,1,1,\n";
+        for strict in [false, true] {
+            let mut engine = Engine::builder().strict_held(strict).build();
+            process_csv(&mut engine, input);
+            engine.accounts.get_mut(&1).expect("Account not found").held = 40.0;
+
+            let result = submit_csv(&mut engine, resolve).remove(0).result;
+            let account = engine.account(1).expect("Account not found");
+            if strict {
+                assert!(matches!(result, Err(TransactionError::HeldUnderflow(1))));
+                assert_eq!((account.available, account.held), (0.0, 40.0));
+                assert!(engine.transaction(1).is_some_and(|tx| tx.is_disputed()));
+            } else {
+                assert!(result.is_ok());
+                assert_eq!((account.available, account.held), (100.0, -60.0));
+            }
+        }
+    }
+
+    // Test that a chargeback only locks the account when the policy says so,
+    // reversing the funds and counting the loss either way
+    #[test]
+    fn test_lock_on_chargeback() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,100.0\n\
+                     deposit,1,2,30.0\n\
+                     dispute,1,1,\n\
+                     chargeback,1,1,\n\
+                     deposit,1,3,5.0\n";
+        for (policy, lock) in [
+            (LockOnChargeback::Always, true),
+            (LockOnChargeback::AboveAmount(50.0), true),
+            (LockOnChargeback::AboveAmount(100.0), false),
+            (LockOnChargeback::Never, false),
+        ] {
+            let mut engine = Engine::builder().lock_on_chargeback(policy).build();
+            let outcomes = submit_csv(&mut engine, input);
+
+            let account = engine.account(1).expect("Account not found");
+            assert_eq!(account.locked, lock, "{policy:?}");
+            assert_eq!(account.held, 0.0);
+            assert_eq!(account.chargeback_losses, 100.0);
+            if lock {
+                assert_eq!(account.lock_reason, Some(LockReason::Chargeback));
+                assert!(matches!(
+                    outcomes[4].result,
+                    Err(TransactionError::AccountLocked(1))
+                ));
+                assert_eq!((account.available, account.total), (30.0, 30.0));
+            } else {
+                assert_eq!(account.lock_reason, None);
+                assert!(outcomes[4].result.is_ok());
+                assert_eq!((account.available, account.total), (35.0, 35.0));
+            }
+        }
+    }
+
+    // Test that a deposit charged back without locking the account cannot be
+    // disputed or charged back again
+    #[test]
+    fn test_redispute_after_chargeback() {
+        let mut engine = Engine::builder()
+            .lock_on_chargeback(LockOnChargeback::Never)
+            .build();
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,100.0\n\
+             deposit,1,2,30.0\n\
+             dispute,1,1,\n\
+             chargeback,1,1,\n\
+             dispute,1,1,\n\
+             chargeback,1,1,\n\
+             resolve,1,1,\n",
+        );
+        assert!(outcomes[..4].iter().all(|outcome| outcome.result.is_ok()));
+        for outcome in &outcomes[4..] {
+            assert!(matches!(
+                outcome.result,
+                Err(TransactionError::AlreadyChargedBack(1))
+            ));
+        }
+        assert!(engine.transaction(1).is_some_and(|tx| tx.is_charged_back()));
+        let account = engine.account(1).expect("Account not found");
+        assert!(!account.locked);
+        assert_eq!(
+            (account.available, account.held, account.total),
+            (30.0, 0.0, 30.0)
+        );
+        assert_eq!(account.chargeback_losses, 100.0);
+        assert!(engine.global_check().is_ok());
+    }
+
+    // Test that a dispute open when the account was locked is finalized by
+    // its chargeback, and stays final once the account is unlocked
+    #[test]
+    fn test_chargeback_of_dispute_open_at_lock() {
+        let mut engine = Engine::builder()
+            .lock_on_chargeback(LockOnChargeback::Never)
+            .build();
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,100.0\n\
+                     deposit,1,2,30.0\n\
+                     dispute,1,1,\n";
+        assert!(submit_csv(&mut engine, input)
+            .iter()
+            .all(|outcome| outcome.result.is_ok()));
+        engine
+            .freeze(1, LockReason::Administrative)
+            .expect("Failed to freeze account");
+
+        // The dispute predates the lock, so its chargeback is still applied
+        let outcomes = submit_csv(&mut engine, "type,client,tx,amount\nchargeback,1,1,\n");
+        assert!(outcomes[0].result.is_ok());
+        assert!(engine.transaction(1).is_some_and(|tx| tx.is_charged_back()));
+        engine.unfreeze(1).expect("Failed to unfreeze account");
+
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             dispute,1,1,\n\
+             chargeback,1,1,\n",
+        );
+        for outcome in &outcomes {
+            assert!(matches!(
+                outcome.result,
+                Err(TransactionError::AlreadyChargedBack(1))
+            ));
+        }
+        let account = engine.account(1).expect("Account not found");
+        assert_eq!(
+            (account.available, account.held, account.total),
+            (30.0, 0.0, 30.0)
+        );
+        assert_eq!((account.locked, account.chargeback_losses), (false, 100.0));
+    }
+
+    // Test that small chargebacks leave the account open until their
+    // cumulative losses cross the limit
+    #[test]
+    fn test_lock_on_cumulative_loss() {
+        let mut engine = Engine::builder()
+            .lock_on_chargeback(LockOnChargeback::AboveAmount(20.0))
+            .lock_on_cumulative_loss(Some(25.0))
+            .build();
+        for (tx, losses) in [(1, 10.0), (2, 20.0)] {
+            let outcomes = submit_csv(
+                &mut engine,
+                &format!(
+                    "type,client,tx,amount\n\
+                     deposit,1,{tx},10.0\n\
+                     dispute,1,{tx},\n\
+                     chargeback,1,{tx},\n"
+                ),
+            );
+            assert!(outcomes.iter().all(|outcome| outcome.result.is_ok()));
+            let account = engine.account(1).expect("Account not found");
+            assert_eq!((account.locked, account.chargeback_losses), (false, losses));
+        }
+
+        // The third chargeback brings the losses to 30, above the limit
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,3,10.0\n\
+             dispute,1,3,\n\
+             chargeback,1,3,\n\
+             deposit,1,4,10.0\n",
+        );
+        assert!(outcomes[2].result.is_ok());
+        assert!(matches!(
+            outcomes[3].result,
+            Err(TransactionError::AccountLocked(1))
+        ));
+        let account = engine.account(1).expect("Account not found");
+        assert_eq!(account.lock_reason, Some(LockReason::Chargeback));
+        assert_eq!(account.chargeback_losses, 30.0);
+    }
+
+    // Test that amounts are rounded half-up to cents only under the policy
+    #[test]
+    fn test_round_to_cents() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,10.126\n\
+                     deposit,2,2,1.005\n\
+                     withdrawal,2,3,0.5049\n\
+                     withdrawal,2,4,0.004\n";
+        let mut engine = Engine::builder().round_to_cents(true).build();
+        let outcomes = submit_csv(&mut engine, input);
+        assert!(matches!(
+            outcomes[3].result,
+            Err(TransactionError::InvalidAmount(4))
+        ));
+        assert_eq!(engine.account(1).expect("Account not found").total, 10.13);
+        assert_eq!(engine.account(2).expect("Account not found").total, 0.51);
+        assert_eq!(engine.transaction(1).map(|tx| tx.amount), Some(10.13));
+
+        // A single rounding step: 10.12496 does not round up twice
+        let mut engine = Engine::builder().round_to_cents(true).build();
+        submit_csv(&mut engine, "type,client,tx,amount\ndeposit,1,1,10.12496\n");
+        assert_eq!(engine.account(1).expect("Account not found").total, 10.12);
+
+        let mut engine = Engine::new();
+        let outcomes = submit_csv(&mut engine, input);
+        assert!(outcomes.iter().all(|outcome| outcome.result.is_ok()));
+        let account = engine.account(1).expect("Account not found");
+        assert_eq!(Account::format_value(account.total), "10.126");
+    }
+
+    // Test that a deposit before the open transaction of its client is
+    // rejected without creating the account, and accepted after it
+    #[test]
+    fn test_require_explicit_open() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,10.0\n\
+                     open,1,2,\n\
+                     deposit,1,3,10.0\n";
+        let mut engine = Engine::builder().require_explicit_open(true).build();
+        let outcomes = submit_csv(&mut engine, &format!("{input}open,1,4,\n"));
+        assert!(matches!(
+            outcomes[0].result,
+            Err(TransactionError::AccountNotOpen(1))
+        ));
+        assert!(outcomes[1..].iter().all(|outcome| outcome.result.is_ok()));
+        let account = engine.account(1).expect("Account not found");
+        assert_eq!((account.available, account.applied), (10.0, 3));
+        assert_eq!(engine.all_account_views().len(), 1);
+
+        // By default the first deposit creates the account
+        let mut engine = Engine::new();
+        let outcomes = submit_csv(&mut engine, input);
+        assert!(outcomes.iter().all(|outcome| outcome.result.is_ok()));
+        assert_eq!(
+            engine.account(1).expect("Account not found").available,
+            20.0
+        );
+    }
+
+    // Test that the transactions of denied clients are rejected without
+    // creating their account, and that an account restored from a snapshot
+    // keeps its balances
+    #[test]
+    fn test_deny_clients() {
+        let mut preloaded = Engine::new();
+        process_csv(&mut preloaded, "type,client,tx,amount\ndeposit,2,1,50.0\n");
+        let mut saved = Vec::new();
+        crate::snapshot::save(&preloaded, &mut saved).expect("Failed to save snapshot");
+
+        let mut engine = Engine::builder()
+            .deny_clients(HashSet::from([2, 3]))
+            .reference_grace(4)
+            .dust_threshold(Some(0.01))
+            .build();
+        crate::snapshot::load_into(&mut engine, saved.as_slice()).expect("Failed to load snapshot");
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,2,10.0\n\
+             withdrawal,2,3,20.0\n\
+             dispute,2,1,\n\
+             deposit,3,4,5.0\n\
+             deposit,3,5,0.001\n",
+        );
+
+        assert!(outcomes[0].result.is_ok());
+        let denied: Vec<Option<u16>> = outcomes[1..]
+            .iter()
+            .map(|outcome| match outcome.result {
+                Err(TransactionError::ClientDenied(client)) => Some(client),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(denied, [Some(2), Some(2), Some(3), Some(3)]);
+        let account = engine.account(2).expect("Account not found");
+        assert_eq!((account.available, account.held), (50.0, 0.0));
+        assert!(engine.account(3).is_none());
+        assert_eq!(engine.reorder_stats().parked, 0);
+    }
+
+    // Test that a simulation locking accounts leaves the engine untouched
+    #[test]
+    fn test_simulate_leaves_engine_untouched() {
+        let mut engine = Engine::builder().reference_grace(4).build();
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,100.0\n\
+             deposit,2,2,50.0\n\
+             withdrawal,2,3,20.0\n\
+             dispute,1,1,\n\
+             dispute,2,2,\n",
+        );
+        let state = |engine: &Engine| {
+            let mut bytes = Vec::new();
+            crate::snapshot::save(engine, &mut bytes).expect("Failed to save snapshot");
+            (bytes, engine.all_account_views(), engine.reorder_stats())
+        };
+        let before = state(&engine);
+
+        let chargebacks: Vec<Transaction> = csv::Reader::from_reader(
+            "type,client,tx,amount\n\
+             chargeback,1,1,\n\
+             chargeback,2,2,\n\
+             chargeback,2,9,\n"
+                .as_bytes(),
+        )
+        .deserialize()
+        .map(|transaction| transaction.expect("Failed to parse transaction"))
+        .collect();
+        let simulation = engine.simulate(&chargebacks);
+
+        let locked: Vec<(u16, bool)> = simulation
+            .accounts
+            .iter()
+            .map(|view| (view.client, view.locked))
+            .collect();
+        assert_eq!(locked, [(1, true), (2, true)]);
+        assert_eq!(simulation.accounts[1].total, -20.0);
+        assert!(matches!(
+            simulation.errors[..],
+            [Outcome {
+                result: Err(TransactionError::NotFound(9, 2)),
+                ..
+            }]
+        ));
+        assert!(state(&engine) == before, "Simulation changed the engine");
+    }
+
+    // Test that a fork of a large engine holds all of its state. The time
+    // taken is measured by `cargo bench --bench fork`
+    #[test]
+    fn test_fork_large_engine() {
+        let mut engine = Engine::with_capacity(1_000, 100_000);
+        for tx in 1..=100_000 {
+            let client = u16::try_from(tx % 1_000).expect("Client out of range");
+            engine
+                .process_transaction(Transaction::deposit(client, tx, 1.0))
+                .expect("Failed to process deposit");
+        }
+
+        let fork = engine.fork();
+        assert_eq!(fork.transaction_count(), 100_000);
+        assert_eq!(fork.all_account_views(), engine.all_account_views());
+    }
+
+    // Test that ids 0 and the maximum ids are ordinary ids: client 0 sorts
+    // first, and a dispute of tx u32::MAX waits for it like any other
+    #[test]
+    fn test_boundary_ids() {
+        let mut engine = Engine::builder().reference_grace(2).build();
+        let input = "type,client,tx,amount\n\
+                     dispute,65535,4294967295,\n\
+                     deposit,65535,4294967295,5.0\n\
+                     deposit,0,0,3.0\n\
+                     deposit,1,1,2.0\n\
+                     dispute,0,0,\n\
+                     chargeback,0,0,\n";
+        let outcomes = submit_csv(&mut engine, input);
+        assert!(outcomes.iter().all(|outcome| outcome.result.is_ok()));
+        assert!(engine.finish().is_empty());
+
+        let views = engine.all_account_views();
+        let clients: Vec<u16> = views.iter().map(|view| view.client).collect();
+        assert_eq!(clients, [0, 1, u16::MAX]);
+        assert!(views[0].locked);
+        assert_eq!((views[2].available, views[2].held), (0.0, 5.0));
+        assert_eq!(engine.is_disputed(u32::MAX), Some(true));
+        assert_eq!(engine.is_disputed(0), Some(false));
+        assert_eq!(engine.check_invariants(), Ok(()));
+
+        let transactions: Vec<Transaction> = csv::Reader::from_reader(input.as_bytes())
+            .deserialize()
+            .map(|transaction| transaction.expect("Failed to parse transaction"))
+            .collect();
+        // A limit of u32::MAX keeps every row, and a limit of 0 the rows of tx 0
+        assert_eq!(
+            engine.balances_after(transactions.clone(), u32::MAX),
+            Engine::new().simulate(&transactions).accounts
+        );
+        let before_any_deposit = engine.balances_after(transactions, 0);
+        assert_eq!(before_any_deposit[0].client, 0);
+        assert!(before_any_deposit[0].locked);
+    }
+
+    #[test]
+    fn test_is_disputed() {
+        let mut engine = Engine::new();
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             deposit,1,2,20.0\n\
+             dispute,1,2,\n\
+             dispute,1,3,\n",
+        );
+
+        assert_eq!(engine.is_disputed(1), Some(false));
+        assert_eq!(engine.is_disputed(2), Some(true));
+        assert_eq!(engine.is_disputed(3), None);
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             resolve,1,2,\n",
+        );
+        assert_eq!(engine.is_disputed(2), Some(false));
+    }
+
+    // Test that a refund reverses a deposit without locking the account, and
+    // only once
+    #[test]
+    fn test_refund() {
+        let mut engine = Engine::new();
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,50.0\n\
+             deposit,1,2,20.0\n\
+             refund,1,1,\n\
+             refund,1,1,\n\
+             dispute,1,1,\n\
+             withdrawal,1,3,5.0\n\
+             refund,1,3,\n",
+        );
+
+        assert!(outcomes[2].result.is_ok());
+        assert!(matches!(
+            outcomes[3].result,
+            Err(TransactionError::AlreadyRefunded(1))
+        ));
+        assert!(matches!(
+            outcomes[4].result,
+            Err(TransactionError::AlreadyRefunded(1))
+        ));
+        assert!(matches!(
+            outcomes[6].result,
+            Err(TransactionError::InvalidRefund(3))
+        ));
+        let account = &engine.accounts[&1];
+        assert!(!account.locked);
+        assert_eq!(
+            (account.available, account.held, account.total),
+            (15.0, 0.0, 15.0)
+        );
+        assert_eq!(engine.global_totals().refunds, 50.0);
+        engine.check_invariants().expect("Invariant violated");
+        engine.global_check().expect("Global totals do not balance");
+    }
+
+    // Test that a deposit charged back without locking the account cannot be
+    // refunded as well
+    #[test]
+    fn test_refund_after_chargeback() {
+        let mut engine = Engine::builder()
+            .lock_on_chargeback(LockOnChargeback::Never)
+            .build();
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,100.0\n\
+             deposit,1,2,150.0\n\
+             dispute,1,1,\n\
+             chargeback,1,1,\n\
+             refund,1,1,\n",
+        );
+
+        assert!(matches!(
+            outcomes[4].result,
+            Err(TransactionError::AlreadyChargedBack(1))
+        ));
+        let account = &engine.accounts[&1];
+        assert_eq!((account.available, account.total), (150.0, 150.0));
+        let totals = engine.global_totals();
+        assert_eq!((totals.chargebacks, totals.refunds), (100.0, 0.0));
+        engine.global_check().expect("Global totals do not balance");
+    }
+
+    // Test that a refund of a deposit already withdrawn fails and changes
+    // nothing
+    #[test]
+    fn test_refund_insufficient_funds() {
+        let mut engine = Engine::new();
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,50.0\n\
+             withdrawal,1,2,30.0\n\
+             refund,1,1,\n",
+        );
+
+        assert!(matches!(
+            outcomes[2].result,
+            Err(TransactionError::InsufficientFunds(1))
+        ));
+        let account = &engine.accounts[&1];
+        assert_eq!((account.available, account.total), (20.0, 20.0));
+        assert!(!engine.transaction(1).expect("Deposit not stored").refunded);
+        // The deposit can still be refunded once funds are back
+        engine
+            .process_transaction(Transaction::deposit(1, 3, 40.0))
+            .expect("Failed to process deposit");
+        engine
+            .process_transaction(Transaction::refund(1, 1))
+            .expect("Failed to process refund");
+        assert_eq!(engine.accounts[&1].total, 10.0);
+    }
+
+    // Test that the net flow sums deposits minus withdrawals, disputes aside,
+    // in a deterministic order
+    #[test]
+    fn test_net_flow() {
+        let mut engine = Engine::new();
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             deposit,1,2,20.0\n\
+             withdrawal,1,3,5.5\n\
+             dispute,1,2,\n\
+             deposit,2,4,7.0\n",
+        );
+
+        assert_eq!(engine.net_flow(1), Some(24.5));
+        assert_eq!(engine.net_flow(2), Some(7.0));
+        assert_eq!(engine.net_flow(3), None);
+
+        // Summed in id order, the small deposits are absorbed by the large
+        // one whatever the order of the map: 1e16 + 1 + 1 - 1e16 == 0
+        for _ in 0..10 {
+            let mut engine = Engine::new();
+            process_csv(
+                &mut engine,
+                "type,client,tx,amount\n\
+                 deposit,1,1,10000000000000000.0\n\
+                 deposit,1,2,1.0\n\
+                 deposit,1,3,1.0\n\
+                 withdrawal,1,4,10000000000000000.0\n",
+            );
+            assert_eq!(engine.net_flow(1), Some(0.0));
+        }
+    }
+
+    // Test each dimension of a transaction filter, then their combination
+    #[test]
+    fn test_transactions_iter_filters() {
+        let mut engine = Engine::new();
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             deposit,1,2,200.0\n\
+             withdrawal,1,3,50.0\n\
+             dispute,1,2,\n\
+             deposit,2,4,300.0\n\
+             withdrawal,2,5,1.0\n\
+             dispute,2,4,\n",
+        );
+        let ids = |filter: TxFilter| {
+            let mut ids: Vec<u32> = engine
+                .transactions_iter()
+                .filter(|status| filter.matches(status))
+                .map(|status| status.tx)
+                .collect();
+            ids.sort_unstable();
+            ids
+        };
+
+        assert_eq!(ids(TxFilter::new()), [1, 2, 3, 4, 5]);
+        assert_eq!(ids(TxFilter::new().client(2)), [4, 5]);
+        assert_eq!(ids(TxFilter::new().kind(TxKind::Withdrawal)), [3, 5]);
+        assert_eq!(ids(TxFilter::new().min_amount(50.0)), [2, 3, 4]);
+        assert_eq!(ids(TxFilter::new().max_amount(50.0)), [1, 3, 5]);
+        assert_eq!(ids(TxFilter::new().disputed(true)), [2, 4]);
+        assert_eq!(ids(TxFilter::new().disputed(false)), [1, 3, 5]);
+        let combined = TxFilter::new()
+            .client(1)
+            .kind(TxKind::Deposit)
+            .min_amount(100.0)
+            .max_amount(250.0)
+            .disputed(true);
+        assert_eq!(ids(combined), [2]);
+
+        let status = engine
+            .transactions_iter()
+            .find(|status| status.tx == 2)
+            .expect("Transaction not found");
+        assert_eq!(
+            status,
+            TxStatus {
+                tx: 2,
+                client: 1,
+                kind: TxKind::Deposit,
+                amount: 200.0,
+                disputed: true,
+                disputes: 1,
+                pending: false,
+                refunded: false,
+            }
+        );
+    }
+
+    // Test that disputes beyond the cap are rejected until one is settled
+    #[test]
+    fn test_max_open_disputes_per_client() {
+        let mut engine = Engine::builder()
+            .max_open_disputes_per_client(Some(2))
+            .build();
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             deposit,1,2,20.0\n\
+             deposit,1,3,30.0\n\
+             deposit,2,4,40.0\n\
+             dispute,1,1,\n\
+             dispute,1,2,\n\
+             dispute,1,3,\n\
+             dispute,2,4,\n",
+        );
+
+        assert!(outcomes[..6].iter().all(|outcome| outcome.result.is_ok()));
+        assert!(matches!(
+            outcomes[6].result,
+            Err(TransactionError::DisputeRateLimited(1))
+        ));
+        // The rejected dispute leaves the deposit untouched
+        let deposit = engine.transaction(3).expect("Transaction not found");
+        assert!(!deposit.is_disputed());
+        assert_eq!(deposit.disputes, 0);
+        assert_eq!(engine.account(1).map(|account| account.held), Some(30.0));
+        // Other clients have their own count
+        assert!(outcomes[7].result.is_ok());
+        let view = engine.account_view(1).expect("Account not found");
+        assert_eq!(view.open_disputes, 2);
+
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             resolve,1,1,\n\
+             dispute,1,3,\n\
+             dispute,1,1,\n",
+        );
+        assert!(outcomes[..2].iter().all(|outcome| outcome.result.is_ok()));
+        assert!(matches!(
+            outcomes[2].result,
+            Err(TransactionError::DisputeRateLimited(1))
+        ));
+        assert_eq!(engine.account(1).map(|account| account.held), Some(50.0));
+    }
+
+    #[test]
+    fn test_velocity_limit_cumulative_deposits() {
+        let mut engine = Engine::builder()
+            .max_total_deposits_per_client(Some(100.0))
+            .build();
+
+        // Ten small deposits reach the limit exactly
+        for tx in 1..=10 {
+            let deposit_tx = Transaction::deposit(1, tx, 10.0);
+            engine
+                .process_transaction(deposit_tx)
+                .expect("Failed to process deposit under the velocity limit");
+        }
+
+        // The next one crosses it
+        let deposit_tx = Transaction::deposit(1, 11, 10.0);
+        let result = engine.process_transaction(deposit_tx);
+
+        if let Err(TransactionError::VelocityLimitExceeded(client_id)) = result {
+            assert_eq!(client_id, 1);
+        } else {
+            panic!("Expected VelocityLimitExceeded error");
+        }
+
+        // Withdrawing does not reset the cumulative deposits
+        let withdrawal_tx = Transaction::withdrawal(1, 12, 50.0);
+        engine
+            .process_transaction(withdrawal_tx)
+            .expect("Failed to process withdrawal");
+        let deposit_tx = Transaction::deposit(1, 13, 10.0);
+        assert!(matches!(
+            engine.process_transaction(deposit_tx),
+            Err(TransactionError::VelocityLimitExceeded(1))
+        ));
+
+        // Other clients have their own budget
+        let deposit_tx = Transaction::deposit(2, 14, 100.0);
+        engine
+            .process_transaction(deposit_tx)
+            .expect("Failed to process deposit for another client");
+
+        let account = engine.accounts.get(&1).expect("Account not found");
+        assert_eq!(account.available, 50.0);
+        assert_eq!(account.total, 50.0);
+    }
+
+    #[test]
+    fn test_rejected_deposits_do_not_count_toward_velocity() {
+        let mut engine = Engine::builder()
+            .max_single_deposit(Some(60.0))
+            .max_total_deposits_per_client(Some(100.0))
+            .build();
+
+        let deposit_tx = Transaction::deposit(1, 1, 60.0);
+        engine
+            .process_transaction(deposit_tx)
+            .expect("Failed to process deposit");
+
+        // Rejected by the single deposit cap
+        let deposit_tx = Transaction::deposit(1, 2, 70.0);
+        assert!(matches!(
+            engine.process_transaction(deposit_tx),
+            Err(TransactionError::DepositCapExceeded(2))
+        ));
+
+        // Rejected by the velocity limit (60 + 50 > 100)
+        let deposit_tx = Transaction::deposit(1, 3, 50.0);
+        assert!(matches!(
+            engine.process_transaction(deposit_tx),
+            Err(TransactionError::VelocityLimitExceeded(1))
+        ));
+
+        // Neither rejection consumed any budget
+        let deposit_tx = Transaction::deposit(1, 4, 40.0);
+        engine
+            .process_transaction(deposit_tx)
+            .expect("Failed to process deposit reaching the velocity limit");
+
+        let account = engine.accounts.get(&1).expect("Account not found");
+        assert_eq!(account.available, 100.0);
+        assert_eq!(account.total, 100.0);
+    }
+
+    #[test]
+    fn test_event_hook_notified() {
+        struct Recorder(Rc<RefCell<Vec<String>>>);
+
+        impl EventHook for Recorder {
+            fn on_event(&mut self, event: &Event<'_>) {
+                let line = match event {
+                    Event::Applied {
+                        transaction,
+                        before,
+                        after,
+                    } => format!(
+                        "applied {} before={:?} after={}",
+                        transaction.tx,
+                        before.map(|account| account.available),
+                        after.available
+                    ),
+                    Event::Rejected {
+                        transaction, error, ..
+                    } => format!("rejected {}: {error}", transaction.tx),
+                    Event::AccountOpened { client, tx } => format!("opened {client} by {tx}"),
+                    _ => return,
+                };
+                self.0.borrow_mut().push(line);
+            }
+        }
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut engine = Engine::new();
+        engine.set_event_hook(Box::new(Recorder(Rc::clone(&events))));
+
+        let deposit_tx = Transaction::deposit(1, 1, 100.0);
+        engine
+            .process_transaction(deposit_tx)
+            .expect("Failed to process deposit");
+        let withdrawal_tx = Transaction::withdrawal(1, 2, 200.0);
+        assert!(engine.process_transaction(withdrawal_tx).is_err());
+
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                "applied 1 before=None after=100".to_string(),
+                "opened 1 by 1".to_string(),
+                "rejected 2: Insufficient funds for client 1".to_string(),
+            ]
+        );
+    }
+
+    // Test that openings, locks and unlocks are reported after the row or
+    // operation causing them, and only once
+    #[test]
+    fn test_lifecycle_events() {
+        struct Recorder(Rc<RefCell<Vec<String>>>);
+
+        impl EventHook for Recorder {
+            fn on_event(&mut self, event: &Event<'_>) {
+                if !matches!(event, Event::Applied { .. } | Event::Rejected { .. }) {
+                    self.0.borrow_mut().push(format!("{event:?}"));
+                }
+            }
+        }
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut engine = Engine::new();
+        engine.set_event_hook(Box::new(Recorder(Rc::clone(&events))));
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,100.0\n\
+             withdrawal,2,2,10.0\n\
+             dispute,1,1,\n\
+             chargeback,1,1,\n\
+             deposit,1,3,5.0\n",
+        );
+        engine
+            .freeze(2, LockReason::Administrative)
+            .expect("Failed to freeze account");
+        engine.unfreeze(2).expect("Failed to unfreeze account");
+        engine.unfreeze(2).expect("Failed to unfreeze account");
+
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                "AccountOpened { client: 1, tx: 1 }",
+                // Opened by a rejected row
+                "AccountOpened { client: 2, tx: 2 }",
+                "AccountLocked { client: 1, tx: Some(1), reason: Chargeback, \
+                 charged_back: 100.0 }",
+                "AccountLocked { client: 2, tx: None, reason: Administrative, \
+                 charged_back: 0.0 }",
+                "AccountUnlocked { client: 2 }",
+            ]
+        );
+    }
+
+    // Test that a registered handler applies its custom type read from CSV,
+    // that a type with no handler is rejected, and that a built-in handler
+    // can be replaced
+    #[test]
+    fn test_custom_handler() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let mut engine = Engine::new();
+        let recorded = Rc::clone(&calls);
+        engine.register_handler(
+            TransactionType::Custom("interest".to_string()),
+            move |engine: &mut Engine, transaction: &Transaction| {
+                recorded.borrow_mut().push(transaction.tx);
+                engine.adjust(transaction.client, transaction.amount.unwrap_or(0.0))
+            },
+        );
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,100.0\n\
+             interest,1,2,2.5\n\
+             bonus,1,3,1.0\n\
+             interest,2,4,1.0\n",
+        );
+
+        assert!(matches!(
+            outcomes[2].result,
+            Err(TransactionError::UnsupportedType(3))
+        ));
+        assert!([0, 1, 3].iter().all(|&i| outcomes[i].result.is_ok()));
+        assert_eq!(*calls.borrow(), [2, 4]);
+        let account = engine.account(1).expect("Account not found");
+        assert_eq!((account.available, account.total), (102.5, 102.5));
+        assert_eq!(account.applied, 2);
+        // Like the built-in types, a custom one creates the account
+        assert_eq!(engine.account(2).map(|account| account.total), Some(1.0));
+        assert_eq!(
+            engine.global_check().map(|totals| totals.adjustments),
+            Ok(3.5)
+        );
+
+        engine.register_handler(
+            TransactionType::Withdrawal,
+            |_: &mut Engine, transaction: &Transaction| {
+                Err(TransactionError::InvalidAmount(transaction.tx))
+            },
+        );
+        assert!(matches!(
+            engine.process_transaction(Transaction::withdrawal(1, 5, 1.0)),
+            Err(TransactionError::InvalidAmount(5))
+        ));
+    }
+
+    // Runs under both hashers (`cargo test --features fast-hash`): the final
+    // state must not depend on the map implementation.
+    #[test]
+    fn test_results_independent_of_hasher() {
+        let mut engine = Engine::new();
+        for client in 0..1_000u16 {
+            let tx = u32::from(client) * 4;
+            let transactions = [
+                (TransactionType::Deposit, tx, Some(1_000.0)),
+                (
+                    TransactionType::Withdrawal,
+                    tx + 1,
+                    Some(f64::from(client) + 1.0),
+                ),
+                (TransactionType::Deposit, tx + 2, Some(0.5)),
+                (TransactionType::Dispute, tx + 2, None),
+            ];
+            for (t_type, tx, amount) in transactions {
+                let transaction = Transaction::new(t_type, client, tx, amount);
+                engine
+                    .process_transaction(transaction)
+                    .expect("Failed to process transaction");
+            }
+            if client % 2 == 0 {
+                let chargeback_tx = Transaction::chargeback(client, tx + 2);
+                engine
+                    .process_transaction(chargeback_tx)
+                    .expect("Failed to process chargeback");
+            }
+        }
+
+        assert_eq!(engine.accounts.len(), 1_000);
+        assert_eq!(engine.transactions.len(), 3_000);
+        assert_eq!(engine.check_invariants(), Ok(()));
+        for client in 0..1_000u16 {
+            let account = engine.accounts.get(&client).expect("Account not found");
+            let available = 999.0 - f64::from(client);
+            assert_eq!(account.available, available);
+            assert_eq!(account.held, if client % 2 == 0 { 0.0 } else { 0.5 });
+            assert_eq!(account.total, account.available + account.held);
+            assert_eq!(account.locked, client % 2 == 0);
+        }
+    }
+
+    // Deposit, then withdraw before, during and after a dispute on a second deposit
+    fn withdrawals_around_dispute(
+        policy: WithdrawalsDuringDispute,
+        settle: TransactionType,
+    ) -> (Engine, Vec<Result<(), TransactionError>>) {
+        let mut engine = Engine::builder().withdrawals_during_dispute(policy).build();
+        let transactions = [
+            (TransactionType::Deposit, 1, Some(100.0)),
+            (TransactionType::Deposit, 2, Some(50.0)),
+            (TransactionType::Withdrawal, 3, Some(10.0)),
+            (TransactionType::Dispute, 2, None),
+            (TransactionType::Withdrawal, 4, Some(10.0)),
+            (settle, 2, None),
+            (TransactionType::Withdrawal, 5, Some(10.0)),
+        ];
+        let results = transactions
+            .into_iter()
+            .map(|(t_type, tx, amount)| {
+                engine.process_transaction(Transaction::new(t_type, 1, tx, amount))
+            })
+            .collect();
+        (engine, results)
+    }
+
+    #[test]
+    fn test_withdrawals_during_dispute_allowed() {
+        let (engine, results) =
+            withdrawals_around_dispute(WithdrawalsDuringDispute::Allow, TransactionType::Resolve);
+
+        assert!(results.iter().all(Result::is_ok));
+        let account = engine.accounts.get(&1).expect("Account not found");
+        assert_eq!(account.available, 120.0);
+        assert_eq!(account.held, 0.0);
+        assert_eq!(account.open_disputes, 0);
+    }
+
+    #[test]
+    fn test_withdrawals_during_dispute_blocked() {
+        let (engine, results) =
+            withdrawals_around_dispute(WithdrawalsDuringDispute::Block, TransactionType::Resolve);
+
+        // Allowed before the dispute
+        assert!(results[2].is_ok());
+        // Blocked while the dispute is open, even though funds are available
+        if let Err(TransactionError::WithdrawalBlockedByDispute(client_id)) = results[4] {
+            assert_eq!(client_id, 1);
         } else {
-            Err(TransactionError::NotUnderDispute(transaction.tx))
+            panic!("Expected WithdrawalBlockedByDispute error");
         }
-    }
-}
+        // Allowed again once the dispute is resolved
+        assert!(results[5].is_ok());
+        assert!(results[6].is_ok());
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::transaction::{Transaction, Type as TransactionType};
+        let account = engine.accounts.get(&1).expect("Account not found");
+        assert_eq!(account.available, 130.0);
+        assert_eq!(account.total, 130.0);
+        assert_eq!(account.open_disputes, 0);
+    }
 
-    // Test processing a deposit transaction
     #[test]
-    fn test_process_deposit() {
-        let mut engine = Engine::new();
-        let deposit_tx = Transaction {
-            t_type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(1000.0),
-            disputed: false,
-        };
-
-        engine
-            .process_transaction(deposit_tx)
-            .expect("Failed to process deposit transaction");
+    fn test_open_dispute_counter_cleared_by_chargeback() {
+        let (engine, results) = withdrawals_around_dispute(
+            WithdrawalsDuringDispute::Block,
+            TransactionType::Chargeback,
+        );
 
-        let account = engine
-            .accounts
-            .get(&1)
-            .expect("Account not found after deposit transaction");
-        assert_eq!(account.available, 1000.0);
-        assert_eq!(account.held, 0.0);
-        assert_eq!(account.total, 1000.0);
-        assert!(!account.locked);
+        assert!(results[4].is_err());
+        assert!(results[5].is_ok());
+        let account = engine.accounts.get(&1).expect("Account not found");
+        assert_eq!(account.open_disputes, 0);
+        assert!(account.locked);
+        // The account is now locked, so the last withdrawal fails for that reason
+        assert!(matches!(
+            results[6],
+            Err(TransactionError::AccountLocked(1))
+        ));
     }
 
-    // Test processing a withdrawal transaction
     #[test]
-    fn test_process_withdrawal() {
+    fn test_accounts_by_client_ascending() {
         let mut engine = Engine::new();
+        for client in (0..=u16::MAX).rev().step_by(5) {
+            let deposit_tx = Transaction::deposit(client, u32::from(client), 1.0);
+            engine
+                .process_transaction(deposit_tx)
+                .expect("Failed to process deposit");
+        }
 
-        // First, deposit some funds
-        let deposit_tx = Transaction {
-            t_type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(1000.0),
-            disputed: false,
-        };
-        engine
-            .process_transaction(deposit_tx)
-            .expect("Failed to process deposit");
-
-        // Then, withdraw some funds
-        let withdrawal_tx = Transaction {
-            t_type: TransactionType::Withdrawal,
-            client: 1,
-            tx: 2,
-            amount: Some(500.0),
-            disputed: false,
-        };
-        engine
-            .process_transaction(withdrawal_tx)
-            .expect("Failed to process withdrawal");
-
-        let account = engine
-            .accounts
-            .get(&1)
-            .expect("Account not found after withdrawal");
-        assert_eq!(account.available, 500.0);
-        assert_eq!(account.held, 0.0);
-        assert_eq!(account.total, 500.0);
-        assert!(!account.locked);
+        let clients: Vec<u16> = engine
+            .accounts_by_client()
+            .map(|account| account.client)
+            .collect();
+        let mut expected: Vec<u16> = engine.accounts.keys().copied().collect();
+        expected.sort_unstable();
+        assert_eq!(clients, expected);
+        assert_eq!(clients.first(), Some(&0));
+        assert_eq!(clients.last(), Some(&u16::MAX));
     }
 
-    // Test withdrawal with insufficient funds
     #[test]
-    fn test_withdrawal_insufficient_funds() {
+    fn test_deposit_balance_overflow() {
         let mut engine = Engine::new();
+        let near_max = f64::MAX * 0.75;
 
-        // Deposit some funds
-        let deposit_tx = Transaction {
-            t_type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(300.0),
-            disputed: false,
-        };
+        let deposit_tx = Transaction::deposit(1, 1, near_max);
         engine
             .process_transaction(deposit_tx)
-            .expect("Failed to process deposit");
+            .expect("Failed to process first deposit");
 
-        // Attempt to withdraw more than available
-        let withdrawal_tx = Transaction {
-            t_type: TransactionType::Withdrawal,
-            client: 1,
-            tx: 2,
-            amount: Some(500.0),
-            disputed: false,
-        };
-        let result = engine.process_transaction(withdrawal_tx);
+        let deposit_tx = Transaction::deposit(1, 2, near_max);
+        let result = engine.process_transaction(deposit_tx);
 
-        assert!(result.is_err());
-        if let Err(TransactionError::InsufficientFunds(client_id)) = result {
+        if let Err(TransactionError::BalanceOverflow(client_id)) = result {
             assert_eq!(client_id, 1);
         } else {
-            panic!("Expected InsufficientFunds error");
+            panic!("Expected BalanceOverflow error");
         }
 
         // Account balances should remain unchanged
-        let account = engine
-            .accounts
-            .get(&1)
-            .expect("Account not found after insufficient funds withdrawal");
-        assert_eq!(account.available, 300.0);
-        assert_eq!(account.held, 0.0);
-        assert_eq!(account.total, 300.0);
-        assert!(!account.locked);
+        let account = engine.accounts.get(&1).expect("Account not found");
+        assert_eq!(account.available, near_max);
+        assert_eq!(account.total, near_max);
+        assert!(!engine.transactions.contains_key(&2));
     }
 
-    // Test processing a dispute on a deposit
     #[test]
-    fn test_process_dispute_deposit() {
+    fn test_withdrawal_balance_overflow() {
         let mut engine = Engine::new();
+        let near_max = f64::MAX * 0.75;
 
-        // Deposit funds
-        let deposit_tx = Transaction {
-            t_type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(1000.0),
-            disputed: false,
-        };
+        let deposit_tx = Transaction::deposit(1, 1, near_max);
         engine
             .process_transaction(deposit_tx)
             .expect("Failed to process deposit");
 
-        // Initiate a dispute on the deposit
-        let dispute_tx = Transaction {
-            t_type: TransactionType::Dispute,
-            client: 1,
-            tx: 1,
-            amount: None,
-            disputed: false,
-        };
-        engine
-            .process_transaction(dispute_tx)
-            .expect("Failed to process dispute");
+        // A negative withdrawal would credit the account and overflow it, but
+        // is rejected as non-positive before touching the balances
+        let withdrawal_tx = Transaction::withdrawal(1, 2, -near_max);
+        assert!(matches!(
+            engine.process_transaction(withdrawal_tx),
+            Err(TransactionError::InvalidAmount(2))
+        ));
 
-        // Check account balances
-        let account = engine
-            .accounts
-            .get(&1)
-            .expect("Account not found after dispute");
-        assert_eq!(account.available, 0.0);
-        assert_eq!(account.held, 1000.0);
-        assert_eq!(account.total, 1000.0);
-        assert!(!account.locked);
+        let account = engine.accounts.get(&1).expect("Account not found");
+        assert_eq!(account.available, near_max);
+        assert_eq!(account.total, near_max);
     }
 
-    // Test resolving a dispute
     #[test]
-    fn test_process_resolve() {
+    fn test_accounts_in_order() {
         let mut engine = Engine::new();
+        for (tx, client) in [(1, 3), (2, 1), (3, 2), (4, 3), (5, 1)] {
+            let deposit_tx = Transaction::deposit(client, tx, 10.0);
+            engine
+                .process_transaction(deposit_tx)
+                .expect("Failed to process deposit");
+        }
 
-        // Deposit funds and dispute
-        let deposit_tx = Transaction {
-            t_type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(500.0),
-            disputed: false,
-        };
+        let clients: Vec<u16> = engine
+            .accounts_in_order()
+            .map(|account| account.client)
+            .collect();
+        assert_eq!(clients, vec![3, 1, 2]);
+    }
+
+    #[test]
+    fn test_check_invariants_detects_corruption() {
+        let mut engine = Engine::new();
+        let deposit_tx = Transaction::deposit(1, 1, 100.0);
         engine
             .process_transaction(deposit_tx)
             .expect("Failed to process deposit");
-
-        let dispute_tx = Transaction {
-            t_type: TransactionType::Dispute,
-            client: 1,
-            tx: 1,
-            amount: None,
-            disputed: false,
-        };
+        let dispute_tx = Transaction::dispute(1, 1);
         engine
             .process_transaction(dispute_tx)
             .expect("Failed to process dispute");
+        assert_eq!(engine.check_invariants(), Ok(()));
 
-        // Resolve the dispute
-        let resolve_tx = Transaction {
-            t_type: TransactionType::Resolve,
-            client: 1,
-            tx: 1,
-            amount: None,
-            disputed: false,
-        };
-        engine
-            .process_transaction(resolve_tx)
-            .expect("Failed to process resolve");
+        let account = engine.accounts.get_mut(&1).expect("Account not found");
+        account.total += 1.0;
+        assert_eq!(engine.check_invariants(), Err(Invariant::TotalMismatch(1)));
 
-        // Check account balances
-        let account = engine
-            .accounts
-            .get(&1)
-            .expect("Account not found after resolve");
-        assert_eq!(account.available, 500.0);
-        assert_eq!(account.held, 0.0);
-        assert_eq!(account.total, 500.0);
-        assert!(!account.locked);
+        let account = engine.accounts.get_mut(&1).expect("Account not found");
+        account.total -= 1.0;
+        account.open_disputes = 0;
+        assert_eq!(
+            engine.check_invariants(),
+            Err(Invariant::OpenDisputesMismatch(1))
+        );
+
+        let account = engine.accounts.get_mut(&1).expect("Account not found");
+        account.open_disputes = 1;
+        account.held = 50.0;
+        account.available = 50.0;
+        assert_eq!(engine.check_invariants(), Err(Invariant::HeldMismatch(1)));
+
+        engine.accounts.remove(&1);
+        assert_eq!(
+            engine.check_invariants(),
+            Err(Invariant::UnknownClient(1, 1))
+        );
     }
 
-    // Test chargeback processing
+    // Test that the account totals add up to the funds moved, fees and an
+    // overdraft written off by a chargeback included, and that a corrupted
+    // total is caught
     #[test]
-    fn test_process_chargeback() {
-        let mut engine = Engine::new();
+    fn test_global_check() {
+        let mut engine = Engine::builder()
+            .fee_schedule(withdrawal_fee(None))
+            .on_chargeback_overdraw(ChargebackOverdraw::ClampToZero)
+            .build();
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,5000.0\n\
+             withdrawal,1,2,2000.0\n\
+             dispute,1,1,\n\
+             chargeback,1,1,\n\
+             deposit,2,3,10.0\n",
+        );
+        assert!(outcomes.iter().all(|outcome| outcome.result.is_ok()));
 
-        // Deposit funds and dispute
-        let deposit_tx = Transaction {
-            t_type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(400.0),
-            disputed: false,
-        };
-        engine
-            .process_transaction(deposit_tx)
-            .expect("Failed to process deposit");
+        assert_eq!(
+            engine.global_check(),
+            Ok(GlobalTotals {
+                opening: 0.0,
+                deposits: 5_010.0,
+                withdrawals: 2_000.0,
+                chargebacks: 2_980.0,
+                refunds: 0.0,
+                fees: 20.0,
+                adjustments: 0.0,
+                accounts: 10.0,
+            })
+        );
 
-        let dispute_tx = Transaction {
-            t_type: TransactionType::Dispute,
-            client: 1,
-            tx: 1,
-            amount: None,
-            disputed: false,
-        };
-        engine
-            .process_transaction(dispute_tx)
-            .expect("Failed to process dispute");
+        let account = engine.accounts.get_mut(&2).expect("Account not found");
+        account.total += 1.0;
+        assert_eq!(
+            engine.global_check(),
+            Err(ImbalanceError {
+                expected: 10.0,
+                actual: 11.0,
+            })
+        );
+    }
 
-        // Process chargeback
-        let chargeback_tx = Transaction {
-            t_type: TransactionType::Chargeback,
-            client: 1,
-            tx: 1,
-            amount: None,
-            disputed: false,
-        };
-        engine
-            .process_transaction(chargeback_tx)
-            .expect("Failed to process chargeback");
+    // Test that a chargeback records its lock reason and cannot be unfrozen
+    #[test]
+    fn test_chargeback_lock_is_not_reversible() {
+        let mut engine = Engine::new();
+        for (t_type, amount) in [
+            (TransactionType::Deposit, Some(100.0)),
+            (TransactionType::Dispute, None),
+            (TransactionType::Chargeback, None),
+        ] {
+            engine
+                .process_transaction(Transaction::new(t_type, 1, 1, amount))
+                .expect("Failed to process transaction");
+        }
 
-        // Check account balances and locked status
-        let account = engine
-            .accounts
-            .get(&1)
-            .expect("Account not found after chargeback");
-        assert_eq!(account.available, 0.0);
-        assert_eq!(account.held, 0.0);
-        assert_eq!(account.total, 0.0);
+        let account = engine.accounts.get(&1).expect("Account not found");
         assert!(account.locked);
+        assert_eq!(account.lock_reason, Some(LockReason::Chargeback));
+
+        assert!(matches!(
+            engine.unfreeze(1),
+            Err(TransactionError::LockNotReversible(1))
+        ));
+        let account = engine.accounts.get(&1).expect("Account not found");
+        assert!(account.locked);
+        assert_eq!(account.lock_reason, Some(LockReason::Chargeback));
+
+        // Freezing again must not downgrade the chargeback lock
+        assert!(matches!(
+            engine.freeze(1, LockReason::Administrative),
+            Err(TransactionError::AccountLocked(1))
+        ));
+        let account = engine.accounts.get(&1).expect("Account not found");
+        assert_eq!(account.lock_reason, Some(LockReason::Chargeback));
     }
 
-    // Test attempting transactions on a locked account
+    // Test freezing and unfreezing accounts according to the lock reason
     #[test]
-    fn test_transaction_on_locked_account() {
+    fn test_freeze_and_unfreeze() {
         let mut engine = Engine::new();
+        for client in [1, 2] {
+            engine
+                .process_transaction(Transaction::deposit(client, u32::from(client), 100.0))
+                .expect("Failed to process deposit");
+        }
 
-        // Deposit funds and process chargeback to lock the account
-        let deposit_tx = Transaction {
-            t_type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(400.0),
-            disputed: false,
-        };
         engine
-            .process_transaction(deposit_tx)
-            .expect("Failed to process deposit");
-
-        let dispute_tx = Transaction {
-            t_type: TransactionType::Dispute,
-            client: 1,
-            tx: 1,
-            amount: None,
-            disputed: false,
-        };
+            .freeze(1, LockReason::Administrative)
+            .expect("Failed to freeze account");
         engine
-            .process_transaction(dispute_tx)
-            .expect("Failed to process dispute");
+            .freeze(2, LockReason::Fraud)
+            .expect("Failed to freeze account");
+        let account = engine.accounts.get(&1).expect("Account not found");
+        assert!(account.locked);
+        assert_eq!(account.lock_reason, Some(LockReason::Administrative));
 
-        let chargeback_tx = Transaction {
-            t_type: TransactionType::Chargeback,
-            client: 1,
-            tx: 1,
-            amount: None,
-            disputed: false,
-        };
+        // Transactions are rejected while frozen
+        let withdrawal = Transaction::withdrawal(1, 3, 10.0);
+        assert!(matches!(
+            engine.process_transaction(withdrawal.clone()),
+            Err(TransactionError::AccountLocked(1))
+        ));
+
+        // An administrative lock can be lifted, a fraud lock cannot
+        engine.unfreeze(1).expect("Failed to unfreeze account");
+        let account = engine.accounts.get(&1).expect("Account not found");
+        assert!(!account.locked);
+        assert_eq!(account.lock_reason, None);
         engine
-            .process_transaction(chargeback_tx)
-            .expect("Failed to process chargeback");
+            .process_transaction(withdrawal)
+            .expect("Failed to process withdrawal after unfreeze");
 
-        // Attempt to process a new deposit on the locked account
-        let new_deposit_tx = Transaction {
-            t_type: TransactionType::Deposit,
-            client: 1,
-            tx: 2,
-            amount: Some(100.0),
-            disputed: false,
-        };
-        let result = engine.process_transaction(new_deposit_tx);
-        assert!(result.is_err());
-        if let Err(TransactionError::AccountLocked(client_id)) = result {
-            assert_eq!(client_id, 1);
-        } else {
-            panic!("Expected AccountLocked error");
-        }
+        assert!(matches!(
+            engine.unfreeze(2),
+            Err(TransactionError::LockNotReversible(2))
+        ));
+        assert!(engine.accounts.get(&2).expect("Account not found").locked);
+
+        assert!(matches!(
+            engine.freeze(3, LockReason::Administrative),
+            Err(TransactionError::AccountNotFound(3))
+        ));
+        assert!(matches!(
+            engine.unfreeze(3),
+            Err(TransactionError::AccountNotFound(3))
+        ));
+    }
+
+    // Deposit, withdraw everything, then dispute and charge back the deposit
+    fn chargeback_after_withdrawal(
+        policy: ChargebackOverdraw,
+    ) -> (Engine, Vec<Result<(), TransactionError>>) {
+        let mut engine = Engine::builder().on_chargeback_overdraw(policy).build();
+        let transactions = [
+            (TransactionType::Deposit, 1, Some(100.0)),
+            (TransactionType::Deposit, 2, Some(30.0)),
+            (TransactionType::Withdrawal, 3, Some(130.0)),
+            (TransactionType::Dispute, 1, None),
+            (TransactionType::Chargeback, 1, None),
+        ];
+        let results = transactions
+            .into_iter()
+            .map(|(t_type, tx, amount)| {
+                engine.process_transaction(Transaction::new(t_type, 1, tx, amount))
+            })
+            .collect();
+        (engine, results)
     }
 
-    // Test dispute on a non-existent transaction
     #[test]
-    fn test_dispute_nonexistent_transaction() {
-        let mut engine = Engine::new();
+    fn test_chargeback_overdraw_allow_negative() {
+        let (engine, results) = chargeback_after_withdrawal(ChargebackOverdraw::AllowNegative);
 
-        // Attempt to dispute a transaction that doesn't exist
-        let dispute_tx = Transaction {
-            t_type: TransactionType::Dispute,
-            client: 1,
-            tx: 999, // Non-existent transaction ID
-            amount: None,
-            disputed: false,
-        };
-        let result = engine.process_transaction(dispute_tx);
+        assert!(results.iter().all(Result::is_ok));
+        let account = engine.accounts.get(&1).expect("Account not found");
+        assert_eq!(account.available, -100.0);
+        assert_eq!(account.held, 0.0);
+        assert_eq!(account.total, -100.0);
+        assert!(account.locked);
+        assert_eq!(engine.check_invariants(), Ok(()));
+    }
 
-        assert!(result.is_err());
-        if let Err(TransactionError::NotFound(tx_id, client_id)) = result {
-            assert_eq!(tx_id, 999);
-            assert_eq!(client_id, 1);
+    #[test]
+    fn test_chargeback_overdraw_reject() {
+        let (engine, results) = chargeback_after_withdrawal(ChargebackOverdraw::Reject);
+
+        assert!(results[..4].iter().all(Result::is_ok));
+        if let Err(TransactionError::ChargebackOverdraw(tx)) = results[4] {
+            assert_eq!(tx, 1);
         } else {
-            panic!("Expected TransactionNotFound error");
+            panic!("Expected ChargebackOverdraw error");
         }
+        // The deposit stays disputed with its funds held
+        let account = engine.accounts.get(&1).expect("Account not found");
+        assert_eq!(account.available, -100.0);
+        assert_eq!(account.held, 100.0);
+        assert_eq!(account.total, 0.0);
+        assert_eq!(account.open_disputes, 1);
+        assert!(!account.locked);
+        assert!(engine.transactions[&1].is_disputed());
+        assert_eq!(engine.check_invariants(), Ok(()));
     }
 
-    // Test duplicate dispute on the same transaction
     #[test]
-    fn test_duplicate_dispute() {
-        let mut engine = Engine::new();
+    fn test_chargeback_overdraw_clamp_to_zero() {
+        let (engine, results) = chargeback_after_withdrawal(ChargebackOverdraw::ClampToZero);
 
-        // Deposit funds and dispute
-        let deposit_tx = Transaction {
-            t_type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(300.0),
-            disputed: false,
-        };
-        engine
-            .process_transaction(deposit_tx)
-            .expect("Failed to process deposit");
+        assert!(results.iter().all(Result::is_ok));
+        let account = engine.accounts.get(&1).expect("Account not found");
+        assert_eq!(account.available, 0.0);
+        assert_eq!(account.held, 0.0);
+        assert_eq!(account.total, 0.0);
+        assert!(account.locked);
+        assert_eq!(engine.check_invariants(), Ok(()));
+    }
 
-        let dispute_tx = Transaction {
-            t_type: TransactionType::Dispute,
-            client: 1,
-            tx: 1,
-            amount: None,
-            disputed: false,
-        };
+    // Deposit 100, withdraw 70, then dispute the deposit
+    fn dispute_after_withdrawal(clamp: bool) -> Engine {
+        let mut engine = Engine::builder().dispute_clamp_to_available(clamp).build();
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,100.0\n\
+             withdrawal,1,2,70.0\n\
+             dispute,1,1,\n",
+        );
         engine
-            .process_transaction(dispute_tx)
-            .expect("Failed to process dispute");
+    }
 
-        // Attempt to dispute again
-        let duplicate_dispute_tx = Transaction {
-            t_type: TransactionType::Dispute,
-            client: 1,
-            tx: 1,
-            amount: None,
-            disputed: false,
-        };
-        let result = engine.process_transaction(duplicate_dispute_tx);
+    // Test that an unclamped dispute holds the full deposit amount
+    #[test]
+    fn test_dispute_unclamped_holds_full_amount() {
+        let engine = dispute_after_withdrawal(false);
 
-        assert!(result.is_err());
-        if let Err(TransactionError::AlreadyDisputed(tx_id)) = result {
-            assert_eq!(tx_id, 1);
-        } else {
-            panic!("Expected TransactionAlreadyDisputed error");
+        let account = engine.accounts.get(&1).expect("Account not found");
+        assert_eq!(account.available, -70.0);
+        assert_eq!(account.held, 100.0);
+        assert_eq!(account.total, 30.0);
+        assert_eq!(account.dispute_shortfall, 0.0);
+        assert_eq!(engine.transactions[&1].held, 100.0);
+        assert_eq!(engine.check_invariants(), Ok(()));
+    }
+
+    // Test that a clamped dispute holds only the available funds
+    #[test]
+    fn test_dispute_clamped_to_available() {
+        let engine = dispute_after_withdrawal(true);
+
+        let account = engine.accounts.get(&1).expect("Account not found");
+        assert_eq!(account.available, 0.0);
+        assert_eq!(account.held, 30.0);
+        assert_eq!(account.total, 30.0);
+        assert_eq!(account.dispute_shortfall, 70.0);
+        assert_eq!(engine.transactions[&1].held, 30.0);
+        assert_eq!(engine.transactions[&1].shortfall(), 70.0);
+        assert_eq!(engine.check_invariants(), Ok(()));
+    }
+
+    // Test that resolving a clamped dispute releases only what was held
+    #[test]
+    fn test_resolve_clamped_dispute() {
+        let mut engine = dispute_after_withdrawal(true);
+        process_csv(&mut engine, "type,client,tx,amount\nresolve,1,1,\n");
+
+        let account = engine.accounts.get(&1).expect("Account not found");
+        assert_eq!(account.available, 30.0);
+        assert_eq!(account.held, 0.0);
+        assert_eq!(account.total, 30.0);
+        assert_eq!(account.dispute_shortfall, 0.0);
+        assert_eq!(engine.check_invariants(), Ok(()));
+    }
+
+    // Test that charging back a clamped dispute writes off the shortfall
+    #[test]
+    fn test_chargeback_clamped_dispute() {
+        let mut engine = dispute_after_withdrawal(true);
+        process_csv(&mut engine, "type,client,tx,amount\nchargeback,1,1,\n");
+
+        let account = engine.accounts.get(&1).expect("Account not found");
+        assert_eq!(account.available, 0.0);
+        assert_eq!(account.held, 0.0);
+        assert_eq!(account.total, 0.0);
+        assert_eq!(account.dispute_shortfall, 0.0);
+        assert!(account.locked);
+        assert_eq!(engine.check_invariants(), Ok(()));
+    }
+
+    fn process_csv(engine: &mut Engine, input: &str) {
+        for transaction in csv::Reader::from_reader(input.as_bytes()).deserialize() {
+            let _ = engine.process_transaction(transaction.expect("Failed to parse transaction"));
         }
     }
 
-    // Test resolve on a non-disputed transaction
+    // Test that replaying a command log, rejected attempts included, rebuilds
+    // the accounts and stored transactions of the logged run
     #[test]
-    fn test_resolve_non_disputed_transaction() {
+    fn test_replay_command_log() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,100.0\n\
+                     withdrawal,1,2,150.0\n\
+                     deposit,2,3,50.0\n\
+                     dispute,2,3,\n\
+                     withdrawal,2,4,10.0\n\
+                     resolve,1,9,\n\
+                     chargeback,2,3,\n\
+                     deposit,2,5,20.0\n\
+                     deposit,1,1,5.0\n\
+                     withdrawal,1,6,40.0\n";
+        let log = tempfile::NamedTempFile::new().expect("Failed to create log file");
         let mut engine = Engine::new();
-
-        // Deposit funds without dispute
-        let deposit_tx = Transaction {
-            t_type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(200.0),
-            disputed: false,
-        };
+        engine.record_commands(
+            command_log::Writer::create(log.path()).expect("Failed to create command log"),
+        );
+        process_csv(&mut engine, input);
         engine
-            .process_transaction(deposit_tx)
-            .expect("Failed to process deposit");
+            .finish_command_log()
+            .expect("Failed to write command log");
 
-        // Attempt to resolve without a prior dispute
-        let resolve_tx = Transaction {
-            t_type: TransactionType::Resolve,
-            client: 1,
-            tx: 1,
-            amount: None,
-            disputed: false,
-        };
-        let result = engine.process_transaction(resolve_tx);
+        let mut replayed = Engine::new();
+        let count = replayed.replay_log(log.path()).expect("Failed to replay");
 
-        assert!(result.is_err());
-        if let Err(TransactionError::NotUnderDispute(tx_id)) = result {
-            assert_eq!(tx_id, 1);
-        } else {
-            panic!("Expected TransactionNotUnderDispute error");
-        }
+        assert_eq!(count, 10);
+        assert_eq!(replayed.all_account_views(), engine.all_account_views());
+        assert_eq!(replayed.transactions_by_id(), engine.transactions_by_id());
+
+        // A policy rejecting a logged success makes the replay diverge
+        let mut capped = Engine::builder().max_balance(Some(60.0)).build();
+        assert!(matches!(
+            capped.replay_log(log.path()),
+            Err(CommandLogError::Diverged { tx: 1, ref logged, ref replayed })
+                if logged == "success" && replayed == "E_BALANCE_CAP_EXCEEDED"
+        ));
+    }
+
+    // Test merging engines that processed disjoint clients
+    #[test]
+    fn test_merge_disjoint_clients() {
+        let mut left = Engine::new();
+        process_csv(
+            &mut left,
+            "type,client,tx,amount\n\
+             deposit,1,1,100.0\n\
+             deposit,3,3,5.0\n\
+             dispute,1,1,\n",
+        );
+        let mut right = Engine::new();
+        process_csv(
+            &mut right,
+            "type,client,tx,amount\n\
+             deposit,2,2,50.0\n\
+             withdrawal,2,4,20.0\n",
+        );
+
+        left.merge(right).expect("Failed to merge engines");
+
+        let order: Vec<u16> = left
+            .accounts_in_order()
+            .map(|account| account.client)
+            .collect();
+        assert_eq!(order, [1, 3, 2]);
+        assert_eq!(left.transactions.len(), 4);
+        assert_eq!(left.accounts[&1].held, 100.0);
+        assert_eq!(left.accounts[&2].available, 30.0);
+        assert_eq!(left.accounts[&3].total, 5.0);
+        assert!(left.global_check().is_ok());
     }
 
-    // Test chargeback on a non-disputed transaction
+    // Test that merging the shards of an input split by client gives the
+    // accounts of a single engine processing the whole input
     #[test]
-    fn test_chargeback_non_disputed_transaction() {
-        let mut engine = Engine::new();
+    fn test_merge_shards_match_single_engine() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,100.0\n\
+                     deposit,2,2,50.0\n\
+                     withdrawal,1,3,30.0\n\
+                     dispute,2,2,\n\
+                     deposit,3,4,7.5\n\
+                     chargeback,2,2,\n\
+                     withdrawal,3,5,10.0\n";
+        let mut whole = Engine::new();
+        process_csv(&mut whole, input);
 
-        // Deposit funds without dispute
-        let deposit_tx = Transaction {
-            t_type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(200.0),
-            disputed: false,
+        let shard = |clients: &[&str]| {
+            let mut lines = input.lines();
+            let header = lines.next().unwrap_or_default();
+            let rows = lines.filter(|line| clients.contains(&line.split(',').nth(1).unwrap_or("")));
+            let mut engine = Engine::new();
+            process_csv(
+                &mut engine,
+                &format!("{header}\n{}\n", rows.collect::<Vec<_>>().join("\n")),
+            );
+            engine
         };
-        engine
-            .process_transaction(deposit_tx)
-            .expect("Failed to process deposit");
+        let mut merged = shard(&["1", "3"]);
+        merged
+            .merge(shard(&["2"]))
+            .expect("Failed to merge engines");
 
-        // Attempt to chargeback without a prior dispute
-        let chargeback_tx = Transaction {
-            t_type: TransactionType::Chargeback,
-            client: 1,
-            tx: 1,
-            amount: None,
-            disputed: false,
+        let by_client = |engine: &Engine| {
+            let mut views = engine.all_account_views();
+            views.sort_by_key(|view| view.client);
+            views
         };
-        let result = engine.process_transaction(chargeback_tx);
+        assert_eq!(by_client(&merged), by_client(&whole));
+        assert_eq!(merged.transactions_by_id(), whole.transactions_by_id());
+        assert_eq!(merged.global_check(), whole.global_check());
+    }
 
-        assert!(result.is_err());
-        if let Err(TransactionError::NotUnderDispute(tx_id)) = result {
-            assert_eq!(tx_id, 1);
+    // Test that conflicting duplicates are reported and leave the engine unchanged
+    #[test]
+    fn test_merge_conflicts() {
+        let mut left = Engine::new();
+        process_csv(&mut left, "type,client,tx,amount\ndeposit,1,1,100.0\n");
+
+        let mut right = Engine::new();
+        process_csv(
+            &mut right,
+            "type,client,tx,amount\ndeposit,1,1,100.0\ndeposit,1,2,5.0\n",
+        );
+        if let Err(MergeError::ConflictingAccount { client, details }) = left.merge(right) {
+            assert_eq!(client, 1);
+            assert_eq!(
+                details,
+                "available 100 != 105, total 100 != 105, peak_total 100 != 105, \
+                 last_tx Some(1) != Some(2), applied 1 != 2"
+            );
         } else {
-            panic!("Expected TransactionNotUnderDispute error");
+            panic!("Expected ConflictingAccount error");
+        }
+        assert_eq!(left.transactions.len(), 1);
+
+        // An identical client would be counted twice in the global totals
+        let mut right = Engine::new();
+        process_csv(&mut right, "type,client,tx,amount\ndeposit,1,1,100.0\n");
+        assert!(matches!(
+            left.merge(right),
+            Err(MergeError::DuplicateAccount(1))
+        ));
+        assert!(left.global_check().is_ok());
+
+        let mut right = Engine::new();
+        process_csv(&mut right, "type,client,tx,amount\ndeposit,2,1,100.0\n");
+        if let Err(MergeError::ConflictingTransaction { tx, details }) = left.merge(right) {
+            assert_eq!(tx, 1);
+            assert_eq!(details, "client 1 != 2");
+        } else {
+            panic!("Expected ConflictingTransaction error");
         }
+        assert_eq!(left.accounts.len(), 1);
+
+        // A corrupted shard whose account does not hold the disputed funds
+        let mut right = Engine::new();
+        process_csv(&mut right, "type,client,tx,amount\ndeposit,2,2,1.0\n");
+        let transaction = right
+            .transactions
+            .get_mut(&2)
+            .expect("Transaction not found");
+        transaction.dispute_state = DisputeState::Disputed;
+        transaction.held = 1.0;
+        assert!(matches!(
+            left.merge(right),
+            Err(MergeError::Inconsistent(Invariant::HeldMismatch(2)))
+        ));
     }
 
-    // Test dispute on a withdrawal (should fail)
+    // Test that reused ids are rejected without overwriting the stored transaction
     #[test]
-    fn test_dispute_withdrawal() {
+    fn test_duplicate_transaction_ids_rejected() {
         let mut engine = Engine::new();
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,100.0\n\
+             dispute,1,1,\n",
+        );
 
-        // Deposit funds
-        let deposit_tx = Transaction {
-            t_type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(500.0),
-            disputed: false,
-        };
-        engine
-            .process_transaction(deposit_tx)
-            .expect("Failed to process deposit");
+        for (t_type, amount) in [
+            (TransactionType::Deposit, Some(5.0)),
+            (TransactionType::Withdrawal, Some(5.0)),
+        ] {
+            let result = engine.process_transaction(Transaction::new(t_type, 1, 1, amount));
+            if let Err(TransactionError::DuplicateTransaction(tx_id)) = result {
+                assert_eq!(tx_id, 1);
+            } else {
+                panic!("Expected DuplicateTransaction error");
+            }
+        }
 
-        // Withdraw funds
-        let withdrawal_tx = Transaction {
-            t_type: TransactionType::Withdrawal,
-            client: 1,
-            tx: 2,
-            amount: Some(200.0),
-            disputed: false,
-        };
-        engine
-            .process_transaction(withdrawal_tx)
-            .expect("Failed to process withdrawal");
+        // The dispute row did not replace the deposit, which is still disputed
+        assert_eq!(engine.transaction_count(), 1);
+        let stored = engine.transaction(1).expect("Transaction not found");
+        assert_eq!(stored.kind, TxKind::Deposit);
+        assert_eq!(stored.amount, 100.0);
+        assert!(stored.is_disputed());
+        let account = engine.accounts.get(&1).expect("Account not found");
+        assert_eq!(account.available, 0.0);
+        assert_eq!(account.held, 100.0);
+        assert_eq!(engine.check_invariants(), Ok(()));
+    }
 
-        // Attempt to dispute the withdrawal
-        let dispute_tx = Transaction {
-            t_type: TransactionType::Dispute,
-            client: 1,
-            tx: 2,
-            amount: None,
-            disputed: false,
-        };
-        let result = engine.process_transaction(dispute_tx);
+    // Test that client 2 can neither dispute nor settle the deposits of client 1
+    #[test]
+    fn test_settle_with_wrong_client() {
+        let mut engine = Engine::new();
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,100.0\n\
+             dispute,1,1,\n\
+             deposit,1,2,10.0\n",
+        );
 
-        assert!(result.is_err());
-        if let Err(TransactionError::InvalidDispute(tx_id)) = result {
-            assert_eq!(tx_id, 2);
-        } else {
-            panic!("Expected InvalidDisputeTransaction error");
+        for (t_type, tx) in [
+            (TransactionType::Resolve, 1),
+            (TransactionType::Chargeback, 1),
+            // The mismatch is reported whatever the dispute state
+            (TransactionType::Resolve, 2),
+            (TransactionType::Dispute, 1),
+            (TransactionType::Dispute, 2),
+        ] {
+            let result = engine.process_transaction(Transaction::new(t_type, 2, tx, None));
+            if let Err(TransactionError::ClientMismatch(tx_id, owner, requester)) = result {
+                assert_eq!((tx_id, owner, requester), (tx, 1, 2));
+            } else {
+                panic!("Expected ClientMismatch error");
+            }
         }
+
+        // The dispute is still open on the owner's account
+        assert!(engine.transactions[&1].is_disputed());
+        let account = engine.accounts.get(&1).expect("Account not found");
+        assert_eq!(account.held, 100.0);
+        assert!(!account.locked);
+        assert_eq!(engine.check_invariants(), Ok(()));
     }
 
-    // Test processing multiple clients
+    // Test that last_tx tracks the last successful transaction only
     #[test]
-    fn test_multiple_clients() {
+    fn test_last_tx_ignores_rejected_transactions() {
         let mut engine = Engine::new();
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             deposit,1,2,5.0\n\
+             dispute,1,1,\n\
+             withdrawal,1,3,100.0\n\
+             resolve,1,2,\n\
+             dispute,2,9,\n",
+        );
 
-        // Client 1 deposits
-        let deposit_tx1 = Transaction {
-            t_type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(1000.0),
-            disputed: false,
-        };
-        engine
-            .process_transaction(deposit_tx1)
-            .expect("Failed to process deposit for client 1");
+        // The dispute of tx 1 is the last accepted row of client 1
+        assert_eq!(engine.accounts[&1].last_tx, Some(1));
+        assert_eq!(engine.accounts[&1].applied, 3);
+        // Client 2 only exists because of a rejected dispute
+        assert_eq!(engine.accounts[&2].last_tx, None);
+        assert!(engine.accounts[&2].is_inactive());
+    }
 
-        // Client 2 deposits
-        let deposit_tx2 = Transaction {
-            t_type: TransactionType::Deposit,
-            client: 2,
-            tx: 2,
-            amount: Some(2000.0),
-            disputed: false,
-        };
-        engine
-            .process_transaction(deposit_tx2)
-            .expect("Failed to process deposit for client 2");
+    // Test that the summary counts every transaction and derives the throughput
+    #[test]
+    fn test_process_all_summary() {
+        let mut engine = Engine::new();
+        // Every withdrawal but the first is covered by the deposits
+        let transactions = (1..=1_000).map(|tx| match tx {
+            10 => Transaction::withdrawal(1, tx, 1_000.0),
+            _ if tx % 10 == 0 => Transaction::withdrawal(1, tx, 1.0),
+            _ => Transaction::deposit(1, tx, 1.0),
+        });
+        let summary = engine.process_all(transactions);
 
-        // Client 1 withdraws
-        let withdrawal_tx1 = Transaction {
-            t_type: TransactionType::Withdrawal,
-            client: 1,
-            tx: 3,
-            amount: Some(500.0),
-            disputed: false,
+        assert_eq!(summary.successful, 999);
+        assert_eq!(summary.errors, 1);
+        assert_eq!(
+            summary.failures,
+            [Failure {
+                position: 10,
+                provenance: None,
+                error: TransactionError::InsufficientFunds(1),
+            }]
+        );
+        assert_eq!(summary.processed(), 1_000);
+        let elapsed = summary.elapsed.expect("Duration not measured");
+        match summary.throughput() {
+            Some(throughput) => assert_eq!(throughput, 1_000.0 / elapsed.as_secs_f64()),
+            None => assert_eq!(elapsed, Duration::ZERO),
+        }
+        let unmeasured = ProcessSummary {
+            elapsed: None,
+            ..summary
         };
-        engine
-            .process_transaction(withdrawal_tx1)
-            .expect("Failed to process withdrawal for client 1");
+        assert_eq!(unmeasured.throughput(), None);
+        assert_eq!(engine.accounts[&1].available, 801.0);
+    }
 
-        // Client 2 disputes their deposit
-        let dispute_tx2 = Transaction {
-            t_type: TransactionType::Dispute,
-            client: 2,
-            tx: 2,
-            amount: None,
-            disputed: false,
+    // Test that the summary lists the error of each rejected row with its
+    // position in the batch and the input line it was read from, if any
+    #[test]
+    fn test_process_all_failures() {
+        let schema = |headers: &str| {
+            let headers = csv::StringRecord::from(headers.split(',').collect::<Vec<_>>());
+            Schema::detect(headers, false).expect("Failed to detect schema")
         };
-        engine
-            .process_transaction(dispute_tx2)
-            .expect("Failed to process dispute for client 2");
+        let file: Arc<str> = Arc::from("input2.csv");
+        let schema = schema("type,client,tx,amount");
+        let rows: Vec<Transaction> =
+            csv::Reader::from_reader(include_str!("../tests/data/input2.csv").as_bytes())
+                .records()
+                .map(|record| {
+                    let record = record.expect("Failed to read record");
+                    schema
+                        .read_from(&file, &record)
+                        .expect("Failed to parse transaction")
+                })
+                .collect();
 
-        // Client 2 chargebacks the disputed transaction
-        let chargeback_tx2 = Transaction {
-            t_type: TransactionType::Chargeback,
-            client: 2,
-            tx: 2,
-            amount: None,
-            disputed: false,
-        };
-        engine
-            .process_transaction(chargeback_tx2)
-            .expect("Failed to process chargeback for client 2");
+        let mut engine = Engine::new();
+        let summary = engine.process_all(rows);
+        assert_eq!(
+            summary.failures,
+            [Failure {
+                position: 2,
+                provenance: Some(Provenance { file, line: 3 }),
+                error: TransactionError::InsufficientFunds(1),
+            }]
+        );
 
-        // Verify Client 1's account
-        let account1 = engine.accounts.get(&1).expect("Account 1 not found");
-        assert_eq!(account1.available, 500.0);
-        assert_eq!(account1.held, 0.0);
-        assert_eq!(account1.total, 500.0);
-        assert!(!account1.locked);
+        let rows = csv::Reader::from_reader(include_str!("../tests/data/input4.csv").as_bytes())
+            .into_deserialize()
+            .map(|row| row.expect("Failed to parse transaction"));
+        let mut engine = Engine::new();
+        let summary = engine.process_all(rows);
+        // Rows without provenance are only known by their position
+        assert_eq!(summary.errors, 1);
+        assert_eq!(
+            summary.failures,
+            [Failure {
+                position: 2,
+                provenance: None,
+                error: TransactionError::NotUnderDispute(1),
+            }]
+        );
+    }
 
-        // Verify Client 2's account
-        let account2 = engine.accounts.get(&2).expect("Account 2 not found");
-        assert_eq!(account2.available, 0.0);
-        assert_eq!(account2.held, 0.0);
-        assert_eq!(account2.total, 0.0);
-        assert!(account2.locked);
+    // Test that insufficient funds count as errors by default and as
+    // skipped with the warn policy, the withdrawal being rejected either way
+    #[test]
+    fn test_insufficient_funds_policy() {
+        for (policy, errors, skipped) in [
+            (InsufficientFunds::Error, 1, 0),
+            (InsufficientFunds::Warn, 0, 1),
+        ] {
+            let rows =
+                csv::Reader::from_reader(include_str!("../tests/data/input2.csv").as_bytes())
+                    .into_deserialize()
+                    .map(|row| row.expect("Failed to parse transaction"));
+            let mut engine = Engine::builder().insufficient_funds(policy).build();
+            let summary = engine.process_all(rows);
+
+            assert_eq!((summary.successful, summary.errors), (1, errors));
+            assert_eq!((summary.skipped, summary.processed()), (skipped, 2));
+            assert_eq!(summary.failures.is_empty(), errors == 0);
+            assert_eq!(engine.account(1).expect("Account not found").total, 100.0);
+        }
+
+        // Only withdrawals are skipped
+        let mut engine = Engine::builder()
+            .insufficient_funds(InsufficientFunds::Warn)
+            .build();
+        let summary = engine.process_all([
+            Transaction::deposit(1, 1, 10.0),
+            Transaction::withdrawal(1, 2, 8.0),
+            Transaction::refund(1, 1),
+        ]);
+        assert_eq!((summary.errors, summary.skipped), (1, 0));
     }
 
-    // Test attempting to process a transaction for a locked account
+    // Test that the debug output counts the state and only shows a sample
     #[test]
-    fn test_locked_account_transaction_rejection() {
-        let mut engine = Engine::new();
+    fn test_debug_summarizes_state() {
+        let mut engine = Engine::default();
+        engine.process_all(
+            (1..=10u16).map(|client| Transaction::deposit(client, client.into(), 1.0)),
+        );
 
-        // Deposit and chargeback to lock the account
-        let deposit_tx = Transaction {
-            t_type: TransactionType::Deposit,
-            client: 2,
-            tx: 2,
-            amount: Some(1000.0),
-            disputed: false,
-        };
-        engine
-            .process_transaction(deposit_tx)
-            .expect("Failed to process deposit");
+        let debug = format!("{engine:?}");
+        assert!(debug.starts_with("Engine { accounts: 10, transactions: 10, pending: 0,"));
+        assert_eq!(debug.matches("Account {").count(), DEBUG_SAMPLE);
+        assert!(debug.contains("client: 3,") && !debug.contains("client: 4,"));
+        assert!(debug.ends_with(", .. }"));
+    }
 
-        let dispute_tx = Transaction {
-            t_type: TransactionType::Dispute,
-            client: 2,
-            tx: 2,
-            amount: None,
-            disputed: false,
-        };
-        engine
-            .process_transaction(dispute_tx)
-            .expect("Failed to process dispute");
+    // Submit every row of a CSV input, collecting the settled outcomes
+    fn submit_csv(engine: &mut Engine, input: &str) -> Vec<Outcome> {
+        let mut outcomes = Vec::new();
+        for transaction in csv::Reader::from_reader(input.as_bytes()).deserialize() {
+            outcomes.extend(engine.submit(transaction.expect("Failed to parse transaction")));
+        }
+        outcomes
+    }
 
-        let chargeback_tx = Transaction {
-            t_type: TransactionType::Chargeback,
-            client: 2,
-            tx: 2,
-            amount: None,
-            disputed: false,
-        };
-        engine
-            .process_transaction(chargeback_tx)
-            .expect("Failed to process chargeback");
+    // Test that a dispute arriving 3 rows before its deposit is applied late
+    #[test]
+    fn test_reference_grace_matches_late_dispute() {
+        let mut engine = Engine::builder().reference_grace(10).build();
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             dispute,1,4,\n\
+             deposit,1,1,5.0\n\
+             deposit,1,2,5.0\n\
+             deposit,1,4,100.0\n\
+             deposit,1,5,1.0\n",
+        );
 
-        // Attempt to process another deposit
-        let new_deposit_tx = Transaction {
-            t_type: TransactionType::Deposit,
-            client: 2,
-            tx: 3,
-            amount: Some(500.0),
-            disputed: false,
-        };
-        let result = engine.process_transaction(new_deposit_tx);
+        // The dispute is settled right after its deposit
+        let settled: Vec<(u32, bool)> = outcomes
+            .iter()
+            .map(|outcome| (outcome.transaction.tx, outcome.result.is_ok()))
+            .collect();
+        assert_eq!(
+            settled,
+            [(1, true), (2, true), (4, true), (4, true), (5, true)]
+        );
+        assert!(engine.finish().is_empty());
+        let account = engine.accounts.get(&1).expect("Account not found");
+        assert_eq!(account.available, 11.0);
+        assert_eq!(account.held, 100.0);
+        assert_eq!(
+            engine.reorder_stats(),
+            ReorderStats {
+                parked: 1,
+                matched_late: 1,
+                expired: 0,
+            }
+        );
+        assert_eq!(engine.check_invariants(), Ok(()));
+    }
 
-        assert!(result.is_err());
-        if let Err(TransactionError::AccountLocked(client_id)) = result {
-            assert_eq!(client_id, 2);
+    // Test that a dispute whose deposit never arrives fails at the end of the run
+    #[test]
+    fn test_reference_grace_expires_at_end() {
+        let mut engine = Engine::builder().reference_grace(10).build();
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,5.0\n\
+             dispute,1,9,\n\
+             deposit,1,2,5.0\n",
+        );
+        assert_eq!(outcomes.len(), 2);
+        assert!(outcomes.iter().all(|outcome| outcome.result.is_ok()));
+        assert_eq!(engine.parked(), 1);
+
+        let expired = engine.finish();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(engine.parked(), 0);
+        if let Err(TransactionError::NotFound(tx_id, client)) = expired[0].result {
+            assert_eq!((tx_id, client), (9, 1));
         } else {
-            panic!("Expected AccountLocked error");
+            panic!("Expected NotFound error");
         }
+        assert_eq!(engine.reorder_stats().expired, 1);
+        assert!(engine.finish().is_empty());
     }
 
-    // Test insufficient funds for withdrawal after dispute
+    // Test that the oldest parked row is rejected when the buffer overflows
     #[test]
-    fn test_withdrawal_after_dispute() {
-        let mut engine = Engine::new();
+    fn test_reference_grace_overflow() {
+        let mut engine = Engine::builder().reference_grace(2).build();
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             dispute,1,7,\n\
+             dispute,1,8,\n\
+             dispute,1,9,\n\
+             deposit,1,7,1.0\n\
+             deposit,1,8,1.0\n",
+        );
 
-        // Deposit funds
-        let deposit_tx = Transaction {
-            t_type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(500.0),
-            disputed: false,
-        };
-        engine
-            .process_transaction(deposit_tx)
-            .expect("Failed to process deposit");
+        let settled: Vec<(u32, bool)> = outcomes
+            .iter()
+            .map(|outcome| (outcome.transaction.tx, outcome.result.is_ok()))
+            .collect();
+        // The dispute of tx 7 expired before its deposit arrived
+        assert_eq!(settled, [(7, false), (7, true), (8, true), (8, true)]);
+        assert_eq!(engine.finish().len(), 1);
+        assert_eq!(
+            engine.reorder_stats(),
+            ReorderStats {
+                parked: 3,
+                matched_late: 1,
+                expired: 2,
+            }
+        );
+    }
 
-        // Dispute the deposit
-        let dispute_tx = Transaction {
-            t_type: TransactionType::Dispute,
-            client: 1,
-            tx: 1,
-            amount: None,
-            disputed: false,
-        };
-        engine
-            .process_transaction(dispute_tx)
-            .expect("Failed to process dispute");
+    // Test that unknown references are rejected immediately without a grace
+    #[test]
+    fn test_submit_without_reference_grace() {
+        let mut engine = Engine::new();
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             dispute,1,4,\n\
+             deposit,1,4,100.0\n",
+        );
+        assert!(matches!(
+            outcomes[0].result,
+            Err(TransactionError::NotFound(4, 1))
+        ));
+        assert!(outcomes[1].result.is_ok());
+        assert_eq!(engine.reorder_stats(), ReorderStats::default());
+    }
 
-        // Attempt to withdraw funds (should fail due to insufficient available funds)
-        let withdrawal_tx = Transaction {
-            t_type: TransactionType::Withdrawal,
-            client: 1,
-            tx: 2,
-            amount: Some(100.0),
-            disputed: false,
-        };
-        let result = engine.process_transaction(withdrawal_tx);
+    // Test that the account views match the CSV report of the same state
+    #[test]
+    fn test_account_views_match_report() {
+        let mut engine = Engine::new();
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,2,1,10.123456\n\
+             deposit,1,2,0.3\n\
+             deposit,1,3,2.00019\n\
+             dispute,1,3,\n\
+             withdrawal,2,4,0.1\n\
+             deposit,3,5,7.0\n\
+             dispute,3,5,\n\
+             chargeback,3,5,\n",
+        );
 
-        assert!(result.is_err());
-        if let Err(TransactionError::InsufficientFunds(client_id)) = result {
-            assert_eq!(client_id, 1);
-        } else {
-            panic!("Expected InsufficientFunds error");
+        let mut output = Vec::new();
+        crate::report::write_accounts(
+            &mut output,
+            engine.accounts_by_client(),
+            &crate::report::Options::default(),
+        )
+        .expect("Failed to write report");
+        let mut reader = csv::Reader::from_reader(output.as_slice());
+        let rows: Vec<(u16, f64, f64, f64, bool)> = reader
+            .deserialize()
+            .map(|row| row.expect("Failed to parse report row"))
+            .collect();
+
+        let views = engine.all_account_views();
+        assert_eq!(views.len(), rows.len());
+        for (view, (client, available, held, total, locked)) in views.iter().zip(rows) {
+            assert_eq!(
+                (
+                    view.client,
+                    view.available,
+                    view.held,
+                    view.total,
+                    view.locked
+                ),
+                (client, available, held, total, locked)
+            );
+            assert_eq!(engine.account_view(client).as_ref(), Some(view));
         }
+        assert!(views[0].has_open_disputes);
+        assert!(!views[1].has_open_disputes);
+        assert_eq!(views[1].available, 10.0234);
+        assert!(engine.account_view(4).is_none());
     }
 
+    // Test that balances as of an intermediate transaction match a partial replay
     #[test]
-    fn test_invalid_amount_in_deposit() {
+    fn test_balances_after_matches_partial_replay() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,100.0\n\
+                     deposit,2,2,50.0\n\
+                     withdrawal,1,3,30.0\n\
+                     dispute,2,2,\n\
+                     deposit,1,4,7.5\n\
+                     resolve,2,2,\n\
+                     withdrawal,2,5,20.0\n\
+                     deposit,3,6,1.0\n";
+        let rows = || {
+            csv::Reader::from_reader(input.as_bytes())
+                .into_deserialize::<Transaction>()
+                .map(|transaction| transaction.expect("Failed to parse transaction"))
+        };
         let mut engine = Engine::new();
+        let _ = engine.process_all(rows());
 
-        // Attempt to deposit with an invalid (None) amount
-        let deposit_tx = Transaction {
-            t_type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: None, // Invalid amount
-            disputed: false,
-        };
-        let result = engine.process_transaction(deposit_tx);
+        let mut partial = Engine::new();
+        process_csv(
+            &mut partial,
+            "type,client,tx,amount\n\
+             deposit,1,1,100.0\n\
+             deposit,2,2,50.0\n\
+             withdrawal,1,3,30.0\n\
+             dispute,2,2,\n\
+             resolve,2,2,\n",
+        );
+        let balances = engine.balances_after(rows(), 3);
+        assert_eq!(balances, partial.all_account_views());
+        assert_eq!(balances.len(), 2);
+        assert_eq!(balances[0].available, 70.0);
+        assert_eq!(balances[1].available, 50.0);
 
-        assert!(result.is_err());
-        if let Err(TransactionError::InvalidAmount(tx_id)) = result {
-            assert_eq!(tx_id, 1);
-        } else {
-            panic!("Expected InvalidAmount error for deposit transaction");
-        }
+        // Rows are filtered on their id, not cut at the first one past the limit
+        let out_of_order = [
+            Transaction::deposit(1, 9, 5.0),
+            Transaction::deposit(1, 2, 1.0),
+        ];
+        let balances = engine.balances_after(out_of_order, 3);
+        assert_eq!(balances[0].available, 1.0);
+
+        // The full range gives the current balances
+        assert_eq!(
+            engine.balances_after(rows(), u32::MAX),
+            engine.all_account_views()
+        );
+
+        // Custom handlers are replayed too
+        let interest = TransactionType::Custom("interest".to_string());
+        engine.register_handler(
+            interest.clone(),
+            |engine: &mut Engine, transaction: &Transaction| {
+                engine.adjust(transaction.client, transaction.amount.unwrap_or(0.0))
+            },
+        );
+        let rows = rows().chain([Transaction::new(interest, 1, 7, Some(2.5))]);
+        let balances = engine.balances_after(rows, u32::MAX);
+        assert_eq!(balances[0].available, 80.0);
     }
 
+    // Test that the transaction cap rejects the first deposit or withdrawal past it
     #[test]
-    fn test_invalid_amount_in_withdrawal() {
+    fn test_max_transactions() {
+        let mut engine = Engine::builder().max_transactions(Some(2)).build();
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             withdrawal,1,2,4.0\n\
+             dispute,1,1,\n\
+             deposit,1,3,1.0\n",
+        );
+
+        assert!(outcomes[..3].iter().all(|outcome| outcome.result.is_ok()));
+        assert!(matches!(
+            outcomes[3].result,
+            Err(TransactionError::CapacityExceeded(
+                Capacity::Transactions,
+                2
+            ))
+        ));
+        assert_eq!(engine.transaction_count(), 2);
+        assert_eq!(engine.accounts[&1].total, 6.0);
+    }
+
+    // Test that the account cap rejects new clients but not existing ones
+    #[test]
+    fn test_max_accounts() {
+        let mut engine = Engine::builder().max_accounts(Some(2)).build();
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             deposit,2,2,5.0\n\
+             deposit,3,3,1.0\n\
+             deposit,1,4,1.0\n",
+        );
+
+        let results: Vec<bool> = outcomes
+            .iter()
+            .map(|outcome| outcome.result.is_ok())
+            .collect();
+        assert_eq!(results, [true, true, false, true]);
+        assert!(matches!(
+            outcomes[2].result,
+            Err(TransactionError::CapacityExceeded(Capacity::Accounts, 2))
+        ));
+        assert_eq!(engine.account_count(), 2);
+        assert!(engine.account(3).is_none());
+    }
+
+    // Test that only accounts with open disputes are listed as holding funds
+    #[test]
+    fn test_accounts_with_held() {
         let mut engine = Engine::new();
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,3,1,5.0\n\
+             deposit,1,2,10.0\n\
+             deposit,2,3,7.0\n\
+             dispute,3,1,\n\
+             dispute,1,2,\n\
+             dispute,2,3,\n\
+             resolve,2,3,\n",
+        );
 
-        // Attempt to withdraw with an invalid (None) amount
-        let withdrawal_tx = Transaction {
-            t_type: TransactionType::Withdrawal,
-            client: 1,
-            tx: 2,
-            amount: None, // Invalid amount
-            disputed: false,
+        let held: Vec<(u16, Amount)> = engine
+            .accounts_with_held()
+            .map(|account| (account.client, account.held))
+            .collect();
+        assert_eq!(held, [(1, 10.0), (3, 5.0)]);
+    }
+
+    // Test that dispute/resolve cycles are counted and leave balances as they were
+    #[test]
+    fn test_redispute_cycles_restore_balances() {
+        let mut engine = Engine::new();
+        process_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,0.2\n\
+             deposit,1,2,0.1\n",
+        );
+        // The raw balances, not the truncated views, so any f64 drift shows
+        let balances = |engine: &Engine| {
+            let account = &engine.accounts[&1];
+            [account.available, account.held, account.total].map(f64::to_bits)
         };
-        let result = engine.process_transaction(withdrawal_tx);
+        let before = balances(&engine);
 
-        assert!(result.is_err());
-        if let Err(TransactionError::InvalidAmount(tx_id)) = result {
-            assert_eq!(tx_id, 2);
-        } else {
-            panic!("Expected InvalidAmount error for withdrawal transaction");
+        for _ in 0..5 {
+            process_csv(
+                &mut engine,
+                "type,client,tx,amount\n\
+                 dispute,1,2,\n\
+                 resolve,1,2,\n",
+            );
+            assert_eq!(balances(&engine), before);
         }
+        let transaction = engine.transaction(2).expect("Transaction not found");
+        assert_eq!(transaction.disputes, 5);
     }
 
+    // Test that a deposit cannot be disputed again past `max_redisputes`
     #[test]
-    fn test_invalid_dispute_on_non_deposit_transaction() {
-        let mut engine = Engine::new();
+    fn test_max_redisputes() {
+        let mut engine = Engine::builder().max_redisputes(Some(2)).build();
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,0.1\n\
+             dispute,1,1,\n\
+             resolve,1,1,\n\
+             dispute,1,1,\n\
+             resolve,1,1,\n\
+             dispute,1,1,\n\
+             resolve,1,1,\n\
+             dispute,1,1,\n",
+        );
 
-        // Register a deposit
-        let deposit_tx = Transaction {
-            t_type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(600.0),
-            disputed: false,
-        };
-        engine
-            .process_transaction(deposit_tx)
-            .expect("Failed to process deposit transaction");
+        assert!(outcomes[..7].iter().all(|outcome| outcome.result.is_ok()));
+        assert!(matches!(
+            outcomes[7].result,
+            Err(TransactionError::RedisputeLimitExceeded(1))
+        ));
+        let account = engine.account(1).expect("Account not found");
+        assert_eq!((account.available, account.held), (0.1, 0.0));
+        assert_eq!(account.open_disputes, 0);
+        let transaction = engine.transaction(1).expect("Transaction not found");
+        assert_eq!(transaction.disputes, 3);
+    }
 
-        // Register a withdrawal transaction
-        let withdrawal_tx = Transaction {
-            t_type: TransactionType::Withdrawal,
-            client: 1,
-            tx: 2,
-            amount: Some(500.0),
-            disputed: false,
-        };
-        engine
-            .process_transaction(withdrawal_tx)
-            .expect("Failed to process withdrawal");
+    // Test that a pending deposit only becomes available once settled
+    #[test]
+    fn test_pending_deposit_then_settle() {
+        let mut engine = Engine::builder().pending_deposits(true).build();
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,100.0\n\
+             withdrawal,1,2,10.0\n\
+             settle,1,1,\n\
+             withdrawal,1,3,10.0\n\
+             settle,1,1,\n\
+             settle,1,3,\n",
+        );
 
-        // Attempt to dispute the withdrawal (invalid operation)
-        let dispute_tx = Transaction {
-            t_type: TransactionType::Dispute,
-            client: 1,
-            tx: 2,
-            amount: None,
-            disputed: false,
-        };
-        let result = engine.process_transaction(dispute_tx);
+        assert!(outcomes[0].result.is_ok());
+        assert!(matches!(
+            outcomes[1].result,
+            Err(TransactionError::InsufficientFunds(1))
+        ));
+        assert!(outcomes[2].result.is_ok());
+        assert!(outcomes[3].result.is_ok());
+        assert!(matches!(
+            outcomes[4].result,
+            Err(TransactionError::NotPending(1))
+        ));
+        assert!(matches!(
+            outcomes[5].result,
+            Err(TransactionError::NotPending(3))
+        ));
+        let account = engine.account(1).expect("Account not found");
+        assert_eq!(account.available, 90.0);
+        assert_eq!(account.pending, 0.0);
+        assert_eq!(account.total, 90.0);
+        assert!(!engine.transactions[&1].pending);
+        assert_eq!(engine.check_invariants(), Ok(()));
+    }
 
-        assert!(result.is_err());
-        if let Err(TransactionError::InvalidDispute(tx_id)) = result {
-            assert_eq!(tx_id, 2);
-        } else {
-            panic!("Expected InvalidDispute error for disputing a withdrawal transaction");
-        }
+    // Test that disputing a pending deposit holds it out of the pending funds
+    // and that it can only be settled once the dispute is resolved
+    #[test]
+    fn test_dispute_pending_deposit() {
+        let mut engine = Engine::builder().pending_deposits(true).build();
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,100.0\n\
+             deposit,1,2,40.0\n\
+             dispute,1,1,\n",
+        );
+        assert!(outcomes.iter().all(|outcome| outcome.result.is_ok()));
+        let account = engine.account(1).expect("Account not found");
+        assert_eq!(account.available, 0.0);
+        assert_eq!(account.held, 100.0);
+        assert_eq!(account.pending, 40.0);
+        assert_eq!(account.total, 140.0);
+        assert_eq!(engine.check_invariants(), Ok(()));
+
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             settle,1,1,\n\
+             resolve,1,1,\n\
+             settle,1,1,\n",
+        );
+        assert!(matches!(
+            outcomes[0].result,
+            Err(TransactionError::AlreadyDisputed(1))
+        ));
+        assert!(outcomes[1..].iter().all(|outcome| outcome.result.is_ok()));
+        let account = engine.account(1).expect("Account not found");
+        assert_eq!(account.available, 100.0);
+        assert_eq!(account.held, 0.0);
+        assert_eq!(account.pending, 40.0);
+        assert_eq!(account.total, 140.0);
+        assert_eq!(engine.check_invariants(), Ok(()));
+
+        // Charged back while pending, the deposit leaves nothing to settle
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             dispute,1,2,\n\
+             chargeback,1,2,\n",
+        );
+        assert!(outcomes.iter().all(|outcome| outcome.result.is_ok()));
+        let account = engine.account(1).expect("Account not found");
+        assert_eq!(account.available, 100.0);
+        assert_eq!(account.pending, 0.0);
+        assert_eq!(account.total, 100.0);
+        assert!(account.locked);
+        assert!(!engine.transactions[&2].pending);
+        assert_eq!(engine.check_invariants(), Ok(()));
     }
 
+    // Test that zero and negative amounts are rejected without a dust policy
     #[test]
-    fn test_invalid_chargeback_on_non_deposit_transaction() {
+    fn test_non_positive_amounts_rejected() {
         let mut engine = Engine::new();
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,0.0\n\
+             deposit,1,2,-5.0\n\
+             deposit,1,3,5.0\n\
+             withdrawal,1,4,0.0\n",
+        );
 
-        // Register a deposit
-        let deposit_tx = Transaction {
-            t_type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(600.0),
-            disputed: false,
-        };
-        engine
-            .process_transaction(deposit_tx)
-            .expect("Failed to process deposit transaction");
+        for (outcome, tx) in outcomes.iter().zip([1, 2]) {
+            assert!(matches!(outcome.result, Err(TransactionError::InvalidAmount(id)) if id == tx));
+        }
+        assert!(outcomes[2].result.is_ok());
+        assert!(matches!(
+            outcomes[3].result,
+            Err(TransactionError::InvalidAmount(4))
+        ));
+        assert_eq!(engine.transaction_count(), 1);
+        assert_eq!(engine.dust_skipped(), 0);
+    }
 
-        // Register a withdrawal transaction
-        let withdrawal_tx = Transaction {
-            t_type: TransactionType::Withdrawal,
-            client: 1,
-            tx: 2,
-            amount: Some(500.0),
-            disputed: true, // intentionally set to cover edge case error handling :-)
-        };
-        engine
-            .process_transaction(withdrawal_tx)
-            .expect("Failed to process withdrawal");
+    // Test that dust, zero amounts included, is skipped before any other
+    // validation, while negative amounts are still rejected
+    #[test]
+    fn test_dust_threshold() {
+        let mut engine = Engine::builder().dust_threshold(Some(0.0001)).build();
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             deposit,5,77,0.0000\n\
+             deposit,6,2,0.00009\n\
+             withdrawal,1,3,0.00005\n\
+             deposit,1,1,0.00001\n\
+             deposit,1,4,-0.00001\n\
+             deposit,1,5,0.0001\n",
+        );
 
-        // Attempt to chargeback the withdrawal (invalid operation)
-        let chargeback_tx = Transaction {
-            t_type: TransactionType::Chargeback,
-            client: 1,
-            tx: 2,
-            amount: None,
-            disputed: false,
-        };
+        assert!(outcomes[..5].iter().all(|outcome| outcome.result.is_ok()));
+        assert!(matches!(
+            outcomes[5].result,
+            Err(TransactionError::InvalidAmount(4))
+        ));
+        assert!(outcomes[6].result.is_ok());
+        assert_eq!(engine.dust_skipped(), 4);
+        assert_eq!(engine.account_count(), 1);
+        assert!(engine.account(5).is_none() && engine.account(6).is_none());
+        let stored: Vec<u32> = engine
+            .transactions_by_id()
+            .iter()
+            .map(|&(tx, _)| tx)
+            .collect();
+        assert_eq!(stored, [1, 5]);
+        let account = engine.account(1).expect("Account not found");
+        assert_eq!(account.available, 10.0001);
+        assert_eq!(account.last_tx, Some(5));
+    }
 
-        let result = engine.process_transaction(chargeback_tx);
-        assert!(result.is_err());
-        if let Err(TransactionError::InvalidChargeback(tx_id)) = result {
-            assert_eq!(tx_id, 2);
-        } else {
-            panic!("Expected InvalidChargeback error for charging back a withdrawal transaction");
-        }
+    // Test that peaks keep intermediate balances and ignore rejected rows
+    #[test]
+    fn test_peak_balances() {
+        let mut engine = Engine::builder().max_single_deposit(Some(5_000.0)).build();
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,1000.0\n\
+             withdrawal,1,2,900.0\n",
+        );
+        assert!(outcomes.iter().all(|outcome| outcome.result.is_ok()));
+        let view = engine.account_view(1).expect("Account not found");
+        assert_eq!(
+            (view.total, view.peak_total, view.peak_held),
+            (100.0, 1000.0, 0.0)
+        );
+
+        let outcomes = submit_csv(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,3,300.0\n\
+             dispute,1,3,\n\
+             resolve,1,3,\n\
+             deposit,1,4,9000.0\n\
+             withdrawal,1,5,5000.0\n\
+             dispute,1,2,\n",
+        );
+        assert!(outcomes[..3].iter().all(|outcome| outcome.result.is_ok()));
+        assert!(outcomes[3..].iter().all(|outcome| outcome.result.is_err()));
+        let account = engine.account(1).expect("Account not found");
+        assert_eq!(account.total, 400.0);
+        assert_eq!(account.held, 0.0);
+        assert_eq!(account.peak_total, 1000.0);
+        assert_eq!(account.peak_held, 300.0);
     }
 }
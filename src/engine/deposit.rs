@@ -0,0 +1,154 @@
+use super::{check_capacity, checked_add, positive_amount, stored, Engine};
+use crate::error::{Capacity, Transaction as TransactionError};
+use crate::store::TransactionStore;
+use crate::transaction::{Transaction, TxKind};
+
+impl<S: TransactionStore> Engine<S> {
+    pub(super) fn process_deposit(
+        &mut self,
+        transaction: &Transaction,
+    ) -> Result<(), TransactionError> {
+        let fee = self.fee(transaction)?;
+        let account = self
+            .accounts
+            .get_mut(&transaction.client)
+            .ok_or(TransactionError::AccountNotFound(transaction.client))?;
+        if self.transactions.contains(transaction.tx) {
+            return Err(TransactionError::DuplicateTransaction(transaction.tx));
+        }
+        check_capacity(
+            self.policy.max_transactions,
+            self.transactions.len(),
+            Capacity::Transactions,
+        )?;
+        let amount = positive_amount(transaction)?;
+
+        if self
+            .policy
+            .max_single_deposit
+            .is_some_and(|limit| amount > limit)
+        {
+            return Err(TransactionError::DepositCapExceeded(transaction.tx));
+        }
+        let deposited = self
+            .deposited
+            .get(&transaction.client)
+            .copied()
+            .unwrap_or(0.0)
+            + amount;
+        if self
+            .policy
+            .max_total_deposits_per_client
+            .is_some_and(|limit| deposited > limit)
+        {
+            return Err(TransactionError::VelocityLimitExceeded(transaction.client));
+        }
+        if self
+            .policy
+            .max_balance
+            .is_some_and(|limit| account.total + amount > limit)
+        {
+            return Err(TransactionError::BalanceCapExceeded(account.client));
+        }
+
+        let client = account.client;
+        let pending = self.policy.pending_deposits;
+        // The fee is paid from the available funds, which a pending deposit
+        // does not credit yet
+        let fee_amount = fee.map_or(0.0, |fee| fee.amount);
+        let payable = if pending {
+            account.available
+        } else {
+            account.available + amount
+        };
+        if fee_amount > 0.0 && payable < fee_amount {
+            return Err(TransactionError::InsufficientFunds(client));
+        }
+        let credited = if pending {
+            &mut account.pending
+        } else {
+            &mut account.available
+        };
+        let balance =
+            checked_add(*credited, amount).ok_or(TransactionError::BalanceOverflow(client))?;
+        let total = checked_add(account.total, amount - fee_amount)
+            .ok_or(TransactionError::BalanceOverflow(client))?;
+
+        *credited = balance;
+        account.available -= fee_amount;
+        account.total = total;
+        self.deposited.insert(transaction.client, deposited);
+        self.totals.deposits += amount;
+        let mut deposit = stored(transaction, amount, TxKind::Deposit);
+        deposit.pending = pending;
+        self.transactions.insert(transaction.tx, deposit);
+        if let Some(fee) = fee {
+            self.collect_fee(fee);
+        }
+        Ok(())
+    }
+
+    pub(super) fn process_settle(
+        &mut self,
+        transaction: &Transaction,
+    ) -> Result<(), TransactionError> {
+        let (account, original_tx) = self.resolve_reference(transaction.tx, transaction.client)?;
+        if !original_tx.pending {
+            return Err(TransactionError::NotPending(transaction.tx));
+        }
+        // The funds are held until the dispute is resolved
+        if original_tx.is_disputed() {
+            return Err(TransactionError::AlreadyDisputed(transaction.tx));
+        }
+
+        let amount = original_tx.amount;
+        let pending = checked_add(account.pending, -amount)
+            .ok_or(TransactionError::BalanceOverflow(account.client))?;
+        let available = checked_add(account.available, amount)
+            .ok_or(TransactionError::BalanceOverflow(account.client))?;
+        account.pending = pending;
+        account.available = available;
+        original_tx.pending = false;
+        Ok(())
+    }
+
+    pub(super) fn process_refund(
+        &mut self,
+        transaction: &Transaction,
+    ) -> Result<(), TransactionError> {
+        let (account, original_tx) = self.resolve_reference(transaction.tx, transaction.client)?;
+        if original_tx.kind != TxKind::Deposit {
+            return Err(TransactionError::InvalidRefund(transaction.tx));
+        }
+        if original_tx.refunded {
+            return Err(TransactionError::AlreadyRefunded(transaction.tx));
+        }
+        // The funds already left the account with the chargeback
+        if original_tx.is_charged_back() {
+            return Err(TransactionError::AlreadyChargedBack(transaction.tx));
+        }
+        // The funds are held until the dispute is resolved
+        if original_tx.is_disputed() {
+            return Err(TransactionError::AlreadyDisputed(transaction.tx));
+        }
+
+        // A deposit still pending is refunded from the pending funds
+        let amount = original_tx.amount;
+        let source = if original_tx.pending {
+            &mut account.pending
+        } else {
+            &mut account.available
+        };
+        if *source < amount {
+            return Err(TransactionError::InsufficientFunds(account.client));
+        }
+        let total = checked_add(account.total, -amount)
+            .ok_or(TransactionError::BalanceOverflow(account.client))?;
+        *source -= amount;
+        account.total = total;
+        original_tx.refunded = true;
+        original_tx.pending = false;
+        self.totals.refunds += amount;
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+use super::{approx_eq, checked_add, Engine};
+use crate::account::LockReason;
+use crate::error::Transaction as TransactionError;
+use crate::policy::{ChargebackOverdraw, Policy};
+use crate::store::TransactionStore;
+use crate::transaction::{DisputeState, Transaction, TxKind};
+
+impl<S: TransactionStore> Engine<S> {
+    pub(super) fn process_dispute(
+        &mut self,
+        transaction: &Transaction,
+    ) -> Result<(), TransactionError> {
+        let Policy {
+            max_redisputes,
+            max_open_disputes_per_client,
+            dispute_clamp_to_available,
+            ..
+        } = self.policy;
+        let (account, original_tx) = self.resolve_reference(transaction.tx, transaction.client)?;
+        if original_tx.refunded {
+            return Err(TransactionError::AlreadyRefunded(transaction.tx));
+        }
+        if original_tx.is_charged_back() {
+            return Err(TransactionError::AlreadyChargedBack(transaction.tx));
+        }
+        if original_tx.is_disputed() {
+            return Err(TransactionError::AlreadyDisputed(transaction.tx));
+        }
+        if original_tx.kind != TxKind::Deposit {
+            return Err(TransactionError::InvalidDispute(transaction.tx));
+        }
+        if max_redisputes.is_some_and(|limit| original_tx.disputes > limit) {
+            return Err(TransactionError::RedisputeLimitExceeded(transaction.tx));
+        }
+        if max_open_disputes_per_client.is_some_and(|limit| account.open_disputes >= limit) {
+            return Err(TransactionError::DisputeRateLimited(account.client));
+        }
+
+        // Part of the deposit may already have been withdrawn, unless it is
+        // still pending
+        let amount = original_tx.amount;
+        let to_hold = if dispute_clamp_to_available && !original_tx.pending {
+            amount.min(account.available.max(0.0))
+        } else {
+            amount
+        };
+        let source = if original_tx.pending {
+            &mut account.pending
+        } else {
+            &mut account.available
+        };
+        let released = checked_add(*source, -to_hold)
+            .ok_or(TransactionError::BalanceOverflow(account.client))?;
+        let held = checked_add(account.held, to_hold)
+            .ok_or(TransactionError::BalanceOverflow(account.client))?;
+        let shortfall = checked_add(account.dispute_shortfall, amount - to_hold)
+            .ok_or(TransactionError::BalanceOverflow(account.client))?;
+        *source = released;
+        account.held = held;
+        account.dispute_shortfall = shortfall;
+        original_tx.dispute_state = DisputeState::Disputed;
+        original_tx.held = to_hold;
+        original_tx.disputes += 1;
+        account.open_disputes += 1;
+        Ok(())
+    }
+
+    pub(super) fn process_resolve(
+        &mut self,
+        transaction: &Transaction,
+    ) -> Result<(), TransactionError> {
+        let strict_held = self.policy.strict_held;
+        let (account, original_tx) = self.resolve_reference(transaction.tx, transaction.client)?;
+        // Only deposits can be disputed, so nothing else can be resolved
+        if original_tx.kind != TxKind::Deposit {
+            return Err(TransactionError::InvalidDispute(transaction.tx));
+        }
+        if original_tx.is_charged_back() {
+            return Err(TransactionError::AlreadyChargedBack(transaction.tx));
+        }
+        if !original_tx.is_disputed() {
+            return Err(TransactionError::NotUnderDispute(transaction.tx));
+        }
+
+        let released = original_tx.held;
+        // A deposit disputed while pending goes back to pending
+        let target = if original_tx.pending {
+            &mut account.pending
+        } else {
+            &mut account.available
+        };
+        let restored = checked_add(*target, released)
+            .ok_or(TransactionError::BalanceOverflow(account.client))?;
+        let held = checked_add(account.held, -released)
+            .ok_or(TransactionError::BalanceOverflow(account.client))?;
+        // Rounding may leave a negligible negative remainder
+        if strict_held && held < 0.0 && !approx_eq(held, 0.0) {
+            return Err(TransactionError::HeldUnderflow(transaction.tx));
+        }
+        *target = restored;
+        account.held = held;
+        account.dispute_shortfall -= original_tx.shortfall();
+        original_tx.dispute_state = DisputeState::Undisputed;
+        original_tx.held = 0.0;
+        account.open_disputes = account.open_disputes.saturating_sub(1);
+        Ok(())
+    }
+
+    pub(super) fn process_chargeback(
+        &mut self,
+        transaction: &Transaction,
+    ) -> Result<(), TransactionError> {
+        let Policy {
+            on_chargeback_overdraw,
+            lock_on_chargeback,
+            lock_on_cumulative_loss,
+            ..
+        } = self.policy;
+        let (account, original_tx) = self.resolve_reference(transaction.tx, transaction.client)?;
+        if original_tx.kind != TxKind::Deposit {
+            return Err(TransactionError::InvalidChargeback(transaction.tx));
+        }
+        if original_tx.is_charged_back() {
+            return Err(TransactionError::AlreadyChargedBack(transaction.tx));
+        }
+        if !original_tx.is_disputed() {
+            return Err(TransactionError::NotUnderDispute(transaction.tx));
+        }
+
+        // Only the held part is charged back; a clamped shortfall is written
+        // off
+        let charged = original_tx.held;
+        let held = checked_add(account.held, -charged)
+            .ok_or(TransactionError::BalanceOverflow(account.client))?;
+        let mut total = checked_add(account.total, -charged)
+            .ok_or(TransactionError::BalanceOverflow(account.client))?;
+
+        // The deposit was (partly) withdrawn before being disputed
+        if account.available < 0.0 {
+            match on_chargeback_overdraw {
+                ChargebackOverdraw::Reject => {
+                    return Err(TransactionError::ChargebackOverdraw(transaction.tx));
+                }
+                ChargebackOverdraw::AllowNegative => {}
+                ChargebackOverdraw::ClampToZero => {
+                    total = checked_add(total, -account.available)
+                        .ok_or(TransactionError::BalanceOverflow(account.client))?;
+                    account.available = 0.0;
+                }
+            }
+        }
+        let removed = account.total - total;
+        account.held = held;
+        account.total = total;
+        account.dispute_shortfall -= original_tx.shortfall();
+
+        // Final even when the account stays open, so that the deposit cannot
+        // be charged back twice
+        original_tx.dispute_state = DisputeState::ChargedBack;
+        original_tx.held = 0.0;
+        // Charged-back funds left the account, so nothing is left to settle
+        original_tx.pending = false;
+        account.open_disputes = account.open_disputes.saturating_sub(1);
+        account.chargeback_losses += charged;
+        let cumulative =
+            lock_on_cumulative_loss.is_some_and(|limit| account.chargeback_losses > limit);
+        if lock_on_chargeback.locks(charged) || cumulative {
+            account.lock(LockReason::Chargeback);
+        }
+        self.totals.chargebacks += removed;
+        Ok(())
+    }
+}
@@ -0,0 +1,55 @@
+use super::{check_capacity, checked_add, positive_amount, stored, Engine};
+use crate::error::{Capacity, Transaction as TransactionError};
+use crate::policy::WithdrawalsDuringDispute;
+use crate::store::TransactionStore;
+use crate::transaction::{Transaction, TxKind};
+
+impl<S: TransactionStore> Engine<S> {
+    pub(super) fn process_withdrawal(
+        &mut self,
+        transaction: &Transaction,
+    ) -> Result<(), TransactionError> {
+        let fee = self.fee(transaction)?;
+        let account = self
+            .accounts
+            .get_mut(&transaction.client)
+            .ok_or(TransactionError::AccountNotFound(transaction.client))?;
+        if self.transactions.contains(transaction.tx) {
+            return Err(TransactionError::DuplicateTransaction(transaction.tx));
+        }
+        check_capacity(
+            self.policy.max_transactions,
+            self.transactions.len(),
+            Capacity::Transactions,
+        )?;
+        let amount = positive_amount(transaction)?;
+
+        if self.policy.withdrawals_during_dispute == WithdrawalsDuringDispute::Block
+            && account.open_disputes > 0
+        {
+            return Err(TransactionError::WithdrawalBlockedByDispute(account.client));
+        }
+
+        // Checked with the fee so that it is never charged in part
+        let charged = amount + fee.map_or(0.0, |fee| fee.amount);
+        if account.available < charged {
+            return Err(TransactionError::InsufficientFunds(account.client));
+        }
+        let available = checked_add(account.available, -charged)
+            .ok_or(TransactionError::BalanceOverflow(account.client))?;
+        let total = checked_add(account.total, -charged)
+            .ok_or(TransactionError::BalanceOverflow(account.client))?;
+
+        account.available = available;
+        account.total = total;
+        self.totals.withdrawals += amount;
+        self.transactions.insert(
+            transaction.tx,
+            stored(transaction, amount, TxKind::Withdrawal),
+        );
+        if let Some(fee) = fee {
+            self.collect_fee(fee);
+        }
+        Ok(())
+    }
+}
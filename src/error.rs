@@ -1,6 +1,9 @@
+use std::fmt;
+
+use serde::ser::{Serialize, SerializeStruct, Serializer};
 use thiserror::Error;
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone, PartialEq)]
 pub enum Transaction {
     #[error("Transaction ID {0} not found for client {1}")]
     NotFound(u32, u16),
@@ -28,4 +31,485 @@ pub enum Transaction {
 
     #[error("Cannot find customer account {0}")]
     AccountNotFound(u16),
+
+    #[error("Deposit transaction ID {0} exceeds the maximum single deposit")]
+    DepositCapExceeded(u32),
+
+    #[error("Deposit velocity limit exceeded for client {0}")]
+    VelocityLimitExceeded(u16),
+
+    #[error("Withdrawal blocked for client {0} while a dispute is open")]
+    WithdrawalBlockedByDispute(u16),
+
+    #[error("Balance of client {0} would overflow")]
+    BalanceOverflow(u16),
+
+    #[error("Account {0} is locked for a reason that cannot be lifted")]
+    LockNotReversible(u16),
+
+    #[error("Chargeback of transaction ID {0} would overdraw the account")]
+    ChargebackOverdraw(u32),
+
+    #[error("Transaction ID {0} already exists")]
+    DuplicateTransaction(u32),
+
+    #[error("Transaction ID {0} belongs to client {1}, not client {2}")]
+    ClientMismatch(u32, u16, u16),
+
+    #[error("Limit of {1} {0} reached")]
+    CapacityExceeded(Capacity, usize),
+
+    #[error("Transaction ID {0} cannot be disputed again")]
+    RedisputeLimitExceeded(u32),
+
+    #[error("Transaction ID {0} is not a pending deposit")]
+    NotPending(u32),
+
+    #[error("Balance cap exceeded for client {0}")]
+    BalanceCapExceeded(u16),
+
+    #[error("Resolving transaction ID {0} would leave negative held funds")]
+    HeldUnderflow(u32),
+
+    #[error("Client {0} has too many open disputes")]
+    DisputeRateLimited(u16),
+
+    #[error("Client {0} has submitted too many transactions")]
+    RateLimited(u16),
+
+    #[error("Account {0} has not been opened")]
+    AccountNotOpen(u16),
+
+    #[error("Transaction ID {0} has a type with no registered handler")]
+    UnsupportedType(u32),
+
+    #[error("Client {0} is denied")]
+    ClientDenied(u16),
+
+    #[error("Cannot refund transaction ID {0} as it is not a deposit")]
+    InvalidRefund(u32),
+
+    #[error("Transaction ID {0} is already refunded")]
+    AlreadyRefunded(u32),
+
+    #[error("Transaction ID {0} was already charged back")]
+    AlreadyChargedBack(u32),
+}
+
+/// Engine limit hit by a [`Transaction::CapacityExceeded`] error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capacity {
+    /// Number of accounts, see `max_accounts` in [`Policy`](crate::policy::Policy).
+    Accounts,
+    /// Number of stored deposits and withdrawals, see `max_transactions` in
+    /// [`Policy`](crate::policy::Policy).
+    Transactions,
+}
+
+impl fmt::Display for Capacity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Capacity::Accounts => "accounts",
+            Capacity::Transactions => "stored transactions",
+        })
+    }
+}
+
+/// Consistency rule of the engine state found to be violated, see
+/// [`Engine::check_invariants`](crate::engine::Engine::check_invariants).
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum Invariant {
+    #[error("Account {0} has a non-finite balance")]
+    NonFiniteBalance(u16),
+
+    #[error("Account {0} total is not the sum of available, held and pending funds")]
+    TotalMismatch(u16),
+
+    #[error("Account {0} held funds do not match its disputed deposits")]
+    HeldMismatch(u16),
+
+    #[error("Account {0} dispute shortfall does not match its disputed deposits")]
+    ShortfallMismatch(u16),
+
+    #[error("Account {0} pending funds do not match its pending deposits")]
+    PendingMismatch(u16),
+
+    #[error("Account {0} open dispute count does not match its disputed deposits")]
+    OpenDisputesMismatch(u16),
+
+    #[error("Account {0} locked status does not match its lock reason")]
+    LockReasonMismatch(u16),
+
+    #[error("Transaction ID {0} is disputed but is not a deposit")]
+    DisputedNonDeposit(u32),
+
+    #[error("Transaction ID {0} belongs to unknown client {1}")]
+    UnknownClient(u32, u16),
+}
+
+/// Error raised when saving or loading an engine snapshot.
+#[derive(Error, Debug)]
+pub enum Snapshot {
+    #[error("Invalid snapshot: {0}")]
+    Format(#[from] serde_json::Error),
+
+    #[error("Unsupported snapshot version {0}")]
+    UnsupportedVersion(u32),
+
+    #[error("Snapshot contains client {0} more than once")]
+    DuplicateClient(u16),
+
+    #[error("Snapshot contains transaction ID {0} more than once")]
+    DuplicateTransaction(u32),
+
+    #[error("Inconsistent snapshot: {0}")]
+    Inconsistent(#[from] Invariant),
+}
+
+/// Error raised when an input file has no usable schema or cannot be
+/// resumed.
+#[derive(Error, Debug)]
+pub enum Input {
+    #[error("Input has both an `amount` and an `amount_minor` column, expected only one")]
+    ConflictingAmountColumns,
+
+    #[error("Input differs from the file the state was saved from, in its first {0} bytes")]
+    Changed(u64),
+
+    #[error("Failed to read the input: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Failed to read the input: {0}")]
+    Csv(#[from] csv::Error),
+}
+
+/// Error raised when the text of an amount does not follow the accepted
+/// grammar, see [`parse_amount`](crate::input::parse_amount).
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum AmountSyntax {
+    #[error(
+        "Invalid amount '{0}', expected an optional sign, digits and an optional fraction, \
+         such as 100, +50.5 or -0.25"
+    )]
+    Invalid(String),
+
+    #[error(
+        "Invalid amount '{0}', exponents are not allowed: expected an optional sign, digits \
+         and an optional fraction, such as 100, +50.5 or -0.25"
+    )]
+    Exponent(String),
+}
+
+/// Error raised when reading or replaying a write-ahead log.
+#[derive(Error, Debug)]
+pub enum Wal {
+    #[error("Failed to access the write-ahead log: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Write-ahead log record at byte {0} fails its checksum")]
+    Checksum(u64),
+
+    #[error("Write-ahead log record at byte {0} cannot be decoded")]
+    Record(u64),
+
+    #[error("Logged transaction ID {tx} was rejected on replay: {source}")]
+    Rejected { tx: u32, source: Transaction },
+}
+
+/// Error raised when reading or replaying a command log.
+#[derive(Error, Debug)]
+pub enum CommandLog {
+    #[error("Failed to access the command log: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Command log record at byte {0} fails its checksum")]
+    Checksum(u64),
+
+    #[error("Command log record at byte {0} cannot be decoded")]
+    Record(u64),
+
+    #[error("Replay diverged at transaction ID {tx}: logged {logged}, replayed {replayed}")]
+    Diverged {
+        tx: u32,
+        logged: String,
+        replayed: String,
+    },
+}
+
+/// Error raised when merging the state of two engines.
+#[derive(Error, Debug)]
+pub enum Merge {
+    #[error("Client {client} has conflicting state: {details}")]
+    ConflictingAccount { client: u16, details: String },
+
+    #[error("Client {0} is present in both states")]
+    DuplicateAccount(u16),
+
+    #[error("Transaction ID {tx} has conflicting state: {details}")]
+    ConflictingTransaction { tx: u32, details: String },
+
+    #[error("Inconsistent merged state: {0}")]
+    Inconsistent(#[from] Invariant),
+}
+
+/// Error raised when comparing two accounts reports, each named after its
+/// role in the message, such as `baseline` and `actual`.
+#[derive(Error, Debug)]
+pub enum Compare {
+    #[error("Failed to read the {0} report: {1}")]
+    Csv(&'static str, csv::Error),
+
+    #[error("The {0} report has no `{1}` column")]
+    MissingColumn(&'static str, String),
+
+    #[error("The {0} report has an invalid client id '{1}'")]
+    InvalidClient(&'static str, String),
+
+    #[error("The {0} report contains client {1} more than once")]
+    DuplicateClient(&'static str, u16),
+
+    #[error("The {0} report has an invalid {1} '{3}' for client {2}")]
+    InvalidValue(&'static str, &'static str, u16, String),
+}
+
+/// Error raised when the account totals do not add up to the funds moved by
+/// the engine, see
+/// [`Engine::global_check`](crate::engine::Engine::global_check).
+#[derive(Error, Debug, PartialEq)]
+#[error("Account totals add up to {actual} while the funds moved add up to {expected}")]
+pub struct Imbalance {
+    pub expected: f64,
+    pub actual: f64,
+}
+
+impl Transaction {
+    /// Short machine-readable code identifying the error kind.
+    ///
+    /// Codes are a stable interface for downstream consumers: an existing code
+    /// is never renamed or reused, and every new variant gets its own code.
+    #[must_use]
+    pub fn code(&self) -> &'static str {
+        match self {
+            Transaction::NotFound(..) => "E_TX_NOT_FOUND",
+            Transaction::InsufficientFunds(_) => "E_INSUFFICIENT_FUNDS",
+            Transaction::AccountLocked(_) => "E_ACCOUNT_LOCKED",
+            Transaction::InvalidAmount(_) => "E_INVALID_AMOUNT",
+            Transaction::AlreadyDisputed(_) => "E_ALREADY_DISPUTED",
+            Transaction::NotUnderDispute(_) => "E_NOT_UNDER_DISPUTE",
+            Transaction::InvalidDispute(_) => "E_INVALID_DISPUTE",
+            Transaction::InvalidChargeback(_) => "E_INVALID_CHARGEBACK",
+            Transaction::AccountNotFound(_) => "E_ACCOUNT_NOT_FOUND",
+            Transaction::DepositCapExceeded(_) => "E_DEPOSIT_CAP_EXCEEDED",
+            Transaction::VelocityLimitExceeded(_) => "E_VELOCITY_LIMIT_EXCEEDED",
+            Transaction::WithdrawalBlockedByDispute(_) => "E_WITHDRAWAL_BLOCKED_BY_DISPUTE",
+            Transaction::BalanceOverflow(_) => "E_BALANCE_OVERFLOW",
+            Transaction::LockNotReversible(_) => "E_LOCK_NOT_REVERSIBLE",
+            Transaction::ChargebackOverdraw(_) => "E_CHARGEBACK_OVERDRAW",
+            Transaction::DuplicateTransaction(_) => "E_DUPLICATE_TX",
+            Transaction::ClientMismatch(..) => "E_CLIENT_MISMATCH",
+            Transaction::CapacityExceeded(..) => "E_CAPACITY_EXCEEDED",
+            Transaction::RedisputeLimitExceeded(_) => "E_REDISPUTE_LIMIT_EXCEEDED",
+            Transaction::NotPending(_) => "E_NOT_PENDING",
+            Transaction::BalanceCapExceeded(_) => "E_BALANCE_CAP_EXCEEDED",
+            Transaction::HeldUnderflow(_) => "E_HELD_UNDERFLOW",
+            Transaction::DisputeRateLimited(_) => "E_DISPUTE_RATE_LIMITED",
+            Transaction::RateLimited(_) => "E_RATE_LIMITED",
+            Transaction::AccountNotOpen(_) => "E_ACCOUNT_NOT_OPEN",
+            Transaction::UnsupportedType(_) => "E_UNSUPPORTED_TYPE",
+            Transaction::ClientDenied(_) => "E_CLIENT_DENIED",
+            Transaction::InvalidRefund(_) => "E_INVALID_REFUND",
+            Transaction::AlreadyRefunded(_) => "E_ALREADY_REFUNDED",
+            Transaction::AlreadyChargedBack(_) => "E_ALREADY_CHARGED_BACK",
+        }
+    }
+
+    /// Transaction id carried by the error, if any.
+    #[must_use]
+    pub fn tx(&self) -> Option<u32> {
+        match self {
+            Transaction::NotFound(tx, _)
+            | Transaction::ClientMismatch(tx, _, _)
+            | Transaction::InvalidAmount(tx)
+            | Transaction::AlreadyDisputed(tx)
+            | Transaction::NotUnderDispute(tx)
+            | Transaction::InvalidDispute(tx)
+            | Transaction::InvalidChargeback(tx)
+            | Transaction::DepositCapExceeded(tx)
+            | Transaction::ChargebackOverdraw(tx)
+            | Transaction::DuplicateTransaction(tx)
+            | Transaction::RedisputeLimitExceeded(tx)
+            | Transaction::NotPending(tx)
+            | Transaction::HeldUnderflow(tx)
+            | Transaction::UnsupportedType(tx)
+            | Transaction::InvalidRefund(tx)
+            | Transaction::AlreadyRefunded(tx)
+            | Transaction::AlreadyChargedBack(tx) => Some(*tx),
+            Transaction::InsufficientFunds(_)
+            | Transaction::AccountLocked(_)
+            | Transaction::AccountNotFound(_)
+            | Transaction::VelocityLimitExceeded(_)
+            | Transaction::WithdrawalBlockedByDispute(_)
+            | Transaction::BalanceOverflow(_)
+            | Transaction::LockNotReversible(_)
+            | Transaction::CapacityExceeded(..)
+            | Transaction::BalanceCapExceeded(_)
+            | Transaction::DisputeRateLimited(_)
+            | Transaction::RateLimited(_)
+            | Transaction::AccountNotOpen(_)
+            | Transaction::ClientDenied(_) => None,
+        }
+    }
+
+    /// Client id carried by the error, if any.
+    #[must_use]
+    pub fn client(&self) -> Option<u16> {
+        match self {
+            Transaction::NotFound(_, client)
+            | Transaction::ClientMismatch(_, _, client)
+            | Transaction::InsufficientFunds(client)
+            | Transaction::AccountLocked(client)
+            | Transaction::AccountNotFound(client)
+            | Transaction::VelocityLimitExceeded(client)
+            | Transaction::WithdrawalBlockedByDispute(client)
+            | Transaction::BalanceOverflow(client)
+            | Transaction::LockNotReversible(client)
+            | Transaction::BalanceCapExceeded(client)
+            | Transaction::DisputeRateLimited(client)
+            | Transaction::RateLimited(client)
+            | Transaction::AccountNotOpen(client)
+            | Transaction::ClientDenied(client) => Some(*client),
+            Transaction::InvalidAmount(_)
+            | Transaction::AlreadyDisputed(_)
+            | Transaction::NotUnderDispute(_)
+            | Transaction::InvalidDispute(_)
+            | Transaction::InvalidChargeback(_)
+            | Transaction::DepositCapExceeded(_)
+            | Transaction::ChargebackOverdraw(_)
+            | Transaction::DuplicateTransaction(_)
+            | Transaction::CapacityExceeded(..)
+            | Transaction::RedisputeLimitExceeded(_)
+            | Transaction::NotPending(_)
+            | Transaction::HeldUnderflow(_)
+            | Transaction::UnsupportedType(_)
+            | Transaction::InvalidRefund(_)
+            | Transaction::AlreadyRefunded(_)
+            | Transaction::AlreadyChargedBack(_) => None,
+        }
+    }
+}
+
+// Serialized as `{ "code", "message", "tx", "client" }` for structured sinks
+impl Serialize for Transaction {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("TransactionError", 4)?;
+        state.serialize_field("code", self.code())?;
+        state.serialize_field("message", &self.to_string())?;
+        state.serialize_field("tx", &self.tx())?;
+        state.serialize_field("client", &self.client())?;
+        state.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    // One value per variant: extend this list along with the enum
+    fn all_errors() -> Vec<Transaction> {
+        vec![
+            Transaction::NotFound(1, 2),
+            Transaction::InsufficientFunds(2),
+            Transaction::AccountLocked(2),
+            Transaction::InvalidAmount(1),
+            Transaction::AlreadyDisputed(1),
+            Transaction::NotUnderDispute(1),
+            Transaction::InvalidDispute(1),
+            Transaction::InvalidChargeback(1),
+            Transaction::AccountNotFound(2),
+            Transaction::DepositCapExceeded(1),
+            Transaction::VelocityLimitExceeded(2),
+            Transaction::WithdrawalBlockedByDispute(2),
+            Transaction::BalanceOverflow(2),
+            Transaction::LockNotReversible(2),
+            Transaction::ChargebackOverdraw(1),
+            Transaction::DuplicateTransaction(1),
+            Transaction::ClientMismatch(1, 2, 3),
+            Transaction::CapacityExceeded(Capacity::Accounts, 4),
+            Transaction::RedisputeLimitExceeded(1),
+            Transaction::NotPending(1),
+            Transaction::BalanceCapExceeded(2),
+            Transaction::HeldUnderflow(1),
+            Transaction::DisputeRateLimited(2),
+            Transaction::RateLimited(2),
+            Transaction::AccountNotOpen(2),
+            Transaction::UnsupportedType(1),
+            Transaction::ClientDenied(2),
+            Transaction::InvalidRefund(1),
+            Transaction::AlreadyRefunded(1),
+            Transaction::AlreadyChargedBack(1),
+        ]
+    }
+
+    #[test]
+    fn test_error_codes_are_stable() {
+        let codes: Vec<&str> = all_errors().iter().map(Transaction::code).collect();
+        assert_eq!(
+            codes,
+            vec![
+                "E_TX_NOT_FOUND",
+                "E_INSUFFICIENT_FUNDS",
+                "E_ACCOUNT_LOCKED",
+                "E_INVALID_AMOUNT",
+                "E_ALREADY_DISPUTED",
+                "E_NOT_UNDER_DISPUTE",
+                "E_INVALID_DISPUTE",
+                "E_INVALID_CHARGEBACK",
+                "E_ACCOUNT_NOT_FOUND",
+                "E_DEPOSIT_CAP_EXCEEDED",
+                "E_VELOCITY_LIMIT_EXCEEDED",
+                "E_WITHDRAWAL_BLOCKED_BY_DISPUTE",
+                "E_BALANCE_OVERFLOW",
+                "E_LOCK_NOT_REVERSIBLE",
+                "E_CHARGEBACK_OVERDRAW",
+                "E_DUPLICATE_TX",
+                "E_CLIENT_MISMATCH",
+                "E_CAPACITY_EXCEEDED",
+                "E_REDISPUTE_LIMIT_EXCEEDED",
+                "E_NOT_PENDING",
+                "E_BALANCE_CAP_EXCEEDED",
+                "E_HELD_UNDERFLOW",
+                "E_DISPUTE_RATE_LIMITED",
+                "E_RATE_LIMITED",
+                "E_ACCOUNT_NOT_OPEN",
+                "E_UNSUPPORTED_TYPE",
+                "E_CLIENT_DENIED",
+                "E_INVALID_REFUND",
+                "E_ALREADY_REFUNDED",
+                "E_ALREADY_CHARGED_BACK",
+            ]
+        );
+        let unique: HashSet<&str> = codes.iter().copied().collect();
+        assert_eq!(unique.len(), codes.len(), "Error codes must be unique");
+    }
+
+    #[test]
+    fn test_error_serialization() {
+        let json = serde_json::to_value(Transaction::NotFound(7, 3)).expect("Failed to serialize");
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "code": "E_TX_NOT_FOUND",
+                "message": "Transaction ID 7 not found for client 3",
+                "tx": 7,
+                "client": 3,
+            })
+        );
+
+        let json =
+            serde_json::to_value(Transaction::InsufficientFunds(3)).expect("Failed to serialize");
+        assert_eq!(json["code"], "E_INSUFFICIENT_FUNDS");
+        assert_eq!(json["tx"], serde_json::Value::Null);
+        assert_eq!(json["client"], 3);
+    }
 }
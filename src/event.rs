@@ -0,0 +1,56 @@
+use crate::account::{Account, LockReason};
+use crate::error::Transaction as TransactionError;
+use crate::transaction::{Amount, Transaction};
+
+/// Notification emitted by the engine after each processed transaction, then
+/// for the changes of the lifecycle of its account.
+///
+/// `before` is `None` when the account did not exist prior to the transaction.
+#[derive(Debug)]
+pub enum Event<'a> {
+    Applied {
+        transaction: &'a Transaction,
+        before: Option<&'a Account>,
+        after: &'a Account,
+    },
+    Rejected {
+        transaction: &'a Transaction,
+        before: Option<&'a Account>,
+        error: &'a TransactionError,
+    },
+    /// The account of `client` was created by the row `tx`, whether the row
+    /// was then applied or rejected.
+    AccountOpened { client: u16, tx: u32 },
+    /// The account of `client` was locked by the row `tx`, or by
+    /// [`Engine::freeze`] when it is `None`. `charged_back` is the amount the
+    /// row charged back, zero if it was not a chargeback.
+    ///
+    /// [`Engine::freeze`]: crate::engine::Engine::freeze
+    AccountLocked {
+        client: u16,
+        tx: Option<u32>,
+        reason: LockReason,
+        charged_back: Amount,
+    },
+    /// The lock of the account of `client` was lifted by [`Engine::unfreeze`].
+    ///
+    /// [`Engine::unfreeze`]: crate::engine::Engine::unfreeze
+    AccountUnlocked { client: u16 },
+}
+
+/// Observer registered on the engine with [`Engine::set_event_hook`].
+///
+/// [`Engine::set_event_hook`]: crate::engine::Engine::set_event_hook
+pub trait EventHook {
+    fn on_event(&mut self, event: &Event<'_>);
+}
+
+/// Several hooks notified of each event in turn, for the engine only takes
+/// one.
+impl EventHook for Vec<Box<dyn EventHook>> {
+    fn on_event(&mut self, event: &Event<'_>) {
+        for hook in self {
+            hook.on_event(event);
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! Synthetic input files for fixtures, benchmarks and soak tests.
+//!
+//! [`write_csv`] streams rows in the input format. Dispute rows only
+//! reference earlier deposits of the same client that are not under dispute,
+//! and resolve and chargeback rows only reference open disputes, so replaying
+//! a generated file never fails on a reference. Once a deposit is charged
+//! back, its client is locked and no longer referenced, nor drawn for later
+//! deposits and withdrawals. Chargebacks stop before more than half of the
+//! clients are locked, so most rows still apply. The same [`Spec`] always produces the
+//! same bytes.
+
+use std::collections::HashSet;
+use std::io::Write;
+
+use crate::transaction::Amount;
+
+/// Shape of a generated file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spec {
+    /// Number of rows, which are also the transaction ids `1..=rows`.
+    pub rows: u32,
+    /// Number of clients, with ids `1..=clients`.
+    pub clients: u16,
+    /// Share of the rows that open a dispute. About as many rows settle one.
+    pub dispute_rate: f64,
+    pub seed: u64,
+}
+
+impl Default for Spec {
+    fn default() -> Self {
+        Spec {
+            rows: 1_000,
+            clients: 10,
+            dispute_rate: 0.01,
+            seed: 0,
+        }
+    }
+}
+
+/// Write the rows described by `spec` as CSV, header included.
+///
+/// # Errors
+///
+/// Returns an error if writing to the underlying writer fails.
+pub fn write_csv<W: Write>(writer: W, spec: &Spec) -> csv::Result<()> {
+    let mut wtr = csv::Writer::from_writer(writer);
+    wtr.write_record(["type", "client", "tx", "amount"])?;
+
+    let mut rng = Lcg(spec.seed);
+    let clients = usize::from(spec.clients.max(1));
+    // Share of the rows in millionths, compared with `Lcg::below(1_000_000)`
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let rate = (spec.dispute_rate.clamp(0.0, 1.0) * 1_000_000.0) as u32;
+    // Deposits as (tx, client) that can be disputed, and the open disputes
+    let mut undisputed: Vec<(u32, u16)> = Vec::new();
+    let mut disputed: Vec<(u32, u16)> = Vec::new();
+    let mut locked: HashSet<u16> = HashSet::new();
+    // Clients that deposits and withdrawals are drawn from
+    let mut unlocked: Vec<u16> = (1..=spec.clients.max(1)).collect();
+
+    for tx in 1..=spec.rows {
+        let roll = rng.below(1_000_000);
+        if roll < rate {
+            if let Some((target, client)) = pick(&mut rng, &mut undisputed, &locked) {
+                wtr.write_record(["dispute", &client.to_string(), &target.to_string(), ""])?;
+                disputed.push((target, client));
+                continue;
+            }
+        } else if roll < rate.saturating_mul(2) {
+            if let Some((target, client)) = pick(&mut rng, &mut disputed, &locked) {
+                // One settled dispute in five is charged back, as long as
+                // at least half of the clients stay unlocked
+                let t_type = if rng.below(5) == 0 && (unlocked.len() - 1) * 2 >= clients {
+                    locked.insert(client);
+                    unlocked.retain(|&other| other != client);
+                    "chargeback"
+                } else {
+                    undisputed.push((target, client));
+                    "resolve"
+                };
+                wtr.write_record([t_type, &client.to_string(), &target.to_string(), ""])?;
+                continue;
+            }
+        }
+
+        let client =
+            unlocked[rng.below(u32::try_from(unlocked.len()).unwrap_or(u32::MAX)) as usize];
+        // Positive amounts with up to four decimal places, below 1 000
+        let amount = Amount::from(rng.below(9_999_999) + 1) / 10_000.0;
+        let t_type = if rng.below(5) < 3 {
+            undisputed.push((tx, client));
+            "deposit"
+        } else {
+            "withdrawal"
+        };
+        wtr.write_record([
+            t_type,
+            &client.to_string(),
+            &tx.to_string(),
+            &amount.to_string(),
+        ])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+// Remove and return a random entry of `pool` whose client is not locked,
+// dropping the entries of locked clients met along the way
+fn pick(rng: &mut Lcg, pool: &mut Vec<(u32, u16)>, locked: &HashSet<u16>) -> Option<(u32, u16)> {
+    while !pool.is_empty() {
+        let index = rng.below(u32::try_from(pool.len()).unwrap_or(u32::MAX)) as usize;
+        let entry = pool.swap_remove(index);
+        if !locked.contains(&entry.1) {
+            return Some(entry);
+        }
+    }
+    None
+}
+
+// Linear congruential generator: reproducible without a `rand` dependency
+pub(crate) struct Lcg(pub(crate) u64);
+
+impl Lcg {
+    // The shift keeps the 31 high bits, which fit in a `u32`
+    #[allow(clippy::cast_possible_truncation)]
+    pub(crate) fn below(&mut self, bound: u32) -> u32 {
+        self.0 = self
+            .0
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        (self.0 >> 33) as u32 % bound
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::transaction::Transaction;
+
+    fn generate(spec: &Spec) -> Vec<u8> {
+        let mut output = Vec::new();
+        write_csv(&mut output, spec).expect("Failed to generate rows");
+        output
+    }
+
+    #[test]
+    fn test_generate_is_deterministic() {
+        let spec = Spec {
+            rows: 2_000,
+            seed: 42,
+            ..Spec::default()
+        };
+        let first = generate(&spec);
+        assert_eq!(first, generate(&spec));
+        let lines = std::str::from_utf8(&first)
+            .expect("Output not valid UTF-8")
+            .lines();
+        assert_eq!(lines.count(), 2_001);
+        let other = generate(&Spec { seed: 43, ..spec });
+        assert_ne!(first, other);
+    }
+
+    // Test that replaying a generated file never fails on a reference
+    #[test]
+    fn test_generated_references_are_consistent() {
+        let spec = Spec {
+            rows: 20_000,
+            clients: 1_000,
+            dispute_rate: 0.05,
+            seed: 7,
+        };
+        let output = generate(&spec);
+        let mut engine = Engine::new();
+        let mut references = 0;
+        for transaction in csv::Reader::from_reader(output.as_slice()).deserialize() {
+            let transaction: Transaction = transaction.expect("Failed to parse generated row");
+            let is_reference = transaction.amount.is_none();
+            references += usize::from(is_reference);
+            if let Err(e) = engine.process_transaction(transaction) {
+                assert!(
+                    !is_reference,
+                    "Reference row rejected with {}: {e}",
+                    e.code()
+                );
+            }
+        }
+        assert!(
+            references > 1_000,
+            "Only {references} reference rows generated"
+        );
+    }
+
+    // Test that chargebacks do not leave most of the rows rejected
+    #[test]
+    fn test_most_generated_rows_apply() {
+        let spec = Spec {
+            rows: 200_000,
+            clients: 50,
+            dispute_rate: 0.01,
+            seed: 3,
+        };
+        let output = generate(&spec);
+        let mut engine = Engine::new();
+        let mut applied = 0;
+        for transaction in csv::Reader::from_reader(output.as_slice()).deserialize() {
+            let transaction: Transaction = transaction.expect("Failed to parse generated row");
+            applied += usize::from(engine.process_transaction(transaction).is_ok());
+        }
+        assert!(applied > 190_000, "Only {applied} rows applied");
+        let locked = engine
+            .all_account_views()
+            .iter()
+            .filter(|view| view.locked)
+            .count();
+        assert!(locked > 0 && locked <= 25, "{locked} clients locked");
+    }
+}
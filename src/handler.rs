@@ -0,0 +1,60 @@
+use crate::engine::{Engine, TransactionMap};
+use crate::error::Transaction as TransactionError;
+use crate::store::TransactionStore;
+use crate::transaction::Transaction;
+
+/// Applies the transactions of one type, registered on the engine with
+/// [`Engine::register_handler`].
+///
+/// The engine calls the handler once the account of the client exists and is
+/// not locked, so handlers only implement what their type does. The built-in
+/// types are handled this way too, and registering a handler for one of them
+/// replaces its built-in processing.
+///
+/// Closures taking the engine and the transaction implement this trait. A
+/// custom handler moves funds with [`Engine::adjust`]:
+///
+/// ```
+/// use process_tx::engine::Engine;
+/// use process_tx::transaction::{Transaction, Type};
+///
+/// let mut engine = Engine::new();
+/// engine.register_handler(
+///     Type::Custom("interest".to_string()),
+///     |engine: &mut Engine, transaction: &Transaction| {
+///         engine.adjust(transaction.client, transaction.amount.unwrap_or(0.0))
+///     },
+/// );
+/// engine.process_transaction(Transaction::deposit(1, 1, 100.0))?;
+/// let interest = Type::Custom("interest".to_string());
+/// engine.process_transaction(Transaction::new(interest, 1, 2, Some(2.5)))?;
+/// assert_eq!(engine.account(1).expect("Account not found").total, 102.5);
+/// # Ok::<(), process_tx::error::Transaction>(())
+/// ```
+pub trait TransactionHandler<S: TransactionStore = TransactionMap> {
+    /// Apply `transaction` to `engine`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error the transaction is rejected with. The handler must
+    /// then leave the engine unchanged.
+    fn apply(
+        &self,
+        engine: &mut Engine<S>,
+        transaction: &Transaction,
+    ) -> Result<(), TransactionError>;
+}
+
+impl<S, F> TransactionHandler<S> for F
+where
+    S: TransactionStore,
+    F: Fn(&mut Engine<S>, &Transaction) -> Result<(), TransactionError>,
+{
+    fn apply(
+        &self,
+        engine: &mut Engine<S>,
+        transaction: &Transaction,
+    ) -> Result<(), TransactionError> {
+        self(engine, transaction)
+    }
+}
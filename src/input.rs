@@ -0,0 +1,697 @@
+//! Columns of an input file, detected from its header.
+//!
+//! Amounts come either as decimals in the `amount` column, or as integer
+//! minor units in the `amount_minor` column, where `12345` stands for
+//! `1.2345`. A file with both columns is refused, since the two could
+//! disagree. With [`Schema::strict_amounts`], a deposit or withdrawal
+//! without a finite amount fails to parse rather than reaching the engine.
+//!
+//! Decimal amounts follow the grammar of [`parse_amount`]: an optional sign,
+//! digits and an optional fraction, with an exponent only when
+//! [`Schema::allow_exponent`] is set.
+//!
+//! A [`Cursor`] marks how far a file was processed, so that a later run can
+//! continue it, and a [`Provenance`] where a transaction was read.
+
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::Arc;
+
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{AmountSyntax, Input as InputError};
+use crate::transaction::{Amount, Transaction, Type, COLUMNS};
+use crate::wal::crc32_update;
+
+/// Header of the integer minor units column.
+pub const AMOUNT_MINOR: &str = "amount_minor";
+
+/// Minor units in one unit of amount, matching the four decimal places of
+/// the accounts report.
+pub const MINOR_UNITS_PER_UNIT: u32 = 10_000;
+
+/// How the records of an input file map to transactions.
+#[derive(Debug, Clone)]
+pub struct Schema {
+    headers: csv::StringRecord,
+    // Columns captured into `Transaction::extra`
+    extra_columns: Vec<usize>,
+    // Position of the `amount_minor` column, if amounts are read from it
+    minor_column: Option<usize>,
+    // Whether deposits and withdrawals need a finite amount to parse
+    strict_amounts: bool,
+    // Position of the `amount` column, checked for exponents unless allowed
+    amount_column: Option<usize>,
+    allow_exponent: bool,
+}
+
+// Column read on top of the `Transaction` fields for minor units,
+// deserialized only to report an invalid value like any parse error
+#[derive(Deserialize)]
+struct MinorAmount {
+    amount_minor: Option<u64>,
+}
+
+// Amount columns of a record refused by strict parsing, deserialized only to
+// report the refused amount like any parse error
+#[derive(Deserialize)]
+#[serde(try_from = "AmountColumns")]
+struct StrictAmount;
+
+#[derive(Deserialize)]
+struct AmountColumns {
+    #[serde(rename = "type")]
+    t_type: Type,
+    amount: Option<Amount>,
+    amount_minor: Option<u64>,
+}
+
+impl TryFrom<AmountColumns> for StrictAmount {
+    type Error = String;
+
+    fn try_from(columns: AmountColumns) -> Result<Self, Self::Error> {
+        if !matches!(columns.t_type, Type::Deposit | Type::Withdrawal) {
+            return Ok(StrictAmount);
+        }
+        let t_type = columns.t_type.as_str();
+        match (columns.amount, columns.amount_minor) {
+            (Some(amount), _) if !amount.is_finite() => {
+                Err(format!("{t_type} amount {amount} is not a finite number"))
+            }
+            (None, None) => Err(format!("{t_type} has no amount")),
+            _ => Ok(StrictAmount),
+        }
+    }
+}
+
+// Amount column of a record written with an exponent, deserialized only to
+// report it like any parse error when exponents are not allowed
+#[derive(Deserialize)]
+#[serde(try_from = "AmountText")]
+struct PlainAmount;
+
+#[derive(Deserialize)]
+struct AmountText {
+    amount: Option<String>,
+}
+
+impl TryFrom<AmountText> for PlainAmount {
+    type Error = AmountSyntax;
+
+    fn try_from(text: AmountText) -> Result<Self, Self::Error> {
+        match text.amount {
+            Some(amount) => parse_amount(&amount, false).map(|_| PlainAmount),
+            None => Ok(PlainAmount),
+        }
+    }
+}
+
+impl Schema {
+    /// Detect the schema of a file from its `headers`. With `capture_extra`,
+    /// the values of the columns the schema does not read are kept in
+    /// [`Transaction::extra`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the header has both an `amount` and an
+    /// `amount_minor` column.
+    pub fn detect(headers: csv::StringRecord, capture_extra: bool) -> Result<Self, InputError> {
+        let position = |name: &str| headers.iter().position(|header| header == name);
+        let minor_column = position(AMOUNT_MINOR);
+        let amount_column = position("amount");
+        if minor_column.is_some() && amount_column.is_some() {
+            return Err(InputError::ConflictingAmountColumns);
+        }
+        let extra_columns = if capture_extra {
+            (0..headers.len())
+                .filter(|&i| !COLUMNS.contains(&&headers[i]) && &headers[i] != AMOUNT_MINOR)
+                .collect()
+        } else {
+            Vec::new()
+        };
+        Ok(Schema {
+            amount_column,
+            headers,
+            extra_columns,
+            minor_column,
+            strict_amounts: false,
+            allow_exponent: false,
+        })
+    }
+
+    /// Refuse deposits and withdrawals whose amount is empty, infinite or
+    /// not a number when parsing them, instead of leaving the engine to
+    /// reject them.
+    #[must_use]
+    pub fn strict_amounts(mut self, strict: bool) -> Self {
+        self.strict_amounts = strict;
+        self
+    }
+
+    /// Accept decimal amounts written with an exponent, such as `1.5e3`,
+    /// which are refused by default.
+    #[must_use]
+    pub fn allow_exponent(mut self, allow: bool) -> Self {
+        self.allow_exponent = allow;
+        self
+    }
+
+    #[must_use]
+    pub fn headers(&self) -> &csv::StringRecord {
+        &self.headers
+    }
+
+    /// Names of the columns captured into [`Transaction::extra`], in file
+    /// order.
+    pub fn extra_names(&self) -> impl Iterator<Item = &str> {
+        self.extra_columns.iter().map(|&i| &self.headers[i])
+    }
+
+    /// Whether amounts are read as integer minor units.
+    #[must_use]
+    pub fn minor_units(&self) -> bool {
+        self.minor_column.is_some()
+    }
+
+    /// Parse a record of the file into a transaction.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a field cannot be parsed, such as a negative or
+    /// fractional `amount_minor`, an `amount` outside the grammar of
+    /// [`parse_amount`], or if strict amounts are refused.
+    pub fn read(&self, record: &csv::StringRecord) -> csv::Result<Transaction> {
+        let mut transaction: Transaction = record.deserialize(Some(&self.headers))?;
+        if let Some(i) = self.minor_column {
+            transaction.amount = self.minor_amount(record, i)?;
+        }
+        // The record is only deserialized again for a refused amount, to
+        // report it with its position like any parse error
+        let amount = self.amount_column.and_then(|i| record.get(i));
+        if !self.allow_exponent && amount.is_some_and(|amount| amount.contains(['e', 'E'])) {
+            record.deserialize::<PlainAmount>(Some(&self.headers))?;
+        }
+        let refused = matches!(transaction.t_type, Type::Deposit | Type::Withdrawal)
+            && !transaction.amount.is_some_and(Amount::is_finite);
+        if self.strict_amounts && refused {
+            record.deserialize::<StrictAmount>(Some(&self.headers))?;
+        }
+        transaction.extra = self
+            .extra_columns
+            .iter()
+            .filter_map(|&i| Some((self.headers[i].to_string(), record.get(i)?.to_string())))
+            .collect();
+        Ok(transaction)
+    }
+
+    // Amount of the `amount_minor` column at `i` of `record`
+    fn minor_amount(&self, record: &csv::StringRecord, i: usize) -> csv::Result<Option<Amount>> {
+        match record.get(i).unwrap_or_default() {
+            "" => Ok(None),
+            field => match field.parse() {
+                Ok(units) => Ok(Some(from_minor_units(units))),
+                Err(_) => record
+                    .deserialize::<MinorAmount>(Some(&self.headers))
+                    .map(|minor| minor.amount_minor.map(from_minor_units)),
+            },
+        }
+    }
+
+    /// Parse a record of `file` into a transaction, as with
+    /// [`Schema::read`], recording its [`Provenance`] on it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a field cannot be parsed.
+    pub fn read_from(
+        &self,
+        file: &Arc<str>,
+        record: &csv::StringRecord,
+    ) -> csv::Result<Transaction> {
+        let mut transaction = self.read(record)?;
+        transaction.provenance = record
+            .position()
+            .map(|position| Provenance::at(file, position));
+        Ok(transaction)
+    }
+}
+
+/// Input file and line a transaction was read from, carried with it to the
+/// rejects file and the diagnostics. The file name is shared by every row of
+/// the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Provenance {
+    pub file: Arc<str>,
+    pub line: u64,
+}
+
+impl Provenance {
+    /// Provenance of the record of `file` at `position`.
+    #[must_use]
+    pub fn at(file: &Arc<str>, position: &csv::Position) -> Self {
+        Provenance {
+            file: Arc::clone(file),
+            line: position.line(),
+        }
+    }
+}
+
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/// Position of the next record of an input file, saved in snapshots so that
+/// processing can resume there.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cursor {
+    /// Header of the file, which a resumed reader does not read again.
+    pub headers: Vec<String>,
+    /// Offset of the next record, in bytes from the start of the file.
+    pub byte: u64,
+    pub line: u64,
+    pub record: u64,
+    /// CRC-32 of the bytes before `byte`, to detect a file that changed.
+    pub checksum: u32,
+}
+
+impl Cursor {
+    /// Cursor at `position` of a file with `headers`, whose bytes are read
+    /// from `file` to compute the checksum.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading `file` fails or it ends before `position`.
+    pub fn new<R: Read>(
+        headers: &csv::StringRecord,
+        position: &csv::Position,
+        file: R,
+    ) -> io::Result<Self> {
+        let (checksum, len) = checksum(file.take(position.byte()))?;
+        if len != position.byte() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(Cursor {
+            headers: headers.iter().map(str::to_string).collect(),
+            byte: position.byte(),
+            line: position.line(),
+            record: position.record(),
+            checksum,
+        })
+    }
+
+    /// Cursor at `position`, further in the file `self` was taken from,
+    /// whose checksum is extended with the bytes in between, read from
+    /// `file`. Saving a cursor every so often thus reads the file once.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading `file` fails or it ends before `position`.
+    pub fn advance<R: Read + Seek>(
+        &self,
+        position: &csv::Position,
+        mut file: R,
+    ) -> io::Result<Self> {
+        let Some(len) = position.byte().checked_sub(self.byte) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "position before the cursor",
+            ));
+        };
+        file.seek(SeekFrom::Start(self.byte))?;
+        let mut crc = self.checksum;
+        let mut rest = file.take(len);
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            match rest.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => crc = crc32_update(crc, &buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if rest.limit() != 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(Cursor {
+            headers: self.headers.clone(),
+            byte: position.byte(),
+            line: position.line(),
+            record: position.record(),
+            checksum: crc,
+        })
+    }
+
+    #[must_use]
+    pub fn headers(&self) -> csv::StringRecord {
+        csv::StringRecord::from(self.headers.clone())
+    }
+
+    /// Open a reader on `file` that continues at the cursor, after checking
+    /// that the bytes before it are unchanged. Records are read with the
+    /// saved header.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`InputError::Changed`] if the file is shorter than the cursor
+    /// or its bytes before the cursor differ, or an error if reading it fails.
+    pub fn resume<R: Read + Seek>(&self, mut file: R) -> Result<csv::Reader<R>, InputError> {
+        let (checksum, len) = checksum((&mut file).take(self.byte))?;
+        if (checksum, len) != (self.checksum, self.byte) {
+            return Err(InputError::Changed(self.byte));
+        }
+        file.rewind()?;
+        let mut rdr = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(file);
+        let mut position = csv::Position::new();
+        position
+            .set_byte(self.byte)
+            .set_line(self.line)
+            .set_record(self.record);
+        rdr.seek(position)?;
+        Ok(rdr)
+    }
+}
+
+// CRC-32 and length of everything read from `reader`
+fn checksum<R: Read>(mut reader: R) -> io::Result<(u32, u64)> {
+    let mut buf = vec![0; 64 * 1024];
+    let (mut crc, mut len) = (0, 0);
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok((crc, len)),
+            Ok(n) => {
+                crc = crc32_update(crc, &buf[..n]);
+                len += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Parse the text of a decimal amount.
+///
+/// An amount is an optional `+` or `-` sign, digits, and an optional fraction
+/// of a `.` followed by digits, such as `100`, `+50.5` or `-0.25`. With
+/// `allow_exponent`, it may end with `e` or `E`, an optional sign and digits,
+/// as in `1.5e3`. Surrounding whitespace, a missing integer part or fraction
+/// as in `.5` or `5.`, and any other character are refused. The spellings of
+/// infinity and NaN are also read, so that strict amounts or the engine can
+/// refuse them with their own error.
+///
+/// # Errors
+///
+/// Returns an error quoting `text` if it does not follow the grammar.
+///
+/// # Examples
+///
+/// ```
+/// use process_tx::input::parse_amount;
+///
+/// assert_eq!(parse_amount("+50.5", false), Ok(50.5));
+/// assert!(parse_amount("1e3", false).is_err());
+/// assert_eq!(parse_amount("1e3", true), Ok(1000.0));
+/// ```
+pub fn parse_amount(text: &str, allow_exponent: bool) -> Result<Amount, AmountSyntax> {
+    let invalid = || AmountSyntax::Invalid(text.to_string());
+    let unsigned = text.strip_prefix(['+', '-']).unwrap_or(text);
+    if ["inf", "infinity", "nan"]
+        .iter()
+        .any(|name| unsigned.eq_ignore_ascii_case(name))
+    {
+        return text.parse().map_err(|_| invalid());
+    }
+    let digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+    let (mantissa, exponent) = match unsigned.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, Some(exponent)),
+        None => (unsigned, None),
+    };
+    let (integer, fraction) = match mantissa.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (mantissa, None),
+    };
+    if !digits(integer) || fraction.is_some_and(|fraction| !digits(fraction)) {
+        return Err(invalid());
+    }
+    if let Some(exponent) = exponent {
+        if !digits(exponent.strip_prefix(['+', '-']).unwrap_or(exponent)) {
+            return Err(invalid());
+        }
+        if !allow_exponent {
+            return Err(AmountSyntax::Exponent(text.to_string()));
+        }
+    }
+    text.parse().map_err(|_| invalid())
+}
+
+// Deserialize an optional amount with `parse_amount`, exponents allowed:
+// `Schema::read` refuses them unless its schema allows them
+pub(crate) fn deserialize_amount<'de, D>(deserializer: D) -> Result<Option<Amount>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct AmountVisitor;
+
+    impl<'de> Visitor<'de> for AmountVisitor {
+        type Value = Option<Amount>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("an amount")
+        }
+
+        fn visit_none<E>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_some<D: Deserializer<'de>>(
+            self,
+            deserializer: D,
+        ) -> Result<Self::Value, D::Error> {
+            deserializer.deserialize_str(self)
+        }
+
+        fn visit_str<E: de::Error>(self, text: &str) -> Result<Self::Value, E> {
+            parse_amount(text, true).map(Some).map_err(E::custom)
+        }
+    }
+
+    deserializer.deserialize_option(AmountVisitor)
+}
+
+// Amounts above 2^53 minor units lose precision, far beyond any balance the
+// report can show exactly
+#[allow(clippy::cast_precision_loss)]
+fn from_minor_units(minor: u64) -> Amount {
+    minor as Amount / Amount::from(MINOR_UNITS_PER_UNIT)
+}
+
+#[cfg(test)]
+#[allow(clippy::float_cmp)]
+mod tests {
+    use super::*;
+
+    fn schema(header: &[&str]) -> Result<Schema, InputError> {
+        Schema::detect(csv::StringRecord::from(header.to_vec()), true)
+    }
+
+    #[test]
+    fn test_read_minor_units() {
+        let schema =
+            schema(&["type", "client", "tx", "amount_minor", "memo"]).expect("Invalid schema");
+        assert!(schema.minor_units());
+        assert_eq!(schema.extra_names().collect::<Vec<_>>(), ["memo"]);
+
+        let read = |fields: &[&str]| schema.read(&csv::StringRecord::from(fields.to_vec()));
+        let deposit = read(&["deposit", "1", "1", "12345", "salary"]).expect("Failed to read");
+        assert_eq!(deposit.amount, Some(1.2345));
+        assert_eq!(deposit.extra["memo"], "salary");
+        let dispute = read(&["dispute", "1", "1", "", ""]).expect("Failed to read");
+        assert_eq!(dispute.amount, None);
+        assert!(read(&["deposit", "1", "2", "-5", ""]).is_err());
+        assert!(read(&["deposit", "1", "2", "1.5", ""]).is_err());
+    }
+
+    #[test]
+    fn test_read_from_records_provenance() {
+        let schema = schema(&["type", "client", "tx", "amount"]).expect("Invalid schema");
+        let mut rdr = csv::Reader::from_reader(INPUT.as_bytes());
+        let file: Arc<str> = Arc::from("day1.csv");
+        let lines: Vec<String> = rdr
+            .records()
+            .map(|record| {
+                let record = record.expect("Failed to read record");
+                let transaction = schema.read_from(&file, &record).expect("Failed to parse");
+                transaction.provenance.expect("No provenance").to_string()
+            })
+            .collect();
+        assert_eq!(lines, ["day1.csv:2", "day1.csv:3", "day1.csv:4"]);
+    }
+
+    #[test]
+    fn test_strict_amounts() {
+        let lenient = schema(&["type", "client", "tx", "amount"]).expect("Invalid schema");
+        let strict = lenient.clone().strict_amounts(true);
+        let read = |schema: &Schema, fields: &[&str]| {
+            schema.read(&csv::StringRecord::from(fields.to_vec()))
+        };
+
+        for amount in ["inf", "-inf", "NaN", ""] {
+            let fields = ["deposit", "1", "1", amount];
+            assert!(read(&lenient, &fields).is_ok(), "{amount}");
+            let error = read(&strict, &fields).expect_err("Strict amount accepted");
+            assert!(error.to_string().contains("deposit"), "{error}");
+        }
+        assert!(read(&strict, &["withdrawal", "1", "2", "inf"]).is_err());
+        let deposit = read(&strict, &["deposit", "1", "3", "2.5"]).expect("Failed to read");
+        assert_eq!(deposit.amount, Some(2.5));
+        // Only deposits and withdrawals carry an amount
+        assert!(read(&strict, &["dispute", "1", "3", ""]).is_ok());
+
+        let minor = schema(&["type", "client", "tx", "amount_minor"])
+            .expect("Invalid schema")
+            .strict_amounts(true);
+        assert!(read(&minor, &["deposit", "1", "1", ""]).is_err());
+        assert!(read(&minor, &["deposit", "1", "1", "25000"]).is_ok());
+    }
+
+    #[test]
+    fn test_parse_amount_grammar() {
+        for (text, amount) in [
+            ("100", 100.0),
+            ("+50.5", 50.5),
+            ("-0.25", -0.25),
+            ("007", 7.0),
+            ("1.23456789", 1.234_567_89),
+        ] {
+            assert_eq!(parse_amount(text, false), Ok(amount), "{text}");
+        }
+        for text in [
+            "", ".5", "5.", "+", "-", "+-1", " 1.0 ", "1.0 ", "1,5", "1.2.3", "0x10", "1_000",
+            "e3", "1e", "1e+", "1.e3",
+        ] {
+            assert_eq!(
+                parse_amount(text, true),
+                Err(AmountSyntax::Invalid(text.to_string())),
+                "{text}"
+            );
+        }
+        for (text, amount) in [("1e3", 1000.0), ("-2.5E-1", -0.25), ("+1e+2", 100.0)] {
+            assert_eq!(
+                parse_amount(text, false),
+                Err(AmountSyntax::Exponent(text.to_string()))
+            );
+            assert_eq!(parse_amount(text, true), Ok(amount), "{text}");
+        }
+        // Non-finite amounts are left to strict amounts and the engine
+        assert!(parse_amount("-inf", false).is_ok_and(f64::is_infinite));
+        assert!(parse_amount("NaN", false).is_ok_and(f64::is_nan));
+
+        let error = parse_amount(".5", false).expect_err("Amount accepted");
+        assert!(error.to_string().contains("'.5'"), "{error}");
+        assert!(error.to_string().contains("such as 100, +50.5"), "{error}");
+    }
+
+    #[test]
+    fn test_allow_exponent() {
+        let schema = schema(&["type", "client", "tx", "amount"]).expect("Invalid schema");
+        let read = |schema: &Schema, fields: &[&str]| {
+            schema.read(&csv::StringRecord::from(fields.to_vec()))
+        };
+
+        let error = read(&schema, &["deposit", "1", "1", "1e3"]).expect_err("Exponent accepted");
+        assert!(
+            error.to_string().contains("exponents are not allowed"),
+            "{error}"
+        );
+        let deposit = read(&schema, &["deposit", "1", "1", "+50.5"]).expect("Failed to read");
+        assert_eq!(deposit.amount, Some(50.5));
+        let error = read(&schema, &["deposit", "1", "1", "5."]).expect_err("Amount accepted");
+        assert!(error.to_string().contains("Invalid amount '5.'"), "{error}");
+
+        let schema = schema.allow_exponent(true);
+        let deposit = read(&schema, &["deposit", "1", "1", "1e3"]).expect("Failed to read");
+        assert_eq!(deposit.amount, Some(1000.0));
+        assert!(read(&schema, &["deposit", "1", "1", "5."]).is_err());
+    }
+
+    #[test]
+    fn test_conflicting_amount_columns() {
+        assert!(matches!(
+            schema(&["type", "client", "tx", "amount", "amount_minor"]),
+            Err(InputError::ConflictingAmountColumns)
+        ));
+        let schema = schema(&["type", "client", "tx", "amount"]).expect("Invalid schema");
+        assert!(!schema.minor_units());
+    }
+
+    const INPUT: &str = "type,client,tx,amount\n\
+                         deposit,1,1,10.0\n\
+                         deposit,2,2,5.0\n\
+                         withdrawal,1,3,4.0\n";
+
+    // Cursor after the first `records` records of `INPUT`
+    fn cursor_after(records: usize) -> Cursor {
+        let mut rdr = csv::Reader::from_reader(INPUT.as_bytes());
+        let headers = rdr.headers().expect("Failed to read header").clone();
+        let mut record = csv::StringRecord::new();
+        for _ in 0..records {
+            rdr.read_record(&mut record).expect("Failed to read record");
+        }
+        Cursor::new(&headers, rdr.position(), INPUT.as_bytes()).expect("Failed to checksum")
+    }
+
+    #[test]
+    fn test_resume_cursor() {
+        let cursor = cursor_after(1);
+        assert_eq!(cursor.headers(), vec!["type", "client", "tx", "amount"]);
+        let file = io::Cursor::new(format!("{INPUT}deposit,3,4,1.0\n"));
+        let mut rdr = cursor.resume(file).expect("Failed to resume");
+        let schema = Schema::detect(cursor.headers(), false).expect("Invalid schema");
+        let mut lines = Vec::new();
+        for record in rdr.records() {
+            let record = record.expect("Failed to read record");
+            let transaction = schema.read(&record).expect("Failed to parse");
+            lines.push((record.position().map(csv::Position::line), transaction.tx));
+        }
+        assert_eq!(lines, [(Some(3), 2), (Some(4), 3), (Some(5), 4)]);
+    }
+
+    #[test]
+    fn test_resume_changed_file() {
+        let cursor = cursor_after(2);
+        let changed = INPUT.replace("5.0", "6.0");
+        assert!(matches!(
+            cursor.resume(io::Cursor::new(changed)),
+            Err(InputError::Changed(_))
+        ));
+        let truncated = &INPUT[..20];
+        assert!(matches!(
+            cursor.resume(io::Cursor::new(truncated)),
+            Err(InputError::Changed(_))
+        ));
+        // Changes after the cursor are the rest of the input
+        let appended = INPUT.replace("4.0", "7.0");
+        assert!(cursor.resume(io::Cursor::new(appended)).is_ok());
+    }
+
+    // Test that advancing a cursor gives the cursor taken at the new position
+    #[test]
+    fn test_advance_cursor() {
+        let mut rdr = csv::Reader::from_reader(INPUT.as_bytes());
+        let mut record = csv::StringRecord::new();
+        for _ in 0..3 {
+            rdr.read_record(&mut record).expect("Failed to read record");
+        }
+        let advanced = cursor_after(1)
+            .advance(rdr.position(), io::Cursor::new(INPUT))
+            .expect("Failed to advance");
+        assert_eq!(advanced, cursor_after(3));
+        assert!(cursor_after(3)
+            .advance(&csv::Position::new(), io::Cursor::new(INPUT))
+            .is_err());
+    }
+}
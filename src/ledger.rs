@@ -0,0 +1,245 @@
+//! Double-entry ledger of the balance movements applied by the engine.
+//!
+//! Every applied transaction produces entries that net to zero: movements
+//! between the `available`, `held` and `pending` funds of an account balance
+//! each other, and funds entering or leaving an account (deposits,
+//! withdrawals, chargebacks) are balanced by an `external` counter-entry.
+//! Fees charged by the fee schedule are balanced by a `fee` entry instead,
+//! and credited to the fee account, if any, against a `fee` entry too.
+//! Summing the entries of a client per bucket gives its available, held and
+//! pending funds.
+
+use std::io::{BufWriter, Write};
+
+use csv::WriterBuilder;
+
+use crate::account::Account;
+use crate::transaction::Amount;
+
+/// Side of a ledger entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bucket {
+    /// Available funds of the client's account.
+    Available,
+    /// Funds of the client's account held by open disputes.
+    Held,
+    /// Deposits of the client's account waiting for a settle row.
+    Pending,
+    /// Counterpart outside the engine: deposited, withdrawn or charged back funds.
+    External,
+    /// Counterpart of fees, charged to the paying account and credited to
+    /// the fee account.
+    Fee,
+}
+
+impl Bucket {
+    /// Name of the bucket as written in the `bucket` column.
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Bucket::Available => "available",
+            Bucket::Held => "held",
+            Bucket::Pending => "pending",
+            Bucket::External => "external",
+            Bucket::Fee => "fee",
+        }
+    }
+}
+
+/// Change of one bucket caused by an applied transaction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub tx: u32,
+    pub client: u16,
+    pub bucket: Bucket,
+    pub delta: Amount,
+}
+
+/// Entries moving an account from `before` to `after`, balanced by an
+/// `external` entry when the total changed. Unchanged buckets get no entry.
+#[must_use]
+pub fn entries(tx: u32, before: Option<&Account>, after: &Account) -> Vec<Entry> {
+    entries_with_fee(tx, before, after, 0.0)
+}
+
+/// Like [`entries`], for a transaction that also charged the account `fee`,
+/// balanced by a `fee` entry rather than the `external` one.
+#[must_use]
+pub fn entries_with_fee(
+    tx: u32,
+    before: Option<&Account>,
+    after: &Account,
+    fee: Amount,
+) -> Vec<Entry> {
+    let (available, held, pending) = before.map_or((0.0, 0.0, 0.0), |account| {
+        (account.available, account.held, account.pending)
+    });
+    let available = after.available - available;
+    let held = after.held - held;
+    let pending = after.pending - pending;
+    let external = -(available + held + pending) - fee;
+
+    [
+        (Bucket::Available, available),
+        (Bucket::Held, held),
+        (Bucket::Pending, pending),
+        (Bucket::External, external),
+        (Bucket::Fee, fee),
+    ]
+    .into_iter()
+    .filter(|&(_, delta)| delta != 0.0)
+    .map(|(bucket, delta)| Entry {
+        tx,
+        client: after.client,
+        bucket,
+        delta,
+    })
+    .collect()
+}
+
+/// Entries crediting a fee charged by transaction `tx` to the available funds
+/// of the fee account `client`.
+#[must_use]
+pub fn fee_credit(tx: u32, client: u16, fee: Amount) -> Vec<Entry> {
+    [(Bucket::Available, fee), (Bucket::Fee, -fee)]
+        .into_iter()
+        .filter(|&(_, delta)| delta != 0.0)
+        .map(|(bucket, delta)| Entry {
+            tx,
+            client,
+            bucket,
+            delta,
+        })
+        .collect()
+}
+
+/// Streams ledger entries as CSV, in the order they are written.
+///
+/// Deltas are written in full rather than truncated like the accounts report,
+/// so that summing them gives the exact balances.
+pub struct CsvWriter<W: Write> {
+    wtr: csv::Writer<BufWriter<W>>,
+}
+
+impl<W: Write> CsvWriter<W> {
+    /// Start the ledger with its header.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if writing to the underlying writer fails.
+    pub fn new(writer: W) -> csv::Result<Self> {
+        let mut wtr = WriterBuilder::new().from_writer(BufWriter::new(writer));
+        wtr.write_record(["tx", "client", "bucket", "delta"])?;
+        Ok(CsvWriter { wtr })
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if writing to the underlying writer fails.
+    pub fn write<'a, I>(&mut self, entries: I) -> csv::Result<()>
+    where
+        I: IntoIterator<Item = &'a Entry>,
+    {
+        for entry in entries {
+            self.wtr.write_record([
+                &entry.tx.to_string(),
+                &entry.client.to_string(),
+                entry.bucket.as_str(),
+                &entry.delta.to_string(),
+            ])?;
+        }
+        Ok(())
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if writing to the underlying writer fails.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.wtr.flush()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::float_cmp)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use std::collections::BTreeMap;
+
+    const FIXTURES: [&str; 8] = [
+        include_str!("../tests/data/input1.csv"),
+        include_str!("../tests/data/input2.csv"),
+        include_str!("../tests/data/input3.csv"),
+        include_str!("../tests/data/input4.csv"),
+        include_str!("../tests/data/input5.csv"),
+        include_str!("../tests/data/input6.csv"),
+        include_str!("../tests/data/input7.csv"),
+        include_str!("../tests/data/input8.csv"),
+    ];
+
+    // Test that the ledger of each fixture sums to its final balances and that
+    // the entries of every transaction net to zero
+    #[test]
+    fn test_ledger_matches_balances() {
+        for input in FIXTURES {
+            let mut engine = Engine::new();
+            engine.record_ledger(true);
+            for transaction in csv::Reader::from_reader(input.as_bytes()).deserialize() {
+                let _ =
+                    engine.process_transaction(transaction.expect("Failed to parse transaction"));
+            }
+            let ledger = engine.take_ledger();
+
+            let mut buckets: BTreeMap<(u16, &str), Amount> = BTreeMap::new();
+            let mut transactions: BTreeMap<(u16, u32), Amount> = BTreeMap::new();
+            for entry in &ledger {
+                *buckets
+                    .entry((entry.client, entry.bucket.as_str()))
+                    .or_default() += entry.delta;
+                *transactions.entry((entry.client, entry.tx)).or_default() += entry.delta;
+            }
+            for account in engine.accounts_by_client() {
+                let sum = |bucket| buckets.get(&(account.client, bucket)).copied();
+                assert_eq!(
+                    sum("available").unwrap_or(0.0),
+                    account.available,
+                    "{input}"
+                );
+                assert_eq!(sum("held").unwrap_or(0.0), account.held, "{input}");
+                assert_eq!(sum("external").unwrap_or(0.0), -account.total, "{input}");
+            }
+            assert!(transactions.values().all(|&net| net == 0.0), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_chargeback_entries() {
+        let mut engine = Engine::new();
+        engine.record_ledger(true);
+        for transaction in csv::Reader::from_reader(FIXTURES[0].as_bytes()).deserialize() {
+            let _ = engine.process_transaction(transaction.expect("Failed to parse transaction"));
+        }
+
+        let mut output = Vec::new();
+        let ledger = engine.take_ledger();
+        let mut writer = CsvWriter::new(&mut output).expect("Failed to write header");
+        writer
+            .write(ledger.iter().filter(|entry| entry.client == 1))
+            .expect("Failed to write ledger");
+        writer.flush().expect("Failed to flush ledger");
+        drop(writer);
+        assert_eq!(
+            String::from_utf8(output).expect("Output not valid UTF-8"),
+            "tx,client,bucket,delta\n\
+             1,1,available,1000\n\
+             1,1,external,-1000\n\
+             2,1,available,-500\n\
+             2,1,external,500\n\
+             1,1,available,-1000\n\
+             1,1,held,1000\n\
+             1,1,held,-1000\n\
+             1,1,external,1000\n"
+        );
+        assert!(engine.take_ledger().is_empty());
+    }
+}
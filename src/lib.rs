@@ -0,0 +1,17 @@
+pub mod account;
+pub mod command_log;
+#[cfg(feature = "difftest")]
+pub mod difftest;
+pub mod engine;
+pub mod error;
+pub mod event;
+pub mod generate;
+pub mod handler;
+pub mod input;
+pub mod ledger;
+pub mod policy;
+pub mod report;
+pub mod snapshot;
+pub mod store;
+pub mod transaction;
+pub mod wal;
@@ -0,0 +1,146 @@
+//! Diagnostics printed on `stderr`, as plain text or, with
+//! `--log-format json`, as one JSON object per line for log aggregation.
+
+use std::str::FromStr;
+
+use process_tx::input::Provenance;
+use process_tx::transaction::Transaction;
+use serde::Serialize;
+
+/// How diagnostics are printed on `stderr`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// The message alone.
+    #[default]
+    Text,
+    /// A JSON object with the `level`, `event`, `tx`, `client`, `code`,
+    /// `message`, `file` and `line` fields, `null` when unknown.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!(
+                "invalid log format '{other}', expected one of: text, json"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Info,
+    Warn,
+    Error,
+}
+
+/// A diagnostic line, identified by its `event` name.
+#[derive(Debug, Serialize)]
+pub struct Diagnostic {
+    level: Level,
+    event: &'static str,
+    tx: Option<u32>,
+    client: Option<u16>,
+    code: Option<&'static str>,
+    message: String,
+    // Input file and line of the record
+    file: Option<String>,
+    line: Option<u64>,
+}
+
+impl Diagnostic {
+    pub fn new(level: Level, event: &'static str, message: impl Into<String>) -> Self {
+        Diagnostic {
+            level,
+            event,
+            tx: None,
+            client: None,
+            code: None,
+            message: message.into(),
+            file: None,
+            line: None,
+        }
+    }
+
+    pub fn info(event: &'static str, message: impl Into<String>) -> Self {
+        Self::new(Level::Info, event, message)
+    }
+
+    pub fn warn(event: &'static str, message: impl Into<String>) -> Self {
+        Self::new(Level::Warn, event, message)
+    }
+
+    pub fn error(event: &'static str, message: impl Into<String>) -> Self {
+        Self::new(Level::Error, event, message)
+    }
+
+    /// Attach the id, client and provenance of `transaction`.
+    pub fn transaction(mut self, transaction: &Transaction) -> Self {
+        self.tx = Some(transaction.tx);
+        self.client = Some(transaction.client);
+        self.provenance(transaction.provenance.as_ref())
+    }
+
+    pub fn tx(mut self, tx: u32) -> Self {
+        self.tx = Some(tx);
+        self
+    }
+
+    pub fn client(mut self, client: u16) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    pub fn code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    pub fn provenance(mut self, provenance: Option<&Provenance>) -> Self {
+        self.file = provenance.map(|provenance| provenance.file.to_string());
+        self.line = provenance.map(|provenance| provenance.line);
+        self
+    }
+
+    pub fn emit(&self, format: LogFormat) {
+        eprintln!("{}", self.render(format));
+    }
+
+    /// The line printed by [`Diagnostic::emit`], without its newline.
+    pub fn render(&self, format: LogFormat) -> String {
+        match format {
+            LogFormat::Text => self.message.clone(),
+            // Only strings and integers are serialized, which cannot fail
+            LogFormat::Json => serde_json::to_string(self).unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_json() {
+        let provenance = Provenance {
+            file: "day1.csv".into(),
+            line: 3,
+        };
+        let diagnostic =
+            Diagnostic::error("parse_error", "Failed to parse \"x\"").provenance(Some(&provenance));
+        assert_eq!(diagnostic.render(LogFormat::Text), "Failed to parse \"x\"");
+        assert_eq!(
+            diagnostic.render(LogFormat::Json),
+            "{\"level\":\"error\",\"event\":\"parse_error\",\"tx\":null,\"client\":null,\
+             \"code\":null,\"message\":\"Failed to parse \\\"x\\\"\",\"file\":\"day1.csv\",\
+             \"line\":3}"
+        );
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+}
@@ -1,81 +1,1222 @@
-use crate::account::Account;
-use chrono::Local;
-use csv::WriterBuilder;
+use crate::account_events::AccountEvents;
+use crate::breakdown::ErrorBreakdown;
+use crate::cli::{Command, CompareOptions, GenerateOptions, Options};
+use crate::log::{Diagnostic, LogFormat};
+use crate::rejects::Rejects;
+use crate::timer::{NoTiming, RunTimer, SystemTimer, Timing};
+use memmap2::Mmap;
+use process_tx::account::Account;
+use process_tx::engine::timed;
+use process_tx::error::{Input as InputError, Transaction as TransactionError};
+use process_tx::event::EventHook;
+use process_tx::input::{Cursor, Provenance, Schema};
+use process_tx::transaction::TxStatus;
+use process_tx::{command_log, engine, generate, ledger, report, snapshot, wal};
+use std::collections::{BTreeMap, HashSet};
 use std::env;
 use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
 use std::process;
+use std::sync::Arc;
+use std::time::Duration;
 
-mod account;
-mod engine;
-mod error;
-mod transaction;
+mod account_events;
+mod breakdown;
+mod cli;
+mod log;
+mod metrics;
+mod rejects;
+mod repl;
+mod timer;
+mod trace;
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let start_time = Local::now();
-    eprintln!(
-        "Program started at {}",
-        start_time.format("%Y-%m-%d %H:%M:%S")
-    );
+// Exit code used when `--fail-fast` stops processing on a rejected record
+const EXIT_REJECTED: i32 = 2;
+// Exit code used when `--max-accounts` or `--max-transactions` is reached
+const EXIT_CAPACITY: i32 = 3;
+// Exit code used when reading the input fails partway, after writing the
+// accounts processed so far
+const EXIT_INPUT: i32 = 4;
+// Exit code used when the account totals do not add up to the funds moved,
+// after writing the outputs
+const EXIT_IMBALANCE: i32 = 5;
+// Exit code used by `compare` when the report differs from the baseline
+const EXIT_DIFFERENCE: i32 = 6;
 
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: {} <transactions.csv>", args[0]);
+fn main() {
+    let command = parse_args();
+    let log_format = command.log_format();
+    if let Err(e) = run_command(command) {
+        Diagnostic::error("fatal", format!("Error: {e}")).emit(log_format);
         process::exit(1);
     }
+}
 
-    let input_path = &args[1];
+fn run_command(command: Command) -> Result<(), Box<dyn Error>> {
+    let options = match command {
+        Command::Run(options) => options,
+        Command::Generate(options) => return generate_file(&options),
+        Command::Compare(options) => return compare(&options),
+    };
 
-    let mut engine = engine::Engine::new();
-    let mut successful_count = 0;
-    let mut error_count = 0;
+    if let Some(path) = &options.repl {
+        return run_repl(path);
+    }
+    if !options.merge_states.is_empty() {
+        return merge_states(&options);
+    }
+
+    let timer: Box<dyn RunTimer> = if options.no_timing {
+        Box::new(NoTiming)
+    } else {
+        Box::new(SystemTimer)
+    };
+    let mut timing = Timing::start(timer);
+    if let Some(line) = timing.start_line() {
+        Diagnostic::info("start", line).emit(options.log_format);
+    }
+
+    let previous = read_previous(&options)?;
+    let mut engine = build_engine(&options)?;
+    let (mut rdr, schema) = open_input(&options, &mut engine)?;
+    let mut run = Run::start(&options, engine, schema)?;
+    let input_error = run.read(&mut rdr, &options.input_path)?;
+    if let Some(e) = &input_error {
+        let message = format!(
+            "Failed to read the input after {} records: {e}. Writing the accounts processed so far.",
+            run.records
+        );
+        Diagnostic::error("input_failed", message).emit(options.log_format);
+    }
+    run.finish()?;
+
+    write_outputs(&options, &run.engine, &mut run.sinks, previous.as_ref())?;
+
+    let summary = timing.summary_line(&run.counts);
+    Diagnostic::info("summary", summary).emit(options.log_format);
+    print_diagnostics(&options, &run.engine, &run.sinks, &run.counts);
+
+    if let Err(e) = run.engine.global_check() {
+        let message = format!("Global balance check failed: {e}");
+        Diagnostic::error("imbalance", message).emit(options.log_format);
+        process::exit(EXIT_IMBALANCE);
+    }
+    if input_error.is_some() {
+        process::exit(EXIT_INPUT);
+    }
+    Ok(())
+}
+
+// Open the input file and detect its schema. With `--resume`, the saved
+// state is first loaded into `engine` and the file continued where the run
+// that saved it stopped.
+fn open_input(
+    options: &Options,
+    engine: &mut engine::Engine,
+) -> Result<(csv::Reader<InputFile>, Schema), Box<dyn Error>> {
+    let (rdr, headers) = if let Some(path) = &options.resume {
+        let cursor = snapshot::load_into_with_cursor(engine, BufReader::new(File::open(path)?))?
+            .ok_or_else(|| format!("State {path} has no input position to resume from"))?;
+        let rdr = accept_input(options, cursor.resume(InputFile::open(options)?));
+        (rdr, cursor.headers())
+    } else {
+        let mut rdr = csv::Reader::from_reader(InputFile::open(options)?);
+        let headers = rdr.headers()?.clone();
+        (rdr, headers)
+    };
+    let schema = accept_input(options, Schema::detect(headers, options.capture_extra));
+    let schema = schema
+        .strict_amounts(options.strict_amounts)
+        .allow_exponent(options.allow_exponent);
+    Ok((rdr, schema))
+}
+
+// Input file, read through system calls or, with `--mmap`, from a memory map
+// of the whole file
+enum InputFile {
+    File(File),
+    Mapped(io::Cursor<Mmap>),
+}
+
+impl InputFile {
+    fn open(options: &Options) -> io::Result<Self> {
+        let file = File::open(&options.input_path)?;
+        if !options.mmap {
+            return Ok(InputFile::File(file));
+        }
+        // Pipes and other special files cannot be mapped
+        if !file.metadata()?.is_file() {
+            let message = format!(
+                "Input {} is not a regular file, reading it without --mmap",
+                options.input_path
+            );
+            Diagnostic::warn("mmap_unsupported", message).emit(options.log_format);
+            return Ok(InputFile::File(file));
+        }
+        // SAFETY: the map is only ever read. A file modified while it is
+        // processed gives inconsistent records, as with a regular read, and
+        // one truncated meanwhile makes the process fault on the lost pages
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(InputFile::Mapped(io::Cursor::new(map)))
+    }
+}
+
+impl Read for InputFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            InputFile::File(file) => file.read(buf),
+            InputFile::Mapped(map) => map.read(buf),
+        }
+    }
+}
+
+// Lets `--resume` skip the part of the file already processed
+impl Seek for InputFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            InputFile::File(file) => file.seek(pos),
+            InputFile::Mapped(map) => map.seek(pos),
+        }
+    }
+}
+
+// Unwrap the result of checking the input file, exiting if it is refused
+fn accept_input<T>(options: &Options, result: Result<T, InputError>) -> T {
+    result.unwrap_or_else(|e| {
+        let message = format!("Refusing {}: {e}", options.input_path);
+        Diagnostic::error("input_refused", message).emit(options.log_format);
+        process::exit(1);
+    })
+}
+
+// Processing state of an input file
+struct Run<'a> {
+    options: &'a Options,
+    engine: engine::Engine,
+    sinks: Sinks,
+    counts: Counts,
+    schema: Schema,
+    // Name of the file being read, recorded as the provenance of its rows
+    file: Arc<str>,
+    records: u64,
+}
+
+impl<'a> Run<'a> {
+    fn start(
+        options: &'a Options,
+        mut engine: engine::Engine,
+        schema: Schema,
+    ) -> Result<Self, Box<dyn Error>> {
+        // Set first so that the recovered transactions are reported too
+        let account_events = set_event_hooks(options, &mut engine)?;
+        // Started first so that replaying the log also rebuilds the recovery
+        if let Some(path) = &options.command_log {
+            engine.record_commands(command_log::Writer::create(Path::new(path))?);
+        }
+        let mut recovered = Vec::new();
+        if let Some(path) = &options.wal_recover {
+            let log = wal::recover(&mut engine, Path::new(path))?;
+            let message = format!(
+                "Recovered {} transactions from the write-ahead log {path}",
+                log.transactions.len()
+            );
+            Diagnostic::info("wal_recovered", message).emit(options.log_format);
+            if log.torn {
+                let message = "Dropped a torn record at the end of the write-ahead log";
+                Diagnostic::warn("wal_torn", message).emit(options.log_format);
+            }
+            recovered = log.transactions;
+        }
+        // Opened after the recovery, which truncates a torn record
+        let mut sinks = Sinks::open(options, &schema, account_events)?;
+        // A new log starts with the recovered transactions, so that it alone
+        // rebuilds the state
+        if options.wal != options.wal_recover {
+            if let Some(wal) = &mut sinks.wal {
+                for transaction in &recovered {
+                    wal.append(transaction)?;
+                }
+            }
+        }
+        if let Some(server) = &sinks.metrics {
+            server.metrics().start_from(&engine);
+        }
+        Ok(Run {
+            options,
+            engine,
+            sinks,
+            counts: Counts::default(),
+            schema,
+            file: Arc::from(""),
+            records: 0,
+        })
+    }
+
+    // Process the records of `rdr`, read from `file`, up to the first error
+    // reading it, which is returned so that the accounts processed so far can
+    // still be written
+    fn read<R: Read>(
+        &mut self,
+        rdr: &mut csv::Reader<R>,
+        file: &str,
+    ) -> Result<Option<csv::Error>, Box<dyn Error>> {
+        self.file = Arc::from(file);
+        if let Some(state) = &mut self.sinks.state {
+            state.position = rdr.position().clone();
+        }
+        let mut record = csv::StringRecord::new();
+        loop {
+            match rdr.read_record(&mut record) {
+                Ok(false) => return Ok(None),
+                Ok(true) => self.process(Ok(record.clone()))?,
+                Err(e) if e.is_io_error() => return Ok(Some(e)),
+                Err(e) => self.process(Err(e))?,
+            }
+            self.sinks.processed(&self.engine, rdr.position())?;
+        }
+    }
+
+    fn process(&mut self, record: csv::Result<csv::StringRecord>) -> Result<(), Box<dyn Error>> {
+        let options = self.options;
+        self.records += 1;
+        if let Some(server) = &self.sinks.metrics {
+            server.metrics().record_row();
+        }
+        if options
+            .progress
+            .is_some_and(|every| self.records.is_multiple_of(every))
+        {
+            let message = format!("Processed {} records", self.records);
+            Diagnostic::info("progress", message).emit(options.log_format);
+        }
+        let position = match &record {
+            Ok(record) => record.position(),
+            Err(e) => e.position(),
+        };
+        let provenance = position.map(|position| Provenance::at(&self.file, position));
+        let line = provenance.as_ref().map(|provenance| provenance.line);
+        if let Ok(record) = &record {
+            if let Some(reason) =
+                oversized_field(options.max_field_len, record, self.schema.headers())
+            {
+                let message = format!(
+                    "Refused transaction record at line {}: {reason}.",
+                    line.map_or("unknown".to_string(), |line| line.to_string())
+                );
+                Diagnostic::error("oversized_record", message)
+                    .provenance(provenance.as_ref())
+                    .emit(options.log_format);
+                self.counts.oversized += 1;
+                if options.fail_fast {
+                    let provenance = provenance.as_ref();
+                    abort_on_first_error(options, &self.engine, &mut self.sinks, provenance);
+                }
+                return Ok(());
+            }
+        }
+        let result = record.and_then(|record| self.schema.read_from(&self.file, &record));
 
-    let mut rdr = csv::Reader::from_path(input_path)?;
-    for result in rdr.deserialize() {
         // Process each transaction and handle any errors
         match result {
+            Ok(transaction) if !options.accepts_client(transaction.client) => {
+                self.counts.skipped += 1;
+            }
             Ok(transaction) => {
-                if let Err(e) = engine.process_transaction(transaction) {
-                    // Error processing transaction: this will be logged to a file in future iterations.
-                    eprintln!("An error has occured on transaction processing : {e}");
-                    error_count += 1;
+                let (tx, client) = (transaction.tx, transaction.client);
+                let process = || -> Result<(), Box<dyn Error>> {
+                    for outcome in self.engine.submit(transaction) {
+                        self.sinks.log_applied(&outcome)?;
+                        let settled = settle(options, &self.engine, &mut self.sinks, outcome);
+                        self.counts.settled(settled);
+                    }
+                    self.sinks.write_ledger(&mut self.engine)?;
+                    self.sinks.write_account_events()?;
+                    Ok(())
+                };
+                // Rows are only timed when slow ones are reported
+                if let Some(threshold) = options.warn_slow_rows {
+                    let (result, elapsed) = timed(process);
+                    result?;
+                    self.check_slow(elapsed, threshold, tx, client, provenance.as_ref());
                 } else {
-                    successful_count += 1;
+                    process()?;
                 }
             }
             Err(e) => {
-                eprintln!(
+                let message = format!(
                     "Failed to parse transaction record at line {}: {}.",
-                    e.position()
-                        .map_or("unknown".to_string(), |pos| pos.line().to_string()),
+                    line.map_or("unknown".to_string(), |line| line.to_string()),
                     e
                 );
+                Diagnostic::error("parse_error", message)
+                    .provenance(provenance.as_ref())
+                    .emit(options.log_format);
+                if options.fail_fast {
+                    let provenance = provenance.as_ref();
+                    abort_on_first_error(options, &self.engine, &mut self.sinks, provenance);
+                }
             }
         }
+        Ok(())
+    }
+
+    // Warn about a row whose processing, including the parked rows it
+    // released, took longer than `--warn-slow-rows`
+    fn check_slow(
+        &mut self,
+        elapsed: Duration,
+        threshold: Duration,
+        tx: u32,
+        client: u16,
+        provenance: Option<&Provenance>,
+    ) {
+        if elapsed <= threshold {
+            return;
+        }
+        self.counts.slow += 1;
+        let location = provenance.map_or(String::new(), |provenance| format!(" at {provenance}"));
+        let message = format!(
+            "Slow row{location}: transaction {tx} took {} ms to process, above {} ms (--warn-slow-rows)",
+            elapsed.as_millis(),
+            threshold.as_millis()
+        );
+        Diagnostic::warn("slow_row", message)
+            .tx(tx)
+            .client(client)
+            .provenance(provenance)
+            .emit(self.options.log_format);
     }
 
-    let mut accounts: Vec<&Account> = engine.accounts.values().collect();
-    accounts.sort_by_key(|account| account.client);
-    let mut wtr = WriterBuilder::new().from_writer(std::io::stdout());
-    wtr.write_record(["client", "available", "held", "total", "locked"])?;
-    for account in accounts {
-        let (available, held, total, locked) = account.formatted_values();
-        wtr.write_record(&[
-            account.client.to_string(),
-            available,
-            held,
-            total,
-            locked.to_string(),
-        ])?;
+    // Settle the references whose transaction never arrived
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        for outcome in self.engine.finish() {
+            let settled = settle(self.options, &self.engine, &mut self.sinks, outcome);
+            self.counts.settled(settled);
+        }
+        self.sinks.write_ledger(&mut self.engine)?;
+        self.sinks.write_account_events()?;
+        self.engine.finish_command_log()?;
+        Ok(())
     }
+}
+
+// Records and transactions counted for the summary line
+#[derive(Default)]
+struct Counts {
+    successful: u64,
+    errors: u64,
+    // Transactions of clients excluded by the client filters
+    skipped: u64,
+    // Records refused by `--max-field-len`
+    oversized: u64,
+    // Transactions of clients on the `--deny-clients` list
+    denied: u64,
+    // Withdrawals skipped by `--insufficient-funds warn`
+    insufficient: u64,
+    // Rows slower than `--warn-slow-rows`
+    slow: u64,
+}
 
-    let end_time = Local::now();
-    eprintln!(
-        "Processing completed at {} in {} ms. Successful transactions: {}. Errors encountered: {}",
-        chrono::Utc::now().format("%Y-%m-%d %H:%M:%S"),
-        (end_time - start_time).num_milliseconds(),
-        successful_count,
-        error_count
+impl Counts {
+    fn settled(&mut self, settled: Settled) {
+        match settled {
+            Settled::Applied => self.successful += 1,
+            Settled::Rejected => self.errors += 1,
+            Settled::Denied => self.denied += 1,
+            Settled::InsufficientFunds => self.insufficient += 1,
+        }
+    }
+}
+
+// How the outcome of a transaction is counted
+#[derive(Clone, Copy)]
+enum Settled {
+    Applied,
+    Rejected,
+    // Rejected because its client is on the `--deny-clients` list
+    Denied,
+    // Withdrawal rejected for insufficient funds with
+    // `--insufficient-funds warn`
+    InsufficientFunds,
+}
+
+// Print the warnings and statistics requested for the end of the run
+fn print_diagnostics(options: &Options, engine: &engine::Engine, sinks: &Sinks, counts: &Counts) {
+    if options.warn_held {
+        for account in engine.accounts_with_held() {
+            let message = format!(
+                "Warning: client {} has {} held by unresolved disputes",
+                account.client,
+                Account::format_value(account.held)
+            );
+            Diagnostic::warn("held_funds", message)
+                .client(account.client)
+                .emit(options.log_format);
+        }
+    }
+    if let Some(breakdown) = &sinks.breakdown {
+        let table = breakdown.table();
+        Diagnostic::info("error_breakdown", table.trim_end()).emit(options.log_format);
+    }
+    let stats = engine.reorder_stats();
+    if stats.parked > 0 {
+        let message = format!(
+            "Out-of-order references: {} parked, {} matched late, {} expired",
+            stats.parked, stats.matched_late, stats.expired
+        );
+        Diagnostic::info("reorder_stats", message).emit(options.log_format);
+    }
+    if counts.slow > 0 {
+        let message = format!("Slow rows: {}", counts.slow);
+        Diagnostic::info("slow_rows", message).emit(options.log_format);
+    }
+    if engine.dust_skipped() > 0 {
+        let message = format!("Dust transactions skipped: {}", engine.dust_skipped());
+        Diagnostic::info("dust_skipped", message).emit(options.log_format);
+    }
+    if !options.fees.rules.is_empty() {
+        let message = format!(
+            "Fees collected: {}",
+            Account::format_value(engine.fees_collected())
+        );
+        Diagnostic::info("fees_collected", message).emit(options.log_format);
+    }
+    let totals = engine.global_totals();
+    let message = format!(
+        "Global totals: opening {}, deposits {}, withdrawals {}, chargebacks {}, \
+         refunds {}, fees {}, accounts {}",
+        Account::format_value(totals.opening),
+        Account::format_value(totals.deposits),
+        Account::format_value(totals.withdrawals),
+        Account::format_value(totals.chargebacks),
+        Account::format_value(totals.refunds),
+        Account::format_value(totals.fees),
+        Account::format_value(totals.accounts)
     );
+    Diagnostic::info("global_totals", message).emit(options.log_format);
+}
+
+// Write the report and the other requested outputs of a completed run
+fn write_outputs(
+    options: &Options,
+    engine: &engine::Engine,
+    sinks: &mut Sinks,
+    previous: Option<&BTreeMap<u16, report::Balances>>,
+) -> Result<(), Box<dyn Error>> {
+    write_report(options, engine)?;
+    sinks.flush()?;
+    sinks.save_state(engine)?;
+
+    if let Some(path) = &options.export_transactions {
+        report::write_transactions(File::create(path)?, engine.transactions_by_id())?;
+    }
+    if let Some(path) = &options.dump_transactions {
+        let mut statuses: Vec<TxStatus> = engine
+            .transactions_iter()
+            .filter(|status| options.dump_filter.matches(status))
+            .collect();
+        statuses.sort_unstable_by_key(|status| status.tx);
+        report::write_transaction_statuses(File::create(path)?, statuses)?;
+    }
+    if let (Some(previous), Some(path)) = (previous, &options.delta_report) {
+        let deltas = report::account_deltas(
+            previous,
+            engine.accounts_by_client(),
+            &report_options(options),
+        );
+        report::write_account_deltas(File::create(path)?, &deltas)?;
+    }
+    Ok(())
+}
+
+// Balances of the --previous report, read before processing so that a
+// missing or invalid report fails the run before any output is written
+fn read_previous(
+    options: &Options,
+) -> Result<Option<BTreeMap<u16, report::Balances>>, Box<dyn Error>> {
+    let Some(path) = &options.previous else {
+        return Ok(None);
+    };
+    let file =
+        File::open(path).map_err(|e| format!("Failed to open previous report {path}: {e}"))?;
+    let balances = report::read_balances(
+        BufReader::new(file),
+        options.decimal_separator.unwrap_or('.'),
+    )
+    .map_err(|e| format!("Invalid previous report {path}: {e}"))?;
+    Ok(Some(balances))
+}
+
+fn build_engine(options: &Options) -> Result<engine::Engine, Box<dyn Error>> {
+    let deny_clients = match &options.deny_clients {
+        Some(path) => cli::parse_client_list(&fs::read_to_string(path)?)
+            .map_err(|e| format!("Invalid client list {path}: {e}"))?,
+        None => HashSet::new(),
+    };
+    let mut engine = engine::Engine::builder()
+        .reserve(options.reserve)
+        .max_single_deposit(options.max_single_deposit)
+        .max_total_deposits_per_client(options.max_total_deposits_per_client)
+        .max_balance(options.max_balance)
+        .withdrawals_during_dispute(options.withdrawals_during_dispute)
+        .on_chargeback_overdraw(options.on_chargeback_overdraw)
+        .dispute_clamp_to_available(options.dispute_clamp_to_available)
+        .reference_grace(options.reference_grace)
+        .max_transactions(options.max_transactions)
+        .max_accounts(options.max_accounts)
+        .max_redisputes(options.max_redisputes)
+        .pending_deposits(options.pending_deposits)
+        .dust_threshold(options.dust_threshold)
+        .fee_schedule(options.fees.clone())
+        .strict_held(options.strict_held)
+        .max_open_disputes_per_client(options.max_open_disputes_per_client)
+        .lock_on_chargeback(options.lock_on_chargeback)
+        .lock_on_cumulative_loss(options.lock_on_cumulative_loss)
+        .require_explicit_open(options.require_explicit_open)
+        .round_to_cents(options.round_to_cents)
+        .deny_clients(deny_clients)
+        .allow_deposits_when_locked(options.allow_deposits_when_locked)
+        .max_transactions_per_client(options.max_transactions_per_client)
+        .insufficient_funds(options.insufficient_funds)
+        .build();
+    engine.record_ledger(options.ledger.is_some());
+    Ok(engine)
+}
+
+// Register the event hooks of the run on `engine`: the tracer of
+// `--trace-tx`, and the collector of `--account-events`, whose file is
+// returned
+fn set_event_hooks(
+    options: &Options,
+    engine: &mut engine::Engine,
+) -> Result<Option<AccountEvents<File>>, Box<dyn Error>> {
+    let mut hooks: Vec<Box<dyn EventHook>> = Vec::new();
+    if !options.trace_tx.is_empty() {
+        let tracer = trace::Tracer::new(
+            options.trace_tx.clone(),
+            std::io::stderr(),
+            options.log_format,
+        );
+        hooks.push(Box::new(tracer));
+    }
+    let account_events = match &options.account_events {
+        Some(path) => {
+            let (account_events, collector) = AccountEvents::new(File::create(path)?)?;
+            hooks.push(Box::new(collector));
+            Some(account_events)
+        }
+        None => None,
+    };
+    if !hooks.is_empty() {
+        engine.set_event_hook(Box::new(hooks));
+    }
+    Ok(account_events)
+}
+
+// Parse the program arguments, exiting with the usage on error
+fn parse_args() -> Command {
+    let mut args = env::args();
+    let program = args.next().unwrap_or_else(|| "process-tx".to_string());
+    let args: Vec<String> = args.collect();
+    match Command::parse(args.iter().cloned()) {
+        Ok(command) => command,
+        Err(e) => {
+            // The usage is only printed with text diagnostics, where a
+            // reader rather than a log scraper sees it
+            let log_format = cli::requested_log_format(&args);
+            Diagnostic::error("fatal", e).emit(log_format);
+            if log_format != LogFormat::Text {
+                process::exit(1);
+            }
+            eprintln!(
+                "Usage: {program} [run] [OPTIONS] <transactions.csv>\n       \
+                 {program} generate [GENERATE OPTIONS]\n       \
+                 {program} compare [COMPARE OPTIONS] [OPTIONS]\n\n{}\n\n{}\n\n{}",
+                cli::OPTIONS_HELP,
+                cli::GENERATE_HELP,
+                cli::COMPARE_HELP
+            );
+            process::exit(1);
+        }
+    }
+}
+
+fn generate_file(options: &GenerateOptions) -> Result<(), Box<dyn Error>> {
+    match &options.out {
+        Some(path) => generate::write_csv(File::create(path)?, &options.spec)?,
+        None => generate::write_csv(std::io::stdout().lock(), &options.spec)?,
+    }
+    Ok(())
+}
+
+// Process the input like a run, then print the differences between its
+// report and the baseline as JSON instead of writing the report
+fn compare(compare: &CompareOptions) -> Result<(), Box<dyn Error>> {
+    let options = &compare.run;
+    let mut engine = build_engine(options)?;
+    let (mut rdr, schema) = open_input(options, &mut engine)?;
+    let mut run = Run::start(options, engine, schema)?;
+    if let Some(e) = run.read(&mut rdr, &options.input_path)? {
+        return Err(format!(
+            "Failed to read the input after {} records: {e}",
+            run.records
+        )
+        .into());
+    }
+    run.finish()?;
+    // Written before exiting with the difference code, which skips the
+    // buffers left to flush on drop
+    run.sinks.flush()?;
+
+    let mut report = Vec::new();
+    report::write_accounts(
+        &mut report,
+        run.engine.accounts_by_client(),
+        &report_options(options),
+    )?;
+    let diff = report::compare_reports(
+        File::open(&compare.baseline)?,
+        report.as_slice(),
+        compare.tolerance,
+    )?;
+    let mut stdout = std::io::stdout().lock();
+    serde_json::to_writer_pretty(&mut stdout, &diff)?;
+    writeln!(stdout)?;
+    if !diff.is_empty() {
+        let message = format!(
+            "Report differs from {}: {} clients added, {} removed, {} fields changed",
+            compare.baseline,
+            diff.added.len(),
+            diff.removed.len(),
+            diff.changed.len()
+        );
+        Diagnostic::error("report_differs", message).emit(options.log_format);
+        process::exit(EXIT_DIFFERENCE);
+    }
+    Ok(())
+}
+
+fn run_repl(path: &str) -> Result<(), Box<dyn Error>> {
+    let mut engine = snapshot::load(File::open(path)?)?;
+    repl::run(
+        &mut engine,
+        std::io::stdin().lock(),
+        std::io::stdout().lock(),
+    )?;
+    Ok(())
+}
 
+fn merge_states(options: &Options) -> Result<(), Box<dyn Error>> {
+    let mut engine = engine::Engine::new();
+    for path in &options.merge_states {
+        let shard = snapshot::load(File::open(path)?)?;
+        engine
+            .merge(shard)
+            .map_err(|e| format!("Failed to merge snapshot {path}: {e}"))?;
+    }
+    write_report(options, &engine)?;
+    if let Some(path) = &options.save_state {
+        snapshot::save(&engine, BufWriter::new(File::create(path)?))?;
+    }
     Ok(())
 }
+
+fn report_options(options: &Options) -> report::Options {
+    report::Options {
+        locked_format: options.locked_format,
+        fixed_decimals: options.fixed_decimals,
+        decimal_separator: options.decimal_separator.unwrap_or('.'),
+        quote: options.quote,
+        skip_inactive: options.skip_inactive,
+        nonzero_only: options.nonzero_only,
+        pending: options.pending_deposits,
+        extended: options.extended_output,
+        crlf: options.crlf,
+    }
+}
+
+fn write_report(options: &Options, engine: &engine::Engine) -> Result<(), Box<dyn Error>> {
+    let report_options = report_options(options);
+    match options.output_format {
+        report::OutputFormat::Csv => {
+            if let Some(path) = &options.output {
+                report::write_accounts(
+                    File::create(path)?,
+                    engine.accounts_by_client(),
+                    &report_options,
+                )?;
+            } else {
+                report::write_accounts(
+                    std::io::stdout().lock(),
+                    engine.accounts_by_client(),
+                    &report_options,
+                )?;
+            }
+        }
+        report::OutputFormat::Table => {
+            if let Some(path) = &options.output {
+                report::write_accounts_table(
+                    File::create(path)?,
+                    engine.accounts_by_client(),
+                    &report_options,
+                )?;
+            } else {
+                report::write_accounts_table(
+                    std::io::stdout().lock(),
+                    engine.accounts_by_client(),
+                    &report_options,
+                )?;
+            }
+        }
+        #[cfg(feature = "parquet")]
+        report::OutputFormat::Parquet => {
+            // The option parser guarantees an output path for binary formats
+            let path = options.output.as_deref().ok_or("missing output path")?;
+            let accounts = engine
+                .accounts_by_client()
+                .filter(|account| report_options.includes(account));
+            report::write_accounts_parquet(File::create(path)?, accounts)?;
+        }
+    }
+    Ok(())
+}
+
+// Describe the first field of `record` longer than `limit` bytes, if any, so
+// that gigantic values are neither parsed nor stored
+fn oversized_field(
+    limit: Option<usize>,
+    record: &csv::StringRecord,
+    headers: &csv::StringRecord,
+) -> Option<String> {
+    let limit = limit?;
+    let (i, field) = record
+        .iter()
+        .enumerate()
+        .find(|(_, field)| field.len() > limit)?;
+    Some(format!(
+        "field `{}` is {} bytes long, above the limit of {limit} (--max-field-len)",
+        headers.get(i).unwrap_or_default(),
+        field.len()
+    ))
+}
+
+// Optional outputs fed with the settled rows
+struct Sinks {
+    breakdown: Option<ErrorBreakdown>,
+    rejects: Option<Rejects<File>>,
+    ledger: Option<ledger::CsvWriter<File>>,
+    account_events: Option<AccountEvents<File>>,
+    metrics: Option<metrics::Server>,
+    wal: Option<wal::Writer<File>>,
+    state: Option<StateSink>,
+}
+
+impl Sinks {
+    fn open(
+        options: &Options,
+        schema: &Schema,
+        account_events: Option<AccountEvents<File>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let rejects = match &options.rejects {
+            Some(path) => {
+                let extra_columns = schema.extra_names().map(str::to_string).collect();
+                Some(Rejects::new(File::create(path)?, extra_columns)?)
+            }
+            None => None,
+        };
+        let ledger = match &options.ledger {
+            Some(path) => Some(ledger::CsvWriter::new(File::create(path)?)?),
+            None => None,
+        };
+        let metrics = match options.metrics_addr {
+            Some(addr) => {
+                let server = metrics::Server::start(addr, options.log_format)?;
+                let message = format!("Serving metrics on http://{}/metrics", server.local_addr());
+                Diagnostic::info("metrics_serving", message).emit(options.log_format);
+                Some(server)
+            }
+            None => None,
+        };
+        // A log just recovered is continued rather than started over
+        let wal = match &options.wal {
+            Some(path) => Some(wal::Writer::open(
+                Path::new(path),
+                options.wal_recover.as_ref() == Some(path),
+            )?),
+            None => None,
+        };
+        let state = options.save_state.as_ref().map(|path| StateSink {
+            path: path.clone(),
+            input_path: options.input_path.clone(),
+            headers: schema.headers().clone(),
+            checkpoint_every: options.checkpoint_every,
+            position: csv::Position::new(),
+            unsaved: 0,
+            cursor: None,
+        });
+        Ok(Sinks {
+            breakdown: options.error_breakdown.map(ErrorBreakdown::new),
+            rejects,
+            ledger,
+            account_events,
+            metrics,
+            wal,
+            state,
+        })
+    }
+
+    // Note that the input was processed up to `position`, saving a
+    // checkpoint of the state when one is due and no row is parked
+    fn processed(
+        &mut self,
+        engine: &engine::Engine,
+        position: &csv::Position,
+    ) -> Result<(), Box<dyn Error>> {
+        let Some(state) = &mut self.state else {
+            return Ok(());
+        };
+        state.position = position.clone();
+        state.unsaved += 1;
+        let due = state
+            .checkpoint_every
+            .is_some_and(|every| state.unsaved >= every);
+        if due && engine.parked() == 0 {
+            self.flush()?;
+            self.save_state(engine)?;
+        }
+        Ok(())
+    }
+
+    // Save the state with `--save-state`, which then holds everything the
+    // write-ahead log would replay
+    fn save_state(&mut self, engine: &engine::Engine) -> Result<(), Box<dyn Error>> {
+        if let Some(state) = &mut self.state {
+            state.save(engine)?;
+            if let Some(wal) = &mut self.wal {
+                wal.reset()?;
+            }
+        }
+        Ok(())
+    }
+
+    // Append an applied transaction to the write-ahead log
+    fn log_applied(&mut self, outcome: &engine::Outcome) -> std::io::Result<()> {
+        match (&mut self.wal, &outcome.result) {
+            (Some(wal), Ok(())) => wal.append(&outcome.transaction),
+            _ => Ok(()),
+        }
+    }
+
+    // Write the ledger entries recorded by the engine since the last call
+    fn write_ledger(&mut self, engine: &mut engine::Engine) -> csv::Result<()> {
+        match &mut self.ledger {
+            Some(ledger) => ledger.write(&engine.take_ledger()),
+            None => Ok(()),
+        }
+    }
+
+    // Write the lifecycle events of the accounts collected since the last call
+    fn write_account_events(&mut self) -> csv::Result<()> {
+        match &mut self.account_events {
+            Some(account_events) => account_events.write(),
+            None => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if let Some(rejects) = &mut self.rejects {
+            rejects.flush()?;
+        }
+        if let Some(ledger) = &mut self.ledger {
+            ledger.flush()?;
+        }
+        if let Some(account_events) = &mut self.account_events {
+            account_events.flush()?;
+        }
+        Ok(())
+    }
+}
+
+// State saved by `--save-state` at the end of the run, every
+// `--checkpoint-every` records and when the run aborts, with the cursor
+// `--resume` continues the input from
+struct StateSink {
+    path: String,
+    input_path: String,
+    headers: csv::StringRecord,
+    checkpoint_every: Option<u64>,
+    // Position of the record after the last one processed
+    position: csv::Position,
+    // Records processed since the last save
+    unsaved: u64,
+    // Cursor of the last save, whose checksum the next one extends
+    cursor: Option<Cursor>,
+}
+
+impl StateSink {
+    fn save(&mut self, engine: &engine::Engine) -> Result<(), Box<dyn Error>> {
+        let file = File::open(&self.input_path)?;
+        let cursor = match &self.cursor {
+            Some(cursor) => cursor.advance(&self.position, file)?,
+            None => Cursor::new(&self.headers, &self.position, file)?,
+        };
+        // Written aside and renamed over the state, so that a crash while
+        // saving leaves the previous one whole
+        let partial = format!("{}.partial", self.path);
+        let mut writer = BufWriter::new(File::create(&partial)?);
+        snapshot::save_with_cursor(engine, Some(&cursor), &mut writer)?;
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&partial, &self.path)?;
+        self.cursor = Some(cursor);
+        self.unsaved = 0;
+        Ok(())
+    }
+}
+
+// Report the result of a processed row, returning whether it was applied
+fn settle(
+    options: &Options,
+    engine: &engine::Engine,
+    sinks: &mut Sinks,
+    outcome: engine::Outcome,
+) -> Settled {
+    if let Some(server) = &sinks.metrics {
+        server.metrics().record_outcome(&outcome, engine);
+    }
+    if let Err(e) = &outcome.result {
+        if let Some(breakdown) = &mut sinks.breakdown {
+            breakdown.record(outcome.transaction.client, e.code());
+        }
+        if let Some(rejects) = &mut sinks.rejects {
+            if let Err(write_error) = rejects.write(&outcome.transaction, e) {
+                let message = format!("Failed to write the rejected row: {write_error}");
+                Diagnostic::error("rejects_failed", message)
+                    .transaction(&outcome.transaction)
+                    .emit(options.log_format);
+            }
+        }
+    }
+    match outcome.result {
+        Ok(()) => Settled::Applied,
+        // Expected for the clients under investigation, so only counted
+        Err(TransactionError::ClientDenied(_)) => Settled::Denied,
+        Err(e)
+            if options
+                .insufficient_funds
+                .skips(&outcome.transaction.t_type, &e) =>
+        {
+            let message = format!("Skipped transaction: [{}] {e}", e.code());
+            Diagnostic::warn("insufficient_funds", message)
+                .transaction(&outcome.transaction)
+                .code(e.code())
+                .emit(options.log_format);
+            Settled::InsufficientFunds
+        }
+        Err(e @ TransactionError::CapacityExceeded(..)) => {
+            let message = format!(
+                "Processing aborted: [{}] {e} ({} accounts, {} stored transactions)",
+                e.code(),
+                engine.account_count(),
+                engine.transaction_count()
+            );
+            Diagnostic::error("capacity_exceeded", message)
+                .transaction(&outcome.transaction)
+                .code(e.code())
+                .emit(options.log_format);
+            abort(options, engine, sinks, EXIT_CAPACITY);
+        }
+        Err(e) => {
+            let message = format!(
+                "An error has occured on transaction processing : [{}] {e}",
+                e.code()
+            );
+            Diagnostic::error("rejected", message)
+                .transaction(&outcome.transaction)
+                .code(e.code())
+                .emit(options.log_format);
+            if options.fail_fast {
+                let provenance = outcome.transaction.provenance.as_ref();
+                abort_on_first_error(options, engine, sinks, provenance);
+            }
+            Settled::Rejected
+        }
+    }
+}
+
+// Exit on the record rejected at `provenance`, with `--fail-fast`
+fn abort_on_first_error(
+    options: &Options,
+    engine: &engine::Engine,
+    sinks: &mut Sinks,
+    provenance: Option<&Provenance>,
+) -> ! {
+    let message = match provenance {
+        Some(provenance) => {
+            format!(
+                "Processing aborted on the first rejected record, at {provenance} (--fail-fast)"
+            )
+        }
+        None => "Processing aborted on the first rejected record (--fail-fast)".to_string(),
+    };
+    Diagnostic::error("aborted", message)
+        .provenance(provenance)
+        .emit(options.log_format);
+    abort(options, engine, sinks, EXIT_REJECTED);
+}
+
+// Exit with `code`, first writing the report of the accounts so far if
+// `--report-on-abort` was given
+fn abort(options: &Options, engine: &engine::Engine, sinks: &mut Sinks, code: i32) -> ! {
+    if let Err(e) = sinks.flush() {
+        let message = format!("Failed to write the rejected rows or the ledger: {e}");
+        Diagnostic::error("outputs_failed", message).emit(options.log_format);
+    }
+    // The cursor stays before the record the run aborts on, so that
+    // `--resume` reads it again once fixed. Parked rows are not saved
+    if sinks.state.is_some() && engine.parked() > 0 {
+        let message = format!(
+            "State not saved: {} rows are parked by --reference-grace",
+            engine.parked()
+        );
+        Diagnostic::warn("state_not_saved", message).emit(options.log_format);
+    } else if let Err(e) = sinks.save_state(engine) {
+        let message = format!("Failed to save the state: {e}");
+        Diagnostic::error("state_failed", message).emit(options.log_format);
+    }
+    if options.report_on_abort {
+        if let Err(e) = write_report(options, engine) {
+            let message = format!("Failed to write the accounts report: {e}");
+            Diagnostic::error("report_failed", message).emit(options.log_format);
+        }
+    }
+    process::exit(code);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use process_tx::transaction::{Transaction, Type};
+    use std::io;
+    use std::thread;
+
+    // Yields its data, then fails every read like a disk or network error
+    struct FailingReader<'a>(&'a [u8]);
+
+    impl Read for FailingReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(io::Error::other("device lost"));
+            }
+            self.0.read(buf)
+        }
+    }
+
+    // Test that a read error stops the input, keeping the records read before it
+    #[test]
+    fn test_read_error_keeps_processed_accounts() {
+        let options = Options::default();
+        let mut rdr = csv::Reader::from_reader(FailingReader(
+            b"type,client,tx,amount\n\
+              deposit,1,1,10.0\n\
+              deposit,2,2,5.0\n\
+              withdrawal,1,3,4.0\n",
+        ));
+        let headers = rdr.headers().expect("Failed to read header").clone();
+        let schema = Schema::detect(headers, false).expect("Invalid schema");
+        let engine = build_engine(&options).expect("Failed to build engine");
+        let mut run = Run::start(&options, engine, schema).expect("Failed to start run");
+
+        let error = run
+            .read(&mut rdr, "input.csv")
+            .expect("Failed to process records");
+        assert!(error.is_some_and(|e| e.is_io_error()));
+        assert_eq!(run.records, 3);
+        assert_eq!(run.counts.successful, 3);
+        let available: Vec<(u16, f64)> = run
+            .engine
+            .accounts_by_client()
+            .map(|account| (account.client, account.available))
+            .collect();
+        assert_eq!(available, [(1, 6.0), (2, 5.0)]);
+    }
+
+    // Test that only the rows processed slower than --warn-slow-rows are
+    // counted as slow, using a handler that sleeps
+    #[test]
+    fn test_warn_slow_rows() {
+        let options = Options {
+            warn_slow_rows: Some(Duration::from_millis(100)),
+            ..Options::default()
+        };
+        let mut rdr = csv::Reader::from_reader(
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             slow,1,2,\n\
+             deposit,1,3,5.0\n"
+                .as_bytes(),
+        );
+        let headers = rdr.headers().expect("Failed to read header").clone();
+        let schema = Schema::detect(headers, false).expect("Invalid schema");
+        let mut engine = build_engine(&options).expect("Failed to build engine");
+        engine.register_handler(
+            Type::Custom("slow".to_string()),
+            |_: &mut engine::Engine, _: &Transaction| {
+                thread::sleep(Duration::from_millis(200));
+                Ok(())
+            },
+        );
+        let mut run = Run::start(&options, engine, schema).expect("Failed to start run");
+
+        run.read(&mut rdr, "input.csv")
+            .expect("Failed to process records");
+        assert_eq!((run.counts.successful, run.counts.slow), (3, 1));
+    }
+
+    // Test that the rejected rows of each input file carry its name and its
+    // own line numbers
+    #[test]
+    fn test_rejects_record_provenance_per_file() {
+        let dir = tempfile::tempdir().expect("Failed to create directory");
+        let path = dir.path().join("rejects.csv");
+        let options = Options {
+            rejects: Some(path.to_str().expect("Path not valid UTF-8").to_string()),
+            ..Options::default()
+        };
+        let mut day1 = csv::Reader::from_reader(
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             withdrawal,1,2,50.0\n"
+                .as_bytes(),
+        );
+        let mut day2 = csv::Reader::from_reader(
+            "type,client,tx,amount\n\
+             withdrawal,1,3,20.0\n\
+             deposit,2,4,5.0\n"
+                .as_bytes(),
+        );
+        let headers = day1.headers().expect("Failed to read header").clone();
+        let schema = Schema::detect(headers, false).expect("Invalid schema");
+        let engine = build_engine(&options).expect("Failed to build engine");
+        let mut run = Run::start(&options, engine, schema).expect("Failed to start run");
+
+        run.read(&mut day1, "day1.csv")
+            .expect("Failed to process records");
+        day2.headers().expect("Failed to read header");
+        run.read(&mut day2, "day2.csv")
+            .expect("Failed to process records");
+        run.sinks.flush().expect("Failed to flush");
+
+        let rejects = fs::read_to_string(path).expect("Failed to read rejects file");
+        let locations: Vec<(&str, &str)> = rejects
+            .lines()
+            .skip(1)
+            .map(|row| {
+                let fields: Vec<&str> = row.split(',').collect();
+                (fields[7], fields[8])
+            })
+            .collect();
+        assert_eq!(locations, [("day1.csv", "3"), ("day2.csv", "2")]);
+    }
+}
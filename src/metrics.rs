@@ -0,0 +1,330 @@
+//! Prometheus endpoint of `--metrics-addr`, available with the `metrics`
+//! feature.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::log::{Diagnostic, LogFormat};
+use process_tx::engine::{Engine, Outcome};
+use process_tx::transaction::Type as TransactionType;
+
+// Pause between two polls of the listener for connections or shutdown
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Counters of a run, updated by the processing loop and read by the
+/// metrics endpoint.
+pub struct Metrics {
+    started: Instant,
+    rows: AtomicU64,
+    accounts: AtomicU64,
+    // Number of accounts of the engine after the last settled row
+    known_accounts: AtomicU64,
+    open_disputes: AtomicU64,
+    // Only locked for rejected rows, whose codes are not known in advance
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Metrics {
+            started: Instant::now(),
+            rows: AtomicU64::new(0),
+            accounts: AtomicU64::new(0),
+            known_accounts: AtomicU64::new(0),
+            open_disputes: AtomicU64::new(0),
+            errors: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Count a record read from the input.
+    pub fn record_row(&self) {
+        self.rows.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Start from the accounts and open disputes of `engine`, such as the
+    /// state restored by `--resume` or `--wal-recover`, which are not counted
+    /// as created by the run.
+    pub fn start_from(&self, engine: &Engine) {
+        let accounts = u64::try_from(engine.account_count()).unwrap_or(u64::MAX);
+        self.known_accounts.store(accounts, Ordering::Relaxed);
+        let open = engine
+            .accounts_by_client()
+            .map(|account| u64::from(account.open_disputes))
+            .sum();
+        self.open_disputes.store(open, Ordering::Relaxed);
+    }
+
+    /// Account for a settled row, given the engine after it.
+    pub fn record_outcome(&self, outcome: &Outcome, engine: &Engine) {
+        let accounts = u64::try_from(engine.account_count()).unwrap_or(u64::MAX);
+        let known = self.known_accounts.swap(accounts, Ordering::Relaxed);
+        self.accounts
+            .fetch_add(accounts.saturating_sub(known), Ordering::Relaxed);
+
+        // Only applied dispute rows open a dispute, and resolve and
+        // chargeback rows settle one, possibly opened before the run
+        match (&outcome.transaction.t_type, &outcome.result) {
+            (TransactionType::Dispute, Ok(())) => {
+                self.open_disputes.fetch_add(1, Ordering::Relaxed);
+            }
+            (TransactionType::Resolve | TransactionType::Chargeback, Ok(())) => {
+                let _ =
+                    self.open_disputes
+                        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |open| {
+                            Some(open.saturating_sub(1))
+                        });
+            }
+            _ => {}
+        }
+
+        if let Err(e) = &outcome.result {
+            let mut errors = self.errors.lock().unwrap_or_else(PoisonError::into_inner);
+            *errors.entry(e.code()).or_default() += 1;
+        }
+    }
+
+    // Body of `/metrics` in the Prometheus text format
+    fn render(&self) -> String {
+        let rows = self.rows.load(Ordering::Relaxed);
+        let elapsed = self.started.elapsed().as_secs_f64();
+        #[allow(clippy::cast_precision_loss)]
+        let rate = if elapsed > 0.0 {
+            rows as f64 / elapsed
+        } else {
+            0.0
+        };
+
+        let mut body = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: &dyn std::fmt::Display| {
+            let _ = writeln!(body, "# HELP {name} {help}");
+            let _ = writeln!(body, "# TYPE {name} {kind}");
+            let _ = writeln!(body, "{name} {value}");
+        };
+        metric(
+            "process_tx_rows_processed_total",
+            "counter",
+            "Records read from the input.",
+            &rows,
+        );
+        metric(
+            "process_tx_accounts_created_total",
+            "counter",
+            "Accounts created by the engine.",
+            &self.accounts.load(Ordering::Relaxed),
+        );
+        metric(
+            "process_tx_disputes_open",
+            "gauge",
+            "Disputes currently open.",
+            &self.open_disputes.load(Ordering::Relaxed),
+        );
+        metric(
+            "process_tx_rows_per_second",
+            "gauge",
+            "Average records read per second since the start.",
+            &rate,
+        );
+        let _ = writeln!(
+            body,
+            "# HELP process_tx_errors_total Rows rejected by the engine, by error code."
+        );
+        let _ = writeln!(body, "# TYPE process_tx_errors_total counter");
+        let errors = self.errors.lock().unwrap_or_else(PoisonError::into_inner);
+        for (code, count) in errors.iter() {
+            let _ = writeln!(body, "process_tx_errors_total{{code=\"{code}\"}} {count}");
+        }
+        body
+    }
+}
+
+/// HTTP server exposing [`Metrics`] on `/metrics`, started with
+/// `--metrics-addr`.
+///
+/// Connections are served one at a time on a background thread, which stops
+/// when the server is dropped.
+pub struct Server {
+    addr: SocketAddr,
+    metrics: Arc<Metrics>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Server {
+    /// Listen on `addr` and serve the metrics of a new run.
+    pub fn start(addr: SocketAddr, log_format: LogFormat) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        listener.set_nonblocking(true)?;
+        let metrics = Arc::new(Metrics::new());
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let metrics = Arc::clone(&metrics);
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || serve(&listener, &metrics, &stop, log_format))
+        };
+        Ok(Server {
+            addr,
+            metrics,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Address listened on, with the actual port when bound to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn serve(listener: &TcpListener, metrics: &Metrics, stop: &AtomicBool, log_format: LogFormat) {
+    while !stop.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(e) = respond(stream, metrics) {
+                    let message = format!("Failed to serve metrics: {e}");
+                    Diagnostic::error("metrics_failed", message).emit(log_format);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+            Err(e) => {
+                let message = format!("Failed to accept a metrics connection: {e}");
+                Diagnostic::error("metrics_failed", message).emit(log_format);
+            }
+        }
+    }
+}
+
+fn respond(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+
+    let (status, body) = if request_line.starts_with("GET /metrics ") {
+        ("200 OK", metrics.render())
+    } else {
+        ("404 Not Found", String::new())
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use process_tx::transaction::Transaction;
+    use std::io::Read;
+
+    // Submit `input` to `engine` and record the outcomes in `metrics`
+    fn record(metrics: &Metrics, engine: &mut Engine, input: &str) {
+        for transaction in csv::Reader::from_reader(input.as_bytes()).deserialize() {
+            let transaction: Transaction = transaction.expect("Failed to parse transaction");
+            for outcome in engine.submit(transaction) {
+                metrics.record_outcome(&outcome, engine);
+            }
+        }
+    }
+
+    fn scrape(server: &Server) -> String {
+        let mut stream = TcpStream::connect(server.local_addr()).expect("Failed to connect");
+        write!(stream, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .expect("Failed to send request");
+        let mut response = String::new();
+        stream
+            .read_to_string(&mut response)
+            .expect("Failed to read response");
+        response
+    }
+
+    #[test]
+    fn test_metrics_endpoint() {
+        let server = Server::start(
+            "127.0.0.1:0".parse().expect("Invalid address"),
+            LogFormat::Text,
+        )
+        .expect("Failed to start server");
+        let metrics = server.metrics();
+        for _ in 0..3 {
+            metrics.record_row();
+        }
+        let mut engine = Engine::new();
+        record(
+            metrics,
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             dispute,1,1,\n\
+             withdrawal,2,2,1.0\n",
+        );
+
+        let response = scrape(&server);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\nprocess_tx_rows_processed_total 3\n"));
+        assert!(response.contains("\nprocess_tx_accounts_created_total 2\n"));
+        assert!(response.contains("\nprocess_tx_disputes_open 1\n"));
+        assert!(response.contains("\nprocess_tx_errors_total{code=\"E_INSUFFICIENT_FUNDS\"} 1\n"));
+        assert!(response.contains("\nprocess_tx_rows_per_second "));
+    }
+
+    // Test that disputes opened before the run, as restored by --resume, are
+    // counted by the gauge and can be settled without wrapping it around, and
+    // that restored accounts are not counted as created
+    #[test]
+    fn test_metrics_start_from_restored_state() {
+        let server = Server::start(
+            "127.0.0.1:0".parse().expect("Invalid address"),
+            LogFormat::Text,
+        )
+        .expect("Failed to start server");
+        let metrics = server.metrics();
+        let mut engine = Engine::new();
+        let restored = "type,client,tx,amount\n\
+                        deposit,1,1,10.0\n\
+                        deposit,2,2,10.0\n\
+                        dispute,1,1,\n\
+                        dispute,2,2,\n";
+        for transaction in csv::Reader::from_reader(restored.as_bytes()).deserialize() {
+            engine.submit(transaction.expect("Failed to parse transaction"));
+        }
+        metrics.start_from(&engine);
+        assert!(scrape(&server).contains("\nprocess_tx_disputes_open 2\n"));
+
+        record(
+            metrics,
+            &mut engine,
+            "type,client,tx,amount\n\
+             resolve,1,1,\n\
+             chargeback,2,2,\n\
+             deposit,3,3,1.0\n",
+        );
+        let response = scrape(&server);
+        assert!(response.contains("\nprocess_tx_disputes_open 0\n"));
+        assert!(response.contains("\nprocess_tx_accounts_created_total 1\n"));
+    }
+}
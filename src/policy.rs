@@ -0,0 +1,304 @@
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use crate::error::Transaction as TransactionError;
+use crate::transaction::{Amount, Type as TransactionType};
+
+/// Business rules applied by the engine on top of the core transaction semantics.
+///
+/// Every rule is disabled by default so that a default policy reproduces the
+/// plain deposit/withdrawal/dispute behavior, in which a chargeback locks the
+/// account.
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    /// Reject any single deposit strictly above this amount.
+    pub max_single_deposit: Option<Amount>,
+    /// Reject deposits that would push a client's cumulative deposits for the
+    /// current run strictly above this amount.
+    pub max_total_deposits_per_client: Option<Amount>,
+    /// Whether withdrawals are allowed while the account has an open dispute.
+    pub withdrawals_during_dispute: WithdrawalsDuringDispute,
+    /// What a chargeback does when the disputed funds were already withdrawn.
+    pub on_chargeback_overdraw: ChargebackOverdraw,
+    /// Hold at most the available funds when a deposit is disputed, tracking
+    /// the rest as the account's dispute shortfall instead of driving the
+    /// available funds negative.
+    pub dispute_clamp_to_available: bool,
+    /// Maximum number of dispute, resolve and chargeback rows referencing a
+    /// transaction not seen yet that are kept pending until it arrives,
+    /// instead of being rejected right away. 0 disables the buffer.
+    pub reference_grace: usize,
+    /// Reject deposits and withdrawals once this many transactions are
+    /// stored, bounding the memory used by the engine.
+    pub max_transactions: Option<usize>,
+    /// Reject transactions of new clients once this many accounts exist.
+    pub max_accounts: Option<usize>,
+    /// Reject disputing a deposit again once it was disputed and settled
+    /// this many times after its first dispute.
+    pub max_redisputes: Option<u32>,
+    /// Credit deposits to the pending funds of the account, moving them to
+    /// the available funds only once a settle row for the deposit arrives.
+    pub pending_deposits: bool,
+    /// Skip deposits and withdrawals of an amount strictly below this one as
+    /// successful no-ops that neither create an account nor store the
+    /// transaction. Zero amounts are skipped too, instead of being rejected.
+    pub dust_threshold: Option<Amount>,
+    /// Reject deposits that would push an account's total funds strictly
+    /// above this amount.
+    pub max_balance: Option<Amount>,
+    /// Fees charged on top of successful deposits and withdrawals. Crediting
+    /// a fee account is checked like a deposit to it, against the open, lock
+    /// and balance policies, and a fee that cannot be credited rejects the
+    /// transaction charging it.
+    pub fees: FeeSchedule,
+    /// Reject a resolve that would leave the held funds of the account
+    /// negative, which only an inconsistent state can cause, instead of
+    /// applying it.
+    pub strict_held: bool,
+    /// Reject disputes of a client that already has this many disputes
+    /// open, until one of them is resolved or charged back.
+    pub max_open_disputes_per_client: Option<u32>,
+    /// Which chargebacks lock the account. A chargeback that does not lock
+    /// it still reverses the disputed funds.
+    pub lock_on_chargeback: LockOnChargeback,
+    /// Lock the account on a chargeback that brings its cumulative
+    /// chargeback losses strictly above this amount, whatever
+    /// `lock_on_chargeback` says.
+    pub lock_on_cumulative_loss: Option<Amount>,
+    /// Reject every transaction of a client whose account was not created
+    /// by an `open` transaction, instead of creating the account on its
+    /// first transaction.
+    pub require_explicit_open: bool,
+    /// Round the amounts of deposits and withdrawals half-up to two decimal
+    /// places before applying them. An amount rounding to zero is rejected
+    /// as non-positive.
+    pub round_to_cents: bool,
+    /// Clients whose transactions are all rejected, without creating their
+    /// account. Accounts they already have are kept unchanged.
+    pub deny_clients: HashSet<u16>,
+    /// Accept deposits on locked accounts, for example to settle a debt.
+    /// Every other transaction of a locked account is still rejected.
+    pub allow_deposits_when_locked: bool,
+    /// Reject the transactions of a client once it has submitted this many
+    /// during the run, whether they were applied or rejected.
+    pub max_transactions_per_client: Option<usize>,
+    /// Whether a withdrawal rejected for insufficient funds counts as an
+    /// error, or as an expected rejection skipped with a warning.
+    pub insufficient_funds: InsufficientFunds,
+}
+
+/// Fees charged by the engine, as an ordered list of rules of which the
+/// first matching a transaction applies.
+///
+/// Listing the rules with the highest threshold first gives tiered fees.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FeeSchedule {
+    pub rules: Vec<FeeRule>,
+}
+
+impl FeeSchedule {
+    /// The rule charging a fee on a transaction of type `t_type` and amount
+    /// `amount`, if any.
+    #[must_use]
+    pub fn rule_for(&self, t_type: &TransactionType, amount: Amount) -> Option<&FeeRule> {
+        self.rules
+            .iter()
+            .find(|rule| rule.applies_to == *t_type && amount > rule.threshold)
+    }
+}
+
+/// Fee of a [`FeeSchedule`], deducted from the available and total funds of
+/// the account on top of the transaction itself.
+///
+/// A transaction whose account cannot pay its fee is rejected as a whole with
+/// [`InsufficientFunds`](crate::error::Transaction::InsufficientFunds). Only
+/// deposit and withdrawal rules ever match, as other rows move no funds of
+/// their own.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeeRule {
+    pub applies_to: TransactionType,
+    /// Amount strictly above which the fee is charged.
+    pub threshold: Amount,
+    /// Share of the transaction amount charged, such as `0.01` for 1%.
+    pub rate: Amount,
+    /// Account credited with the fee, created if needed and credited even
+    /// when locked. Without one the fee leaves the engine.
+    pub fee_account: Option<u16>,
+}
+
+impl FromStr for FeeRule {
+    type Err = String;
+
+    /// Parse `<deposit|withdrawal>:<threshold>:<rate>[:<fee account>]`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid fee rule '{s}', expected <deposit|withdrawal>:<threshold>:<rate>[:<fee account>]"
+            )
+        };
+        let mut parts = s.split(':');
+        let applies_to = match parts.next() {
+            Some("deposit") => TransactionType::Deposit,
+            Some("withdrawal") => TransactionType::Withdrawal,
+            _ => return Err(invalid()),
+        };
+        let mut amount = || {
+            parts
+                .next()
+                .and_then(|part| part.parse::<Amount>().ok())
+                .filter(|amount| amount.is_finite() && *amount >= 0.0)
+                .ok_or_else(invalid)
+        };
+        let threshold = amount()?;
+        let rate = amount()?;
+        let fee_account = match parts.next() {
+            Some(client) => Some(client.parse().map_err(|_| invalid())?),
+            None => None,
+        };
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(FeeRule {
+            applies_to,
+            threshold,
+            rate,
+            fee_account,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WithdrawalsDuringDispute {
+    /// Withdrawals are checked against the available funds only.
+    #[default]
+    Allow,
+    /// Any open dispute blocks every withdrawal until all disputes settle.
+    Block,
+}
+
+impl FromStr for WithdrawalsDuringDispute {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(WithdrawalsDuringDispute::Allow),
+            "block" => Ok(WithdrawalsDuringDispute::Block),
+            other => Err(format!(
+                "invalid withdrawal policy '{other}', expected one of: allow, block"
+            )),
+        }
+    }
+}
+
+/// How a withdrawal rejected for insufficient funds is counted. The
+/// withdrawal is rejected either way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InsufficientFunds {
+    /// Counted as an error like any other rejection.
+    #[default]
+    Error,
+    /// Counted as skipped, for pipelines where such withdrawals are expected.
+    Warn,
+}
+
+impl InsufficientFunds {
+    /// Whether `error`, returned for a transaction of type `t_type`, is a
+    /// withdrawal skipped for insufficient funds rather than an error.
+    #[must_use]
+    pub fn skips(self, t_type: &TransactionType, error: &TransactionError) -> bool {
+        self == InsufficientFunds::Warn
+            && *t_type == TransactionType::Withdrawal
+            && matches!(error, TransactionError::InsufficientFunds(_))
+    }
+}
+
+impl FromStr for InsufficientFunds {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(InsufficientFunds::Error),
+            "warn" => Ok(InsufficientFunds::Warn),
+            other => Err(format!(
+                "invalid insufficient funds policy '{other}', expected one of: error, warn"
+            )),
+        }
+    }
+}
+
+/// Chargebacks that lock the account. A chargeback that does not lock it
+/// still removes the funds and finalizes the dispute: the deposit cannot be
+/// disputed again.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum LockOnChargeback {
+    /// Every chargeback locks the account.
+    #[default]
+    Always,
+    /// Only a chargeback of an amount strictly above this one locks the
+    /// account.
+    AboveAmount(Amount),
+    /// No chargeback locks the account.
+    Never,
+}
+
+impl LockOnChargeback {
+    /// Whether a chargeback of `amount` locks the account.
+    #[must_use]
+    pub fn locks(self, amount: Amount) -> bool {
+        match self {
+            LockOnChargeback::Always => true,
+            LockOnChargeback::AboveAmount(threshold) => amount > threshold,
+            LockOnChargeback::Never => false,
+        }
+    }
+}
+
+impl FromStr for LockOnChargeback {
+    type Err = String;
+
+    /// Parse `always`, `never` or `above:<amount>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let threshold = s
+            .strip_prefix("above:")
+            .and_then(|amount| amount.parse::<Amount>().ok())
+            .filter(|amount| amount.is_finite() && *amount >= 0.0);
+        match (s, threshold) {
+            ("always", _) => Ok(LockOnChargeback::Always),
+            ("never", _) => Ok(LockOnChargeback::Never),
+            (_, Some(threshold)) => Ok(LockOnChargeback::AboveAmount(threshold)),
+            (other, None) => Err(format!(
+                "invalid chargeback lock policy '{other}', expected one of: always, never, above:<amount>"
+            )),
+        }
+    }
+}
+
+/// Handling of a chargeback on an account whose available funds are negative,
+/// because the charged-back deposit was already withdrawn.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChargebackOverdraw {
+    /// Reject the chargeback; the deposit stays disputed and its funds held.
+    Reject,
+    /// Apply the chargeback and leave the negative available balance.
+    #[default]
+    AllowNegative,
+    /// Apply the chargeback and write off the shortfall, so that available
+    /// funds end at zero.
+    ClampToZero,
+}
+
+impl FromStr for ChargebackOverdraw {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(ChargebackOverdraw::Reject),
+            "allow-negative" => Ok(ChargebackOverdraw::AllowNegative),
+            "clamp-to-zero" => Ok(ChargebackOverdraw::ClampToZero),
+            other => Err(format!(
+                "invalid chargeback overdraw policy '{other}', expected one of: reject, allow-negative, clamp-to-zero"
+            )),
+        }
+    }
+}
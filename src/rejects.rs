@@ -0,0 +1,101 @@
+use std::io::Write;
+
+use process_tx::error::Transaction as TransactionError;
+use process_tx::transaction::Transaction;
+
+/// CSV file of the rows rejected by the engine, written with `--rejects`.
+///
+/// Each row echoes the rejected transaction, followed by the error code and
+/// message, the input file and line it was read from, then the extra input
+/// columns captured with `--capture-extra`.
+pub struct Rejects<W: Write> {
+    writer: csv::Writer<W>,
+    extra_columns: Vec<String>,
+}
+
+impl<W: Write> Rejects<W> {
+    /// Start the file with its header, including the given extra columns.
+    pub fn new(writer: W, extra_columns: Vec<String>) -> csv::Result<Self> {
+        let mut writer = csv::Writer::from_writer(writer);
+        let header = [
+            "type", "client", "tx", "amount", "note", "code", "error", "file", "line",
+        ];
+        writer.write_record(
+            header
+                .iter()
+                .copied()
+                .chain(extra_columns.iter().map(String::as_str)),
+        )?;
+        Ok(Rejects {
+            writer,
+            extra_columns,
+        })
+    }
+
+    pub fn write(
+        &mut self,
+        transaction: &Transaction,
+        error: &TransactionError,
+    ) -> csv::Result<()> {
+        let amount = transaction
+            .amount
+            .map(|amount| amount.to_string())
+            .unwrap_or_default();
+        let provenance = transaction.provenance.as_ref();
+        let fields = [
+            transaction.t_type.as_str(),
+            &transaction.client.to_string(),
+            &transaction.tx.to_string(),
+            &amount,
+            transaction.note.as_deref().unwrap_or_default(),
+            error.code(),
+            &error.to_string(),
+            provenance.map_or("", |provenance| &provenance.file),
+            &provenance.map_or(String::new(), |provenance| provenance.line.to_string()),
+        ];
+        let extra = self
+            .extra_columns
+            .iter()
+            .map(|column| transaction.extra.get(column).map_or("", String::as_str));
+        self.writer
+            .write_record(fields.iter().copied().chain(extra))
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use process_tx::input::Provenance;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_rejects_echo_extra_columns() {
+        let mut rejects = Rejects::new(Vec::new(), vec!["memo".into(), "channel".into()])
+            .expect("Failed to write header");
+        let transaction = Transaction {
+            extra: HashMap::from([("memo".into(), "rent, march".into())]),
+            provenance: Some(Provenance {
+                file: "day1.csv".into(),
+                line: 6,
+            }),
+            ..Transaction::withdrawal(2, 5, 1.5)
+        };
+        rejects
+            .write(&transaction, &TransactionError::InsufficientFunds(2))
+            .expect("Failed to write reject");
+        rejects.flush().expect("Failed to flush");
+
+        let output = String::from_utf8(rejects.writer.into_inner().expect("Failed to finish"))
+            .expect("Output not valid UTF-8");
+        assert_eq!(
+            output,
+            "type,client,tx,amount,note,code,error,file,line,memo,channel\n\
+             withdrawal,2,5,1.5,,E_INSUFFICIENT_FUNDS,Insufficient funds for client 2,day1.csv,6,\
+             \"rent, march\",\n"
+        );
+    }
+}
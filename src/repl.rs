@@ -0,0 +1,193 @@
+use std::io::{BufRead, Write};
+
+use process_tx::engine::Engine;
+use process_tx::input::parse_amount;
+use process_tx::report;
+use process_tx::transaction::{Amount, Transaction, Type as TransactionType};
+
+use crate::trace::describe;
+
+pub const COMMANDS_HELP: &str = "\
+Available commands:
+  account <client>                  Show the balances of an account
+  tx <tx>                           Show a stored transaction and its dispute state
+  deposit <tx> <client> <amount>    Process a deposit
+  withdrawal <tx> <client> <amount> Process a withdrawal
+  dispute <tx> <client>             Process a dispute
+  resolve <tx> <client>             Process a resolve
+  chargeback <tx> <client>          Process a chargeback
+  settle <tx> <client>              Release a pending deposit
+  open <tx> <client>                Open an account
+  refund <tx> <client>              Refund a deposit
+  report                            Print the accounts report
+  help                              Show this list
+  quit                              Leave the REPL";
+
+/// Read commands line by line from `input` and apply them to `engine`,
+/// writing the results to `output`, until `quit` or the end of the input.
+pub fn run<R, W>(engine: &mut Engine, input: R, mut output: W) -> std::io::Result<()>
+where
+    R: BufRead,
+    W: Write,
+{
+    for line in input.lines() {
+        let line = line?;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => {}
+            ["quit" | "exit"] => break,
+            ["help"] => writeln!(output, "{COMMANDS_HELP}")?,
+            ["report"] => {
+                report::write_accounts(
+                    &mut output,
+                    engine.accounts_by_client(),
+                    &report::Options::default(),
+                )?;
+            }
+            ["account", client] => match client.parse() {
+                Ok(client) => match engine.account(client) {
+                    Some(account) => writeln!(output, "{}", describe(account))?,
+                    None => writeln!(output, "account {client} not found")?,
+                },
+                Err(_) => writeln!(output, "invalid client id '{client}'")?,
+            },
+            ["tx", tx] => match tx.parse() {
+                Ok(tx) => match engine.transaction(tx) {
+                    Some(transaction) => writeln!(
+                        output,
+                        "type={} client={} tx={tx} amount={} disputed={}",
+                        transaction.kind.as_str(),
+                        transaction.client,
+                        transaction.amount,
+                        transaction.is_disputed()
+                    )?,
+                    None => writeln!(output, "tx {tx} not found")?,
+                },
+                Err(_) => writeln!(output, "invalid transaction id '{tx}'")?,
+            },
+            [verb, args @ ..] => match parse_transaction(verb, args) {
+                Ok(Some(transaction)) => {
+                    let client = transaction.client;
+                    match engine.process_transaction(transaction) {
+                        Ok(()) => match engine.account(client) {
+                            Some(account) => writeln!(output, "ok: {}", describe(account))?,
+                            None => writeln!(output, "ok")?,
+                        },
+                        Err(e) => writeln!(output, "error: [{}] {e}", e.code())?,
+                    }
+                }
+                Ok(None) => writeln!(output, "unknown command '{verb}'\n{COMMANDS_HELP}")?,
+                Err(e) => writeln!(output, "{e}")?,
+            },
+        }
+    }
+    output.flush()
+}
+
+// Build the transaction for a mutating verb, or `None` if the verb is unknown
+fn parse_transaction(verb: &str, args: &[&str]) -> Result<Option<Transaction>, String> {
+    let t_type = match verb {
+        "deposit" => TransactionType::Deposit,
+        "withdrawal" => TransactionType::Withdrawal,
+        "dispute" => TransactionType::Dispute,
+        "resolve" => TransactionType::Resolve,
+        "chargeback" => TransactionType::Chargeback,
+        "settle" => TransactionType::Settle,
+        "open" => TransactionType::OpenAccount,
+        "refund" => TransactionType::Refund,
+        _ => return Ok(None),
+    };
+    let with_amount = matches!(
+        t_type,
+        TransactionType::Deposit | TransactionType::Withdrawal
+    );
+    let (tx, client, amount) = match (args, with_amount) {
+        ([tx, client, amount], true) => (tx, client, Some(amount)),
+        ([tx, client], false) => (tx, client, None),
+        (_, true) => return Err(format!("usage: {verb} <tx> <client> <amount>")),
+        (_, false) => return Err(format!("usage: {verb} <tx> <client>")),
+    };
+    // Amounts follow the syntax of the input file
+    let amount: Option<Amount> = amount
+        .map(|amount| parse_amount(amount, false).map_err(|e| e.to_string()))
+        .transpose()?;
+    let client = client
+        .parse()
+        .map_err(|_| format!("invalid client id '{client}'"))?;
+    let tx = tx
+        .parse()
+        .map_err(|_| format!("invalid transaction id '{tx}'"))?;
+    Ok(Some(Transaction::new(t_type, client, tx, amount)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn run_script(engine: &mut Engine, script: &str) -> Vec<String> {
+        let mut output = Vec::new();
+        run(engine, Cursor::new(script), &mut output).expect("Failed to run REPL");
+        String::from_utf8(output)
+            .expect("Output not valid UTF-8")
+            .lines()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_repl_queries_and_mutations() {
+        let mut engine = Engine::new();
+        let lines = run_script(
+            &mut engine,
+            "deposit 1001 42 100.5\n\
+             \n\
+             account 42\n\
+             dispute 1001 42\n\
+             tx 1001\n\
+             withdrawal 1002 42 10\n\
+             report\n\
+             quit\n\
+             account 42\n",
+        );
+        assert_eq!(
+            lines,
+            [
+                "ok: client=42 available=100.5 held=0.0 total=100.5 locked=false",
+                "client=42 available=100.5 held=0.0 total=100.5 locked=false",
+                "ok: client=42 available=0.0 held=100.5 total=100.5 locked=false",
+                "type=deposit client=42 tx=1001 amount=100.5 disputed=true",
+                "error: [E_INSUFFICIENT_FUNDS] Insufficient funds for client 42",
+                "client,available,held,total,locked",
+                "42,0.0,100.5,100.5,false",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_repl_reports_invalid_commands() {
+        let mut engine = Engine::new();
+        let lines = run_script(
+            &mut engine,
+            "account 7\n\
+             tx 9\n\
+             account x\n\
+             deposit 1 2\n\
+             dispute 1 2\n\
+             deposit 1 2 1e3\n\
+             frobnicate\n",
+        );
+        assert_eq!(lines[0], "account 7 not found");
+        assert_eq!(lines[1], "tx 9 not found");
+        assert_eq!(lines[2], "invalid client id 'x'");
+        assert_eq!(lines[3], "usage: deposit <tx> <client> <amount>");
+        assert_eq!(
+            lines[4],
+            "error: [E_TX_NOT_FOUND] Transaction ID 1 not found for client 2"
+        );
+        // Amounts are parsed like those of the input file
+        assert!(lines[5].starts_with("Invalid amount '1e3', exponents are not allowed"));
+        assert_eq!(lines[6], "unknown command 'frobnicate'");
+        assert_eq!(lines[7..], COMMANDS_HELP.lines().collect::<Vec<_>>());
+    }
+}
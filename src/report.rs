@@ -0,0 +1,756 @@
+use std::borrow::Cow;
+use std::io::{BufWriter, Write};
+use std::str::FromStr;
+
+use csv::{Terminator, WriterBuilder};
+
+use crate::account::Account;
+use crate::transaction::{StoredTx, TxStatus, Type};
+
+#[cfg(feature = "parquet")]
+mod columnar;
+mod compare;
+mod delta;
+mod table;
+
+#[cfg(feature = "parquet")]
+pub use columnar::{accounts_schema, write_accounts_parquet, AMOUNT_PRECISION, AMOUNT_SCALE};
+pub use compare::{compare_reports, FieldDelta, ReportDiff};
+pub use delta::{account_deltas, read_balances, write_account_deltas, AccountDelta, Balances};
+pub use table::write_accounts_table;
+
+/// File format of the accounts report.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    /// Aligned text table, for reading in a terminal.
+    Table,
+    /// Requires the `parquet` feature.
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            "table" => Ok(OutputFormat::Table),
+            #[cfg(feature = "parquet")]
+            "parquet" => Ok(OutputFormat::Parquet),
+            #[cfg(not(feature = "parquet"))]
+            "parquet" => {
+                Err("parquet output requires building with --features parquet".to_string())
+            }
+            other => Err(format!(
+                "invalid output format '{other}', expected one of: csv, table, parquet"
+            )),
+        }
+    }
+}
+
+impl OutputFormat {
+    /// Whether the report is text, which can be written to `stdout` and
+    /// supports every column and amount formatting option.
+    #[must_use]
+    pub fn is_text(self) -> bool {
+        match self {
+            OutputFormat::Csv | OutputFormat::Table => true,
+            #[cfg(feature = "parquet")]
+            OutputFormat::Parquet => false,
+        }
+    }
+}
+
+/// Controls how the `locked` column is rendered in the accounts report.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LockedFormat {
+    /// `true` / `false`
+    #[default]
+    Bool,
+    /// `1` / `0`
+    Binary,
+    /// `yes` / `no`
+    YesNo,
+}
+
+impl LockedFormat {
+    #[must_use]
+    pub fn render(self, locked: bool) -> &'static str {
+        match (self, locked) {
+            (LockedFormat::Bool, true) => "true",
+            (LockedFormat::Bool, false) => "false",
+            (LockedFormat::Binary, true) => "1",
+            (LockedFormat::Binary, false) => "0",
+            (LockedFormat::YesNo, true) => "yes",
+            (LockedFormat::YesNo, false) => "no",
+        }
+    }
+}
+
+impl FromStr for LockedFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bool" => Ok(LockedFormat::Bool),
+            "binary" => Ok(LockedFormat::Binary),
+            "yesno" => Ok(LockedFormat::YesNo),
+            other => Err(format!(
+                "invalid locked format '{other}', expected one of: bool, binary, yesno"
+            )),
+        }
+    }
+}
+
+/// Quoting of the fields of the accounts report.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Quote {
+    /// Never quote, even fields containing delimiters or quotes.
+    Never,
+    /// Quote every field, including the header.
+    Always,
+    /// Quote only fields that would otherwise be ambiguous.
+    #[default]
+    Necessary,
+}
+
+impl Quote {
+    fn style(self) -> csv::QuoteStyle {
+        match self {
+            Quote::Never => csv::QuoteStyle::Never,
+            Quote::Always => csv::QuoteStyle::Always,
+            Quote::Necessary => csv::QuoteStyle::Necessary,
+        }
+    }
+}
+
+impl FromStr for Quote {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(Quote::Never),
+            "always" => Ok(Quote::Always),
+            "necessary" => Ok(Quote::Necessary),
+            other => Err(format!(
+                "invalid quote style '{other}', expected one of: never, always, necessary"
+            )),
+        }
+    }
+}
+
+// Each output toggle is independent of the others
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub locked_format: LockedFormat,
+    /// Print amounts with exactly four decimal places instead of as few as
+    /// needed.
+    pub fixed_decimals: bool,
+    /// Character separating the integer and fractional parts of amounts.
+    pub decimal_separator: char,
+    pub quote: Quote,
+    /// Leave out every account without any successfully applied transaction,
+    /// including those holding funds or locked.
+    pub skip_inactive: bool,
+    /// Leave out unlocked accounts whose balances are all zero.
+    pub nonzero_only: bool,
+    /// Append the `pending` column, for runs with pending deposits. Text
+    /// formats only.
+    pub pending: bool,
+    /// Append the `last_tx`, `peak_total`, `peak_held`, `open_disputes` and
+    /// `chargeback_losses` columns. Text formats only.
+    pub extended: bool,
+    /// End rows with `\r\n` instead of `\n`. CSV only.
+    pub crlf: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            locked_format: LockedFormat::default(),
+            fixed_decimals: false,
+            decimal_separator: '.',
+            quote: Quote::default(),
+            skip_inactive: false,
+            nonzero_only: false,
+            pending: false,
+            extended: false,
+            crlf: false,
+        }
+    }
+}
+
+impl Options {
+    /// Whether `account` is part of the report.
+    ///
+    /// Accounts that only had rejected transactions and hold nothing are
+    /// never reported.
+    #[must_use]
+    pub fn includes(&self, account: &Account) -> bool {
+        if account.is_inactive() && (self.skip_inactive || account.is_zero()) {
+            return false;
+        }
+        !self.nonzero_only || !account.is_zero()
+    }
+
+    fn format_amount(&self, value: f64) -> String {
+        let amount = if self.fixed_decimals {
+            Account::format_value_fixed(value)
+        } else {
+            Account::format_value(value)
+        };
+        if self.decimal_separator == '.' {
+            amount
+        } else {
+            amount.replace('.', &self.decimal_separator.to_string())
+        }
+    }
+
+    fn header(&self) -> Vec<&'static str> {
+        let mut header = vec!["client", "available", "held", "total", "locked"];
+        if self.pending {
+            header.push("pending");
+        }
+        if self.extended {
+            header.extend([
+                "last_tx",
+                "peak_total",
+                "peak_held",
+                "open_disputes",
+                "chargeback_losses",
+            ]);
+        }
+        header
+    }
+
+    // Fields of the row of `account`, in the order of the header
+    fn row(&self, account: &Account) -> Vec<String> {
+        let mut row = vec![
+            account.client.to_string(),
+            self.format_amount(account.available),
+            self.format_amount(account.held),
+            self.format_amount(account.total),
+            self.locked_format.render(account.locked).to_string(),
+        ];
+        if self.pending {
+            row.push(self.format_amount(account.pending));
+        }
+        if self.extended {
+            let last_tx = account.last_tx.map(|tx| tx.to_string());
+            row.extend([
+                last_tx.unwrap_or_default(),
+                self.format_amount(account.peak_total),
+                self.format_amount(account.peak_held),
+                account.open_disputes.to_string(),
+                self.format_amount(account.chargeback_losses),
+            ]);
+        }
+        row
+    }
+}
+
+// Number of rows after which the output is flushed, so downstream consumers
+// receive data while the report is still being generated.
+const FLUSH_INTERVAL: usize = 4_096;
+
+/// Write the accounts report as CSV, in the order the accounts are given.
+///
+/// Every row, the header included, ends with a single `\n` whatever the
+/// platform, or `\r\n` with [`Options::crlf`], so the report always ends
+/// with exactly one line terminator.
+///
+/// Rows are streamed through a buffered writer and flushed periodically, so the
+/// memory needed for reporting does not grow with the number of accounts.
+///
+/// # Errors
+///
+/// Returns an error if writing to the underlying writer fails.
+pub fn write_accounts<'a, W, I>(writer: W, accounts: I, options: &Options) -> csv::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = &'a Account>,
+{
+    let mut wtr = WriterBuilder::new()
+        .quote_style(options.quote.style())
+        .terminator(if options.crlf {
+            Terminator::CRLF
+        } else {
+            Terminator::Any(b'\n')
+        })
+        .from_writer(BufWriter::new(writer));
+    wtr.write_record(options.header())?;
+    let accounts = accounts
+        .into_iter()
+        .filter(|account| options.includes(account));
+    for (row, account) in accounts.enumerate() {
+        if row > 0 && row % FLUSH_INTERVAL == 0 {
+            wtr.flush()?;
+        }
+        wtr.write_record(options.row(account))?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Export transactions in the input CSV format, so the file can be processed again.
+///
+/// Amounts use the same four-decimal truncation as the accounts report, which
+/// keeps an export/import round trip stable. The `note` column is left empty
+/// for transactions without a memo.
+///
+/// The deposits and withdrawals come first, in the order they are given,
+/// followed by a `refund` row for each refunded deposit, a `dispute` row for
+/// each transaction under dispute or charged back, and a `chargeback` row for
+/// each charged back one, so that processing the export rebuilds the same
+/// balances and dispute state.
+///
+/// # Errors
+///
+/// Returns an error if writing to the underlying writer fails.
+pub fn write_transactions<'a, W, I>(writer: W, transactions: I) -> csv::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = (u32, Cow<'a, StoredTx>)>,
+{
+    let transactions: Vec<(u32, Cow<'a, StoredTx>)> = transactions.into_iter().collect();
+    let mut wtr = WriterBuilder::new().from_writer(BufWriter::new(writer));
+    wtr.write_record(["type", "client", "tx", "amount", "note"])?;
+    for (tx, transaction) in &transactions {
+        wtr.write_record([
+            transaction.kind.as_str(),
+            &transaction.client.to_string(),
+            &tx.to_string(),
+            &Account::format_value(transaction.amount),
+            transaction.note.as_deref().unwrap_or_default(),
+        ])?;
+    }
+    for follow_up in [Type::Refund, Type::Dispute, Type::Chargeback] {
+        for (tx, transaction) in &transactions {
+            let applies = match follow_up {
+                Type::Refund => transaction.refunded,
+                Type::Dispute => transaction.is_disputed() || transaction.is_charged_back(),
+                _ => transaction.is_charged_back(),
+            };
+            if applies {
+                let client = transaction.client.to_string();
+                wtr.write_record([follow_up.as_str(), &client, &tx.to_string(), "", ""])?;
+            }
+        }
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Write the status of transactions as CSV, in the order they are given,
+/// with amounts truncated to four decimal places like the accounts report.
+///
+/// # Errors
+///
+/// Returns an error if writing to the underlying writer fails.
+pub fn write_transaction_statuses<W, I>(writer: W, statuses: I) -> csv::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = TxStatus>,
+{
+    let mut wtr = WriterBuilder::new().from_writer(BufWriter::new(writer));
+    wtr.write_record([
+        "tx", "client", "type", "amount", "disputed", "disputes", "pending", "refunded",
+    ])?;
+    for status in statuses {
+        wtr.write_record([
+            &status.tx.to_string(),
+            &status.client.to_string(),
+            status.kind.as_str(),
+            &Account::format_value(status.amount),
+            &status.disputed.to_string(),
+            &status.disputes.to_string(),
+            &status.pending.to_string(),
+            &status.refunded.to_string(),
+        ])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::transaction::{DisputeState, Transaction};
+
+    #[test]
+    fn test_locked_format_render() {
+        assert_eq!(LockedFormat::Bool.render(true), "true");
+        assert_eq!(LockedFormat::Bool.render(false), "false");
+        assert_eq!(LockedFormat::Binary.render(true), "1");
+        assert_eq!(LockedFormat::Binary.render(false), "0");
+        assert_eq!(LockedFormat::YesNo.render(true), "yes");
+        assert_eq!(LockedFormat::YesNo.render(false), "no");
+    }
+
+    #[test]
+    fn test_write_accounts_keeps_iterator_order() {
+        let accounts: Vec<Account> = [3, 1, 2]
+            .into_iter()
+            .map(|client| Account {
+                client,
+                available: f64::from(client),
+                total: f64::from(client),
+                ..Default::default()
+            })
+            .collect();
+
+        let mut output = Vec::new();
+        write_accounts(&mut output, &accounts, &Options::default())
+            .expect("Failed to write report");
+
+        assert_eq!(
+            String::from_utf8(output).expect("Output not valid UTF-8"),
+            "client,available,held,total,locked\n\
+             3,3.0,0.0,3.0,false\n\
+             1,1.0,0.0,1.0,false\n\
+             2,2.0,0.0,2.0,false\n"
+        );
+    }
+
+    fn sample_accounts() -> Vec<Account> {
+        vec![
+            Account {
+                client: 1,
+                available: 1.5,
+                held: 0.0001,
+                total: 1.500_19,
+                ..Default::default()
+            },
+            Account {
+                client: 2,
+                available: -12.25,
+                held: 12.25,
+                total: 0.0,
+                locked: true,
+                ..Default::default()
+            },
+        ]
+    }
+
+    fn write_sample(options: &Options) -> String {
+        let mut output = Vec::new();
+        write_accounts(&mut output, &sample_accounts(), options).expect("Failed to write report");
+        String::from_utf8(output).expect("Output not valid UTF-8")
+    }
+
+    // Test that the default options keep the shortest decimals and minimal quoting
+    #[test]
+    fn test_write_accounts_default_format() {
+        assert_eq!(
+            write_sample(&Options::default()),
+            "client,available,held,total,locked\n\
+             1,1.5,0.0001,1.5001,false\n\
+             2,-12.25,12.25,0.0,true\n"
+        );
+    }
+
+    // Test that the report ends with exactly one `\n`, with or without rows
+    #[test]
+    fn test_write_accounts_single_trailing_newline() {
+        let report = write_sample(&Options::default());
+        assert!(report.ends_with("true\n"));
+        assert!(!report.contains('\r'));
+
+        let mut output = Vec::new();
+        write_accounts(&mut output, &[], &Options::default()).expect("Failed to write report");
+        assert_eq!(output, b"client,available,held,total,locked\n");
+    }
+
+    #[test]
+    fn test_write_accounts_fixed_decimals() {
+        let options = Options {
+            fixed_decimals: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            write_sample(&options),
+            "client,available,held,total,locked\n\
+             1,1.5000,0.0001,1.5001,false\n\
+             2,-12.2500,12.2500,0.0000,true\n"
+        );
+    }
+
+    #[test]
+    fn test_write_accounts_quote_styles() {
+        let options = Options {
+            quote: Quote::Always,
+            ..Default::default()
+        };
+        assert_eq!(
+            write_sample(&options),
+            "\"client\",\"available\",\"held\",\"total\",\"locked\"\n\
+             \"1\",\"1.5\",\"0.0001\",\"1.5001\",\"false\"\n\
+             \"2\",\"-12.25\",\"12.25\",\"0.0\",\"true\"\n"
+        );
+
+        // No field needs quoting, so `never` matches the default output
+        let options = Options {
+            quote: Quote::Never,
+            ..Default::default()
+        };
+        assert_eq!(write_sample(&options), write_sample(&Options::default()));
+    }
+
+    // Test that a comma separator quotes the amounts when necessary
+    #[test]
+    fn test_write_accounts_decimal_separator() {
+        let options = Options {
+            decimal_separator: ',',
+            ..Default::default()
+        };
+        assert_eq!(
+            write_sample(&options),
+            "client,available,held,total,locked\n\
+             1,\"1,5\",\"0,0001\",\"1,5001\",false\n\
+             2,\"-12,25\",\"12,25\",\"0,0\",true\n"
+        );
+    }
+
+    // Test fixed decimals combined with full quoting and another locked format
+    #[test]
+    fn test_write_accounts_combined_options() {
+        let options = Options {
+            locked_format: LockedFormat::Binary,
+            fixed_decimals: true,
+            quote: Quote::Always,
+            ..Default::default()
+        };
+        assert_eq!(
+            write_sample(&options),
+            "\"client\",\"available\",\"held\",\"total\",\"locked\"\n\
+             \"1\",\"1.5000\",\"0.0001\",\"1.5001\",\"0\"\n\
+             \"2\",\"-12.2500\",\"12.2500\",\"0.0000\",\"1\"\n"
+        );
+    }
+
+    // Test that a preloaded account without activity is only reported without --skip-inactive
+    #[test]
+    fn test_write_accounts_skip_inactive() {
+        let mut engine = Engine::new();
+        engine.accounts.insert(
+            2,
+            Account {
+                client: 2,
+                available: 5.0,
+                total: 5.0,
+                ..Default::default()
+            },
+        );
+        engine
+            .process_transaction(Transaction::deposit(1, 7, 1.0))
+            .expect("Failed to process deposit");
+
+        let report = |options: &Options| {
+            let mut output = Vec::new();
+            write_accounts(&mut output, engine.accounts_by_client(), options)
+                .expect("Failed to write report");
+            String::from_utf8(output).expect("Output not valid UTF-8")
+        };
+        assert_eq!(
+            report(&Options::default()),
+            "client,available,held,total,locked\n\
+             1,1.0,0.0,1.0,false\n\
+             2,5.0,0.0,5.0,false\n"
+        );
+        let options = Options {
+            skip_inactive: true,
+            extended: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            report(&options),
+            "client,available,held,total,locked,last_tx,peak_total,peak_held,open_disputes,\
+             chargeback_losses\n\
+             1,1.0,0.0,1.0,false,7,1.0,0.0,0,0.0\n"
+        );
+    }
+
+    #[test]
+    fn test_export_round_trip() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1000.9999999\n\
+                     deposit,2,2,0.1\n\
+                     withdrawal,1,3,0.30001\n\
+                     deposit,2,4,2.2\n\
+                     withdrawal,2,5,1.0005\n";
+
+        let mut engine = Engine::new();
+        for transaction in csv::Reader::from_reader(input.as_bytes()).deserialize() {
+            engine
+                .process_transaction(transaction.expect("Failed to parse transaction"))
+                .expect("Failed to process transaction");
+        }
+
+        let mut exported = Vec::new();
+        write_transactions(&mut exported, engine.transactions_by_id())
+            .expect("Failed to export transactions");
+        let exported = String::from_utf8(exported).expect("Export not valid UTF-8");
+        assert_eq!(
+            exported,
+            "type,client,tx,amount,note\n\
+             deposit,1,1,1000.9999,\n\
+             deposit,2,2,0.1,\n\
+             withdrawal,1,3,0.3,\n\
+             deposit,2,4,2.2,\n\
+             withdrawal,2,5,1.0005,\n"
+        );
+
+        let mut reimported = Engine::new();
+        for transaction in csv::Reader::from_reader(exported.as_bytes()).deserialize() {
+            reimported
+                .process_transaction(transaction.expect("Failed to parse exported transaction"))
+                .expect("Failed to process exported transaction");
+        }
+
+        let report = |engine: &Engine| {
+            let mut output = Vec::new();
+            write_accounts(
+                &mut output,
+                engine.accounts_by_client(),
+                &Options::default(),
+            )
+            .expect("Failed to write report");
+            String::from_utf8(output).expect("Output not valid UTF-8")
+        };
+        assert_eq!(report(&engine), report(&reimported));
+    }
+
+    // Test that disputes, chargebacks and refunds are exported, and that the
+    // export rebuilds the same balances and dispute state
+    #[test]
+    fn test_export_round_trip_chargeback() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,10.0\n\
+                     deposit,1,2,5.0\n\
+                     withdrawal,1,3,1.0\n\
+                     dispute,1,1,\n\
+                     chargeback,1,1,\n\
+                     deposit,2,4,7.5\n\
+                     deposit,2,5,2.0\n\
+                     dispute,2,4,\n\
+                     refund,2,5,\n\
+                     deposit,3,6,3.0\n\
+                     dispute,3,6,\n\
+                     resolve,3,6,\n";
+
+        let mut engine = Engine::new();
+        for transaction in csv::Reader::from_reader(input.as_bytes()).deserialize() {
+            engine
+                .process_transaction(transaction.expect("Failed to parse transaction"))
+                .expect("Failed to process transaction");
+        }
+
+        let mut exported = Vec::new();
+        write_transactions(&mut exported, engine.transactions_by_id())
+            .expect("Failed to export transactions");
+        let exported = String::from_utf8(exported).expect("Export not valid UTF-8");
+        assert_eq!(
+            exported,
+            "type,client,tx,amount,note\n\
+             deposit,1,1,10.0,\n\
+             deposit,1,2,5.0,\n\
+             withdrawal,1,3,1.0,\n\
+             deposit,2,4,7.5,\n\
+             deposit,2,5,2.0,\n\
+             deposit,3,6,3.0,\n\
+             refund,2,5,,\n\
+             dispute,1,1,,\n\
+             dispute,2,4,,\n\
+             chargeback,1,1,,\n"
+        );
+
+        let mut reimported = Engine::new();
+        for transaction in csv::Reader::from_reader(exported.as_bytes()).deserialize() {
+            reimported
+                .process_transaction(transaction.expect("Failed to parse exported transaction"))
+                .expect("Failed to process exported transaction");
+        }
+
+        let report = |engine: &Engine| {
+            let mut output = Vec::new();
+            write_accounts(
+                &mut output,
+                engine.accounts_by_client(),
+                &Options::default(),
+            )
+            .expect("Failed to write report");
+            String::from_utf8(output).expect("Output not valid UTF-8")
+        };
+        assert_eq!(report(&engine), report(&reimported));
+        let states = |engine: &Engine| -> Vec<(u32, DisputeState, bool)> {
+            engine
+                .transactions_by_id()
+                .into_iter()
+                .map(|(tx, stored)| (tx, stored.dispute_state, stored.refunded))
+                .collect()
+        };
+        assert_eq!(states(&reimported), states(&engine));
+    }
+
+    // Test that a memo survives a dispute and is exported with its transaction
+    #[test]
+    fn test_export_keeps_notes() {
+        let input = "type,client,tx,amount,note\n\
+                     deposit,1,1,10.0,\"invoice 42, paid late\"\n\
+                     deposit,1,2,5.0,\n\
+                     dispute,1,1,,\n";
+
+        let mut engine = Engine::new();
+        for transaction in csv::Reader::from_reader(input.as_bytes()).deserialize() {
+            engine
+                .process_transaction(transaction.expect("Failed to parse transaction"))
+                .expect("Failed to process transaction");
+        }
+        let deposit = engine.transaction(1).expect("Transaction not found");
+        assert!(deposit.is_disputed());
+        assert_eq!(deposit.note.as_deref(), Some("invoice 42, paid late"));
+
+        let mut exported = Vec::new();
+        write_transactions(&mut exported, engine.transactions_by_id())
+            .expect("Failed to export transactions");
+        assert_eq!(
+            String::from_utf8(exported).expect("Export not valid UTF-8"),
+            "type,client,tx,amount,note\n\
+             deposit,1,1,10.0,\"invoice 42, paid late\"\n\
+             deposit,1,2,5.0,\n\
+             dispute,1,1,,\n"
+        );
+    }
+
+    #[test]
+    fn test_output_format_from_str() {
+        assert_eq!("csv".parse(), Ok(OutputFormat::Csv));
+        assert_eq!("table".parse(), Ok(OutputFormat::Table));
+        #[cfg(feature = "parquet")]
+        assert_eq!("parquet".parse(), Ok(OutputFormat::Parquet));
+        #[cfg(not(feature = "parquet"))]
+        assert!("parquet".parse::<OutputFormat>().is_err());
+        assert!("json".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn test_locked_format_from_str() {
+        assert_eq!("bool".parse(), Ok(LockedFormat::Bool));
+        assert_eq!("binary".parse(), Ok(LockedFormat::Binary));
+        assert_eq!("yesno".parse(), Ok(LockedFormat::YesNo));
+        assert!("true".parse::<LockedFormat>().is_err());
+    }
+
+    #[test]
+    fn test_quote_from_str() {
+        assert_eq!("never".parse(), Ok(Quote::Never));
+        assert_eq!("always".parse(), Ok(Quote::Always));
+        assert_eq!("necessary".parse(), Ok(Quote::Necessary));
+        assert!("minimal".parse::<Quote>().is_err());
+    }
+}
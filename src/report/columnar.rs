@@ -0,0 +1,212 @@
+use std::io::Write;
+use std::sync::Arc;
+
+use arrow_array::builder::{ArrayBuilder, BooleanBuilder, Decimal128Builder, UInt16Builder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{ArrowError, DataType, Field, Schema, SchemaRef};
+use parquet::arrow::ArrowWriter;
+use parquet::errors::Result;
+
+use crate::account::Account;
+
+/// Precision of the `available`, `held` and `total` decimal columns.
+pub const AMOUNT_PRECISION: u8 = 18;
+/// Scale of the decimal columns: four decimal places, like the CSV report.
+pub const AMOUNT_SCALE: i8 = 4;
+
+// Number of accounts buffered in a record batch before it is handed to the
+// writer, so large reports never need a single giant batch.
+const BATCH_SIZE: usize = 65_536;
+
+/// Arrow schema of the accounts report.
+#[must_use]
+pub fn accounts_schema() -> SchemaRef {
+    let amount = DataType::Decimal128(AMOUNT_PRECISION, AMOUNT_SCALE);
+    Arc::new(Schema::new(vec![
+        Field::new("client", DataType::UInt16, false),
+        Field::new("available", amount.clone(), false),
+        Field::new("held", amount.clone(), false),
+        Field::new("total", amount, false),
+        Field::new("locked", DataType::Boolean, false),
+    ]))
+}
+
+/// Write the accounts report as Parquet, in the order the accounts are given.
+///
+/// Balances are stored as `decimal(18,4)`, truncated to four decimal places
+/// like the CSV report.
+///
+/// # Errors
+///
+/// Returns an error if a balance does not fit the decimal column or if
+/// writing to the underlying writer fails.
+pub fn write_accounts_parquet<'a, W, I>(writer: W, accounts: I) -> Result<()>
+where
+    W: Write + Send,
+    I: IntoIterator<Item = &'a Account>,
+{
+    write_batches(writer, accounts, BATCH_SIZE)
+}
+
+fn write_batches<'a, W, I>(writer: W, accounts: I, batch_size: usize) -> Result<()>
+where
+    W: Write + Send,
+    I: IntoIterator<Item = &'a Account>,
+{
+    let schema = accounts_schema();
+    let mut writer = ArrowWriter::try_new(writer, schema.clone(), None)?;
+    let mut batch = BatchBuilder::with_capacity(batch_size);
+    for account in accounts {
+        batch.append(account);
+        if batch.len() == batch_size {
+            writer.write(&batch.finish(&schema)?)?;
+        }
+    }
+    if batch.len() > 0 {
+        writer.write(&batch.finish(&schema)?)?;
+    }
+    writer.close()?;
+    Ok(())
+}
+
+// Column builders for one record batch of the accounts report
+struct BatchBuilder {
+    client: UInt16Builder,
+    available: Decimal128Builder,
+    held: Decimal128Builder,
+    total: Decimal128Builder,
+    locked: BooleanBuilder,
+}
+
+impl BatchBuilder {
+    fn with_capacity(capacity: usize) -> Self {
+        BatchBuilder {
+            client: UInt16Builder::with_capacity(capacity),
+            available: Decimal128Builder::with_capacity(capacity),
+            held: Decimal128Builder::with_capacity(capacity),
+            total: Decimal128Builder::with_capacity(capacity),
+            locked: BooleanBuilder::with_capacity(capacity),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.client.len()
+    }
+
+    fn append(&mut self, account: &Account) {
+        self.client.append_value(account.client);
+        self.available.append_value(to_decimal(account.available));
+        self.held.append_value(to_decimal(account.held));
+        self.total.append_value(to_decimal(account.total));
+        self.locked.append_value(account.locked);
+    }
+
+    // Build the record batch and reset the builders for the next one
+    fn finish(&mut self, schema: &SchemaRef) -> std::result::Result<RecordBatch, ArrowError> {
+        let amount =
+            |builder: &mut Decimal128Builder| -> std::result::Result<ArrayRef, ArrowError> {
+                let array = builder
+                    .finish()
+                    .with_precision_and_scale(AMOUNT_PRECISION, AMOUNT_SCALE)?;
+                array.validate_decimal_precision(AMOUNT_PRECISION)?;
+                Ok(Arc::new(array))
+            };
+        let columns = vec![
+            Arc::new(self.client.finish()) as ArrayRef,
+            amount(&mut self.available)?,
+            amount(&mut self.held)?,
+            amount(&mut self.total)?,
+            Arc::new(self.locked.finish()),
+        ];
+        RecordBatch::try_new(schema.clone(), columns)
+    }
+}
+
+// Fixed-point value with four decimal places, truncated like `Account::format_value`
+fn to_decimal(value: f64) -> i128 {
+    Account::format_value_fixed(value)
+        .replace('.', "")
+        .parse()
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::LockReason;
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{Decimal128Type, UInt16Type};
+    use bytes::Bytes;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    #[test]
+    fn test_write_accounts_parquet_round_trip() {
+        let accounts: Vec<Account> = [3, 1, 2, 7, 5]
+            .into_iter()
+            .map(|client| Account {
+                client,
+                available: f64::from(client) + 0.123_45,
+                held: 1.5,
+                pending: 0.0,
+                total: f64::from(client) + 1.623_45,
+                locked: client == 2,
+                lock_reason: (client == 2).then_some(LockReason::Chargeback),
+                open_disputes: 0,
+                dispute_shortfall: 0.0,
+                last_tx: None,
+                applied: 0,
+                peak_total: 0.0,
+                peak_held: 0.0,
+                chargeback_losses: 0.0,
+            })
+            .collect();
+
+        // A batch size of 2 exercises the batch boundaries
+        let mut output = Vec::new();
+        write_batches(&mut output, &accounts, 2).expect("Failed to write parquet");
+
+        let builder = ParquetRecordBatchReaderBuilder::try_new(Bytes::from(output))
+            .expect("Failed to open parquet");
+        assert_eq!(builder.schema().fields(), accounts_schema().fields());
+
+        let mut clients: Vec<u16> = Vec::new();
+        let mut available: Vec<i128> = Vec::new();
+        let mut held: Vec<i128> = Vec::new();
+        let mut total: Vec<i128> = Vec::new();
+        let mut locked: Vec<bool> = Vec::new();
+        for batch in builder.build().expect("Failed to read parquet") {
+            let batch = batch.expect("Failed to read record batch");
+            clients.extend(batch.column(0).as_primitive::<UInt16Type>().values());
+            available.extend(batch.column(1).as_primitive::<Decimal128Type>().values());
+            held.extend(batch.column(2).as_primitive::<Decimal128Type>().values());
+            total.extend(batch.column(3).as_primitive::<Decimal128Type>().values());
+            locked.extend(batch.column(4).as_boolean().values().iter());
+        }
+
+        assert_eq!(clients, [3, 1, 2, 7, 5]);
+        assert_eq!(available, [31_234, 11_234, 21_234, 71_234, 51_234]);
+        assert_eq!(held, [15_000; 5]);
+        assert_eq!(total, [46_234, 26_234, 36_234, 86_234, 66_234]);
+        assert_eq!(locked, [false, false, true, false, false]);
+    }
+
+    #[test]
+    fn test_to_decimal_truncates_like_the_report() {
+        assert_eq!(to_decimal(0.57), 5_700);
+        assert_eq!(to_decimal(1.005), 10_050);
+        assert_eq!(to_decimal(-123.456_789), -1_234_567);
+        assert_eq!(to_decimal(0.0), 0);
+    }
+
+    #[test]
+    fn test_write_accounts_parquet_rejects_out_of_range_balance() {
+        let accounts = [Account {
+            client: 1,
+            available: 1e20,
+            total: 1e20,
+            ..Default::default()
+        }];
+        let mut output = Vec::new();
+        assert!(write_accounts_parquet(&mut output, &accounts).is_err());
+    }
+}
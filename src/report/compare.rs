@@ -0,0 +1,211 @@
+use std::collections::BTreeMap;
+use std::io::Read;
+
+use serde::Serialize;
+
+use crate::error::Compare as CompareError;
+
+/// Field of a client that differs between two accounts reports.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldDelta {
+    pub client: u16,
+    /// Name of the column.
+    pub field: String,
+    pub baseline: String,
+    pub actual: String,
+    /// Actual minus baseline value, for numeric fields.
+    pub delta: Option<f64>,
+}
+
+/// Differences between a baseline accounts report and an actual one.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ReportDiff {
+    /// Clients only in the actual report, by id.
+    pub added: Vec<u16>,
+    /// Clients only in the baseline report, by id.
+    pub removed: Vec<u16>,
+    /// Fields of the clients in both reports, by client id then column.
+    pub changed: Vec<FieldDelta>,
+}
+
+impl ReportDiff {
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Compare two accounts reports in CSV, whatever the order of their rows and
+/// columns.
+///
+/// Rows are matched by their `client` column, and the columns of the
+/// baseline compared by name, so that columns added to the actual report
+/// since the baseline was written are ignored. Fields that are numbers in
+/// both reports are compared by value, so `1.5` equals `1.5000`, and differ
+/// when they are more than `tolerance` apart; other fields differ when their
+/// text does.
+///
+/// # Errors
+///
+/// Returns an error if either report cannot be read, has no `client` column
+/// or an invalid or repeated client id, or if the actual report lacks a
+/// column of the baseline.
+pub fn compare_reports<B: Read, A: Read>(
+    baseline: B,
+    actual: A,
+    tolerance: f64,
+) -> Result<ReportDiff, CompareError> {
+    let (columns, baseline) = read_report("baseline", baseline)?;
+    let (actual_columns, actual) = read_report("actual", actual)?;
+    // Position of each baseline column in the actual report
+    let positions = columns
+        .iter()
+        .map(|column| {
+            actual_columns
+                .iter()
+                .position(|name| name == column)
+                .ok_or_else(|| CompareError::MissingColumn("actual", column.clone()))
+        })
+        .collect::<Result<Vec<usize>, CompareError>>()?;
+
+    let mut diff = ReportDiff {
+        added: actual
+            .keys()
+            .filter(|client| !baseline.contains_key(client))
+            .copied()
+            .collect(),
+        ..ReportDiff::default()
+    };
+    for (&client, expected) in &baseline {
+        let Some(row) = actual.get(&client) else {
+            diff.removed.push(client);
+            continue;
+        };
+        for ((field, expected), &position) in columns.iter().zip(expected).zip(&positions) {
+            let found = row.get(position).map_or("", String::as_str);
+            let delta = match (expected.parse::<f64>(), found.parse::<f64>()) {
+                (Ok(expected), Ok(found)) if (found - expected).abs() <= tolerance => continue,
+                (Ok(expected), Ok(found)) => Some(found - expected),
+                _ if expected == found => continue,
+                _ => None,
+            };
+            diff.changed.push(FieldDelta {
+                client,
+                field: field.clone(),
+                baseline: expected.clone(),
+                actual: found.to_string(),
+                delta,
+            });
+        }
+    }
+    Ok(diff)
+}
+
+// Columns of a report other than `client`, and the fields of these columns
+// by client id
+pub(super) type Rows = BTreeMap<u16, Vec<String>>;
+
+// Read an accounts report in CSV, named `name` in errors
+pub(super) fn read_report<R: Read>(
+    name: &'static str,
+    reader: R,
+) -> Result<(Vec<String>, Rows), CompareError> {
+    let mut rdr = csv::Reader::from_reader(reader);
+    let headers = rdr
+        .headers()
+        .map_err(|e| CompareError::Csv(name, e))?
+        .clone();
+    let client_column = headers
+        .iter()
+        .position(|column| column.trim() == "client")
+        .ok_or_else(|| CompareError::MissingColumn(name, "client".to_string()))?;
+    let others = |record: &csv::StringRecord| -> Vec<String> {
+        record
+            .iter()
+            .enumerate()
+            .filter(|&(column, _)| column != client_column)
+            .map(|(_, field)| field.trim().to_string())
+            .collect()
+    };
+
+    let mut rows = Rows::new();
+    for record in rdr.records() {
+        let record = record.map_err(|e| CompareError::Csv(name, e))?;
+        let id = record.get(client_column).unwrap_or_default().trim();
+        let client = id
+            .parse()
+            .map_err(|_| CompareError::InvalidClient(name, id.to_string()))?;
+        if rows.insert(client, others(&record)).is_some() {
+            return Err(CompareError::DuplicateClient(name, client));
+        }
+    }
+    Ok((others(&headers), rows))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASELINE: &str = "client,available,held,total,locked\n\
+                            1,1.5,0.0,1.5,false\n\
+                            2,-500.0,0.0,-500.0,true\n";
+
+    // Test that reordered rows and columns and padded decimals compare equal
+    #[test]
+    fn test_compare_equivalent_reports() {
+        let actual = "locked,client,total,held,available\n\
+                      true,2,-500,0.0000,-500.0000\n\
+                      false,1,1.5000,0,1.5000\n";
+        let diff = compare_reports(BASELINE.as_bytes(), actual.as_bytes(), 0.0)
+            .expect("Failed to compare reports");
+        assert!(diff.is_empty());
+    }
+
+    #[test]
+    fn test_compare_differing_reports() {
+        let actual = "client,available,held,total,locked,pending\n\
+                      1,1.25,0.0,1.2501,true,0.0\n\
+                      3,1.0,0.0,1.0,false,0.0\n";
+        let diff = compare_reports(BASELINE.as_bytes(), actual.as_bytes(), 0.001)
+            .expect("Failed to compare reports");
+
+        assert_eq!(diff.added, [3]);
+        assert_eq!(diff.removed, [2]);
+        let changed: Vec<(&str, &str, &str, Option<f64>)> = diff
+            .changed
+            .iter()
+            .map(|delta| {
+                (
+                    delta.field.as_str(),
+                    delta.baseline.as_str(),
+                    delta.actual.as_str(),
+                    delta.delta,
+                )
+            })
+            .collect();
+        assert_eq!(
+            changed,
+            [
+                ("available", "1.5", "1.25", Some(-0.25)),
+                ("total", "1.5", "1.2501", Some(1.2501 - 1.5)),
+                ("locked", "false", "true", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compare_invalid_reports() {
+        let missing = "client,available\n1,1.5\n";
+        assert!(matches!(
+            compare_reports(BASELINE.as_bytes(), missing.as_bytes(), 0.0),
+            Err(CompareError::MissingColumn("actual", column)) if column == "held"
+        ));
+        let repeated = "client,available,held,total,locked\n\
+                        1,1.5,0.0,1.5,false\n\
+                        1,1.5,0.0,1.5,false\n";
+        assert!(matches!(
+            compare_reports(repeated.as_bytes(), BASELINE.as_bytes(), 0.0),
+            Err(CompareError::DuplicateClient("baseline", 1))
+        ));
+    }
+}
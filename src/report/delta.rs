@@ -0,0 +1,363 @@
+use std::collections::BTreeMap;
+use std::io::{BufWriter, Read, Write};
+
+use csv::WriterBuilder;
+
+use super::compare::read_report;
+use super::{LockedFormat, Options};
+use crate::account::{Account, AccountView};
+use crate::error::Compare as CompareError;
+
+/// Balances of a client in an accounts report.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Balances {
+    pub available: f64,
+    pub held: f64,
+    pub total: f64,
+    pub locked: bool,
+}
+
+/// Change of the balances of a client between two runs, or the client added
+/// or gone since the previous run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccountDelta {
+    pub client: u16,
+    /// Balances in the previous report, `None` for a new client.
+    pub previous: Option<Balances>,
+    /// Balances in the current report, `None` for a disappeared client.
+    pub current: Option<Balances>,
+}
+
+impl AccountDelta {
+    /// `new`, `disappeared` or `changed`.
+    #[must_use]
+    pub fn status(&self) -> &'static str {
+        match (&self.previous, &self.current) {
+            (None, _) => "new",
+            (_, None) => "disappeared",
+            _ => "changed",
+        }
+    }
+
+    /// Current minus previous total, a missing report row counting as zero.
+    #[must_use]
+    pub fn total_delta(&self) -> f64 {
+        delta(
+            self.previous.map(|balances| balances.total),
+            self.current.map(|balances| balances.total),
+        )
+    }
+}
+
+/// Read the balances of each client of an accounts report, such as the
+/// report of a previous run, whose amounts use `decimal_separator`.
+///
+/// The `locked` column may be rendered in any [`LockedFormat`].
+///
+/// # Errors
+///
+/// Returns an error if the report cannot be read, lacks one of the
+/// compared columns, or has an invalid client id, amount or locked value.
+pub fn read_balances<R: Read>(
+    reader: R,
+    decimal_separator: char,
+) -> Result<BTreeMap<u16, Balances>, CompareError> {
+    let name = "previous";
+    let (columns, rows) = read_report(name, reader)?;
+    let position = |column: &'static str| {
+        columns
+            .iter()
+            .position(|name| name == column)
+            .ok_or_else(|| CompareError::MissingColumn(name, column.to_string()))
+    };
+    let positions = [
+        position("available")?,
+        position("held")?,
+        position("total")?,
+    ];
+    let locked_position = position("locked")?;
+
+    rows.into_iter()
+        .map(|(client, row)| {
+            let field = |position: usize| row.get(position).map_or("", String::as_str);
+            let invalid = |column: &'static str, value: &str| {
+                CompareError::InvalidValue(name, column, client, value.to_string())
+            };
+            let mut amounts = [0.0; 3];
+            for ((amount, &position), column) in
+                amounts
+                    .iter_mut()
+                    .zip(&positions)
+                    .zip(["available", "held", "total"])
+            {
+                let value = field(position);
+                *amount =
+                    parse_amount(value, decimal_separator).ok_or_else(|| invalid(column, value))?;
+            }
+            let value = field(locked_position);
+            let locked = parse_locked(value).ok_or_else(|| invalid("locked", value))?;
+            let [available, held, total] = amounts;
+            Ok((
+                client,
+                Balances {
+                    available,
+                    held,
+                    total,
+                    locked,
+                },
+            ))
+        })
+        .collect()
+}
+
+/// Compare the balances of a previous run, as read by [`read_balances`],
+/// with the accounts of this run that `options` includes in the report, and
+/// list the clients whose `available`, `held`, `total` or `locked` value
+/// changed, appeared or disappeared.
+///
+/// Current amounts are truncated to four decimal places like the report.
+/// The deltas are sorted by absolute total delta, largest first, then by
+/// client id.
+pub fn account_deltas<'a, I>(
+    previous: &BTreeMap<u16, Balances>,
+    accounts: I,
+    options: &Options,
+) -> Vec<AccountDelta>
+where
+    I: IntoIterator<Item = &'a Account>,
+{
+    let mut current: BTreeMap<u16, Balances> = accounts
+        .into_iter()
+        .filter(|account| options.includes(account))
+        .map(|account| {
+            let view = AccountView::from(account);
+            let balances = Balances {
+                available: view.available,
+                held: view.held,
+                total: view.total,
+                locked: view.locked,
+            };
+            (view.client, balances)
+        })
+        .collect();
+    let mut deltas: Vec<AccountDelta> = previous
+        .iter()
+        .filter_map(|(&client, &previous)| {
+            let current = current.remove(&client);
+            (current != Some(previous)).then_some(AccountDelta {
+                client,
+                previous: Some(previous),
+                current,
+            })
+        })
+        .collect();
+    deltas.extend(current.into_iter().map(|(client, current)| AccountDelta {
+        client,
+        previous: None,
+        current: Some(current),
+    }));
+    deltas.sort_by(|a, b| {
+        let by_delta = b.total_delta().abs().total_cmp(&a.total_delta().abs());
+        by_delta.then(a.client.cmp(&b.client))
+    });
+    deltas
+}
+
+/// Write account deltas as CSV, in the order they are given, with the old
+/// value, new value and delta of each amount and the old and new `locked`
+/// value. The old values of a new client and the new values of a
+/// disappeared one are left empty.
+///
+/// # Errors
+///
+/// Returns an error if writing to the underlying writer fails.
+pub fn write_account_deltas<W: Write>(writer: W, deltas: &[AccountDelta]) -> csv::Result<()> {
+    let mut wtr = WriterBuilder::new().from_writer(BufWriter::new(writer));
+    wtr.write_record([
+        "client",
+        "status",
+        "available_old",
+        "available_new",
+        "available_delta",
+        "held_old",
+        "held_new",
+        "held_delta",
+        "total_old",
+        "total_new",
+        "total_delta",
+        "locked_old",
+        "locked_new",
+    ])?;
+    for account in deltas {
+        let mut row = vec![account.client.to_string(), account.status().to_string()];
+        for amount in [
+            |balances: Balances| balances.available,
+            |balances: Balances| balances.held,
+            |balances: Balances| balances.total,
+        ] {
+            let previous = account.previous.map(amount);
+            let current = account.current.map(amount);
+            row.extend([
+                previous.map(Account::format_value).unwrap_or_default(),
+                current.map(Account::format_value).unwrap_or_default(),
+                Account::format_value(delta(previous, current)),
+            ]);
+        }
+        let locked = |balances: Option<Balances>| balances.map(|b| b.locked.to_string());
+        row.push(locked(account.previous).unwrap_or_default());
+        row.push(locked(account.current).unwrap_or_default());
+        wtr.write_record(row)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+// Read an amount written with `decimal_separator`
+fn parse_amount(value: &str, decimal_separator: char) -> Option<f64> {
+    if decimal_separator == '.' {
+        return value.parse().ok();
+    }
+    if value.contains('.') {
+        return None;
+    }
+    value.replace(decimal_separator, ".").parse().ok()
+}
+
+// Read a `locked` value rendered in any `LockedFormat`
+fn parse_locked(value: &str) -> Option<bool> {
+    [
+        LockedFormat::Bool,
+        LockedFormat::Binary,
+        LockedFormat::YesNo,
+    ]
+    .into_iter()
+    .find_map(|format| {
+        [true, false]
+            .into_iter()
+            .find(|&locked| format.render(locked) == value)
+    })
+}
+
+// Current minus previous amount, a missing one counting as zero, rounded to
+// the four decimal places of the report so that the difference of two
+// printed amounts is not truncated below its printed value
+fn delta(previous: Option<f64>, current: Option<f64>) -> f64 {
+    let delta = current.unwrap_or_default() - previous.unwrap_or_default();
+    // Adding zero turns a negative zero into zero
+    (delta * 10_000.0).round() / 10_000.0 + 0.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(client: u16, available: f64, held: f64, locked: bool) -> Account {
+        Account {
+            client,
+            available,
+            held,
+            total: available + held,
+            locked,
+            ..Default::default()
+        }
+    }
+
+    // Test that unchanged clients are left out and the others sorted by
+    // absolute total delta, with empty fields for missing rows
+    #[test]
+    fn test_account_deltas() {
+        let previous = "client,available,held,total,locked\n\
+                        1,10.0,0.0,10.0,false\n\
+                        2,1.2,0.0,1.2,no\n\
+                        3,5.0,0.0,5.0,false\n\
+                        4,7.0,0.0,7.0,false\n";
+        let previous = read_balances(previous.as_bytes(), '.').expect("Failed to read report");
+        let accounts = [
+            account(1, 8.0, 2.0, true),
+            account(2, 1.100_09, 0.0, false),
+            account(4, 7.0, 0.0, false),
+            account(5, 20.5, 0.0, false),
+        ];
+        let deltas = account_deltas(&previous, &accounts, &Options::default());
+        let clients: Vec<(u16, &str)> = deltas
+            .iter()
+            .map(|delta| (delta.client, delta.status()))
+            .collect();
+        assert_eq!(
+            clients,
+            [
+                (5, "new"),
+                (3, "disappeared"),
+                (2, "changed"),
+                (1, "changed")
+            ]
+        );
+
+        let mut output = Vec::new();
+        write_account_deltas(&mut output, &deltas).expect("Failed to write deltas");
+        let expected = [
+            "client,status,available_old,available_new,available_delta,held_old,held_new,\
+             held_delta,total_old,total_new,total_delta,locked_old,locked_new",
+            "5,new,,20.5,20.5,,0.0,0.0,,20.5,20.5,,false",
+            "3,disappeared,5.0,,-5.0,0.0,,0.0,5.0,,-5.0,false,",
+            "2,changed,1.2,1.1,-0.1,0.0,0.0,0.0,1.2,1.1,-0.1,false,false",
+            "1,changed,10.0,8.0,-2.0,0.0,2.0,2.0,10.0,10.0,0.0,false,true",
+        ];
+        assert_eq!(
+            String::from_utf8(output).expect("Output not valid UTF-8"),
+            expected.map(|line| format!("{line}\n")).concat()
+        );
+    }
+
+    // Test that accounts left out of the report are compared as missing
+    #[test]
+    fn test_account_deltas_report_options() {
+        let previous = "client,available,held,total,locked\n1,0.0,0.0,0.0,false\n";
+        let previous = read_balances(previous.as_bytes(), '.').expect("Failed to read report");
+        let options = Options {
+            nonzero_only: true,
+            ..Default::default()
+        };
+        let deltas = account_deltas(&previous, &[account(1, 0.0, 0.0, false)], &options);
+        assert_eq!(deltas.len(), 1);
+        assert_eq!(deltas[0].status(), "disappeared");
+    }
+
+    #[test]
+    fn test_read_balances_decimal_separator() {
+        let report = "client,available,held,total,locked\n1,\"-500,25\",\"0,0\",\"-500,25\",true\n";
+        let balances = read_balances(report.as_bytes(), ',').expect("Failed to read report");
+        assert_eq!(
+            balances.get(&1),
+            Some(&Balances {
+                available: -500.25,
+                held: 0.0,
+                total: -500.25,
+                locked: true,
+            })
+        );
+        assert!(matches!(
+            read_balances(report.as_bytes(), '.'),
+            Err(CompareError::InvalidValue("previous", "available", 1, value)) if value == "-500,25"
+        ));
+    }
+
+    #[test]
+    fn test_read_balances_invalid_report() {
+        let amount = "client,available,held,total,locked\n1,1;5,0.0,1.5,false\n";
+        assert!(matches!(
+            read_balances(amount.as_bytes(), '.'),
+            Err(CompareError::InvalidValue("previous", "available", 1, value)) if value == "1;5"
+        ));
+        let locked = "client,available,held,total,locked\n1,1.5,0.0,1.5,maybe\n";
+        assert!(matches!(
+            read_balances(locked.as_bytes(), '.'),
+            Err(CompareError::InvalidValue("previous", "locked", 1, value)) if value == "maybe"
+        ));
+        let missing = "client,available,total,locked\n1,1.5,1.5,false\n";
+        assert!(matches!(
+            read_balances(missing.as_bytes(), '.'),
+            Err(CompareError::MissingColumn("previous", column)) if column == "held"
+        ));
+    }
+}
@@ -0,0 +1,96 @@
+use std::io::{self, BufWriter, Write};
+
+use super::Options;
+use crate::account::Account;
+
+/// Write the accounts report as a table with right-aligned columns, in the
+/// order the accounts are given, for reading in a terminal.
+///
+/// The fields are those of the CSV report, never quoted. Every row is
+/// rendered before the first is written, so that the column widths fit the
+/// widest field: the memory needed grows with the number of accounts.
+///
+/// # Errors
+///
+/// Returns an error if writing to the underlying writer fails.
+pub fn write_accounts_table<'a, W, I>(writer: W, accounts: I, options: &Options) -> io::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = &'a Account>,
+{
+    let header = options.header();
+    let rows: Vec<Vec<String>> = accounts
+        .into_iter()
+        .filter(|account| options.includes(account))
+        .map(|account| options.row(account))
+        .collect();
+    let mut widths: Vec<usize> = header.iter().map(|name| name.len()).collect();
+    for row in &rows {
+        for (width, field) in widths.iter_mut().zip(row) {
+            *width = (*width).max(field.chars().count());
+        }
+    }
+
+    let mut out = BufWriter::new(writer);
+    write_line(&mut out, &header, &widths)?;
+    let rule: Vec<String> = widths.iter().map(|&width| "-".repeat(width)).collect();
+    writeln!(out, "{}", rule.join("-+-"))?;
+    for row in &rows {
+        write_line(&mut out, row, &widths)?;
+    }
+    out.flush()
+}
+
+fn write_line<W: Write, S: AsRef<str>>(
+    out: &mut W,
+    fields: &[S],
+    widths: &[usize],
+) -> io::Result<()> {
+    for (column, (field, &width)) in fields.iter().zip(widths).enumerate() {
+        if column > 0 {
+            write!(out, " | ")?;
+        }
+        write!(out, "{:>width$}", field.as_ref())?;
+    }
+    writeln!(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test that the header, the rule and the rows line up column by column
+    #[test]
+    fn test_write_accounts_table() {
+        let accounts = [
+            Account {
+                client: 1,
+                available: 1.5,
+                total: 1.5,
+                ..Default::default()
+            },
+            Account {
+                client: 42,
+                available: -1_000.25,
+                held: 1_500.0,
+                total: 499.75,
+                locked: true,
+                ..Default::default()
+            },
+        ];
+        let mut output = Vec::new();
+        write_accounts_table(&mut output, &accounts, &Options::default())
+            .expect("Failed to write table");
+
+        let expected = [
+            "client | available |   held |  total | locked",
+            "-------+-----------+--------+--------+-------",
+            "     1 |       1.5 |    0.0 |    1.5 |  false",
+            "    42 |  -1000.25 | 1500.0 | 499.75 |   true",
+        ];
+        assert_eq!(
+            String::from_utf8(output).expect("Output not valid UTF-8"),
+            expected.map(|line| format!("{line}\n")).concat()
+        );
+    }
+}
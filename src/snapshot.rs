@@ -0,0 +1,420 @@
+use std::collections::HashSet;
+use std::io::{Read, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::account::Account;
+use crate::engine::Engine;
+use crate::error::Snapshot as SnapshotError;
+use crate::input::Cursor;
+use crate::store::TransactionStore;
+use crate::transaction::{Amount, DisputeState, StoredTx, TxKind};
+
+/// Version written to new snapshots. Bumped whenever the layout changes
+/// incompatibly; older versions are rejected on load.
+pub const FORMAT_VERSION: u32 = 1;
+
+// Saved engine state. Policies are configuration, not state, and are not saved.
+#[derive(Serialize, Deserialize)]
+struct State {
+    version: u32,
+    // In creation order, so `Engine::accounts_in_order` survives a round trip
+    accounts: Vec<Account>,
+    // Sorted by transaction id, keeping snapshots of equal states identical
+    transactions: Vec<StoredTransaction>,
+    deposited: Vec<(u16, Amount)>,
+    // Fees charged so far; absent from older snapshots
+    #[serde(default, skip_serializing_if = "is_no_amount")]
+    fees_collected: Amount,
+    // Where processing of the input file stopped, absent when not resumable
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cursor: Option<Cursor>,
+}
+
+// Flat layout of a stored transaction, keyed by its id. Each flag is a field
+// of its own, so that older snapshots without it still load
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Serialize, Deserialize)]
+struct StoredTransaction {
+    #[serde(rename = "type")]
+    kind: TxKind,
+    client: u16,
+    tx: u32,
+    amount: Amount,
+    disputed: bool,
+    // Funds held by an open dispute; older snapshots always held the full amount
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    held: Option<Amount>,
+    // Disputes so far, including an open one; absent from older snapshots
+    #[serde(default, skip_serializing_if = "is_zero")]
+    disputes: u32,
+    // Deposit waiting for a settle row; absent from older snapshots
+    #[serde(default, skip_serializing_if = "is_false")]
+    pending: bool,
+    // Deposit reversed by a refund; absent from older snapshots
+    #[serde(default, skip_serializing_if = "is_false")]
+    refunded: bool,
+    // Deposit charged back, which cannot be disputed again; absent from
+    // older snapshots
+    #[serde(default, skip_serializing_if = "is_false")]
+    charged_back: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+}
+
+#[allow(clippy::trivially_copy_pass_by_ref)]
+fn is_zero(count: &u32) -> bool {
+    *count == 0
+}
+
+#[allow(clippy::trivially_copy_pass_by_ref)]
+fn is_no_amount(amount: &Amount) -> bool {
+    *amount == 0.0
+}
+
+#[allow(clippy::trivially_copy_pass_by_ref)]
+fn is_false(flag: &bool) -> bool {
+    !*flag
+}
+
+/// Save the accounts, stored transactions and dispute state of `engine` as JSON.
+///
+/// # Errors
+///
+/// Returns an error if writing to the underlying writer fails.
+pub fn save<S: TransactionStore, W: Write>(
+    engine: &Engine<S>,
+    writer: W,
+) -> Result<(), SnapshotError> {
+    save_with_cursor(engine, None, writer)
+}
+
+/// Save `engine` like [`save`], along with the `cursor` of the input file it
+/// processed, returned by [`load_into_with_cursor`].
+///
+/// # Errors
+///
+/// Returns an error if writing to the underlying writer fails.
+pub fn save_with_cursor<S: TransactionStore, W: Write>(
+    engine: &Engine<S>,
+    cursor: Option<&Cursor>,
+    mut writer: W,
+) -> Result<(), SnapshotError> {
+    let mut deposited: Vec<(u16, Amount)> = engine.deposited().collect();
+    deposited.sort_unstable_by_key(|&(client, _)| client);
+    let state = State {
+        version: FORMAT_VERSION,
+        accounts: engine.accounts_in_order().cloned().collect(),
+        transactions: engine
+            .transactions_by_id()
+            .into_iter()
+            .map(|(tx, transaction)| StoredTransaction {
+                kind: transaction.kind,
+                client: transaction.client,
+                tx,
+                amount: transaction.amount,
+                disputed: transaction.is_disputed(),
+                held: transaction.is_disputed().then_some(transaction.held),
+                disputes: transaction.disputes,
+                pending: transaction.pending,
+                refunded: transaction.refunded,
+                charged_back: transaction.is_charged_back(),
+                note: transaction.note.clone(),
+            })
+            .collect(),
+        deposited,
+        fees_collected: engine.fees_collected(),
+        cursor: cursor.cloned(),
+    };
+    serde_json::to_writer(&mut writer, &state)?;
+    writer.flush().map_err(serde_json::Error::io)?;
+    Ok(())
+}
+
+/// Load a snapshot written by [`save`] into `engine`, which should be empty.
+///
+/// The engine keeps its own policy, so a snapshot can be resumed under
+/// different limits. The restored state is validated with
+/// [`Engine::check_invariants`].
+///
+/// # Errors
+///
+/// Returns an error if the snapshot cannot be read or parsed, has an
+/// unsupported version, repeats a client or transaction id, or describes an
+/// inconsistent state.
+pub fn load_into<S: TransactionStore, R: Read>(
+    engine: &mut Engine<S>,
+    reader: R,
+) -> Result<(), SnapshotError> {
+    load_into_with_cursor(engine, reader).map(drop)
+}
+
+/// Load a snapshot into `engine` like [`load_into`], returning the input
+/// cursor saved with it, if any.
+///
+/// # Errors
+///
+/// See [`load_into`].
+pub fn load_into_with_cursor<S: TransactionStore, R: Read>(
+    engine: &mut Engine<S>,
+    reader: R,
+) -> Result<Option<Cursor>, SnapshotError> {
+    let state: State = serde_json::from_reader(reader)?;
+    if state.version != FORMAT_VERSION {
+        return Err(SnapshotError::UnsupportedVersion(state.version));
+    }
+
+    let mut clients = HashSet::new();
+    if let Some(account) = state
+        .accounts
+        .iter()
+        .find(|account| !clients.insert(account.client))
+    {
+        return Err(SnapshotError::DuplicateClient(account.client));
+    }
+    let mut ids = HashSet::new();
+    if let Some(transaction) = state
+        .transactions
+        .iter()
+        .find(|transaction| !ids.insert(transaction.tx))
+    {
+        return Err(SnapshotError::DuplicateTransaction(transaction.tx));
+    }
+
+    let transactions = state
+        .transactions
+        .into_iter()
+        .map(|stored| {
+            let (dispute_state, held) = match (stored.disputed, stored.charged_back) {
+                (true, _) => (DisputeState::Disputed, stored.held.unwrap_or(stored.amount)),
+                (false, true) => (DisputeState::ChargedBack, 0.0),
+                (false, false) => (DisputeState::Undisputed, 0.0),
+            };
+            (
+                stored.tx,
+                StoredTx {
+                    client: stored.client,
+                    amount: stored.amount,
+                    kind: stored.kind,
+                    dispute_state,
+                    held,
+                    disputes: stored.disputes,
+                    pending: stored.pending,
+                    refunded: stored.refunded,
+                    note: stored.note,
+                },
+            )
+        })
+        .collect();
+    engine.restore(
+        state.accounts,
+        transactions,
+        state.deposited,
+        state.fees_collected,
+    );
+    engine.check_invariants()?;
+    Ok(state.cursor)
+}
+
+/// Load a snapshot written by [`save`] into a new engine with the default policy.
+///
+/// # Errors
+///
+/// See [`load_into`].
+pub fn load<R: Read>(reader: R) -> Result<Engine, SnapshotError> {
+    let mut engine = Engine::new();
+    load_into(&mut engine, reader)?;
+    Ok(engine)
+}
+
+#[cfg(test)]
+#[allow(clippy::float_cmp)]
+mod tests {
+    use super::*;
+    use crate::error::Invariant;
+    use crate::policy::{FeeRule, FeeSchedule};
+    use crate::report::{write_accounts, Options};
+    use crate::transaction::Type;
+
+    fn process(engine: &mut Engine, input: &str) {
+        for transaction in csv::Reader::from_reader(input.as_bytes()).deserialize() {
+            let _ = engine.process_transaction(transaction.expect("Failed to parse transaction"));
+        }
+    }
+
+    fn report(engine: &Engine) -> String {
+        let mut output = Vec::new();
+        write_accounts(
+            &mut output,
+            engine.accounts_by_client(),
+            &Options::default(),
+        )
+        .expect("Failed to write report");
+        String::from_utf8(output).expect("Output not valid UTF-8")
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut engine = Engine::new();
+        process(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,3,1,100.1234\n\
+             deposit,1,2,0.1\n\
+             deposit,1,3,0.2\n\
+             dispute,1,3,\n\
+             deposit,2,4,50.0\n\
+             dispute,2,4,\n\
+             chargeback,2,4,\n",
+        );
+
+        let mut saved = Vec::new();
+        save(&engine, &mut saved).expect("Failed to save snapshot");
+        let mut restored = load(saved.as_slice()).expect("Failed to load snapshot");
+
+        assert_eq!(report(&restored), report(&engine));
+        // The restored totals become the opening balance of the run
+        let totals = restored
+            .global_check()
+            .expect("Global totals out of balance");
+        assert_eq!(totals.opening, totals.accounts);
+        let order: Vec<u16> = restored
+            .accounts_in_order()
+            .map(|account| account.client)
+            .collect();
+        assert_eq!(order, [3, 1, 2]);
+        let account = restored.accounts.get(&1).expect("Account not found");
+        assert_eq!(account.available, engine.accounts[&1].available);
+        assert_eq!(account.open_disputes, 1);
+
+        // Dispute state survives, so processing continues where it left off
+        let input = "type,client,tx,amount\nresolve,1,3,\n";
+        process(&mut engine, input);
+        process(&mut restored, input);
+        assert_eq!(report(&restored), report(&engine));
+        let transaction = restored.transaction(3).expect("Transaction not found");
+        assert!(!transaction.is_disputed());
+        assert_eq!(transaction.disputes, 1);
+        // A charged-back deposit stays final
+        assert!(restored
+            .transaction(4)
+            .is_some_and(|tx| tx.is_charged_back()));
+    }
+
+    // Test that the lowest and highest ids survive a round trip, disputes
+    // included
+    // Test that the fees collected survive a round trip
+    #[test]
+    fn test_snapshot_fees_collected() {
+        let mut engine = Engine::builder()
+            .fee_schedule(FeeSchedule {
+                rules: vec![FeeRule {
+                    applies_to: Type::Withdrawal,
+                    threshold: 0.0,
+                    rate: 0.5,
+                    fee_account: None,
+                }],
+            })
+            .build();
+        process(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             withdrawal,1,2,4.0\n",
+        );
+
+        let mut saved = Vec::new();
+        save(&engine, &mut saved).expect("Failed to save snapshot");
+        let restored = load(saved.as_slice()).expect("Failed to load snapshot");
+        assert_eq!(restored.fees_collected(), 2.0);
+    }
+
+    #[test]
+    fn test_snapshot_boundary_ids() {
+        let mut engine = Engine::new();
+        process(
+            &mut engine,
+            "type,client,tx,amount\n\
+             deposit,65535,4294967295,5.0\n\
+             deposit,0,0,3.0\n\
+             dispute,65535,4294967295,\n",
+        );
+
+        let mut saved = Vec::new();
+        save(&engine, &mut saved).expect("Failed to save snapshot");
+        let mut restored = load(saved.as_slice()).expect("Failed to load snapshot");
+
+        assert_eq!(report(&restored), report(&engine));
+        let order: Vec<u16> = restored
+            .accounts_in_order()
+            .map(|account| account.client)
+            .collect();
+        assert_eq!(order, [u16::MAX, 0]);
+        process(
+            &mut restored,
+            "type,client,tx,amount\n\
+             chargeback,65535,4294967295,\n\
+             dispute,0,0,\n",
+        );
+        assert_eq!(
+            report(&restored),
+            "client,available,held,total,locked\n\
+             0,0.0,3.0,3.0,false\n\
+             65535,0.0,0.0,0.0,true\n"
+        );
+    }
+
+    // Test that the input cursor survives a round trip, and that snapshots
+    // saved without one still load
+    #[test]
+    fn test_snapshot_cursor() {
+        let engine = Engine::new();
+        let cursor = Cursor {
+            headers: vec!["type".to_string(), "client".to_string()],
+            byte: 40,
+            line: 3,
+            record: 2,
+            checksum: 0xCBF4_3926,
+        };
+        let mut saved = Vec::new();
+        save_with_cursor(&engine, Some(&cursor), &mut saved).expect("Failed to save snapshot");
+        let loaded = load_into_with_cursor(&mut Engine::new(), saved.as_slice())
+            .expect("Failed to load snapshot");
+        assert_eq!(loaded, Some(cursor));
+
+        let mut saved = Vec::new();
+        save(&engine, &mut saved).expect("Failed to save snapshot");
+        let loaded = load_into_with_cursor(&mut Engine::new(), saved.as_slice())
+            .expect("Failed to load snapshot");
+        assert_eq!(loaded, None);
+    }
+
+    #[test]
+    fn test_snapshot_rejects_invalid_state() {
+        let unsupported = r#"{"version":99,"accounts":[],"transactions":[],"deposited":[]}"#;
+        assert!(matches!(
+            load(unsupported.as_bytes()),
+            Err(SnapshotError::UnsupportedVersion(99))
+        ));
+
+        let account = r#"{"client":1,"available":1.0,"held":0.0,"total":1.0,"locked":false,"lock_reason":null,"open_disputes":0}"#;
+        let duplicate = format!(
+            r#"{{"version":1,"accounts":[{account},{account}],"transactions":[],"deposited":[]}}"#
+        );
+        assert!(matches!(
+            load(duplicate.as_bytes()),
+            Err(SnapshotError::DuplicateClient(1))
+        ));
+
+        let orphan = r#"{"version":1,"accounts":[],"transactions":[{"type":"deposit","client":1,"tx":1,"amount":1.0,"disputed":false}],"deposited":[]}"#;
+        assert!(matches!(
+            load(orphan.as_bytes()),
+            Err(SnapshotError::Inconsistent(Invariant::UnknownClient(1, 1)))
+        ));
+
+        assert!(matches!(
+            load("not json".as_bytes()),
+            Err(SnapshotError::Format(_))
+        ));
+    }
+}
@@ -0,0 +1,165 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+
+use crate::transaction::StoredTx;
+
+mod spill;
+
+pub use spill::{SpillStats, SpillStore};
+
+/// Storage of the deposits and withdrawals kept by the engine, keyed by
+/// transaction id.
+///
+/// The engine defaults to an in-memory [`TransactionMap`]; implement this
+/// trait to keep transactions elsewhere, such as on disk for inputs larger
+/// than memory, and pass the store to [`Engine::with_store`].
+///
+/// The store is owned by the engine, hence the `'static` bound.
+///
+/// [`TransactionMap`]: crate::engine::TransactionMap
+/// [`Engine::with_store`]: crate::engine::Engine::with_store
+pub trait TransactionStore: 'static {
+    fn get(&self, tx: u32) -> Option<&StoredTx>;
+
+    fn get_mut(&mut self, tx: u32) -> Option<&mut StoredTx>;
+
+    /// Store a transaction, returning the one previously stored under `tx`.
+    fn insert(&mut self, tx: u32, transaction: StoredTx) -> Option<StoredTx>;
+
+    fn remove(&mut self, tx: u32) -> Option<StoredTx>;
+
+    /// Number of stored transactions.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether a transaction is stored under `tx`. Stores whose `get` does
+    /// not reach every transaction, such as [`SpillStore`], override it.
+    fn contains(&self, tx: u32) -> bool {
+        self.get(tx).is_some()
+    }
+
+    /// Transaction stored under `tx` wherever it is kept, read back as a
+    /// copy when it is not in memory. Stores whose `get` does not reach
+    /// every transaction, such as [`SpillStore`], override it.
+    fn load(&self, tx: u32) -> Option<Cow<'_, StoredTx>> {
+        self.get(tx).map(Cow::Borrowed)
+    }
+
+    /// Iterate over every stored transaction, in no particular order, those
+    /// not in memory being read back as copies.
+    fn iter(&self) -> impl Iterator<Item = (u32, Cow<'_, StoredTx>)>;
+}
+
+impl<H: BuildHasher + 'static> TransactionStore for HashMap<u32, StoredTx, H> {
+    fn get(&self, tx: u32) -> Option<&StoredTx> {
+        HashMap::get(self, &tx)
+    }
+
+    fn get_mut(&mut self, tx: u32) -> Option<&mut StoredTx> {
+        HashMap::get_mut(self, &tx)
+    }
+
+    fn insert(&mut self, tx: u32, transaction: StoredTx) -> Option<StoredTx> {
+        HashMap::insert(self, tx, transaction)
+    }
+
+    fn remove(&mut self, tx: u32) -> Option<StoredTx> {
+        HashMap::remove(self, &tx)
+    }
+
+    fn len(&self) -> usize {
+        HashMap::len(self)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (u32, Cow<'_, StoredTx>)> {
+        HashMap::iter(self).map(|(&tx, transaction)| (tx, Cow::Borrowed(transaction)))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::float_cmp)]
+mod tests {
+    use super::*;
+    use crate::engine::{Engine, TransactionMap};
+    use std::cell::RefCell;
+
+    // In-memory store recording the calls made by the engine
+    #[derive(Default)]
+    struct RecordingStore {
+        transactions: TransactionMap,
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl TransactionStore for RecordingStore {
+        fn get(&self, tx: u32) -> Option<&StoredTx> {
+            self.calls.borrow_mut().push(format!("get {tx}"));
+            self.transactions.get(&tx)
+        }
+
+        fn get_mut(&mut self, tx: u32) -> Option<&mut StoredTx> {
+            self.calls.borrow_mut().push(format!("get_mut {tx}"));
+            self.transactions.get_mut(&tx)
+        }
+
+        fn insert(&mut self, tx: u32, transaction: StoredTx) -> Option<StoredTx> {
+            self.calls.borrow_mut().push(format!("insert {tx}"));
+            self.transactions.insert(tx, transaction)
+        }
+
+        fn remove(&mut self, tx: u32) -> Option<StoredTx> {
+            self.calls.borrow_mut().push(format!("remove {tx}"));
+            self.transactions.remove(&tx)
+        }
+
+        fn len(&self) -> usize {
+            self.transactions.len()
+        }
+
+        fn iter(&self) -> impl Iterator<Item = (u32, Cow<'_, StoredTx>)> {
+            self.transactions
+                .iter()
+                .map(|(&tx, transaction)| (tx, Cow::Borrowed(transaction)))
+        }
+    }
+
+    // Test that the engine stores and updates transactions through the trait
+    #[test]
+    fn test_engine_uses_store() {
+        let mut engine = Engine::with_store(RecordingStore::default());
+        for transaction in csv::Reader::from_reader(
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             withdrawal,1,2,4.0\n\
+             dispute,1,1,\n\
+             resolve,1,1,\n\
+             dispute,1,7,\n"
+                .as_bytes(),
+        )
+        .deserialize()
+        {
+            let _ = engine.process_transaction(transaction.expect("Failed to parse transaction"));
+        }
+
+        assert_eq!(
+            *engine.store().calls.borrow(),
+            [
+                "get 1",
+                "insert 1",
+                "get 2",
+                "insert 2",
+                "get_mut 1",
+                "get_mut 1",
+                "get_mut 7",
+            ]
+        );
+        assert_eq!(engine.transaction_count(), 2);
+        let deposit = engine.transaction(1).expect("Transaction not found");
+        assert!(!deposit.is_disputed());
+        assert_eq!(engine.accounts[&1].available, 6.0);
+        assert_eq!(engine.check_invariants(), Ok(()));
+    }
+}
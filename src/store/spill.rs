@@ -0,0 +1,405 @@
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::TransactionStore;
+use crate::engine::Map;
+use crate::transaction::{DisputeState, StoredTx, TxKind};
+
+/// Counters of a [`SpillStore`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpillStats {
+    /// Transactions written to the spill file to make room in memory.
+    pub spilled: u64,
+    /// Lookups served from the spill file.
+    pub disk_reads: u64,
+}
+
+/// Transaction store keeping the most recently used transactions in memory
+/// and spilling the others to an append-only file, for inputs whose
+/// transactions do not fit in memory.
+///
+/// A transaction is used when it is stored or looked up mutably, which the
+/// engine does for every dispute, resolve, chargeback, settle and refund, so
+/// these rows find the deposit they reference wherever it is and bring it
+/// back into memory. Disputed and pending deposits are never spilled: the
+/// engine checks them when an account is locked, and
+/// [`Engine::check_invariants`] walks them. The memory limit is exceeded when
+/// they alone fill it.
+///
+/// [`get`](TransactionStore::get) only sees the transactions in memory, while
+/// [`load`](TransactionStore::load) and [`iter`](TransactionStore::iter) read
+/// the spilled ones back from the file without bringing them into memory.
+///
+/// # Panics
+///
+/// The store panics if reading or writing the spill file fails, as the
+/// [`TransactionStore`] methods cannot report errors.
+///
+/// [`Engine::check_invariants`]: crate::engine::Engine::check_invariants
+pub struct SpillStore {
+    capacity: usize,
+    // Transactions in memory, with the tick of their last use
+    resident: Map<u32, (u64, StoredTx)>,
+    // Ids of the transactions in memory by the tick of their last use,
+    // oldest first
+    recency: BTreeMap<u64, u32>,
+    clock: u64,
+    file: File,
+    // Offset and length of the last record of each spilled transaction;
+    // records left behind are never reclaimed
+    offsets: Map<u32, (u64, usize)>,
+    end: u64,
+    // Updated by the lookups through a shared reference too
+    stats: Cell<SpillStats>,
+}
+
+impl SpillStore {
+    /// Create a store keeping up to `capacity` transactions in memory,
+    /// spilling the others to the file at `path`, which is truncated.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be created.
+    pub fn create(path: &Path, capacity: usize) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(SpillStore {
+            capacity: capacity.max(1),
+            resident: Map::default(),
+            recency: BTreeMap::new(),
+            clock: 0,
+            file,
+            offsets: Map::default(),
+            end: 0,
+            stats: Cell::new(SpillStats::default()),
+        })
+    }
+
+    /// Number of transactions in memory.
+    #[must_use]
+    pub fn resident(&self) -> usize {
+        self.resident.len()
+    }
+
+    #[must_use]
+    pub fn stats(&self) -> SpillStats {
+        self.stats.get()
+    }
+
+    // Keep `transaction` in memory as the most recently used one
+    fn admit(&mut self, tx: u32, transaction: StoredTx) -> &mut StoredTx {
+        if self.resident.len() >= self.capacity {
+            self.evict();
+        }
+        self.clock += 1;
+        self.recency.insert(self.clock, tx);
+        let (_, transaction) = self
+            .resident
+            .entry(tx)
+            .insert_entry((self.clock, transaction))
+            .into_mut();
+        transaction
+    }
+
+    // Spill the least recently used transaction that may leave memory
+    fn evict(&mut self) {
+        let Some((&tick, &tx)) = self.recency.iter().find(|&(_, tx)| {
+            let (_, transaction) = &self.resident[tx];
+            !transaction.is_disputed() && !transaction.pending
+        }) else {
+            return;
+        };
+        self.recency.remove(&tick);
+        if let Some((_, transaction)) = self.resident.remove(&tx) {
+            self.spill(tx, &transaction);
+        }
+    }
+
+    fn spill(&mut self, tx: u32, transaction: &StoredTx) {
+        let mut record = Vec::new();
+        encode(transaction, &mut record);
+        self.file
+            .seek(SeekFrom::Start(self.end))
+            .and_then(|_| self.file.write_all(&record))
+            .expect("Failed to write to the spill file");
+        self.offsets.insert(tx, (self.end, record.len()));
+        self.end += record.len() as u64;
+        let mut stats = self.stats.get();
+        stats.spilled += 1;
+        self.stats.set(stats);
+    }
+
+    // Read a spilled transaction from the file, leaving it there
+    fn read_spilled(&self, tx: u32) -> Option<StoredTx> {
+        let &(offset, len) = self.offsets.get(&tx)?;
+        let mut record = vec![0; len];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))
+            .and_then(|_| file.read_exact(&mut record))
+            .expect("Failed to read from the spill file");
+        let mut stats = self.stats.get();
+        stats.disk_reads += 1;
+        self.stats.set(stats);
+        Some(decode(&record).expect("Corrupt record in the spill file"))
+    }
+
+    // Take a spilled transaction back from the file
+    fn unspill(&mut self, tx: u32) -> Option<StoredTx> {
+        let transaction = self.read_spilled(tx)?;
+        self.offsets.remove(&tx);
+        Some(transaction)
+    }
+
+    // Forget a transaction in memory
+    fn take_resident(&mut self, tx: u32) -> Option<StoredTx> {
+        let (tick, transaction) = self.resident.remove(&tx)?;
+        self.recency.remove(&tick);
+        Some(transaction)
+    }
+}
+
+impl TransactionStore for SpillStore {
+    fn get(&self, tx: u32) -> Option<&StoredTx> {
+        self.resident.get(&tx).map(|(_, transaction)| transaction)
+    }
+
+    fn get_mut(&mut self, tx: u32) -> Option<&mut StoredTx> {
+        let transaction = match self.take_resident(tx) {
+            Some(transaction) => transaction,
+            None => self.unspill(tx)?,
+        };
+        Some(self.admit(tx, transaction))
+    }
+
+    fn insert(&mut self, tx: u32, transaction: StoredTx) -> Option<StoredTx> {
+        let previous = self.remove(tx);
+        self.admit(tx, transaction);
+        previous
+    }
+
+    fn remove(&mut self, tx: u32) -> Option<StoredTx> {
+        self.take_resident(tx).or_else(|| self.unspill(tx))
+    }
+
+    fn len(&self) -> usize {
+        self.resident.len() + self.offsets.len()
+    }
+
+    fn contains(&self, tx: u32) -> bool {
+        self.resident.contains_key(&tx) || self.offsets.contains_key(&tx)
+    }
+
+    fn load(&self, tx: u32) -> Option<Cow<'_, StoredTx>> {
+        match self.get(tx) {
+            Some(transaction) => Some(Cow::Borrowed(transaction)),
+            None => self.read_spilled(tx).map(Cow::Owned),
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (u32, Cow<'_, StoredTx>)> {
+        let resident = self
+            .resident
+            .iter()
+            .map(|(&tx, (_, transaction))| (tx, Cow::Borrowed(transaction)));
+        let spilled = self.offsets.keys().map(|&tx| {
+            let transaction = self
+                .read_spilled(tx)
+                .expect("Spilled transaction not found");
+            (tx, Cow::Owned(transaction))
+        });
+        resident.chain(spilled)
+    }
+}
+
+// Fixed-size part of a record: client, amount, kind, dispute state, held,
+// disputes, pending, refunded and whether a note follows
+const FIXED_LEN: usize = 2 + 8 + 1 + 1 + 8 + 4 + 1 + 1 + 1;
+
+fn encode(transaction: &StoredTx, buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&transaction.client.to_le_bytes());
+    buf.extend_from_slice(&transaction.amount.to_le_bytes());
+    buf.push(match transaction.kind {
+        TxKind::Deposit => 0,
+        TxKind::Withdrawal => 1,
+    });
+    buf.push(match transaction.dispute_state {
+        DisputeState::Undisputed => 0,
+        DisputeState::Disputed => 1,
+        DisputeState::ChargedBack => 2,
+    });
+    buf.extend_from_slice(&transaction.held.to_le_bytes());
+    buf.extend_from_slice(&transaction.disputes.to_le_bytes());
+    buf.push(u8::from(transaction.pending));
+    buf.push(u8::from(transaction.refunded));
+    buf.push(u8::from(transaction.note.is_some()));
+    if let Some(note) = &transaction.note {
+        buf.extend_from_slice(note.as_bytes());
+    }
+}
+
+fn decode(record: &[u8]) -> Option<StoredTx> {
+    let (fixed, note) = record.split_at_checked(FIXED_LEN)?;
+    let client = u16::from_le_bytes(fixed[0..2].try_into().ok()?);
+    let amount = f64::from_le_bytes(fixed[2..10].try_into().ok()?);
+    let kind = match fixed[10] {
+        0 => TxKind::Deposit,
+        1 => TxKind::Withdrawal,
+        _ => return None,
+    };
+    let dispute_state = match fixed[11] {
+        0 => DisputeState::Undisputed,
+        1 => DisputeState::Disputed,
+        2 => DisputeState::ChargedBack,
+        _ => return None,
+    };
+    let held = f64::from_le_bytes(fixed[12..20].try_into().ok()?);
+    let disputes = u32::from_le_bytes(fixed[20..24].try_into().ok()?);
+    let note = match fixed[26] {
+        0 => None,
+        _ => Some(String::from_utf8(note.to_vec()).ok()?),
+    };
+    Some(StoredTx {
+        client,
+        amount,
+        kind,
+        dispute_state,
+        held,
+        disputes,
+        pending: fixed[24] != 0,
+        refunded: fixed[25] != 0,
+        note,
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::float_cmp)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::error::{Merge as MergeError, Transaction as TransactionError};
+    use crate::transaction::Transaction;
+
+    // Test that a dispute and resolve reach a deposit spilled long ago, with
+    // no more than the capacity in memory
+    #[test]
+    fn test_resolve_spilled_deposit() {
+        let dir = tempfile::tempdir().expect("Failed to create directory");
+        let store =
+            SpillStore::create(&dir.path().join("spill"), 4).expect("Failed to create spill store");
+        let mut engine = Engine::with_store(store);
+        for tx in 1..=100 {
+            let mut deposit = Transaction::deposit(1, tx, 1.0);
+            deposit.note = (tx == 1).then(|| "first".to_string());
+            engine
+                .process_transaction(deposit)
+                .expect("Failed to process deposit");
+        }
+        assert_eq!(engine.store().resident(), 4);
+        assert_eq!(engine.store().len(), 100);
+        assert!(engine.store().get(1).is_none());
+
+        engine
+            .process_transaction(Transaction::dispute(1, 1))
+            .expect("Failed to dispute spilled deposit");
+        assert_eq!(engine.store().stats().disk_reads, 1);
+        let account = engine.account(1).expect("Account not found");
+        assert_eq!((account.available, account.held), (99.0, 1.0));
+
+        // The disputed deposit stays in memory while new ones come in
+        for tx in 101..=110 {
+            engine
+                .process_transaction(Transaction::deposit(1, tx, 1.0))
+                .expect("Failed to process deposit");
+        }
+        assert!(engine.is_disputed(1).expect("Transaction not found"));
+        assert_eq!(engine.check_invariants(), Ok(()));
+        engine
+            .process_transaction(Transaction::resolve(1, 1))
+            .expect("Failed to resolve spilled deposit");
+        let account = engine.account(1).expect("Account not found");
+        assert_eq!((account.available, account.held), (110.0, 0.0));
+
+        // A spilled id is still known, and comes back as it was stored
+        assert!(matches!(
+            engine.process_transaction(Transaction::deposit(1, 50, 1.0)),
+            Err(TransactionError::DuplicateTransaction(50))
+        ));
+        let store = engine.store();
+        assert_eq!((store.resident(), store.len()), (4, 110));
+        assert_eq!(store.stats().spilled, 107);
+    }
+
+    // Test that lookups, iteration and merging see spilled transactions
+    // without bringing them back into memory
+    #[test]
+    fn test_read_spilled_transactions() {
+        let dir = tempfile::tempdir().expect("Failed to create directory");
+        let store =
+            SpillStore::create(&dir.path().join("spill"), 2).expect("Failed to create spill store");
+        let mut engine = Engine::with_store(store);
+        for tx in 1..=10 {
+            engine
+                .process_transaction(Transaction::deposit(1, tx, 2.0))
+                .expect("Failed to process deposit");
+        }
+        engine
+            .process_transaction(Transaction::withdrawal(1, 11, 5.0))
+            .expect("Failed to process withdrawal");
+        assert_eq!(engine.store().resident(), 2);
+
+        let first = engine.transaction(1).expect("Transaction not found");
+        assert_eq!((first.client, first.amount), (1, 2.0));
+        assert_eq!(engine.is_disputed(1), Some(false));
+        assert_eq!(engine.transactions().count(), 11);
+        assert_eq!(engine.net_flow(1), Some(15.0));
+        assert_eq!(engine.store().resident(), 2);
+
+        let mut other = Engine::new();
+        other
+            .process_transaction(Transaction::deposit(2, 1, 3.0))
+            .expect("Failed to process deposit");
+        assert!(matches!(
+            engine.merge(other),
+            Err(MergeError::ConflictingTransaction { tx: 1, .. })
+        ));
+    }
+
+    // Test that records read back from the file are identical
+    #[test]
+    fn test_spilled_records_round_trip() {
+        let dir = tempfile::tempdir().expect("Failed to create directory");
+        let mut store =
+            SpillStore::create(&dir.path().join("spill"), 1).expect("Failed to create spill store");
+        let transaction = StoredTx {
+            client: 7,
+            amount: 12.5,
+            kind: TxKind::Withdrawal,
+            dispute_state: DisputeState::Undisputed,
+            held: 0.0,
+            disputes: 3,
+            pending: false,
+            refunded: true,
+            note: Some("rent, march".to_string()),
+        };
+        store.insert(1, transaction.clone());
+        store.insert(
+            2,
+            StoredTx {
+                note: None,
+                ..transaction.clone()
+            },
+        );
+        assert!(store.get(1).is_none());
+        assert_eq!(store.remove(1), Some(transaction));
+        assert!(!store.contains(1));
+        assert_eq!(store.len(), 1);
+    }
+}
@@ -0,0 +1,179 @@
+use chrono::{DateTime, Utc};
+
+use crate::Counts;
+
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// Source of the timestamps reported on stderr.
+pub trait RunTimer {
+    /// Current time, or `None` when timing is not reported.
+    fn now(&mut self) -> Option<DateTime<Utc>>;
+}
+
+/// Wall clock timer, in UTC.
+pub struct SystemTimer;
+
+impl RunTimer for SystemTimer {
+    fn now(&mut self) -> Option<DateTime<Utc>> {
+        Some(Utc::now())
+    }
+}
+
+/// Timer used with `--no-timing`, making stderr reproducible across runs.
+pub struct NoTiming;
+
+impl RunTimer for NoTiming {
+    fn now(&mut self) -> Option<DateTime<Utc>> {
+        None
+    }
+}
+
+/// Formats the start and summary lines of a run from a [`RunTimer`].
+pub struct Timing {
+    timer: Box<dyn RunTimer>,
+    started: Option<DateTime<Utc>>,
+}
+
+impl Timing {
+    pub fn start(mut timer: Box<dyn RunTimer>) -> Self {
+        let started = timer.now();
+        Timing { timer, started }
+    }
+
+    /// Line announcing the start of the run, if timing is reported.
+    pub fn start_line(&self) -> Option<String> {
+        self.started
+            .map(|started| format!("Program started at {}", started.format(TIMESTAMP_FORMAT)))
+    }
+
+    /// Final summary line, including the end time and duration if timing is reported.
+    pub fn summary_line(&mut self, counts: &Counts) -> String {
+        let timing = match (self.started, self.timer.now()) {
+            (Some(started), Some(ended)) => format!(
+                " at {} in {} ms",
+                ended.format(TIMESTAMP_FORMAT),
+                (ended - started).num_milliseconds()
+            ),
+            _ => String::new(),
+        };
+        // Only mention skipped records when a client filter excluded some
+        let skipped = if counts.skipped > 0 {
+            format!(". Skipped transactions: {}", counts.skipped)
+        } else {
+            String::new()
+        };
+        // Likewise for records refused by `--max-field-len`
+        let oversized = if counts.oversized > 0 {
+            format!(". Oversized records: {}", counts.oversized)
+        } else {
+            String::new()
+        };
+        // And for the transactions of clients denied by `--deny-clients`
+        let denied = if counts.denied > 0 {
+            format!(". Denied transactions: {}", counts.denied)
+        } else {
+            String::new()
+        };
+        // And for the withdrawals skipped by `--insufficient-funds warn`
+        let insufficient = if counts.insufficient > 0 {
+            format!(". Skipped for insufficient funds: {}", counts.insufficient)
+        } else {
+            String::new()
+        };
+        format!(
+            "Processing completed{timing}. Successful transactions: {}. Errors encountered: {}{skipped}{oversized}{denied}{insufficient}",
+            counts.successful, counts.errors
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+
+    // Counts of 3 successful transactions and 1 error
+    fn counts() -> Counts {
+        Counts {
+            successful: 3,
+            errors: 1,
+            ..Counts::default()
+        }
+    }
+
+    // Test timer returning a fixed start time, advancing by a fixed step on each call
+    struct FixedTimer {
+        next: DateTime<Utc>,
+        step: TimeDelta,
+    }
+
+    impl RunTimer for FixedTimer {
+        fn now(&mut self) -> Option<DateTime<Utc>> {
+            let now = self.next;
+            self.next += self.step;
+            Some(now)
+        }
+    }
+
+    #[test]
+    fn test_timing_lines() {
+        let timer = FixedTimer {
+            next: DateTime::from_timestamp(1_700_000_000, 0).expect("Invalid timestamp"),
+            step: TimeDelta::milliseconds(1_250),
+        };
+        let mut timing = Timing::start(Box::new(timer));
+
+        assert_eq!(
+            timing.start_line().as_deref(),
+            Some("Program started at 2023-11-14 22:13:20")
+        );
+        assert_eq!(
+            timing.summary_line(&counts()),
+            "Processing completed at 2023-11-14 22:13:21 in 1250 ms. \
+             Successful transactions: 3. Errors encountered: 1"
+        );
+    }
+
+    #[test]
+    fn test_no_timing_lines() {
+        let mut timing = Timing::start(Box::new(NoTiming));
+
+        assert_eq!(timing.start_line(), None);
+        assert_eq!(
+            timing.summary_line(&counts()),
+            "Processing completed. Successful transactions: 3. Errors encountered: 1"
+        );
+        assert_eq!(
+            timing.summary_line(&Counts {
+                skipped: 2,
+                ..counts()
+            }),
+            "Processing completed. Successful transactions: 3. Errors encountered: 1. \
+             Skipped transactions: 2"
+        );
+        assert_eq!(
+            timing.summary_line(&Counts {
+                oversized: 4,
+                ..counts()
+            }),
+            "Processing completed. Successful transactions: 3. Errors encountered: 1. \
+             Oversized records: 4"
+        );
+        assert_eq!(
+            timing.summary_line(&Counts {
+                denied: 5,
+                ..counts()
+            }),
+            "Processing completed. Successful transactions: 3. Errors encountered: 1. \
+             Denied transactions: 5"
+        );
+        assert_eq!(
+            timing.summary_line(&Counts {
+                insufficient: 6,
+                ..counts()
+            }),
+            "Processing completed. Successful transactions: 3. Errors encountered: 1. \
+             Skipped for insufficient funds: 6"
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashSet;
+use std::io::Write;
+
+use crate::log::{Diagnostic, LogFormat};
+use process_tx::account::Account;
+use process_tx::event::{Event, EventHook};
+use process_tx::transaction::{Transaction, Type as TransactionType};
+
+/// Event hook writing a step-by-step narrative for a set of transaction ids.
+///
+/// Dispute, resolve and chargeback rows carry the id of the transaction they
+/// reference, so they are traced along with the original deposit.
+pub struct Tracer<W: Write> {
+    ids: HashSet<u32>,
+    out: W,
+    format: LogFormat,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(ids: HashSet<u32>, out: W, format: LogFormat) -> Self {
+        Tracer { ids, out, format }
+    }
+
+    fn trace(&mut self, event: &Event<'_>) -> std::io::Result<()> {
+        let (transaction, before) = match event {
+            Event::Applied {
+                transaction,
+                before,
+                ..
+            }
+            | Event::Rejected {
+                transaction,
+                before,
+                ..
+            } => (*transaction, *before),
+            // Lifecycle events follow the row they come from, traced already
+            _ => return Ok(()),
+        };
+
+        let mut steps = vec![format!("row: {}", describe_row(transaction))];
+        steps.push(match before {
+            Some(account) => format!("before: {}", describe(account)),
+            None => format!("before: account {} does not exist yet", transaction.client),
+        });
+        match event {
+            Event::Applied { after, .. } => {
+                steps.push(format!("applied: {}", describe_branch(&transaction.t_type)));
+                steps.push(format!("after: {}", describe(after)));
+            }
+            Event::Rejected { error, .. } => {
+                steps.push(format!("rejected: {error}"));
+                steps.push("after: balances unchanged".to_string());
+            }
+            _ => {}
+        }
+        for step in steps {
+            let message = format!("[trace tx {}] {step}", transaction.tx);
+            let line = Diagnostic::info("trace", message)
+                .transaction(transaction)
+                .render(self.format);
+            writeln!(self.out, "{line}")?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> EventHook for Tracer<W> {
+    fn on_event(&mut self, event: &Event<'_>) {
+        let (Event::Applied { transaction, .. } | Event::Rejected { transaction, .. }) = event
+        else {
+            return;
+        };
+        if self.ids.contains(&transaction.tx) {
+            // Tracing is best effort: a failing diagnostic stream must not stop processing
+            let _ = self.trace(event);
+        }
+    }
+}
+
+fn describe_row(transaction: &Transaction) -> String {
+    let t_type = transaction.t_type.as_str();
+    let amount = transaction
+        .amount
+        .map_or_else(String::new, |amount| amount.to_string());
+    format!(
+        "type={t_type} client={} tx={} amount={amount}",
+        transaction.client, transaction.tx
+    )
+}
+
+fn describe_branch(t_type: &TransactionType) -> &'static str {
+    match t_type {
+        TransactionType::Deposit => "deposit credited available and total",
+        TransactionType::Withdrawal => "withdrawal debited available and total",
+        TransactionType::Dispute => "dispute moved the deposit amount from available to held",
+        TransactionType::Resolve => "resolve released the held amount back to available",
+        TransactionType::Chargeback => {
+            "chargeback removed the held amount from total and locked the account"
+        }
+        TransactionType::Settle => "settle moved the pending deposit amount to available",
+        TransactionType::OpenAccount => "open created the account",
+        TransactionType::Refund => "refund debited the deposit amount from available and total",
+        TransactionType::Custom(_) => "the handler registered for the type applied it",
+    }
+}
+
+pub(crate) fn describe(account: &Account) -> String {
+    let (available, held, total, locked) = account.formatted_values();
+    format!(
+        "client={} available={available} held={held} total={total} locked={locked}",
+        account.client
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use process_tx::error::Transaction as TransactionError;
+
+    #[test]
+    fn test_tracer_filters_ids() {
+        let mut tracer = Tracer::new(HashSet::from([1]), Vec::new(), LogFormat::Text);
+        let before = Account {
+            client: 1,
+            available: 500.0,
+            total: 500.0,
+            ..Default::default()
+        };
+        let after = Account {
+            client: 1,
+            held: 500.0,
+            total: 500.0,
+            ..Default::default()
+        };
+        let dispute_tx = Transaction::dispute(1, 1);
+        tracer.on_event(&Event::Applied {
+            transaction: &dispute_tx,
+            before: Some(&before),
+            after: &after,
+        });
+
+        let other_tx = Transaction {
+            tx: 2,
+            ..dispute_tx.clone()
+        };
+        tracer.on_event(&Event::Rejected {
+            transaction: &other_tx,
+            before: None,
+            error: &TransactionError::NotFound(2, 1),
+        });
+
+        let output = String::from_utf8(tracer.out).expect("Trace not valid UTF-8");
+        assert_eq!(
+            output,
+            "[trace tx 1] row: type=dispute client=1 tx=1 amount=\n\
+             [trace tx 1] before: client=1 available=500.0 held=0.0 total=500.0 locked=false\n\
+             [trace tx 1] applied: dispute moved the deposit amount from available to held\n\
+             [trace tx 1] after: client=1 available=0.0 held=500.0 total=500.0 locked=false\n"
+        );
+    }
+}
@@ -1,6 +1,15 @@
-use serde::Deserialize;
+use std::collections::HashMap;
+use std::str::FromStr;
 
-#[derive(Debug, Deserialize, Clone)]
+use serde::{Deserialize, Serialize};
+
+use crate::input::Provenance;
+
+/// Monetary amount as carried by transactions and account balances.
+pub type Amount = f64;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[serde(rename_all = "lowercase")]
 pub enum Type {
     Deposit,
@@ -8,16 +17,402 @@ pub enum Type {
     Dispute,
     Resolve,
     Chargeback,
+    /// Releases a pending deposit to the available funds, see
+    /// [`Policy::pending_deposits`](crate::policy::Policy::pending_deposits).
+    Settle,
+    /// Opens the account of the client, see
+    /// [`Policy::require_explicit_open`](crate::policy::Policy::require_explicit_open).
+    #[serde(rename = "open")]
+    OpenAccount,
+    /// Reverses a deposit without a dispute, such as a merchant refund.
+    Refund,
+    /// Any other type, applied by the handler registered for it with
+    /// [`Engine::register_handler`](crate::engine::Engine::register_handler).
+    #[serde(untagged)]
+    Custom(String),
+}
+
+impl Type {
+    /// Name of the type as written in the `type` column.
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        match self {
+            Type::Deposit => "deposit",
+            Type::Withdrawal => "withdrawal",
+            Type::Dispute => "dispute",
+            Type::Resolve => "resolve",
+            Type::Chargeback => "chargeback",
+            Type::Settle => "settle",
+            Type::OpenAccount => "open",
+            Type::Refund => "refund",
+            Type::Custom(name) => name,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct Transaction {
     #[serde(rename = "type")]
     pub t_type: Type,
     pub client: u16,
     pub tx: u32,
-    pub amount: Option<f64>,
-    // Flag to indicate if the transaction is under dispute
+    /// Amount of the `amount` column, read as described by
+    /// [`parse_amount`](crate::input::parse_amount) with exponents allowed.
+    #[serde(default, deserialize_with = "crate::input::deserialize_amount")]
+    pub amount: Option<Amount>,
+    /// Free-text memo from the optional `note` column, kept with the stored
+    /// transaction and exported with it. Not used for balances.
+    #[serde(default)]
+    pub note: Option<String>,
+    /// Values of the input columns not read into the fields above, by column
+    /// name. Only filled when the reader captures them, see [`COLUMNS`].
+    #[serde(skip)]
+    #[cfg_attr(feature = "arbitrary", arbitrary(default))]
+    pub extra: HashMap<String, String>,
+    /// File and line the transaction was read from, set by
+    /// [`Schema::read_from`](crate::input::Schema::read_from). Kept on the
+    /// [`Outcome`](crate::engine::Outcome), so that a rejected row can be
+    /// located even when it was settled after later rows.
     #[serde(skip)]
+    #[cfg_attr(feature = "arbitrary", arbitrary(default))]
+    pub provenance: Option<Provenance>,
+}
+
+impl Transaction {
+    /// Transaction of type `t_type` without a note or extra columns.
+    #[must_use]
+    pub fn new(t_type: Type, client: u16, tx: u32, amount: Option<Amount>) -> Self {
+        Transaction {
+            t_type,
+            client,
+            tx,
+            amount,
+            note: None,
+            extra: HashMap::new(),
+            provenance: None,
+        }
+    }
+
+    /// Deposit of `amount` to the account of `client`.
+    ///
+    /// ```
+    /// use process_tx::engine::Engine;
+    /// use process_tx::transaction::Transaction;
+    ///
+    /// let mut engine = Engine::new();
+    /// engine.process_transaction(Transaction::deposit(1, 1, 10.0))?;
+    /// let account = engine.account(1).expect("Account not found");
+    /// assert_eq!((account.available, account.total), (10.0, 10.0));
+    /// # Ok::<(), process_tx::error::Transaction>(())
+    /// ```
+    #[must_use]
+    pub fn deposit(client: u16, tx: u32, amount: Amount) -> Self {
+        Self::new(Type::Deposit, client, tx, Some(amount))
+    }
+
+    /// Withdrawal of `amount` from the account of `client`.
+    ///
+    /// ```
+    /// use process_tx::engine::Engine;
+    /// use process_tx::transaction::Transaction;
+    ///
+    /// let mut engine = Engine::new();
+    /// engine.process_transaction(Transaction::deposit(1, 1, 10.0))?;
+    /// engine.process_transaction(Transaction::withdrawal(1, 2, 4.0))?;
+    /// assert!(engine.process_transaction(Transaction::withdrawal(1, 3, 7.0)).is_err());
+    /// assert_eq!(engine.account(1).expect("Account not found").available, 6.0);
+    /// # Ok::<(), process_tx::error::Transaction>(())
+    /// ```
+    #[must_use]
+    pub fn withdrawal(client: u16, tx: u32, amount: Amount) -> Self {
+        Self::new(Type::Withdrawal, client, tx, Some(amount))
+    }
+
+    /// Dispute of the deposit `tx` of `client`, holding its funds.
+    ///
+    /// ```
+    /// use process_tx::engine::Engine;
+    /// use process_tx::transaction::Transaction;
+    ///
+    /// let mut engine = Engine::new();
+    /// engine.process_transaction(Transaction::deposit(1, 1, 10.0))?;
+    /// engine.process_transaction(Transaction::dispute(1, 1))?;
+    /// let account = engine.account(1).expect("Account not found");
+    /// assert_eq!((account.available, account.held), (0.0, 10.0));
+    /// # Ok::<(), process_tx::error::Transaction>(())
+    /// ```
+    #[must_use]
+    pub fn dispute(client: u16, tx: u32) -> Self {
+        Self::new(Type::Dispute, client, tx, None)
+    }
+
+    /// Resolution of the dispute of `tx`, releasing the held funds.
+    ///
+    /// ```
+    /// use process_tx::engine::Engine;
+    /// use process_tx::transaction::Transaction;
+    ///
+    /// let mut engine = Engine::new();
+    /// engine.process_transaction(Transaction::deposit(1, 1, 10.0))?;
+    /// engine.process_transaction(Transaction::dispute(1, 1))?;
+    /// engine.process_transaction(Transaction::resolve(1, 1))?;
+    /// let account = engine.account(1).expect("Account not found");
+    /// assert_eq!((account.available, account.held), (10.0, 0.0));
+    /// # Ok::<(), process_tx::error::Transaction>(())
+    /// ```
+    #[must_use]
+    pub fn resolve(client: u16, tx: u32) -> Self {
+        Self::new(Type::Resolve, client, tx, None)
+    }
+
+    /// Chargeback of the disputed deposit `tx`, removing the held funds and
+    /// locking the account.
+    ///
+    /// ```
+    /// use process_tx::engine::Engine;
+    /// use process_tx::transaction::Transaction;
+    ///
+    /// let mut engine = Engine::new();
+    /// engine.process_transaction(Transaction::deposit(1, 1, 10.0))?;
+    /// engine.process_transaction(Transaction::dispute(1, 1))?;
+    /// engine.process_transaction(Transaction::chargeback(1, 1))?;
+    /// let account = engine.account(1).expect("Account not found");
+    /// assert_eq!((account.total, account.locked), (0.0, true));
+    /// # Ok::<(), process_tx::error::Transaction>(())
+    /// ```
+    #[must_use]
+    pub fn chargeback(client: u16, tx: u32) -> Self {
+        Self::new(Type::Chargeback, client, tx, None)
+    }
+
+    /// Refund of the deposit `tx` of `client`, debiting its amount from the
+    /// available funds without a dispute or a lock.
+    ///
+    /// ```
+    /// use process_tx::engine::Engine;
+    /// use process_tx::transaction::Transaction;
+    ///
+    /// let mut engine = Engine::new();
+    /// engine.process_transaction(Transaction::deposit(1, 1, 10.0))?;
+    /// engine.process_transaction(Transaction::deposit(1, 2, 4.0))?;
+    /// engine.process_transaction(Transaction::refund(1, 1))?;
+    /// let account = engine.account(1).expect("Account not found");
+    /// assert_eq!((account.total, account.locked), (4.0, false));
+    /// # Ok::<(), process_tx::error::Transaction>(())
+    /// ```
+    #[must_use]
+    pub fn refund(client: u16, tx: u32) -> Self {
+        Self::new(Type::Refund, client, tx, None)
+    }
+}
+
+/// Input columns read into the fields of [`Transaction`]. Other columns are
+/// ignored, or captured into [`Transaction::extra`] by readers that ask for it.
+pub const COLUMNS: [&str; 5] = ["type", "client", "tx", "amount", "note"];
+
+/// Kind of a stored transaction. Only deposits and withdrawals move funds and
+/// are kept for later reference by disputes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TxKind {
+    Deposit,
+    Withdrawal,
+}
+
+impl TxKind {
+    /// Name of the kind as written in the `type` column.
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TxKind::Deposit => "deposit",
+            TxKind::Withdrawal => "withdrawal",
+        }
+    }
+}
+
+impl FromStr for TxKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deposit" => Ok(TxKind::Deposit),
+            "withdrawal" => Ok(TxKind::Withdrawal),
+            other => Err(format!(
+                "invalid transaction type '{other}', expected one of: deposit, withdrawal"
+            )),
+        }
+    }
+}
+
+/// Dispute lifecycle of a stored transaction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DisputeState {
+    /// Never disputed, or the last dispute was resolved.
+    #[default]
+    Undisputed,
+    /// Under dispute: the amount is held until a resolve or chargeback.
+    Disputed,
+    /// Charged back, which is final whether or not the account was locked:
+    /// the deposit can no longer be disputed, resolved, charged back or
+    /// refunded.
+    ChargedBack,
+}
+
+/// Deposit or withdrawal kept by the engine, keyed by its transaction id.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredTx {
+    pub client: u16,
+    /// Always positive: a deposit or withdrawal without a positive amount is
+    /// refused before anything is stored.
+    pub amount: Amount,
+    pub kind: TxKind,
+    pub dispute_state: DisputeState,
+    /// Funds held by the open dispute, less than `amount` when the dispute
+    /// was clamped to the available funds. Zero when not disputed.
+    pub held: Amount,
+    /// Number of times the transaction was disputed, including an open dispute.
+    pub disputes: u32,
+    /// Deposit whose funds are still pending, waiting for a settle row.
+    pub pending: bool,
+    /// Deposit reversed by a refund, which can no longer be disputed.
+    pub refunded: bool,
+    /// Memo carried by the original row, if any.
+    pub note: Option<String>,
+}
+
+impl StoredTx {
+    #[must_use]
+    pub fn is_disputed(&self) -> bool {
+        self.dispute_state == DisputeState::Disputed
+    }
+
+    #[must_use]
+    pub fn is_charged_back(&self) -> bool {
+        self.dispute_state == DisputeState::ChargedBack
+    }
+
+    /// Part of the disputed amount that could not be held.
+    #[must_use]
+    pub fn shortfall(&self) -> Amount {
+        if self.is_disputed() {
+            self.amount - self.held
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Read-only view of a stored transaction for library consumers, see
+/// [`Engine::transactions_iter`](crate::engine::Engine::transactions_iter).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct TxStatus {
+    pub tx: u32,
+    pub client: u16,
+    pub kind: TxKind,
+    pub amount: Amount,
     pub disputed: bool,
+    /// Number of times the transaction was disputed, including an open dispute.
+    pub disputes: u32,
+    pub pending: bool,
+    pub refunded: bool,
+}
+
+impl TxStatus {
+    #[must_use]
+    pub fn new(tx: u32, transaction: &StoredTx) -> Self {
+        TxStatus {
+            tx,
+            client: transaction.client,
+            kind: transaction.kind,
+            amount: transaction.amount,
+            disputed: transaction.is_disputed(),
+            disputes: transaction.disputes,
+            pending: transaction.pending,
+            refunded: transaction.refunded,
+        }
+    }
+}
+
+/// Conditions a [`TxStatus`] must all meet, built from a filter matching
+/// every transaction.
+///
+/// ```
+/// use process_tx::engine::Engine;
+/// use process_tx::transaction::{Transaction, TxFilter, TxKind};
+///
+/// let mut engine = Engine::new();
+/// engine.process_transaction(Transaction::deposit(1, 1, 10.0))?;
+/// engine.process_transaction(Transaction::deposit(1, 2, 500.0))?;
+/// engine.process_transaction(Transaction::withdrawal(1, 3, 200.0))?;
+/// let filter = TxFilter::new().kind(TxKind::Deposit).min_amount(100.0);
+/// let large: Vec<u32> = engine
+///     .transactions_iter()
+///     .filter(|status| filter.matches(status))
+///     .map(|status| status.tx)
+///     .collect();
+/// assert_eq!(large, [2]);
+/// # Ok::<(), process_tx::error::Transaction>(())
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TxFilter {
+    client: Option<u16>,
+    kind: Option<TxKind>,
+    min_amount: Option<Amount>,
+    max_amount: Option<Amount>,
+    disputed: Option<bool>,
+}
+
+impl TxFilter {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only transactions of `client`.
+    #[must_use]
+    pub fn client(mut self, client: u16) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Only deposits or only withdrawals.
+    #[must_use]
+    pub fn kind(mut self, kind: TxKind) -> Self {
+        self.kind = Some(kind);
+        self
+    }
+
+    /// Only transactions of at least `amount`.
+    #[must_use]
+    pub fn min_amount(mut self, amount: Amount) -> Self {
+        self.min_amount = Some(amount);
+        self
+    }
+
+    /// Only transactions of at most `amount`.
+    #[must_use]
+    pub fn max_amount(mut self, amount: Amount) -> Self {
+        self.max_amount = Some(amount);
+        self
+    }
+
+    /// Only transactions under dispute, or only those that are not.
+    #[must_use]
+    pub fn disputed(mut self, disputed: bool) -> Self {
+        self.disputed = Some(disputed);
+        self
+    }
+
+    /// Whether `status` meets every condition of the filter.
+    #[must_use]
+    pub fn matches(&self, status: &TxStatus) -> bool {
+        self.client.is_none_or(|client| status.client == client)
+            && self.kind.is_none_or(|kind| status.kind == kind)
+            && self.min_amount.is_none_or(|min| status.amount >= min)
+            && self.max_amount.is_none_or(|max| status.amount <= max)
+            && self
+                .disputed
+                .is_none_or(|disputed| status.disputed == disputed)
+    }
 }
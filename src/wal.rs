@@ -0,0 +1,409 @@
+//! Write-ahead log of the applied transactions, for crash recovery.
+//!
+//! Each record is the transaction as applied, encoded in a small binary
+//! layout and framed as `length (u32) | crc32 (u32) | payload`, all integers
+//! little-endian. A crash while appending leaves at most one torn record at
+//! the end of the log, which [`recover`] drops before replaying the rest.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use crate::engine::Engine;
+use crate::error::Wal as WalError;
+use crate::store::TransactionStore;
+use crate::transaction::{Transaction, Type as TransactionType};
+
+// Bytes of the length and checksum preceding each payload
+const HEADER_LEN: usize = 8;
+
+/// Appends applied transactions to a log, one record per call.
+pub struct Writer<W: Write> {
+    inner: W,
+    // Reused encoding buffer
+    record: Vec<u8>,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(inner: W) -> Self {
+        Writer {
+            inner,
+            record: Vec::new(),
+        }
+    }
+
+    /// Append `transaction` and flush it, so that it is in the log before the
+    /// next transaction is processed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if writing to the underlying writer fails.
+    pub fn append(&mut self, transaction: &Transaction) -> io::Result<()> {
+        start_record(&mut self.record);
+        encode(transaction, &mut self.record);
+        seal_record(&mut self.record)?;
+        self.inner.write_all(&self.record)?;
+        self.inner.flush()
+    }
+}
+
+impl Writer<File> {
+    /// Open the log at `path` for appending, keeping its records when
+    /// `keep` is set and truncating it otherwise.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be opened.
+    pub fn open(path: &Path, keep: bool) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        if !keep {
+            file.set_len(0)?;
+        }
+        Ok(Writer::new(file))
+    }
+
+    /// Drop every record, once the state they rebuild is saved elsewhere.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be truncated.
+    pub fn reset(&mut self) -> io::Result<()> {
+        self.inner.set_len(0)
+    }
+}
+
+/// Records read back from a log.
+#[derive(Debug)]
+pub struct Log {
+    pub transactions: Vec<Transaction>,
+    /// Length of the complete records, where a torn record starts.
+    pub valid_len: u64,
+    /// Whether the log ends with a torn record, left out of `transactions`.
+    pub torn: bool,
+}
+
+/// Read the records of a log, stopping at a torn final record.
+///
+/// # Errors
+///
+/// Returns an error if reading fails, or if a complete record other than the
+/// last one fails its checksum or cannot be decoded.
+pub fn read<R: Read>(mut reader: R) -> Result<Log, WalError> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+
+    let (payloads, valid_len) = records(&bytes).map_err(WalError::Checksum)?;
+    let mut transactions = Vec::with_capacity(payloads.len());
+    for (offset, payload) in payloads {
+        transactions.push(decode(payload).ok_or(WalError::Record(offset))?);
+    }
+    Ok(Log {
+        transactions,
+        valid_len,
+        torn: valid_len < bytes.len() as u64,
+    })
+}
+
+/// Rebuild the state logged at `path` by replaying its records into
+/// `engine`, truncating a torn final record so that the log can be appended
+/// to again. Returns the log that was replayed.
+///
+/// # Errors
+///
+/// Returns an error if the log cannot be read or truncated, is corrupt, or
+/// holds a transaction that `engine` rejects, as happens when it is replayed
+/// under a different policy.
+pub fn recover<S: TransactionStore>(engine: &mut Engine<S>, path: &Path) -> Result<Log, WalError> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let log = read(&mut file)?;
+    if log.torn {
+        file.set_len(log.valid_len)?;
+    }
+    for transaction in &log.transactions {
+        engine
+            .process_transaction(transaction.clone())
+            .map_err(|source| WalError::Rejected {
+                tx: transaction.tx,
+                source,
+            })?;
+    }
+    Ok(log)
+}
+
+// Clear `record` and reserve the header of a new record, for the payload to
+// be appended to
+pub(crate) fn start_record(record: &mut Vec<u8>) {
+    record.clear();
+    record.resize(HEADER_LEN, 0);
+}
+
+// Fill the header of a record once its payload is appended
+pub(crate) fn seal_record(record: &mut [u8]) -> io::Result<()> {
+    let payload = &record[HEADER_LEN..];
+    let len = u32::try_from(payload.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "record too large"))?;
+    let crc = crc32(payload);
+    record[..4].copy_from_slice(&len.to_le_bytes());
+    record[4..HEADER_LEN].copy_from_slice(&crc.to_le_bytes());
+    Ok(())
+}
+
+// Payloads of the records of a log, with their offsets
+pub(crate) type Payloads<'a> = Vec<(u64, &'a [u8])>;
+
+// Split `bytes` into the payloads of its complete records and the length
+// they cover. A torn final record is left out; a record failing its checksum
+// elsewhere fails with its offset.
+pub(crate) fn records(bytes: &[u8]) -> Result<(Payloads<'_>, u64), u64> {
+    let mut payloads = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let Some(header) = bytes.get(offset..offset + HEADER_LEN) else {
+            break;
+        };
+        let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        let end = offset + HEADER_LEN + len;
+        let Some(payload) = bytes.get(offset + HEADER_LEN..end) else {
+            break;
+        };
+        if crc32(payload) != crc {
+            // Only the last record can be torn by a crash
+            if end == bytes.len() {
+                break;
+            }
+            return Err(offset as u64);
+        }
+        payloads.push((offset as u64, payload));
+        offset = end;
+    }
+    Ok((payloads, offset as u64))
+}
+
+pub(crate) fn encode(transaction: &Transaction, buf: &mut Vec<u8>) {
+    buf.push(match transaction.t_type {
+        TransactionType::Deposit => 0,
+        TransactionType::Withdrawal => 1,
+        TransactionType::Dispute => 2,
+        TransactionType::Resolve => 3,
+        TransactionType::Chargeback => 4,
+        TransactionType::Settle => 5,
+        TransactionType::OpenAccount => 6,
+        TransactionType::Custom(_) => 7,
+        TransactionType::Refund => 8,
+    });
+    if let TransactionType::Custom(name) = &transaction.t_type {
+        encode_str(name, buf);
+    }
+    buf.extend_from_slice(&transaction.client.to_le_bytes());
+    buf.extend_from_slice(&transaction.tx.to_le_bytes());
+    match transaction.amount {
+        Some(amount) => {
+            buf.push(1);
+            buf.extend_from_slice(&amount.to_le_bytes());
+        }
+        None => buf.push(0),
+    }
+    match &transaction.note {
+        Some(note) => {
+            buf.push(1);
+            encode_str(note, buf);
+        }
+        None => buf.push(0),
+    }
+}
+
+// Append `s` prefixed with its length in bytes
+fn encode_str(s: &str, buf: &mut Vec<u8>) {
+    let len = u32::try_from(s.len()).unwrap_or(u32::MAX);
+    buf.extend_from_slice(&len.to_le_bytes());
+    buf.extend_from_slice(&s.as_bytes()[..len as usize]);
+}
+
+fn decode(payload: &[u8]) -> Option<Transaction> {
+    let mut rest = payload;
+    let transaction = decode_prefix(&mut rest)?;
+    rest.is_empty().then_some(transaction)
+}
+
+// Decode the transaction at the start of `rest`, advancing past it
+pub(crate) fn decode_prefix(rest: &mut &[u8]) -> Option<Transaction> {
+    let mut take = |n: usize| -> Option<&[u8]> {
+        let (head, tail) = rest.split_at_checked(n)?;
+        *rest = tail;
+        Some(head)
+    };
+    let t_type = match take(1)?[0] {
+        0 => TransactionType::Deposit,
+        1 => TransactionType::Withdrawal,
+        2 => TransactionType::Dispute,
+        3 => TransactionType::Resolve,
+        4 => TransactionType::Chargeback,
+        5 => TransactionType::Settle,
+        6 => TransactionType::OpenAccount,
+        7 => {
+            let len = u32::from_le_bytes(take(4)?.try_into().ok()?);
+            TransactionType::Custom(String::from_utf8(take(len as usize)?.to_vec()).ok()?)
+        }
+        8 => TransactionType::Refund,
+        _ => return None,
+    };
+    let client = u16::from_le_bytes(take(2)?.try_into().ok()?);
+    let tx = u32::from_le_bytes(take(4)?.try_into().ok()?);
+    let amount = match take(1)?[0] {
+        0 => None,
+        1 => Some(f64::from_le_bytes(take(8)?.try_into().ok()?)),
+        _ => return None,
+    };
+    let note = match take(1)?[0] {
+        0 => None,
+        1 => {
+            let len = u32::from_le_bytes(take(4)?.try_into().ok()?);
+            Some(String::from_utf8(take(len as usize)?.to_vec()).ok()?)
+        }
+        _ => return None,
+    };
+    Some(Transaction {
+        note,
+        ..Transaction::new(t_type, client, tx, amount)
+    })
+}
+
+// CRC-32 (IEEE), computed bitwise: records are small and a table or a crate
+// would not pay off
+fn crc32(bytes: &[u8]) -> u32 {
+    crc32_update(0, bytes)
+}
+
+// Extend the CRC-32 `crc` of some bytes with the bytes that follow them
+pub(crate) fn crc32_update(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+#[allow(clippy::float_cmp)]
+mod tests {
+    use super::*;
+
+    const INPUT: &str = "type,client,tx,amount,note\n\
+                         deposit,1,1,100.0,salary\n\
+                         deposit,2,2,50.5,\n\
+                         withdrawal,1,3,30.0,\n\
+                         dispute,2,2,,\n\
+                         deposit,1,4,0.1234,\n\
+                         chargeback,2,2,,\n";
+
+    fn transactions() -> Vec<Transaction> {
+        csv::Reader::from_reader(INPUT.as_bytes())
+            .deserialize()
+            .map(|transaction| transaction.expect("Failed to parse transaction"))
+            .collect()
+    }
+
+    fn write_log(transactions: &[Transaction]) -> Vec<u8> {
+        let mut writer = Writer::new(Vec::new());
+        for transaction in transactions {
+            writer.append(transaction).expect("Failed to append");
+        }
+        writer.inner
+    }
+
+    #[test]
+    fn test_crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    // Test that every record reads back as the transaction appended, refunds
+    // and custom types included
+    #[test]
+    fn test_read_round_trip() {
+        let mut transactions = transactions();
+        let interest = TransactionType::Custom("interest".to_string());
+        transactions.push(Transaction::new(interest, 1, 5, Some(1.5)));
+        transactions.push(Transaction::refund(1, 1));
+        let bytes = write_log(&transactions);
+        let log = read(bytes.as_slice()).expect("Failed to read log");
+
+        assert!(!log.torn);
+        assert_eq!(log.valid_len, bytes.len() as u64);
+        assert_eq!(log.transactions.len(), transactions.len());
+        for (read, written) in log.transactions.iter().zip(&transactions) {
+            assert_eq!(read.t_type, written.t_type);
+            assert_eq!((read.client, read.tx), (written.client, written.tx));
+            assert_eq!(read.amount, written.amount);
+            assert_eq!(read.note, written.note);
+        }
+    }
+
+    // Test that the lowest and highest ids are encoded without loss
+    #[test]
+    fn test_read_boundary_ids() {
+        let transactions: Vec<Transaction> = [(0, 0), (u16::MAX, u32::MAX)]
+            .into_iter()
+            .map(|(client, tx)| Transaction::deposit(client, tx, 1.0))
+            .collect();
+        let log = read(write_log(&transactions).as_slice()).expect("Failed to read log");
+
+        let ids: Vec<(u16, u32)> = log
+            .transactions
+            .iter()
+            .map(|transaction| (transaction.client, transaction.tx))
+            .collect();
+        assert_eq!(ids, [(0, 0), (u16::MAX, u32::MAX)]);
+    }
+
+    // Test that recovering a log torn mid-record rebuilds the state of the
+    // complete records and truncates the torn one
+    #[test]
+    fn test_recover_torn_log() {
+        let transactions = transactions();
+        let prefix = write_log(&transactions[..4]);
+        let full = write_log(&transactions);
+        let file = tempfile::NamedTempFile::new().expect("Failed to create log file");
+        let torn_len = prefix.len() + 5;
+        std::fs::write(file.path(), &full[..torn_len]).expect("Failed to write log");
+
+        let mut recovered = Engine::new();
+        let log = recover(&mut recovered, file.path()).expect("Failed to recover");
+
+        assert!(log.torn);
+        assert_eq!(log.transactions.len(), 4);
+        assert_eq!(
+            std::fs::read(file.path()).expect("Failed to read log"),
+            prefix
+        );
+        let mut expected = Engine::new();
+        for transaction in &transactions[..4] {
+            expected
+                .process_transaction(transaction.clone())
+                .expect("Failed to process transaction");
+        }
+        assert_eq!(recovered.all_account_views(), expected.all_account_views());
+        assert_eq!(
+            recovered
+                .transaction(1)
+                .and_then(|stored| stored.note.clone()),
+            Some("salary".to_string())
+        );
+    }
+
+    // Test that a corrupt record followed by others is an error rather than
+    // a torn tail
+    #[test]
+    fn test_read_corrupt_record() {
+        let mut bytes = write_log(&transactions());
+        bytes[HEADER_LEN] ^= 0xFF;
+        assert!(matches!(read(bytes.as_slice()), Err(WalError::Checksum(0))));
+    }
+}
@@ -1,8 +1,22 @@
 use assert_cmd::Command;
+use process_tx::report;
 use rstest::rstest;
 use std::fs;
+use std::io::Write;
 use std::path::Path;
 
+// Compare an accounts report with the expected one like the `compare`
+// subcommand, whatever the order of the rows and the formatting of amounts
+fn compare_report(expected: &str, actual: &str) -> Result<(), String> {
+    let diff = report::compare_reports(expected.as_bytes(), actual.as_bytes(), 0.0)
+        .map_err(|e| e.to_string())?;
+    if diff.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("{diff:?}"))
+    }
+}
+
 #[rstest]
 #[case("input1.csv", "expected_output1.csv")] // Test Case 1: Basic transactions with dispute and chargeback
 #[case("input2.csv", "expected_output2.csv")] // Test Case 2: Withdrawal with insufficient funds
@@ -12,6 +26,11 @@ use std::path::Path;
 #[case("input6.csv", "expected_output6.csv")] // Test Case 6: Dispute without resolution or chargeback
 #[case("input7.csv", "expected_output7.csv")] // Test Case 7: Multiple disputes without resolution
 #[case("input8.csv", "expected_output8.csv")] // Test Case 8: Dispute followed by attempted withdrawal
+#[case("input1_bom.csv", "expected_output1.csv")] // Test Case 9: Case 1 exported with a leading UTF-8 BOM
+#[case("input1_extra_columns.csv", "expected_output1.csv")] // Test Case 10: Case 1 with shuffled and extra columns
+#[case("input1_minor.csv", "expected_output1.csv")] // Test Case 11: Case 1 with amounts in minor units
+#[case("input5_minor.csv", "expected_output5.csv")] // Test Case 12: Case 5 with amounts in minor units
+#[case("input_boundary_ids.csv", "expected_output_boundary_ids.csv")] // Test Case 13: Client and transaction ids 0 and their maximum values
 fn test_end_to_end_processing(#[case] input_file: &str, #[case] expected_output_file: &str) {
     // Build the full paths to the input and expected output files
     let input_path = Path::new("tests/data").join(input_file);
@@ -19,7 +38,7 @@ fn test_end_to_end_processing(#[case] input_file: &str, #[case] expected_output_
 
     // Read the expected output
     let expected_output = fs::read_to_string(&expected_output_path)
-        .expect(&format!("Failed to read {}", expected_output_file));
+        .unwrap_or_else(|_| panic!("Failed to read {expected_output_file}"));
 
     // Run binary and capture the output
     let output = Command::cargo_bin("process-tx")
@@ -38,7 +57,1958 @@ fn test_end_to_end_processing(#[case] input_file: &str, #[case] expected_output_
     // Compare the actual output with the expected output
     assert_eq!(
         actual_output, expected_output,
-        "Test failed for input file: {}",
-        input_file
+        "Test failed for input file: {input_file}"
+    );
+}
+
+// Test that a file with both amount columns is refused before any processing
+#[test]
+fn test_both_amount_columns_refused() {
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .arg("--no-timing")
+        .arg(Path::new("tests/data").join("input_both_amounts.csv"))
+        .output()
+        .expect("Failed to execute command");
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).expect("Output not valid UTF-8");
+    assert!(
+        stderr.contains("has both an `amount` and an `amount_minor` column"),
+        "{stderr}"
+    );
+}
+
+// Test that --strict-amounts reports deposits and withdrawals without a
+// finite amount as parse errors, with their line, instead of processing them
+#[test]
+fn test_strict_amounts() {
+    let mut input = tempfile::NamedTempFile::new().expect("Failed to create input file");
+    write!(
+        input,
+        "type,client,tx,amount\n\
+         deposit,1,1,inf\n\
+         deposit,1,2,\n\
+         deposit,1,3,10.0\n\
+         withdrawal,1,4,NaN\n"
+    )
+    .expect("Failed to write input");
+
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .args(["--no-timing", "--strict-amounts"])
+        .arg(input.path())
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).expect("Output not valid UTF-8"),
+        "client,available,held,total,locked\n1,10.0,0.0,10.0,false\n"
+    );
+    let stderr = String::from_utf8(output.stderr).expect("Output not valid UTF-8");
+    let parse_errors: Vec<&str> = stderr
+        .lines()
+        .filter(|line| line.starts_with("Failed to parse transaction record"))
+        .collect();
+    assert_eq!(parse_errors.len(), 3, "{stderr}");
+    assert!(
+        parse_errors[0].contains("at line 2") && parse_errors[0].contains("deposit amount inf")
+    );
+    assert!(
+        parse_errors[1].contains("at line 3") && parse_errors[1].contains("deposit has no amount")
+    );
+    assert!(
+        parse_errors[2].contains("at line 5") && parse_errors[2].contains("withdrawal amount NaN")
+    );
+    assert!(stderr.contains("Successful transactions: 1. Errors encountered: 0"));
+}
+
+// Test that --previous lists the clients changed since a perturbed copy of
+// the report of the same fixture, by absolute total delta
+#[test]
+fn test_delta_report() {
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .args(["--no-timing", "tests/data/input1.csv"])
+        .output()
+        .expect("Failed to execute command");
+    assert!(output.status.success());
+    let report = String::from_utf8(output.stdout).expect("Output not valid UTF-8");
+    // Client 1 gets locked, client 2 is new and client 3 disappeared
+    let perturbed = report
+        .replace("1,-500.0,0.0,-500.0,true", "1,400.0,0.0,400.0,false")
+        .replace("2,1000.0,0.0,1000.0,false", "3,5.0,0.0,5.0,false");
+    assert_ne!(perturbed, report);
+    let mut previous = tempfile::NamedTempFile::new().expect("Failed to create report file");
+    write!(previous, "{perturbed}").expect("Failed to write report");
+    let delta = tempfile::NamedTempFile::new().expect("Failed to create delta file");
+
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .arg("--previous")
+        .arg(previous.path())
+        .arg("--delta-report")
+        .arg(delta.path())
+        .args(["--no-timing", "tests/data/input1.csv"])
+        .output()
+        .expect("Failed to execute command");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).expect("Output not valid UTF-8"),
+        report
+    );
+    let expected = [
+        "client,status,available_old,available_new,available_delta,held_old,held_new,\
+         held_delta,total_old,total_new,total_delta,locked_old,locked_new",
+        "2,new,,1000.0,1000.0,,0.0,0.0,,1000.0,1000.0,,false",
+        "1,changed,400.0,-500.0,-900.0,0.0,0.0,0.0,400.0,-500.0,-900.0,false,true",
+        "3,disappeared,5.0,,-5.0,0.0,,0.0,5.0,,-5.0,false,",
+    ];
+    assert_eq!(
+        fs::read_to_string(delta.path()).expect("Failed to read delta file"),
+        expected.map(|line| format!("{line}\n")).concat()
+    );
+}
+
+// Test that a missing --previous report fails the run before any output is
+// written, and that the report of a run with a decimal comma compares
+// unchanged with the same run
+#[test]
+fn test_delta_report_previous() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let state = dir.path().join("state.json");
+    let delta = dir.path().join("delta.csv");
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .arg("--previous")
+        .arg(dir.path().join("missing.csv"))
+        .arg("--delta-report")
+        .arg(&delta)
+        .arg("--save-state")
+        .arg(&state)
+        .args(["--no-timing", "tests/data/input1.csv"])
+        .output()
+        .expect("Failed to execute command");
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Failed to open previous report"));
+    assert!(!state.exists());
+
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .args([
+            "--decimal-separator",
+            ",",
+            "--no-timing",
+            "tests/data/input1.csv",
+        ])
+        .output()
+        .expect("Failed to execute command");
+    assert!(output.status.success());
+    let previous = dir.path().join("previous.csv");
+    fs::write(&previous, &output.stdout).expect("Failed to write report");
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .arg("--previous")
+        .arg(&previous)
+        .arg("--delta-report")
+        .arg(&delta)
+        .args([
+            "--decimal-separator",
+            ",",
+            "--no-timing",
+            "tests/data/input1.csv",
+        ])
+        .output()
+        .expect("Failed to execute command");
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(&delta).expect("Failed to read delta file"),
+        "client,status,available_old,available_new,available_delta,held_old,held_new,\
+         held_delta,total_old,total_new,total_delta,locked_old,locked_new\n"
+    );
+}
+
+// Test that amounts outside the grammar fail to parse with their text, and
+// that --allow-exponent accepts the ones written with an exponent
+#[test]
+fn test_amount_grammar() {
+    let mut input = tempfile::NamedTempFile::new().expect("Failed to create input file");
+    write!(
+        input,
+        "type,client,tx,amount\n\
+         deposit,1,1,100\n\
+         deposit,1,2,+50.5\n\
+         deposit,1,3,1e3\n\
+         deposit,1,4,.5\n"
+    )
+    .expect("Failed to write input");
+
+    let run = |args: &[&str]| {
+        Command::cargo_bin("process-tx")
+            .expect("Binary not found")
+            .arg("--no-timing")
+            .args(args)
+            .arg(input.path())
+            .output()
+            .expect("Failed to execute command")
+    };
+
+    let output = run(&[]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).expect("Output not valid UTF-8"),
+        "client,available,held,total,locked\n1,150.5,0.0,150.5,false\n"
+    );
+    let stderr = String::from_utf8(output.stderr).expect("Output not valid UTF-8");
+    let parse_errors: Vec<&str> = stderr
+        .lines()
+        .filter(|line| line.starts_with("Failed to parse transaction record"))
+        .collect();
+    assert_eq!(parse_errors.len(), 2, "{stderr}");
+    assert!(parse_errors[0].contains("at line 4") && parse_errors[0].contains("'1e3'"));
+    assert!(parse_errors[1].contains("at line 5") && parse_errors[1].contains("'.5'"));
+
+    let output = run(&["--allow-exponent"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).expect("Output not valid UTF-8"),
+        "client,available,held,total,locked\n1,1150.5,0.0,1150.5,false\n"
+    );
+}
+
+// Test that reading the input through a memory map gives the report of the
+// fixture, identical to the one of a regular read
+#[test]
+fn test_mmap_input() {
+    let expected = fs::read_to_string("tests/data/expected_output1.csv")
+        .expect("Failed to read expected_output1.csv");
+    let run = |args: &[&str]| {
+        let output = Command::cargo_bin("process-tx")
+            .expect("Binary not found")
+            .args(args)
+            .arg("tests/data/input1.csv")
+            .output()
+            .expect("Failed to execute command");
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).expect("Output not valid UTF-8")
+    };
+
+    let mapped = run(&["--mmap"]);
+    compare_report(&expected, &mapped).expect("Output differs from expected_output1.csv");
+    assert_eq!(mapped, run(&[]));
+
+    // A pipe cannot be mapped and is read as without --mmap
+    let input = fs::read("tests/data/input1.csv").expect("Failed to read input1.csv");
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .args(["--no-timing", "--mmap", "/dev/stdin"])
+        .write_stdin(input)
+        .output()
+        .expect("Failed to execute command");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), mapped);
+    let stderr = String::from_utf8(output.stderr).expect("Output not valid UTF-8");
+    assert!(stderr.contains("reading it without --mmap"), "{stderr}");
+}
+
+// Test that --fixed-decimals only changes the formatting of the fixture reports
+#[rstest]
+#[case("input1.csv", "expected_output1.csv")]
+#[case("input2.csv", "expected_output2.csv")]
+#[case("input5.csv", "expected_output5.csv")]
+#[case("input8.csv", "expected_output8.csv")]
+fn test_fixed_decimals_matches_fixtures(#[case] input_file: &str, #[case] expected_file: &str) {
+    let expected = fs::read_to_string(Path::new("tests/data").join(expected_file))
+        .unwrap_or_else(|_| panic!("Failed to read {expected_file}"));
+
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .arg("--fixed-decimals")
+        .arg(Path::new("tests/data").join(input_file))
+        .output()
+        .expect("Failed to execute command");
+
+    let actual = String::from_utf8(output.stdout).expect("Output not valid UTF-8");
+    assert!(actual.contains(".0000,"), "{actual}");
+    if let Err(difference) = compare_report(&expected, &actual) {
+        panic!("{input_file}: {difference}");
+    }
+}
+
+// Test that --output moves the report to a file, leaving stdout empty and
+// the diagnostics on stderr
+#[test]
+fn test_output_to_file() {
+    let dir = tempfile::tempdir().expect("Failed to create directory");
+    let report = dir.path().join("accounts.csv");
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .args(["--no-timing", "--output"])
+        .arg(&report)
+        .arg(Path::new("tests/data").join("input1.csv"))
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).expect("Stderr not valid UTF-8");
+    assert!(stderr.contains("Successful transactions: "));
+    let expected_output = fs::read_to_string(Path::new("tests/data").join("expected_output1.csv"))
+        .expect("Failed to read expected output file");
+    assert_eq!(
+        fs::read_to_string(&report)
+            .expect("Failed to read report")
+            .trim_end(),
+        expected_output.replace("\r\n", "\n").trim_end()
+    );
+}
+
+// Test that the report ends with exactly one `\n`, on stdout and in a file
+#[test]
+fn test_report_ends_with_single_newline() {
+    let dir = tempfile::tempdir().expect("Failed to create directory");
+    let report = dir.path().join("accounts.csv");
+    let input = Path::new("tests/data").join("input1.csv");
+    let stdout = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .arg(&input)
+        .output()
+        .expect("Failed to execute command")
+        .stdout;
+    Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .arg("--output")
+        .arg(&report)
+        .arg(&input)
+        .assert()
+        .success();
+    let file = fs::read(&report).expect("Failed to read report");
+
+    for output in [stdout, file] {
+        assert!(output.ends_with(b"\n"));
+        assert!(!output.ends_with(b"\n\n"));
+        assert!(!output.contains(&b'\r'));
+    }
+}
+
+// Test that --table prints the report as aligned columns
+#[test]
+fn test_table_report() {
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .arg("--table")
+        .arg(Path::new("tests/data").join("input5.csv"))
+        .output()
+        .expect("Failed to execute command");
+    assert!(output.status.success());
+
+    assert_eq!(
+        String::from_utf8(output.stdout).expect("Output not valid UTF-8"),
+        "client | available | held |  total | locked\n\
+         -------+-----------+------+--------+-------\n\
+         \x20    1 |     700.0 |  0.0 |  700.0 |  false\n\
+         \x20    2 |    -500.0 |  0.0 | -500.0 |   true\n"
+    );
+}
+
+// Test that compare finds no difference with the expected report of the input
+#[test]
+fn test_compare_with_expected_output() {
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .args(["compare", "--no-timing", "--input"])
+        .arg(Path::new("tests/data").join("input1.csv"))
+        .arg("--baseline")
+        .arg(Path::new("tests/data").join("expected_output1.csv"))
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    let diff: serde_json::Value =
+        serde_json::from_slice(&output.stdout).expect("Output not valid JSON");
+    assert_eq!(
+        diff,
+        serde_json::json!({ "added": [], "removed": [], "changed": [] })
+    );
+}
+
+// Test that compare reports the field changed in a baseline written with
+// four decimal places, with the sign of the actual minus baseline value, and
+// still writes the other outputs in full
+#[test]
+fn test_compare_with_modified_baseline() {
+    let mut baseline = tempfile::NamedTempFile::new().expect("Failed to create baseline");
+    write!(
+        baseline,
+        "client,available,held,total,locked\n\
+         1,-500.0000,0.2500,-500.0000,true\n\
+         2,1000.0000,0.0000,1000.0000,false\n"
+    )
+    .expect("Failed to write baseline");
+    let ledger = tempfile::NamedTempFile::new().expect("Failed to create ledger file");
+
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .args(["compare", "--no-timing", "--input"])
+        .arg(Path::new("tests/data").join("input1.csv"))
+        .arg("--baseline")
+        .arg(baseline.path())
+        .arg("--ledger")
+        .arg(ledger.path())
+        .output()
+        .expect("Failed to execute command");
+
+    assert_eq!(output.status.code(), Some(6));
+    // The ledger is flushed before exiting with the difference code
+    let entries = fs::read_to_string(ledger.path()).expect("Failed to read ledger");
+    assert!(entries.starts_with("tx,client,bucket,delta\n"), "{entries}");
+    assert!(
+        entries.lines().count() > 1 && entries.ends_with('\n'),
+        "{entries}"
+    );
+    let diff: serde_json::Value =
+        serde_json::from_slice(&output.stdout).expect("Output not valid JSON");
+    assert_eq!(
+        diff,
+        serde_json::json!({
+            "added": [],
+            "removed": [],
+            "changed": [{
+                "client": 1,
+                "field": "held",
+                "baseline": "0.2500",
+                "actual": "0.0",
+                "delta": -0.25
+            }]
+        })
+    );
+    let stderr = String::from_utf8(output.stderr).expect("Output not valid UTF-8");
+    assert!(stderr.contains("1 fields changed"), "{stderr}");
+}
+
+// Test that --crlf ends every line of the report with CRLF, here for an
+// input exported with a BOM as Windows tools do
+#[test]
+fn test_crlf_report() {
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .arg("--crlf")
+        .arg(Path::new("tests/data").join("input1_bom.csv"))
+        .output()
+        .expect("Failed to execute command");
+    assert!(output.status.success());
+    let report = String::from_utf8(output.stdout).expect("Output not valid UTF-8");
+
+    assert!(report.ends_with("\r\n"));
+    assert_eq!(report.matches('\n').count(), report.matches("\r\n").count());
+    let expected = fs::read_to_string(Path::new("tests/data").join("expected_output1.csv"))
+        .expect("Failed to read expected_output1.csv");
+    assert_eq!(compare_report(&expected, &report), Ok(()));
+}
+
+#[test]
+fn test_locked_format_binary() {
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .args(["--locked-format", "binary"])
+        .arg(Path::new("tests/data").join("input1.csv"))
+        .output()
+        .expect("Failed to execute command");
+
+    let actual_output = String::from_utf8(output.stdout).expect("Output not valid UTF-8");
+    let expected_output = "client,available,held,total,locked\n\
+                           1,-500.0,0.0,-500.0,1\n\
+                           2,1000.0,0.0,1000.0,0";
+
+    assert_eq!(actual_output.trim_end(), expected_output);
+}
+
+#[test]
+fn test_trace_tx_narrative() {
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .args(["--trace-tx", "1"])
+        .arg(Path::new("tests/data").join("input6.csv"))
+        .output()
+        .expect("Failed to execute command");
+
+    let stdout = String::from_utf8(output.stdout).expect("Output not valid UTF-8");
+    let stderr = String::from_utf8(output.stderr).expect("Output not valid UTF-8");
+    let expected_output = fs::read_to_string("tests/data/expected_output6.csv")
+        .expect("Failed to read expected_output6.csv");
+
+    // The report is unaffected by tracing
+    assert_eq!(stdout.trim_end(), expected_output.trim_end());
+
+    // The dispute on tx 1 is narrated with the held amounts before and after
+    assert!(stderr.contains("[trace tx 1] row: type=dispute client=1 tx=1"));
+    assert!(stderr.contains(
+        "[trace tx 1] before: client=1 available=300.0 held=0.0 total=300.0 locked=false"
+    ));
+    assert!(stderr.contains(
+        "[trace tx 1] after: client=1 available=-200.0 held=500.0 total=300.0 locked=false"
+    ));
+    // The untraced withdrawal does not appear
+    assert!(!stderr.contains("tx=2"));
+}
+
+#[test]
+fn test_fail_fast_stops_on_first_error() {
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .arg("--fail-fast")
+        .arg(Path::new("tests/data").join("input2.csv"))
+        .output()
+        .expect("Failed to execute command");
+
+    let stdout = String::from_utf8(output.stdout).expect("Output not valid UTF-8");
+    let stderr = String::from_utf8(output.stderr).expect("Output not valid UTF-8");
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stdout.is_empty(), "No account rows expected, got: {stdout}");
+    assert!(stderr.contains("Insufficient funds for client 1"));
+    let input = Path::new("tests/data").join("input2.csv");
+    assert!(stderr.contains(&format!(
+        "Processing aborted on the first rejected record, at {}:3 (--fail-fast)",
+        input.display()
+    )));
+    assert!(!stderr.contains("Processing completed"));
+}
+
+#[test]
+fn test_no_timing_stderr_is_reproducible() {
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .arg("--no-timing")
+        .arg(Path::new("tests/data").join("input2.csv"))
+        .output()
+        .expect("Failed to execute command");
+
+    let stderr = String::from_utf8(output.stderr).expect("Output not valid UTF-8");
+    assert_eq!(
+        stderr,
+        "An error has occured on transaction processing : \
+         [E_INSUFFICIENT_FUNDS] Insufficient funds for client 1\n\
+         Processing completed. Successful transactions: 1. Errors encountered: 1\n\
+         Global totals: opening 0.0, deposits 100.0, withdrawals 0.0, chargebacks 0.0, \
+         refunds 0.0, fees 0.0, accounts 100.0\n"
+    );
+}
+
+// Test that a header-only input, mapped or not, reports zero totals
+#[test]
+fn test_empty_input_totals() {
+    let mut input = tempfile::NamedTempFile::new().expect("Failed to create input file");
+    writeln!(input, "type,client,tx,amount").expect("Failed to write input");
+    for args in [&["--no-timing"][..], &["--no-timing", "--mmap"]] {
+        let output = Command::cargo_bin("process-tx")
+            .expect("Binary not found")
+            .args(args)
+            .arg(input.path())
+            .output()
+            .expect("Failed to execute command");
+        assert!(output.status.success());
+        let stderr = String::from_utf8(output.stderr).expect("Output not valid UTF-8");
+        assert!(
+            stderr.ends_with(
+                "Global totals: opening 0.0, deposits 0.0, withdrawals 0.0, chargebacks 0.0, \
+                 refunds 0.0, fees 0.0, accounts 0.0\n"
+            ),
+            "{stderr}"
+        );
+    }
+}
+
+// Test that --insufficient-funds warn counts the rejected withdrawal of
+// input2.csv as skipped with a warning instead of as an error
+#[test]
+fn test_insufficient_funds_warn() {
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .args(["--no-timing", "--insufficient-funds", "warn"])
+        .arg(Path::new("tests/data").join("input2.csv"))
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).expect("Output not valid UTF-8");
+    assert_eq!(
+        stderr.lines().take(2).collect::<Vec<_>>(),
+        [
+            "Skipped transaction: [E_INSUFFICIENT_FUNDS] Insufficient funds for client 1",
+            "Processing completed. Successful transactions: 1. Errors encountered: 0. \
+             Skipped for insufficient funds: 1",
+        ]
+    );
+    let expected = fs::read_to_string(Path::new("tests/data").join("expected_output2.csv"))
+        .expect("Failed to read expected_output2.csv");
+    let report = String::from_utf8(output.stdout).expect("Output not valid UTF-8");
+    assert_eq!(compare_report(&expected, &report), Ok(()));
+}
+
+// Test that two runs with every optional output write the same bytes, so
+// that no output depends on the iteration order of a hash map
+#[test]
+fn test_outputs_are_deterministic() {
+    let mut input = tempfile::NamedTempFile::new().expect("Failed to create input file");
+    writeln!(input, "type,client,tx,amount").expect("Failed to write input");
+    for client in 1..=200_u32 {
+        let tx = client * 10;
+        writeln!(
+            input,
+            "deposit,{client},{tx},10.0\n\
+             deposit,{client},{},5.0\n\
+             withdrawal,{client},{},20.0\n\
+             dispute,{client},{tx},",
+            tx + 1,
+            tx + 2
+        )
+        .expect("Failed to write input");
+        match client % 3 {
+            0 => writeln!(input, "chargeback,{client},{tx},"),
+            1 => writeln!(input, "resolve,{client},{tx},"),
+            _ => Ok(()),
+        }
+        .expect("Failed to write input");
+    }
+    // Clients changed, unchanged and gone since the previous report
+    let mut previous = tempfile::NamedTempFile::new().expect("Failed to create previous report");
+    write!(
+        previous,
+        "client,available,held,total,locked\n\
+         1,0.0,0.0,0.0,false\n\
+         2,15.0,0.0,15.0,false\n\
+         999,1.0,0.0,1.0,false\n"
+    )
+    .expect("Failed to write previous report");
+    let files = [
+        "report.csv",
+        "rejects.csv",
+        "ledger.csv",
+        "events.csv",
+        "export.csv",
+        "dump.csv",
+        "delta.csv",
+        "state.json",
+        "commands.log",
+        "wal.bin",
+    ];
+
+    let run = |log_format: &str| {
+        let dir = tempfile::tempdir().expect("Failed to create directory");
+        let output = Command::cargo_bin("process-tx")
+            .expect("Binary not found")
+            .current_dir(dir.path())
+            .args(["--no-timing", "--error-breakdown", "--warn-held"])
+            .args(["--extended-output", "--output", "report.csv"])
+            .args(["--rejects", "rejects.csv", "--ledger", "ledger.csv"])
+            .args(["--account-events", "events.csv"])
+            .args(["--export-transactions", "export.csv"])
+            .args([
+                "--save-state",
+                "state.json",
+                "--command-log",
+                "commands.log",
+            ])
+            .args(["--wal", "wal.bin", "--trace-tx", "10", "--trace-tx", "31"])
+            .args([
+                "--dump-transactions",
+                "dump.csv",
+                "--delta-report",
+                "delta.csv",
+            ])
+            .arg("--previous")
+            .arg(previous.path())
+            .args(["--log-format", log_format])
+            .arg(input.path())
+            .output()
+            .expect("Failed to execute command");
+        assert!(output.status.success());
+        let contents: Vec<Vec<u8>> = files
+            .iter()
+            .map(|file| fs::read(dir.path().join(file)).expect("Failed to read output file"))
+            .collect();
+        (output.stdout, output.stderr, contents)
+    };
+
+    for log_format in ["text", "json"] {
+        let (stdout, stderr, contents) = run(log_format);
+        let (stdout_again, stderr_again, contents_again) = run(log_format);
+        assert_eq!(stdout, stdout_again);
+        assert_eq!(
+            stderr, stderr_again,
+            "{log_format} diagnostics differ between runs"
+        );
+        for ((file, first), second) in files.iter().zip(&contents).zip(&contents_again) {
+            assert!(!first.is_empty() || *file == "wal.bin", "{file} is empty");
+            assert!(first == second, "{file} differs between runs");
+        }
+    }
+}
+
+#[test]
+fn test_clients_allowlist() {
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .args(["--no-timing", "--clients", "2"])
+        .arg(Path::new("tests/data").join("input5.csv"))
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).expect("Output not valid UTF-8");
+    assert_eq!(
+        stdout,
+        "client,available,held,total,locked\n2,-500.0,0.0,-500.0,true\n"
+    );
+    let stderr = String::from_utf8(output.stderr).expect("Output not valid UTF-8");
+    assert_eq!(
+        stderr,
+        "Processing completed. Successful transactions: 4. Errors encountered: 0. \
+         Skipped transactions: 4\n\
+         Global totals: opening 0.0, deposits 2000.0, withdrawals 500.0, chargebacks 2000.0, \
+         refunds 0.0, fees 0.0, accounts -500.0\n"
+    );
+}
+
+// Test that the transactions of denied clients are rejected into the rejects
+// file and counted apart, leaving them out of the report
+#[test]
+fn test_deny_clients() {
+    let dir = tempfile::tempdir().expect("Failed to create directory");
+    let denied = dir.path().join("denied.csv");
+    fs::write(&denied, "client\n1\n").expect("Failed to write client list");
+    let rejects = dir.path().join("rejects.csv");
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .args(["--no-timing", "--deny-clients"])
+        .arg(&denied)
+        .arg("--rejects")
+        .arg(&rejects)
+        .arg(Path::new("tests/data").join("input5.csv"))
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).expect("Output not valid UTF-8");
+    assert_eq!(
+        stdout,
+        "client,available,held,total,locked\n2,-500.0,0.0,-500.0,true\n"
+    );
+    let stderr = String::from_utf8(output.stderr).expect("Output not valid UTF-8");
+    assert!(stderr.starts_with(
+        "Processing completed. Successful transactions: 4. Errors encountered: 0. \
+         Denied transactions: 4\n"
+    ));
+    let rejects = fs::read_to_string(rejects).expect("Failed to read rejects file");
+    assert_eq!(rejects.lines().count(), 5);
+    assert!(rejects
+        .lines()
+        .skip(1)
+        .all(|line| line.contains(",1,") && line.contains("E_CLIENT_DENIED")));
+}
+
+#[test]
+fn test_progress_lines_on_stderr() {
+    // 2 500 deposits for 25 clients, with progress every 1 000 records
+    let mut input = tempfile::NamedTempFile::new().expect("Failed to create input file");
+    writeln!(input, "type,client,tx,amount").expect("Failed to write input");
+    for tx in 1..=2_500 {
+        writeln!(input, "deposit,{},{tx},1.0", tx % 25).expect("Failed to write input");
+    }
+
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .args(["--no-timing", "--progress-every", "1000"])
+        .arg(input.path())
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).expect("Output not valid UTF-8");
+    assert_eq!(
+        stderr,
+        "Processed 1000 records\n\
+         Processed 2000 records\n\
+         Processing completed. Successful transactions: 2500. Errors encountered: 0\n\
+         Global totals: opening 0.0, deposits 2500.0, withdrawals 0.0, chargebacks 0.0, \
+         refunds 0.0, fees 0.0, accounts 2500.0\n"
+    );
+    let stdout = String::from_utf8(output.stdout).expect("Output not valid UTF-8");
+    assert!(stdout.starts_with("client,available,held,total,locked\n0,100.0,"));
+    assert_eq!(stdout.lines().count(), 26);
+}
+
+#[test]
+fn test_merge_states_of_client_shards() {
+    let dir = tempfile::tempdir().expect("Failed to create temporary directory");
+    let input = Path::new("tests/data").join("input5.csv");
+
+    // Process each client of the fixture in its own shard
+    let mut snapshots = Vec::new();
+    for client in ["1", "2"] {
+        let snapshot = dir.path().join(format!("client{client}.snap"));
+        Command::cargo_bin("process-tx")
+            .expect("Binary not found")
+            .args(["--no-timing", "--clients", client, "--save-state"])
+            .arg(&snapshot)
+            .arg(&input)
+            .assert()
+            .success();
+        snapshots.push(snapshot);
+    }
+
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .arg("--merge-states")
+        .args(&snapshots)
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    let expected_output = fs::read_to_string(Path::new("tests/data").join("expected_output5.csv"))
+        .expect("Failed to read expected output file");
+    let stdout = String::from_utf8(output.stdout).expect("Output not valid UTF-8");
+    assert_eq!(stdout.trim(), expected_output.trim());
+}
+
+// Test that an account whose only transaction was rejected is not reported
+#[test]
+fn test_rejected_only_account_not_reported() {
+    let mut input = tempfile::NamedTempFile::new().expect("Failed to create input file");
+    write!(
+        input,
+        "type,client,tx,amount\n\
+         withdrawal,7,1,5.0\n"
+    )
+    .expect("Failed to write input");
+
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .arg("--no-timing")
+        .arg(input.path())
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).expect("Output not valid UTF-8");
+    assert_eq!(stdout, "client,available,held,total,locked\n");
+}
+
+#[test]
+fn test_skip_inactive_accounts() {
+    // Client 2 only appears through a dispute of an unknown transaction
+    let mut input = tempfile::NamedTempFile::new().expect("Failed to create input file");
+    write!(
+        input,
+        "type,client,tx,amount\n\
+         deposit,1,1,10.0\n\
+         dispute,2,99,\n\
+         withdrawal,1,2,4.0\n\
+         withdrawal,1,3,50.0\n"
+    )
+    .expect("Failed to write input");
+
+    let run = |args: &[&str]| {
+        let output = Command::cargo_bin("process-tx")
+            .expect("Binary not found")
+            .arg("--no-timing")
+            .args(args)
+            .arg(input.path())
+            .output()
+            .expect("Failed to execute command");
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).expect("Output not valid UTF-8")
+    };
+
+    assert_eq!(
+        run(&[]),
+        "client,available,held,total,locked\n\
+         1,6.0,0.0,6.0,false\n"
+    );
+    assert_eq!(
+        run(&["--skip-inactive", "--extended-output"]),
+        "client,available,held,total,locked,last_tx,peak_total,peak_held,open_disputes,\
+         chargeback_losses\n\
+         1,6.0,0.0,6.0,false,2,10.0,0.0,0,0.0\n"
+    );
+}
+
+#[test]
+fn test_pending_deposits() {
+    let mut input = tempfile::NamedTempFile::new().expect("Failed to create input file");
+    write!(
+        input,
+        "type,client,tx,amount\n\
+         deposit,1,1,10.0\n\
+         deposit,1,2,2.5\n\
+         withdrawal,1,3,4.0\n\
+         settle,1,1,\n\
+         withdrawal,1,4,4.0\n\
+         deposit,2,5,1.0\n\
+         dispute,2,5,\n"
+    )
+    .expect("Failed to write input");
+
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .arg("--no-timing")
+        .arg("--pending-deposits")
+        .arg(input.path())
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).expect("Output not valid UTF-8"),
+        "client,available,held,total,locked,pending\n\
+         1,6.0,0.0,8.5,false,2.5\n\
+         2,0.0,1.0,1.0,false,0.0\n"
+    );
+}
+
+#[test]
+fn test_dust_threshold() {
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .args(["--no-timing", "--dust-threshold", "0.0001"])
+        .arg(Path::new("tests/data").join("input_dust.csv"))
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    let expected_output =
+        fs::read_to_string(Path::new("tests/data").join("expected_output_dust.csv"))
+            .expect("Failed to read expected output file");
+    assert_eq!(
+        String::from_utf8(output.stdout).expect("Output not valid UTF-8"),
+        expected_output
+    );
+    let stderr = String::from_utf8(output.stderr).expect("Stderr not valid UTF-8");
+    assert!(stderr.contains("Successful transactions: 8. Errors encountered: 0\n"));
+    assert!(stderr.contains("Dust transactions skipped: 3\n"));
+}
+
+// Test that withdrawals above the threshold pay a fee credited to the fee
+// account, and that the ledger records it
+#[test]
+fn test_withdrawal_fee() {
+    let dir = tempfile::tempdir().expect("Failed to create directory");
+    let input = dir.path().join("input.csv");
+    fs::write(
+        &input,
+        "type,client,tx,amount\n\
+         deposit,1,1,5000.0\n\
+         withdrawal,1,2,1000.0\n\
+         withdrawal,1,3,2000.0\n\
+         deposit,2,4,1005.0\n\
+         withdrawal,2,5,1001.0\n",
+    )
+    .expect("Failed to write input");
+    let ledger = dir.path().join("ledger.csv");
+
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .args([
+            "--no-timing",
+            "--fee",
+            "withdrawal:1000:0.01:99",
+            "--ledger",
+        ])
+        .arg(&ledger)
+        .arg(&input)
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).expect("Output not valid UTF-8"),
+        "client,available,held,total,locked\n\
+         1,1980.0,0.0,1980.0,false\n\
+         2,1005.0,0.0,1005.0,false\n\
+         99,20.0,0.0,20.0,false\n"
+    );
+    let stderr = String::from_utf8(output.stderr).expect("Stderr not valid UTF-8");
+    assert!(stderr.contains("[E_INSUFFICIENT_FUNDS]"));
+    assert!(stderr.contains("Fees collected: 20.0\n"));
+    let ledger = fs::read_to_string(&ledger).expect("Failed to read ledger");
+    assert!(ledger.contains(
+        "\n3,1,available,-2020\n3,1,external,2000\n3,1,fee,20\n3,99,available,20\n3,99,fee,-20\n"
+    ));
+}
+
+// Test that replaying the write-ahead log of a run rebuilds its accounts, and
+// that a recovered run continues with the rest of the input
+#[test]
+fn test_wal_recovery() {
+    let input = fs::read_to_string(Path::new("tests/data").join("input1.csv"))
+        .expect("Failed to read input file");
+    let expected_output = fs::read_to_string(Path::new("tests/data").join("expected_output1.csv"))
+        .expect("Failed to read expected output file");
+    let (head, tail) = input.split_at(input.find("dispute").expect("No dispute row"));
+    let dir = tempfile::tempdir().expect("Failed to create directory");
+    let wal = dir.path().join("run.wal");
+    let write = |name: &str, contents: &str| {
+        let path = dir.path().join(name);
+        fs::write(&path, contents).expect("Failed to write input");
+        path
+    };
+    let run = |args: &[&str], input: &Path| {
+        let output = Command::cargo_bin("process-tx")
+            .expect("Binary not found")
+            .arg("--no-timing")
+            .args(args)
+            .arg(&wal)
+            .arg(input)
+            .output()
+            .expect("Failed to execute command");
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).expect("Output not valid UTF-8")
+    };
+
+    let full = write("full.csv", &input);
+    assert_eq!(run(&["--wal"], &full).trim(), expected_output.trim());
+    let empty = write("empty.csv", "type,client,tx,amount\n");
+    assert_eq!(
+        run(&["--wal-recover"], &empty).trim(),
+        expected_output.trim()
+    );
+
+    let head = write("head.csv", head);
+    let tail = write("tail.csv", &format!("type,client,tx,amount\n{tail}"));
+    run(&["--wal"], &head);
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .arg("--no-timing")
+        .arg("--wal-recover")
+        .arg(&wal)
+        .arg("--wal")
+        .arg(&wal)
+        .arg(&tail)
+        .output()
+        .expect("Failed to execute command");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout)
+            .expect("Output not valid UTF-8")
+            .trim(),
+        expected_output.trim()
+    );
+    // The log now covers both runs
+    assert_eq!(
+        run(&["--wal-recover"], &empty).trim(),
+        expected_output.trim()
+    );
+
+    // A log other than the recovered one starts with the recovered records
+    let other = dir.path().join("other.wal");
+    Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .arg("--wal-recover")
+        .arg(&wal)
+        .arg("--wal")
+        .arg(&other)
+        .arg(&empty)
+        .assert()
+        .success();
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .arg("--no-timing")
+        .arg("--wal-recover")
+        .arg(&other)
+        .arg(&empty)
+        .output()
+        .expect("Failed to execute command");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout)
+            .expect("Output not valid UTF-8")
+            .trim(),
+        expected_output.trim()
+    );
+
+    // Saving a snapshot empties the log
+    let snapshot = dir.path().join("state.json");
+    let snapshot = snapshot.to_str().expect("Path not valid UTF-8");
+    run(&["--save-state", snapshot, "--wal"], &full);
+    assert!(fs::read(&wal).expect("Failed to read log").is_empty());
+}
+
+// Test that a run resumed from the state saved after half of the input
+// reports the same accounts as an uninterrupted run
+#[test]
+fn test_resume_matches_uninterrupted_run() {
+    let input = fs::read_to_string(Path::new("tests/data").join("input5.csv"))
+        .expect("Failed to read input file");
+    let expected_output = fs::read_to_string(Path::new("tests/data").join("expected_output5.csv"))
+        .expect("Failed to read expected output file");
+    let half = input.match_indices('\n').nth(4).expect("Input too short").0 + 1;
+    let dir = tempfile::tempdir().expect("Failed to create directory");
+    let head = dir.path().join("head.csv");
+    fs::write(&head, &input[..half]).expect("Failed to write input");
+    let full = dir.path().join("full.csv");
+    fs::write(&full, &input).expect("Failed to write input");
+    let state = dir.path().join("state.json");
+    let run = |args: &[&Path]| {
+        let output = Command::cargo_bin("process-tx")
+            .expect("Binary not found")
+            .arg("--no-timing")
+            .args(args)
+            .output()
+            .expect("Failed to execute command");
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).expect("Output not valid UTF-8")
+    };
+
+    // The run dies after the first half, which it saved
+    run(&[Path::new("--save-state"), &state, &head]);
+    let resumed = run(&[Path::new("--resume"), &state, &full]);
+    compare_report(&expected_output, &resumed).expect("Resumed run differs");
+
+    // Resuming saves a cursor at the end of the file, past every record
+    run(&[
+        Path::new("--resume"),
+        &state,
+        Path::new("--save-state"),
+        &state,
+        &full,
+    ]);
+    let resumed = run(&[Path::new("--resume"), &state, &full]);
+    compare_report(&expected_output, &resumed).expect("Resumed run differs");
+}
+
+// Test that resuming refuses an input whose processed part changed
+#[test]
+fn test_resume_changed_file_refused() {
+    let dir = tempfile::tempdir().expect("Failed to create directory");
+    let input = dir.path().join("input.csv");
+    let state = dir.path().join("state.json");
+    fs::write(&input, "type,client,tx,amount\ndeposit,1,1,10.0\n").expect("Failed to write input");
+    Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .arg("--save-state")
+        .arg(&state)
+        .arg(&input)
+        .assert()
+        .success();
+
+    fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,1,1,90.0\ndeposit,1,2,5.0\n",
+    )
+    .expect("Failed to write input");
+    Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .arg("--resume")
+        .arg(&state)
+        .arg(&input)
+        .assert()
+        .code(1)
+        .stdout("")
+        .stderr(predicates::str::contains(
+            "Input differs from the file the state was saved from",
+        ));
+}
+
+// Test that a run aborted by --fail-fast saves its state before the record
+// it aborted on, which the resumed run reads again once fixed
+#[test]
+fn test_resume_after_abort() {
+    let dir = tempfile::tempdir().expect("Failed to create directory");
+    let input = dir.path().join("input.csv");
+    let state = dir.path().join("state.json");
+    fs::write(
+        &input,
+        "type,client,tx,amount\n\
+         deposit,1,1,10.0\n\
+         withdrawal,1,2,50.0\n\
+         deposit,2,3,5.0\n",
+    )
+    .expect("Failed to write input");
+    Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .args(["--no-timing", "--fail-fast", "--save-state"])
+        .arg(&state)
+        .arg(&input)
+        .assert()
+        .code(2)
+        .stdout("");
+
+    fs::write(
+        &input,
+        "type,client,tx,amount\n\
+         deposit,1,1,10.0\n\
+         withdrawal,1,2,5.0\n\
+         deposit,2,3,5.0\n",
+    )
+    .expect("Failed to write input");
+    Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .args(["--no-timing", "--resume"])
+        .arg(&state)
+        .arg(&input)
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked\n\
+             1,5.0,0.0,5.0,false\n\
+             2,5.0,0.0,5.0,false\n",
+        );
+}
+
+// Test that a run killed midway resumes from its last checkpoint and ends
+// with the report of an uninterrupted run
+#[test]
+fn test_resume_after_kill() {
+    let dir = tempfile::tempdir().expect("Failed to create directory");
+    let input = dir.path().join("large.csv");
+    let state = dir.path().join("state.json");
+    Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .args(["generate", "--rows", "100000", "--out"])
+        .arg(&input)
+        .assert()
+        .success();
+    let report = |output: std::process::Output| {
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).expect("Output not valid UTF-8")
+    };
+    let expected = report(
+        Command::cargo_bin("process-tx")
+            .expect("Binary not found")
+            .arg("--no-timing")
+            .arg(&input)
+            .output()
+            .expect("Failed to execute command"),
+    );
+
+    let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin("process-tx"))
+        .args(["--no-timing", "--checkpoint-every", "1000", "--save-state"])
+        .arg(&state)
+        .arg(&input)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .expect("Failed to start process");
+    while !state.exists() {
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    child.kill().expect("Failed to kill process");
+    child.wait().expect("Failed to wait for process");
+
+    let resumed = report(
+        Command::cargo_bin("process-tx")
+            .expect("Binary not found")
+            .arg("--no-timing")
+            .arg("--resume")
+            .arg(&state)
+            .arg(&input)
+            .output()
+            .expect("Failed to execute command"),
+    );
+    compare_report(&expected, &resumed).expect("Resumed run differs");
+}
+
+// Test that replaying the command log of a run with rejections rebuilds the
+// state it saved
+#[test]
+fn test_command_log_replay() {
+    let dir = tempfile::tempdir().expect("Failed to create directory");
+    let input = dir.path().join("input.csv");
+    fs::write(
+        &input,
+        "type,client,tx,amount\n\
+         deposit,1,1,100.0\n\
+         withdrawal,1,2,250.0\n\
+         deposit,2,3,40.0\n\
+         dispute,2,3,\n\
+         resolve,2,7,\n\
+         chargeback,2,3,\n\
+         deposit,2,4,10.0\n\
+         withdrawal,1,5,30.0\n",
+    )
+    .expect("Failed to write input");
+    let log = dir.path().join("run.log");
+    let state = dir.path().join("state.json");
+
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .arg("--no-timing")
+        .arg("--command-log")
+        .arg(&log)
+        .arg("--save-state")
+        .arg(&state)
+        .arg(&input)
+        .output()
+        .expect("Failed to execute command");
+    assert!(output.status.success());
+
+    let saved = process_tx::snapshot::load(fs::File::open(&state).expect("Failed to open state"))
+        .expect("Failed to load state");
+    let mut replayed = process_tx::engine::Engine::new();
+    assert_eq!(replayed.replay_log(&log).expect("Failed to replay"), 8);
+    assert_eq!(replayed.all_account_views(), saved.all_account_views());
+    assert_eq!(replayed.transactions_by_id(), saved.transactions_by_id());
+}
+
+#[test]
+fn test_reference_grace_reorders_disputes() {
+    let mut input = tempfile::NamedTempFile::new().expect("Failed to create input file");
+    write!(
+        input,
+        "type,client,tx,amount\n\
+         dispute,1,3,\n\
+         deposit,1,1,5.0\n\
+         deposit,1,3,10.0\n\
+         resolve,1,8,\n"
+    )
+    .expect("Failed to write input");
+
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .args(["--no-timing", "--reference-grace", "100"])
+        .arg(input.path())
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).expect("Output not valid UTF-8");
+    assert_eq!(
+        stdout,
+        "client,available,held,total,locked\n1,5.0,10.0,15.0,false\n"
+    );
+    let stderr = String::from_utf8(output.stderr).expect("Output not valid UTF-8");
+    assert_eq!(
+        stderr,
+        "An error has occured on transaction processing : [E_TX_NOT_FOUND] \
+         Transaction ID 8 not found for client 1\n\
+         Processing completed. Successful transactions: 3. Errors encountered: 1\n\
+         Out-of-order references: 2 parked, 1 matched late, 1 expired\n\
+         Global totals: opening 0.0, deposits 15.0, withdrawals 0.0, chargebacks 0.0, \
+         refunds 0.0, fees 0.0, accounts 15.0\n"
+    );
+}
+
+#[test]
+fn test_max_transactions_aborts() {
+    let mut input = tempfile::NamedTempFile::new().expect("Failed to create input file");
+    write!(
+        input,
+        "type,client,tx,amount\n\
+         deposit,1,1,10.0\n\
+         deposit,2,2,5.0\n\
+         dispute,1,1,\n\
+         deposit,3,3,1.0\n\
+         deposit,4,4,1.0\n"
+    )
+    .expect("Failed to write input");
+    let run = |flags: &[&str]| {
+        Command::cargo_bin("process-tx")
+            .expect("Binary not found")
+            .args(["--no-timing", "--max-transactions", "2"])
+            .args(flags)
+            .arg(input.path())
+            .output()
+            .expect("Failed to execute command")
+    };
+
+    // Stops at tx 3, the first deposit past the cap, without a report
+    let output = run(&[]);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).expect("Output not valid UTF-8");
+    assert_eq!(
+        stderr,
+        "Processing aborted: [E_CAPACITY_EXCEEDED] Limit of 2 stored transactions reached \
+         (3 accounts, 2 stored transactions)\n"
+    );
+
+    // The accounts processed so far are still reported
+    let output = run(&["--report-on-abort"]);
+    assert_eq!(output.status.code(), Some(3));
+    let stdout = String::from_utf8(output.stdout).expect("Output not valid UTF-8");
+    assert_eq!(
+        stdout,
+        "client,available,held,total,locked\n\
+         1,0.0,10.0,10.0,false\n\
+         2,5.0,0.0,5.0,false\n"
+    );
+}
+
+#[test]
+fn test_decimal_separator() {
+    // Input amounts keep the dot whatever the output separator
+    let mut input = tempfile::NamedTempFile::new().expect("Failed to create input file");
+    write!(
+        input,
+        "type,client,tx,amount\n\
+         deposit,1,1,1000.12\n\
+         withdrawal,1,2,0.5\n"
+    )
+    .expect("Failed to write input");
+
+    let run = |args: &[&str]| {
+        let output = Command::cargo_bin("process-tx")
+            .expect("Binary not found")
+            .args(["--no-timing", "--decimal-separator", ","])
+            .args(args)
+            .arg(input.path())
+            .output()
+            .expect("Failed to execute command");
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).expect("Output not valid UTF-8")
+    };
+
+    assert_eq!(
+        run(&[]),
+        "client,available,held,total,locked\n\
+         1,\"999,62\",\"0,0\",\"999,62\",false\n"
+    );
+
+    // Unquoted, the comma would split every amount in two fields
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .args(["--decimal-separator", ",", "--quote", "never"])
+        .arg(input.path())
+        .output()
+        .expect("Failed to execute command");
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).expect("Output not valid UTF-8");
+    assert!(
+        stderr.contains("cannot be combined with '--quote never'"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_error_breakdown() {
+    // Client 7 overdraws repeatedly, client 2 disputes an unknown transaction
+    let mut input = tempfile::NamedTempFile::new().expect("Failed to create input file");
+    writeln!(
+        input,
+        "type,client,tx,amount\ndeposit,7,1,1.0\ndispute,2,99,"
+    )
+    .expect("Failed to write input");
+    for tx in 2..=6 {
+        writeln!(input, "withdrawal,7,{tx},5.0").expect("Failed to write input");
+    }
+    writeln!(input, "resolve,7,1,").expect("Failed to write input");
+
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .args(["--no-timing", "--error-breakdown"])
+        .arg(input.path())
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).expect("Output not valid UTF-8");
+    assert!(stderr.ends_with(
+        "Processing completed. Successful transactions: 1. Errors encountered: 7\n\
+         Errors by client (top 2):\n\
+         client     errors  codes\n     \
+         7          6  E_INSUFFICIENT_FUNDS=5 E_NOT_UNDER_DISPUTE=1\n     \
+         2          1  E_TX_NOT_FOUND=1\n\
+         Global totals: opening 0.0, deposits 1.0, withdrawals 0.0, chargebacks 0.0, \
+         refunds 0.0, fees 0.0, accounts 1.0\n"
+    ));
+}
+
+#[test]
+fn test_rejects_file_keeps_extra_columns() {
+    let mut input = tempfile::NamedTempFile::new().expect("Failed to create input file");
+    write!(
+        input,
+        "client,type,memo,tx,amount,channel\n\
+         1,deposit,salary,1,10.0,web\n\
+         1,withdrawal,\"rent, march\",2,50.0,atm\n\
+         1,dispute,,9,,phone\n"
+    )
+    .expect("Failed to write input");
+    let rejects = tempfile::NamedTempFile::new().expect("Failed to create rejects file");
+    let run = |flags: &[&str]| {
+        let output = Command::cargo_bin("process-tx")
+            .expect("Binary not found")
+            .arg("--no-timing")
+            .arg("--rejects")
+            .arg(rejects.path())
+            .args(flags)
+            .arg(input.path())
+            .output()
+            .expect("Failed to execute command");
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8(output.stdout).expect("Output not valid UTF-8"),
+            "client,available,held,total,locked\n1,10.0,0.0,10.0,false\n"
+        );
+        fs::read_to_string(rejects.path()).expect("Failed to read rejects file")
+    };
+
+    // Unknown columns are ignored unless captured
+    let file = input.path().display();
+    assert_eq!(
+        run(&[]),
+        format!(
+            "type,client,tx,amount,note,code,error,file,line\n\
+             withdrawal,1,2,50,,E_INSUFFICIENT_FUNDS,Insufficient funds for client 1,{file},3\n\
+             dispute,1,9,,,E_TX_NOT_FOUND,Transaction ID 9 not found for client 1,{file},4\n"
+        )
+    );
+    assert_eq!(
+        run(&["--capture-extra"]),
+        format!(
+            "type,client,tx,amount,note,code,error,file,line,memo,channel\n\
+             withdrawal,1,2,50,,E_INSUFFICIENT_FUNDS,Insufficient funds for client 1,{file},3,\
+             \"rent, march\",atm\n\
+             dispute,1,9,,,E_TX_NOT_FOUND,Transaction ID 9 not found for client 1,{file},4,,phone\n"
+        )
+    );
+}
+
+#[test]
+fn test_warn_held() {
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .args(["--no-timing", "--warn-held"])
+        .arg(Path::new("tests/data").join("input6.csv"))
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).expect("Output not valid UTF-8");
+    assert_eq!(
+        stderr,
+        "Processing completed. Successful transactions: 3. Errors encountered: 0\n\
+         Warning: client 1 has 500.0 held by unresolved disputes\n\
+         Global totals: opening 0.0, deposits 500.0, withdrawals 200.0, chargebacks 0.0, \
+         refunds 0.0, fees 0.0, accounts 300.0\n"
+    );
+}
+
+#[test]
+fn test_ledger_file() {
+    let ledger = tempfile::NamedTempFile::new().expect("Failed to create ledger file");
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .arg("--no-timing")
+        .arg("--ledger")
+        .arg(ledger.path())
+        .arg(Path::new("tests/data").join("input1.csv"))
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(ledger.path()).expect("Failed to read ledger file"),
+        "tx,client,bucket,delta\n\
+         1,1,available,1000\n\
+         1,1,external,-1000\n\
+         2,1,available,-500\n\
+         2,1,external,500\n\
+         1,1,available,-1000\n\
+         1,1,held,1000\n\
+         1,1,held,-1000\n\
+         1,1,external,1000\n\
+         3,2,available,2000\n\
+         3,2,external,-2000\n\
+         4,2,available,-1000\n\
+         4,2,external,1000\n\
+         3,2,available,-2000\n\
+         3,2,held,2000\n\
+         3,2,available,2000\n\
+         3,2,held,-2000\n"
+    );
+}
+
+#[test]
+fn test_account_events_file() {
+    let events = tempfile::NamedTempFile::new().expect("Failed to create events file");
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .arg("--no-timing")
+        .arg("--account-events")
+        .arg(events.path())
+        .arg(Path::new("tests/data").join("input5.csv"))
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(events.path()).expect("Failed to read events file"),
+        "event,client,tx,reason,charged_back\n\
+         opened,1,1,,\n\
+         opened,2,2,,\n\
+         locked,2,2,chargeback,2000.0\n"
+    );
+}
+
+// Test that --dump-transactions writes the stored transactions matching
+// every filter, by transaction id
+#[test]
+fn test_dump_transactions() {
+    let mut input = tempfile::NamedTempFile::new().expect("Failed to create input file");
+    write!(
+        input,
+        "type,client,tx,amount\n\
+         deposit,1,1,10.0\n\
+         deposit,2,2,250.5\n\
+         deposit,1,3,120.0\n\
+         withdrawal,1,4,100.0\n\
+         dispute,2,2,\n\
+         resolve,2,2,\n\
+         dispute,2,2,\n\
+         dispute,1,1,\n\
+         deposit,3,5,500.0\n"
+    )
+    .expect("Failed to write input");
+    let dump = tempfile::NamedTempFile::new().expect("Failed to create dump file");
+
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .arg("--dump-transactions")
+        .arg(dump.path())
+        .args(["--filter-type", "deposit", "--filter-min-amount", "100"])
+        .arg(input.path())
+        .output()
+        .expect("Failed to execute command");
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(dump.path()).expect("Failed to read dump file"),
+        "tx,client,type,amount,disputed,disputes,pending,refunded\n\
+         2,2,deposit,250.5,true,2,false,false\n\
+         3,1,deposit,120.0,false,0,false,false\n\
+         5,3,deposit,500.0,false,0,false,false\n"
+    );
+
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .arg("--dump-transactions")
+        .arg(dump.path())
+        .args(["--filter-disputed", "--filter-min-amount", "100"])
+        .arg(input.path())
+        .output()
+        .expect("Failed to execute command");
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(dump.path()).expect("Failed to read dump file"),
+        "tx,client,type,amount,disputed,disputes,pending,refunded\n\
+         2,2,deposit,250.5,true,2,false,false\n"
+    );
+}
+
+#[test]
+fn test_max_field_len_refuses_long_amount() {
+    let mut input = tempfile::NamedTempFile::new().expect("Failed to create input file");
+    write!(
+        input,
+        "type,client,tx,amount\n\
+         deposit,1,1,1.0\n\
+         deposit,1,2,{}\n\
+         deposit,1,3,2.0\n",
+        "9".repeat(10_000_000)
+    )
+    .expect("Failed to write input");
+
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .args(["--no-timing", "--max-field-len", "64"])
+        .arg(input.path())
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).expect("Output not valid UTF-8"),
+        "client,available,held,total,locked\n1,3.0,0.0,3.0,false\n"
+    );
+    assert_eq!(
+        String::from_utf8(output.stderr).expect("Output not valid UTF-8"),
+        "Refused transaction record at line 3: field `amount` is 10000000 bytes long, \
+         above the limit of 64 (--max-field-len).\n\
+         Processing completed. Successful transactions: 2. Errors encountered: 0. \
+         Oversized records: 1\n\
+         Global totals: opening 0.0, deposits 3.0, withdrawals 0.0, chargebacks 0.0, \
+         refunds 0.0, fees 0.0, accounts 3.0\n"
+    );
+}
+
+#[test]
+fn test_nonzero_only() {
+    // Client 2 only appears through a dispute of an unknown transaction,
+    // client 3 withdraws everything and client 4 is locked at zero
+    let mut input = tempfile::NamedTempFile::new().expect("Failed to create input file");
+    write!(
+        input,
+        "type,client,tx,amount\n\
+         deposit,1,1,10.0\n\
+         dispute,2,99,\n\
+         deposit,3,2,5.0\n\
+         withdrawal,3,3,5.0\n\
+         deposit,4,4,2.0\n\
+         dispute,4,4,\n\
+         chargeback,4,4,\n"
+    )
+    .expect("Failed to write input");
+
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .args(["--no-timing", "--nonzero-only"])
+        .arg(input.path())
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).expect("Output not valid UTF-8"),
+        "client,available,held,total,locked\n\
+         1,10.0,0.0,10.0,false\n\
+         4,0.0,0.0,0.0,true\n"
+    );
+}
+
+#[test]
+fn test_generate_then_run() {
+    let dir = tempfile::tempdir().expect("Failed to create temporary directory");
+    let generate = |name: &str| {
+        let path = dir.path().join(name);
+        Command::cargo_bin("process-tx")
+            .expect("Binary not found")
+            .args(["generate", "--rows", "5000", "--clients", "50"])
+            .args(["--dispute-rate", "0.05", "--seed", "42", "--out"])
+            .arg(&path)
+            .assert()
+            .success();
+        fs::read(path).expect("Failed to read generated file")
+    };
+    let generated = generate("first.csv");
+    assert_eq!(generated, generate("second.csv"));
+
+    let rejects = dir.path().join("rejects.csv");
+    Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .args(["run", "--no-timing", "--rejects"])
+        .arg(&rejects)
+        .arg(dir.path().join("first.csv"))
+        .assert()
+        .success();
+    // Only deposits and withdrawals may be rejected, never a reference
+    let rejects = fs::read_to_string(rejects).expect("Failed to read rejects file");
+    assert!(rejects
+        .lines()
+        .skip(1)
+        .all(|line| line.starts_with("deposit,") || line.starts_with("withdrawal,")));
+}
+
+#[test]
+fn test_corrupt_rows_mid_file() {
+    let mut input = tempfile::NamedTempFile::new().expect("Failed to create input file");
+    input
+        .write_all(
+            b"type,client,tx,amount\n\
+              deposit,1,1,10.0\n\
+              deposit,1,2\n\
+              deposit,2,3,\xff\xfe\n\
+              deposit,2,4,5.0\n",
+        )
+        .expect("Failed to write input");
+
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .arg("--no-timing")
+        .arg(input.path())
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).expect("Output not valid UTF-8"),
+        "client,available,held,total,locked\n\
+         1,10.0,0.0,10.0,false\n\
+         2,5.0,0.0,5.0,false\n"
     );
+    let stderr = String::from_utf8(output.stderr).expect("Output not valid UTF-8");
+    assert!(stderr.contains("Failed to parse transaction record at line 3"));
+    assert!(stderr.contains("Failed to parse transaction record at line 4"));
+}
+
+// Test that --log-format json prints every diagnostic as a JSON object with
+// typed fields
+#[test]
+fn test_json_log_format() {
+    let mut input = tempfile::NamedTempFile::new().expect("Failed to create input file");
+    write!(
+        input,
+        "type,client,tx,amount\n\
+         deposit,1,1,10.0\n\
+         withdrawal,1,2,50.0\n\
+         deposit,2,3,abc\n"
+    )
+    .expect("Failed to write input");
+
+    let output = Command::cargo_bin("process-tx")
+        .expect("Binary not found")
+        .args(["--log-format", "json"])
+        .arg(input.path())
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).expect("Output not valid UTF-8");
+    let events: Vec<serde_json::Value> = stderr
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap_or_else(|e| panic!("{line}: {e}")))
+        .collect();
+    for event in &events {
+        assert!(event["level"].is_string(), "{event}");
+        assert!(event["event"].is_string(), "{event}");
+        assert!(event["message"].is_string(), "{event}");
+        for field in ["tx", "client", "code", "file", "line"] {
+            assert!(event.get(field).is_some(), "{field} missing from {event}");
+        }
+    }
+    let event = |name: &str| {
+        events
+            .iter()
+            .find(|event| event["event"] == name)
+            .unwrap_or_else(|| panic!("No {name} event in {stderr}"))
+    };
+
+    let rejected = event("rejected");
+    assert_eq!(rejected["level"], "error");
+    assert_eq!(rejected["code"], "E_INSUFFICIENT_FUNDS");
+    assert_eq!(rejected["tx"].as_u64(), Some(2));
+    assert_eq!(rejected["client"].as_u64(), Some(1));
+    let file = input.path().to_str().expect("Input path not valid UTF-8");
+    assert_eq!(rejected["file"], file);
+    assert_eq!(rejected["line"].as_u64(), Some(3));
+
+    let parse_error = event("parse_error");
+    assert_eq!(parse_error["level"], "error");
+    assert_eq!(parse_error["file"], file);
+    assert_eq!(parse_error["line"].as_u64(), Some(4));
+    assert!(parse_error["tx"].is_null() && parse_error["code"].is_null());
+
+    assert_eq!(event("summary")["level"], "info");
+}
+
+// Test that errors stopping the program are JSON objects too
+#[test]
+fn test_json_log_format_fatal_errors() {
+    let fatal = |args: &[&str]| {
+        let output = Command::cargo_bin("process-tx")
+            .expect("Binary not found")
+            .args(["--no-timing", "--log-format", "json"])
+            .args(args)
+            .output()
+            .expect("Failed to execute command");
+        assert_eq!(output.status.code(), Some(1));
+        let stderr = String::from_utf8(output.stderr).expect("Output not valid UTF-8");
+        let event: serde_json::Value =
+            serde_json::from_str(stderr.trim_end()).unwrap_or_else(|e| panic!("{stderr}: {e}"));
+        assert_eq!(event["level"], "error");
+        assert_eq!(event["event"], "fatal");
+        event["message"].as_str().unwrap_or_default().to_string()
+    };
+    assert!(fatal(&["/nonexistent.csv"]).contains("No such file or directory"));
+    assert_eq!(fatal(&["--bogus", "input.csv"]), "unknown option '--bogus'");
+}
+
+// Test that the metrics of a run reading from a pipe are scraped while it
+// waits for more input, once every row written so far is processed
+#[cfg(feature = "metrics")]
+#[test]
+fn test_metrics_endpoint_during_run() {
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpStream;
+    use std::process::Stdio;
+    use std::time::{Duration, Instant};
+
+    let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin("process-tx"))
+        .args(["--no-timing", "--metrics-addr", "127.0.0.1:0", "/dev/stdin"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start process");
+    let mut stdin = child.stdin.take().expect("No stdin");
+    // The address is printed once the header is read
+    writeln!(
+        stdin,
+        "type,client,tx,amount\n\
+         deposit,1,1,10.0\n\
+         deposit,2,2,5.0\n\
+         dispute,1,1,\n\
+         withdrawal,2,3,50.0"
+    )
+    .expect("Failed to write input");
+    stdin.flush().expect("Failed to flush input");
+    let mut stderr = BufReader::new(child.stderr.take().expect("No stderr"));
+    let mut line = String::new();
+    stderr.read_line(&mut line).expect("Failed to read stderr");
+    let addr = line
+        .trim()
+        .strip_prefix("Serving metrics on http://")
+        .and_then(|rest| rest.strip_suffix("/metrics"))
+        .expect("Metrics address not printed")
+        .to_string();
+    // Keep draining the rejected rows so that the run never blocks on stderr
+    let drain = std::thread::spawn(move || std::io::copy(&mut stderr, &mut std::io::sink()));
+
+    let scrape = || {
+        let mut stream = TcpStream::connect(&addr).expect("Failed to connect");
+        write!(stream, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .expect("Failed to send request");
+        let mut response = String::new();
+        stream
+            .read_to_string(&mut response)
+            .expect("Failed to read response");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        response
+    };
+    // Scrape until the rows written so far are processed
+    let scrape_after = |rows: u64| {
+        let expected = format!("\nprocess_tx_rows_processed_total {rows}\n");
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let response = scrape();
+            if response.contains(&expected) {
+                return response;
+            }
+            assert!(Instant::now() < deadline, "Rows not processed: {response}");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    };
+
+    let response = scrape_after(4);
+    for name in [
+        "process_tx_rows_processed_total",
+        "process_tx_errors_total",
+        "process_tx_accounts_created_total",
+        "process_tx_disputes_open",
+        "process_tx_rows_per_second",
+    ] {
+        assert!(
+            response.contains(&format!("# TYPE {name} ")),
+            "Missing {name}"
+        );
+    }
+    assert!(response.contains("\nprocess_tx_accounts_created_total 2\n"));
+    assert!(response.contains("\nprocess_tx_disputes_open 1\n"));
+    assert!(response.contains("\nprocess_tx_errors_total{code=\"E_INSUFFICIENT_FUNDS\"} 1\n"));
+
+    writeln!(stdin, "resolve,1,1,").expect("Failed to write input");
+    stdin.flush().expect("Failed to flush input");
+    let response = scrape_after(5);
+    assert!(response.contains("\nprocess_tx_disputes_open 0\n"));
+
+    drop(stdin);
+    assert!(child.wait().expect("Failed to wait for process").success());
+    drain
+        .join()
+        .expect("Failed to drain stderr")
+        .expect("Failed to read stderr");
 }
@@ -0,0 +1,197 @@
+use process_tx::account::{AccountView, LockReason};
+use process_tx::engine::Engine;
+use process_tx::error::Transaction as TransactionError;
+use process_tx::input::Schema;
+use process_tx::report;
+use process_tx::transaction::{DisputeState, Transaction};
+use rstest::rstest;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Row of an `expected_output*.csv` fixture.
+#[derive(Debug, Deserialize, PartialEq)]
+struct ExpectedAccount {
+    client: u16,
+    available: f64,
+    held: f64,
+    total: f64,
+    locked: bool,
+}
+
+impl From<AccountView> for ExpectedAccount {
+    fn from(view: AccountView) -> Self {
+        ExpectedAccount {
+            client: view.client,
+            available: view.available,
+            held: view.held,
+            total: view.total,
+            locked: view.locked,
+        }
+    }
+}
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new("tests/data").join(name)
+}
+
+fn read_fixture<T: for<'de> Deserialize<'de>>(name: &str) -> Vec<T> {
+    csv::Reader::from_path(fixture(name))
+        .unwrap_or_else(|e| panic!("Failed to open {name}: {e}"))
+        .deserialize()
+        .enumerate()
+        .map(|(row, record)| record.unwrap_or_else(|e| panic!("{name}, row {}: {e}", row + 1)))
+        .collect()
+}
+
+// Read an input fixture in either amount schema
+fn read_transactions(name: &str) -> Vec<Transaction> {
+    let mut rdr = csv::Reader::from_path(fixture(name))
+        .unwrap_or_else(|e| panic!("Failed to open {name}: {e}"));
+    let headers = rdr.headers().expect("Failed to read header").clone();
+    let schema = Schema::detect(headers, false).unwrap_or_else(|e| panic!("{name}: {e}"));
+    rdr.records()
+        .enumerate()
+        .map(|(row, record)| {
+            record
+                .and_then(|record| schema.read(&record))
+                .unwrap_or_else(|e| panic!("{name}, row {}: {e}", row + 1))
+        })
+        .collect()
+}
+
+/// Parse an expected output fixture into typed accounts, ordered by client.
+fn expected_accounts(name: &str) -> Vec<ExpectedAccount> {
+    read_fixture(name)
+}
+
+// The accounts the report would show, ordered by client
+fn accounts(engine: &Engine) -> Vec<ExpectedAccount> {
+    let options = report::Options::default();
+    engine
+        .accounts_by_client()
+        .filter(|account| options.includes(account))
+        .map(|account| ExpectedAccount::from(AccountView::from(account)))
+        .collect()
+}
+
+// Process an input fixture, returning the rejections in input order
+fn process(engine: &mut Engine, input: &str) -> Vec<TransactionError> {
+    read_transactions(input)
+        .into_iter()
+        .filter_map(|transaction| engine.process_transaction(transaction).err())
+        .collect()
+}
+
+// Every fixture of the binary test in tests/e2e_tests.rs, with its number of
+// rejected transactions
+#[rstest]
+#[case("input1.csv", "expected_output1.csv", 0)]
+#[case("input2.csv", "expected_output2.csv", 1)]
+#[case("input3.csv", "expected_output3.csv", 1)]
+#[case("input4.csv", "expected_output4.csv", 1)]
+#[case("input5.csv", "expected_output5.csv", 0)]
+#[case("input6.csv", "expected_output6.csv", 0)]
+#[case("input7.csv", "expected_output7.csv", 0)]
+#[case("input8.csv", "expected_output8.csv", 1)]
+#[case("input1_bom.csv", "expected_output1.csv", 0)]
+#[case("input1_extra_columns.csv", "expected_output1.csv", 0)]
+#[case("input1_minor.csv", "expected_output1.csv", 0)]
+#[case("input5_minor.csv", "expected_output5.csv", 0)]
+#[case("input_boundary_ids.csv", "expected_output_boundary_ids.csv", 1)]
+fn test_fixture_accounts(#[case] input: &str, #[case] expected: &str, #[case] errors: u64) {
+    let mut engine = Engine::new();
+    let summary = engine.process_all(read_transactions(input));
+
+    assert_eq!(summary.errors, errors, "{input}");
+    assert_eq!(accounts(&engine), expected_accounts(expected), "{input}");
+    engine.check_invariants().expect("Invariants violated");
+    engine.global_check().expect("Global totals out of balance");
+}
+
+#[test]
+fn test_fixture_dust() {
+    let mut engine = Engine::builder().dust_threshold(Some(0.0001)).build();
+    let errors = process(&mut engine, "input_dust.csv");
+
+    assert!(errors.is_empty(), "{errors:?}");
+    assert_eq!(engine.dust_skipped(), 3);
+    assert_eq!(
+        accounts(&engine),
+        expected_accounts("expected_output_dust.csv")
+    );
+    engine.global_check().expect("Global totals out of balance");
+}
+
+// Case 1: the chargeback locks client 1 while the resolve leaves the deposit
+// of client 2 undisputed
+#[test]
+fn test_fixture_chargeback_and_resolve() {
+    let mut engine = Engine::new();
+    assert!(process(&mut engine, "input1.csv").is_empty());
+
+    let client1 = engine.account(1).expect("Account not found");
+    assert_eq!(client1.lock_reason, Some(LockReason::Chargeback));
+    assert_eq!(client1.open_disputes, 0);
+    let deposit = engine.transaction(3).expect("Transaction not found");
+    assert_eq!(deposit.dispute_state, DisputeState::Undisputed);
+    assert_eq!(deposit.disputes, 1);
+}
+
+#[test]
+fn test_fixture_insufficient_funds() {
+    let mut engine = Engine::new();
+    let errors = process(&mut engine, "input2.csv");
+
+    assert!(matches!(
+        errors[..],
+        [TransactionError::InsufficientFunds(1)]
+    ));
+    assert!(engine.transaction(2).is_none());
+}
+
+#[test]
+fn test_fixture_dispute_unknown_transaction() {
+    let mut engine = Engine::new();
+    let errors = process(&mut engine, "input3.csv");
+
+    assert!(matches!(errors[..], [TransactionError::NotFound(99, 1)]));
+    assert_eq!(engine.transaction_count(), 0);
+}
+
+#[test]
+fn test_fixture_chargeback_without_dispute() {
+    let mut engine = Engine::new();
+    let errors = process(&mut engine, "input4.csv");
+
+    assert!(matches!(errors[..], [TransactionError::NotUnderDispute(1)]));
+    assert_eq!(engine.account(1).map(|account| account.locked), Some(false));
+}
+
+// Cases 6 to 8: disputes left open keep their deposits disputed, and the
+// withdrawal of case 8 only sees the funds left available
+#[rstest]
+#[case("input6.csv", &[1], 0)]
+#[case("input7.csv", &[1, 2], 0)]
+#[case("input8.csv", &[1], 1)]
+fn test_fixture_open_disputes(
+    #[case] input: &str,
+    #[case] disputed: &[u32],
+    #[case] insufficient_funds: usize,
+) {
+    let mut engine = Engine::new();
+    let errors = process(&mut engine, input);
+
+    let open: Vec<u32> = engine
+        .transactions_by_id()
+        .into_iter()
+        .filter(|(_, stored)| stored.is_disputed())
+        .map(|(tx, _)| tx)
+        .collect();
+    assert_eq!(open, disputed, "{input}");
+    let account = engine.account(1).expect("Account not found");
+    assert_eq!(account.open_disputes as usize, disputed.len(), "{input}");
+    assert_eq!(errors.len(), insufficient_funds, "{input}");
+    assert!(errors
+        .iter()
+        .all(|e| matches!(e, TransactionError::InsufficientFunds(1))));
+}